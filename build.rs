@@ -0,0 +1,4 @@
+fn main() {
+    // Generates the gRPC service in `grpc::proto`, see `proto/majordomo.proto`
+    tonic_build::compile_protos("proto/majordomo.proto").unwrap();
+}
@@ -0,0 +1,541 @@
+use std::collections::HashMap;
+use std::ops::{Deref, DerefMut};
+
+use rand::Rng;
+
+use rocket::State;
+
+use rocket_contrib::json::Json;
+
+use serde_json::json;
+
+use crate::auth::{check_admin, check_imported_key, check_scopes, hash_key, AuthHeader};
+use crate::namespaces::check_namespace;
+use crate::server::Collection;
+use crate::signing::{check_public_key, fingerprint};
+use crate::storage::{reload_api_keys, reload_handlers, Storage};
+use crate::types::{
+    AdminRequest, ApiKeyInfo, CreateKeyRequest, EnvInfo, ExportedKey, Failure, FailureKind,
+    Handler, ImportKeysRequest, KeyFormat, RevokeKeyRequest, UpdateKeyRequest, UserResponse,
+};
+
+/// Generate a new random API Key
+pub fn generate_key() -> String {
+    let bytes: [u8; 16] = rand::thread_rng().gen();
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// The key registry, sorted by hash
+///
+/// # Arguments
+///
+/// * `map` - The collection of Client API keys, by hash
+fn hashed_keys(map: &HashMap<String, ApiKeyInfo>) -> Vec<ExportedKey> {
+    let mut exported = map
+        .iter()
+        .map(|(key_hash, info)| ExportedKey {
+            key_hash: key_hash.clone(),
+            info: info.clone(),
+        })
+        .collect::<Vec<ExportedKey>>();
+    exported.sort_by(|a, b| a.key_hash.cmp(&b.key_hash));
+    exported
+}
+
+/// Rocket Endpoint which adds a batch of API Keys at once, e.g. when onboarding a new team
+///
+/// Keys which already exist have their label, contact, scopes, expiry, activation requirement
+/// and rate limit replaced, and their namespace if one is given. Whoever activated them, and
+/// their signing keys, are kept. Keys which were not provided are generated. Responds with the
+/// list of imported keys, in the order they were given, since this is the only time generated
+/// keys are ever revealed. Keys which were provided must be random, see
+/// `auth::check_imported_key`. Keys are imported all at once or not at all.
+///
+/// # Arguments
+///
+/// * `auth` - The admin key from the `Authorization` header, if any
+/// * `env` - Environment variables
+/// * `storage` - Where the keys are saved
+/// * `api_keys` - A reference to the collection of Client API keys
+/// * `post_data` - The keys to import. See `ImportKeysRequest`
+#[post("/admin/import_keys", data = "<post_data>")]
+pub fn import_keys(
+    auth: AuthHeader,
+    env: State<EnvInfo>,
+    storage: State<Storage>,
+    api_keys: Collection<String, ApiKeyInfo>,
+    post_data: Json<ImportKeysRequest>,
+) -> Json<UserResponse> {
+    let data = post_data.0;
+
+    let admin_key = auth.key_or(&data.admin_key);
+    if let Err(cause) = auth.verify(
+        &admin_key,
+        check_admin(&admin_key, &env),
+        "Invalid admin key",
+    ) {
+        return Json(UserResponse::from(cause));
+    }
+
+    if env.read_only {
+        return Json(UserResponse::read_only());
+    }
+
+    if let Err(cause) = data.keys.iter().try_for_each(|k| check_scopes(&k.scopes)) {
+        return Json(UserResponse::failure(cause));
+    }
+    // The keys the admin chose, rather than left to be generated
+    let mut chosen = data
+        .keys
+        .iter()
+        .filter_map(|k| k.key.as_deref().filter(|k| !k.is_empty()));
+    if let Err(cause) = chosen.try_for_each(check_imported_key) {
+        return Json(UserResponse::failure(cause));
+    }
+
+    let mut guard = api_keys.write().unwrap();
+    let map = guard.deref_mut();
+
+    let mut imported = Vec::new();
+    let mut changed = Vec::new();
+    // The keys as they were, to put back if the import fails part way
+    let mut previous = Vec::new();
+    let mut merged = Ok(());
+    for key in data.keys {
+        let value = key
+            .key
+            .filter(|k| !k.is_empty())
+            .unwrap_or_else(generate_key);
+        let hash = hash_key(&value);
+        if let Some(namespace) = &key.namespace {
+            merged = check_namespace(namespace, &hash, map)
+                .map_err(|cause| Failure::new(FailureKind::Conflict, cause));
+            if merged.is_err() {
+                break;
+            }
+        }
+        previous.push((hash.clone(), map.get(&hash).cloned()));
+
+        let info = map.entry(hash.clone()).or_default();
+        info.label = key.label;
+        info.contact = key.contact;
+        info.scopes = key.scopes;
+        info.expires_at = key.expires_at;
+        info.requires_activation = key.requires_activation;
+        info.rate_limit = key.rate_limit;
+        if key.namespace.is_some() {
+            info.namespace = key.namespace;
+        }
+        changed.push(hash);
+        imported.push(value);
+    }
+
+    let saved = merged.and_then(|_| {
+        storage.save_api_keys(map, &changed).map_err(|e| {
+            log_event!("keys.save_error", storage = storage.describe(), error = e);
+            Failure::new(
+                FailureKind::Internal,
+                "Server error while saving keys".into(),
+            )
+        })
+    });
+    if let Err(cause) = saved {
+        // Most recent first, in case a key was imported twice
+        for (hash, info) in previous.into_iter().rev() {
+            match info {
+                Some(info) => map.insert(hash, info),
+                None => map.remove(&hash),
+            };
+        }
+        return Json(UserResponse::from(cause));
+    }
+
+    log_event!("keys.import", count = imported.len());
+
+    Json(
+        UserResponse::success_with_raw(imported).unwrap_or(UserResponse::failure(
+            "Internal Server Error Code 5: Ping Luis Hoderlein about it".into(),
+        )),
+    )
+}
+
+/// Rocket Endpoint which exports the key registry, for backup purposes
+/// Keys are hashed, so the export can be stored without becoming a secret itself.
+///
+/// # Arguments
+///
+/// * `auth` - The admin key from the `Authorization` header, if any
+/// * `env` - Environment variables
+/// * `api_keys` - A reference to the collection of Client API keys
+/// * `post_data` - Must contain the admin key, unless it was passed in the header
+#[post("/admin/export_keys", data = "<post_data>")]
+pub fn export_keys(
+    auth: AuthHeader,
+    env: State<EnvInfo>,
+    api_keys: Collection<String, ApiKeyInfo>,
+    post_data: Option<Json<AdminRequest>>,
+) -> Json<UserResponse> {
+    let admin_key = auth.key_or(&post_data.map(|d| d.0.admin_key).unwrap_or_default());
+
+    if let Err(cause) = auth.verify(
+        &admin_key,
+        check_admin(&admin_key, &env),
+        "Invalid admin key",
+    ) {
+        return Json(UserResponse::from(cause));
+    }
+
+    let guard = api_keys.read().unwrap();
+    let exported = hashed_keys(guard.deref());
+
+    Json(
+        UserResponse::success_with_raw(exported).unwrap_or(UserResponse::failure(
+            "Internal Server Error Code 6: Ping Luis Hoderlein about it".into(),
+        )),
+    )
+}
+
+/// Rocket Endpoint which changes the label, contact, scopes, rate limit and/or namespace of an
+/// existing key
+///
+/// # Arguments
+///
+/// * `auth` - The admin key from the `Authorization` header, if any
+/// * `env` - Environment variables
+/// * `storage` - Where the keys are saved
+/// * `api_keys` - A reference to the collection of Client API keys
+/// * `post_data` - The key to update, and its new details. See `UpdateKeyRequest`
+#[post("/admin/update_key", data = "<post_data>")]
+pub fn update_key(
+    auth: AuthHeader,
+    env: State<EnvInfo>,
+    storage: State<Storage>,
+    api_keys: Collection<String, ApiKeyInfo>,
+    post_data: Json<UpdateKeyRequest>,
+) -> Json<UserResponse> {
+    let data = post_data.0;
+
+    let admin_key = auth.key_or(&data.admin_key);
+    if let Err(cause) = auth.verify(
+        &admin_key,
+        check_admin(&admin_key, &env),
+        "Invalid admin key",
+    ) {
+        return Json(UserResponse::from(cause));
+    }
+
+    if env.read_only {
+        return Json(UserResponse::read_only());
+    }
+
+    if let Err(cause) = data.scopes.as_deref().map(check_scopes).unwrap_or(Ok(())) {
+        return Json(UserResponse::failure(cause));
+    }
+    let mut signing_keys = data.signing_keys.iter().flatten();
+    if let Err(cause) = signing_keys.try_for_each(|pem| check_public_key(pem)) {
+        return Json(UserResponse::failure(cause));
+    }
+
+    let mut guard = api_keys.write().unwrap();
+    let map = guard.deref_mut();
+
+    if !map.contains_key(&data.key_hash) {
+        return Json(UserResponse::failure_of(
+            FailureKind::NotFound,
+            "Unknown key".into(),
+        ));
+    }
+    if let Some(namespace) = &data.namespace {
+        if let Err(cause) = check_namespace(namespace, &data.key_hash, map) {
+            return Json(UserResponse::failure_of(FailureKind::Conflict, cause));
+        }
+    }
+    let info = map.get_mut(&data.key_hash).unwrap();
+
+    if data.label.is_some() {
+        info.label = data.label;
+    }
+    if data.contact.is_some() {
+        info.contact = data.contact;
+    }
+    if let Some(scopes) = data.scopes {
+        info.scopes = scopes;
+    }
+    if data.rate_limit.is_some() {
+        info.rate_limit = data.rate_limit;
+    }
+    if data.namespace.is_some() {
+        info.namespace = data.namespace;
+    }
+    if let Some(signing_keys) = data.signing_keys {
+        info.signing_keys = signing_keys;
+    }
+
+    log_event!(
+        "audit.key_update",
+        key = &data.key_hash[..8.min(data.key_hash.len())],
+        label = info.label.clone().unwrap_or_default(),
+        contact = info.contact.clone().unwrap_or_default(),
+        scopes = info.scopes.join(","),
+        rate_limit = info.rate_limit.unwrap_or(env.key_rate_limit),
+        namespace = info.namespace.clone().unwrap_or_default(),
+        signing_keys = info
+            .signing_keys
+            .iter()
+            .map(|pem| fingerprint(pem))
+            .collect::<Vec<String>>()
+            .join(","),
+    );
+
+    match storage.save_api_keys(map, &[data.key_hash]) {
+        Ok(_) => Json(UserResponse::success()),
+        Err(e) => {
+            log_event!("keys.save_error", storage = storage.describe(), error = e);
+            Json(UserResponse::failure_of(
+                FailureKind::Internal,
+                "Server error while saving keys".into(),
+            ))
+        }
+    }
+}
+
+/// Rocket Endpoint which issues a new, randomly generated API Key
+///
+/// Responds with the key. This is the only time it is ever revealed, afterwards it is only
+/// listed by its hash.
+///
+/// # Arguments
+///
+/// * `auth` - The admin key from the `Authorization` header, if any
+/// * `env` - Environment variables
+/// * `storage` - Where the keys are saved
+/// * `api_keys` - A reference to the collection of Client API keys
+/// * `post_data` - The details of the new key. See `CreateKeyRequest`
+#[post("/admin/create_key", data = "<post_data>")]
+pub fn create_key(
+    auth: AuthHeader,
+    env: State<EnvInfo>,
+    storage: State<Storage>,
+    api_keys: Collection<String, ApiKeyInfo>,
+    post_data: Json<CreateKeyRequest>,
+) -> Json<UserResponse> {
+    let data = post_data.0;
+
+    let admin_key = auth.key_or(&data.admin_key);
+    if let Err(cause) = auth.verify(
+        &admin_key,
+        check_admin(&admin_key, &env),
+        "Invalid admin key",
+    ) {
+        return Json(UserResponse::from(cause));
+    }
+
+    if env.read_only {
+        return Json(UserResponse::read_only());
+    }
+
+    if let Err(cause) = check_scopes(&data.scopes) {
+        return Json(UserResponse::failure(cause));
+    }
+
+    let mut guard = api_keys.write().unwrap();
+    let map = guard.deref_mut();
+
+    let key = generate_key();
+    let hash = hash_key(&key);
+    if let Some(namespace) = &data.namespace {
+        if let Err(cause) = check_namespace(namespace, &hash, map) {
+            return Json(UserResponse::failure_of(FailureKind::Conflict, cause));
+        }
+    }
+    let info = ApiKeyInfo {
+        label: data.label,
+        contact: data.contact,
+        scopes: data.scopes,
+        expires_at: data.expires_at,
+        requires_activation: data.requires_activation,
+        activated_by: None,
+        rate_limit: data.rate_limit,
+        namespace: data.namespace,
+        signing_keys: Vec::new(),
+        key_format: KeyFormat::Hashed,
+    };
+    map.insert(hash.clone(), info);
+
+    if let Err(e) = storage.save_api_keys(map, &[hash.clone()]) {
+        log_event!("keys.save_error", storage = storage.describe(), error = e);
+        map.remove(&hash);
+        return Json(UserResponse::failure_of(
+            FailureKind::Internal,
+            "Server error while saving keys".into(),
+        ));
+    }
+
+    log_event!("audit.key_create", key = &hash[..8]);
+
+    Json(UserResponse::success_with_data(key))
+}
+
+/// Rocket Endpoint which revokes an API Key, so it stops working immediately
+///
+/// Handlers owned by the key are left in place, but can no longer be changed by anyone.
+///
+/// # Arguments
+///
+/// * `auth` - The admin key from the `Authorization` header, if any
+/// * `env` - Environment variables
+/// * `storage` - Where the keys are saved
+/// * `api_keys` - A reference to the collection of Client API keys
+/// * `post_data` - The hash of the key to revoke. See `RevokeKeyRequest`
+#[post("/admin/revoke_key", data = "<post_data>")]
+pub fn revoke_key(
+    auth: AuthHeader,
+    env: State<EnvInfo>,
+    storage: State<Storage>,
+    api_keys: Collection<String, ApiKeyInfo>,
+    post_data: Json<RevokeKeyRequest>,
+) -> Json<UserResponse> {
+    let data = post_data.0;
+
+    let admin_key = auth.key_or(&data.admin_key);
+    if let Err(cause) = auth.verify(
+        &admin_key,
+        check_admin(&admin_key, &env),
+        "Invalid admin key",
+    ) {
+        return Json(UserResponse::from(cause));
+    }
+
+    if env.read_only {
+        return Json(UserResponse::read_only());
+    }
+
+    let mut guard = api_keys.write().unwrap();
+    let map = guard.deref_mut();
+
+    let info = match map.remove(&data.key_hash) {
+        Some(info) => info,
+        None => {
+            return Json(UserResponse::failure_of(
+                FailureKind::NotFound,
+                "Unknown key".into(),
+            ))
+        }
+    };
+
+    if let Err(e) = storage.save_api_keys(map, &[data.key_hash.clone()]) {
+        log_event!("keys.save_error", storage = storage.describe(), error = e);
+        map.insert(data.key_hash, info);
+        return Json(UserResponse::failure_of(
+            FailureKind::Internal,
+            "Server error while saving keys".into(),
+        ));
+    }
+
+    log_event!(
+        "audit.key_revoke",
+        key = &data.key_hash[..8.min(data.key_hash.len())],
+    );
+
+    Json(UserResponse::success())
+}
+
+/// Rocket Endpoint which lists every API Key, hashed, with its details
+///
+/// # Arguments
+///
+/// * `auth` - The admin key from the `Authorization` header, if any
+/// * `env` - Environment variables
+/// * `api_keys` - A reference to the collection of Client API keys
+/// * `post_data` - Must contain the admin key, unless it was passed in the header
+#[post("/admin/list_keys", data = "<post_data>")]
+pub fn list_keys(
+    auth: AuthHeader,
+    env: State<EnvInfo>,
+    api_keys: Collection<String, ApiKeyInfo>,
+    post_data: Option<Json<AdminRequest>>,
+) -> Json<UserResponse> {
+    let admin_key = auth.key_or(&post_data.map(|d| d.0.admin_key).unwrap_or_default());
+
+    if let Err(cause) = auth.verify(
+        &admin_key,
+        check_admin(&admin_key, &env),
+        "Invalid admin key",
+    ) {
+        return Json(UserResponse::from(cause));
+    }
+
+    let guard = api_keys.read().unwrap();
+    Json(
+        UserResponse::success_with_raw(hashed_keys(guard.deref()))
+            .unwrap_or_else(|| UserResponse::failure("Unable to list keys".into())),
+    )
+}
+
+/// Rocket Endpoint which reloads the handlers and api keys from storage, e.g. after they were
+/// edited by hand, without restarting
+///
+/// Handlers are compiled as they are loaded, and if any of them doesn't compile, none are
+/// replaced. Read-only replicas reload on their own, but may be told to reload right away.
+///
+/// # Arguments
+///
+/// * `auth` - The admin key from the `Authorization` header, if any
+/// * `env` - Environment variables
+/// * `storage` - Where the handlers and api keys are saved
+/// * `api_keys` - A reference to the collection of Client API keys
+/// * `handlers` - A reference to the collection of User created handlers, indexed by their uris
+/// * `post_data` - Must contain the admin key, unless it was passed in the header
+#[post("/admin/reload", data = "<post_data>")]
+pub fn reload(
+    auth: AuthHeader,
+    env: State<EnvInfo>,
+    storage: State<Storage>,
+    api_keys: Collection<String, ApiKeyInfo>,
+    handlers: Collection<String, Handler>,
+    post_data: Option<Json<AdminRequest>>,
+) -> Json<UserResponse> {
+    let admin_key = auth.key_or(&post_data.map(|d| d.0.admin_key).unwrap_or_default());
+
+    if let Err(cause) = auth.verify(
+        &admin_key,
+        check_admin(&admin_key, &env),
+        "Invalid admin key",
+    ) {
+        return Json(UserResponse::from(cause));
+    }
+
+    let handler_count = match reload_handlers(&storage, &handlers) {
+        Some(count) => count,
+        None => {
+            return Json(UserResponse::failure(format!(
+                "Unable to load handlers from {}, so nothing was reloaded",
+                storage.describe()
+            )))
+        }
+    };
+    let key_count = match reload_api_keys(&storage, &api_keys) {
+        Some(count) => count,
+        None => {
+            return Json(UserResponse::failure(format!(
+                "Reloaded {} Handlers, but unable to load api keys from {}",
+                handler_count,
+                storage.describe()
+            )))
+        }
+    };
+
+    log_event!(
+        "audit.reload",
+        storage = storage.describe(),
+        handlers = handler_count,
+        api_keys = key_count
+    );
+    Json(
+        UserResponse::success_with_raw(json!({
+            "handlers": handler_count,
+            "api_keys": key_count,
+        }))
+        .unwrap_or_else(|| UserResponse::failure("Unable to describe the reload".into())),
+    )
+}
@@ -0,0 +1,99 @@
+use serde_json::{json, Value};
+
+/// The handler every security alert goes to, whichever repository it is about
+pub const HANDLER: &str = "github-security";
+
+/// The severity as one of `critical`, `high`, `medium`, `low` or `unknown`
+///
+/// GitHub calls medium `moderate` in some places, and leaves the severity out in others.
+///
+/// # Arguments
+///
+/// * `severity` - The severity as GitHub sent it, if at all
+fn severity(severity: &Value) -> String {
+    match severity.as_str().map(str::to_lowercase).as_deref() {
+        Some("moderate") | Some("medium") => "medium".into(),
+        Some(s @ "critical") | Some(s @ "high") | Some(s @ "low") => s.into(),
+        _ => "unknown".into(),
+    }
+}
+
+/// The text at a json value, or an empty string
+fn text(value: &Value) -> &str {
+    value.as_str().unwrap_or_default()
+}
+
+/// Turn a security alert delivered by GitHub into the same shape whatever kind it is, or None
+/// if the event isn't a security alert
+///
+/// Understands `dependabot_alert`, `repository_vulnerability_alert` and `security_advisory`
+/// events. The result has the `source` event, the `action` (e.g. `created`), the `severity`
+/// (see `severity`), the vulnerable `package` and its `ecosystem` (e.g. `npm`), the `repo` it
+/// was found in, the advisory's `summary`, `ghsa_id` and `cve_id`, a `url` to read more at, the
+/// `vulnerable_range` of versions, and the `patched_version`. Anything GitHub didn't send is
+/// empty. Global advisories aren't about any repository, so their `repo` is always empty, and
+/// only the first vulnerable package is described.
+///
+/// # Arguments
+///
+/// * `event` - The kind of event, from the `X-GitHub-Event` header
+/// * `payload` - The payload of the delivery
+pub fn normalize(event: &str, payload: &Value) -> Option<Value> {
+    let repo = text(&payload["repository"]["full_name"]);
+    let action = text(&payload["action"]);
+
+    let alert = match event {
+        "dependabot_alert" => {
+            let alert = &payload["alert"];
+            let advisory = &alert["security_advisory"];
+            let vulnerability = &alert["security_vulnerability"];
+            json!({
+                "severity": severity(&advisory["severity"]),
+                "package": text(&alert["dependency"]["package"]["name"]),
+                "ecosystem": text(&alert["dependency"]["package"]["ecosystem"]),
+                "summary": text(&advisory["summary"]),
+                "ghsa_id": text(&advisory["ghsa_id"]),
+                "cve_id": text(&advisory["cve_id"]),
+                "url": text(&alert["html_url"]),
+                "vulnerable_range": text(&vulnerability["vulnerable_version_range"]),
+                "patched_version": text(&vulnerability["first_patched_version"]["identifier"]),
+            })
+        }
+        "repository_vulnerability_alert" => {
+            let alert = &payload["alert"];
+            json!({
+                "severity": severity(&alert["severity"]),
+                "package": text(&alert["affected_package_name"]),
+                "ecosystem": "",
+                "summary": "",
+                "ghsa_id": text(&alert["ghsa_id"]),
+                "cve_id": text(&alert["external_identifier"]),
+                "url": text(&alert["external_reference"]),
+                "vulnerable_range": text(&alert["affected_range"]),
+                "patched_version": text(&alert["fixed_in"]),
+            })
+        }
+        "security_advisory" => {
+            let advisory = &payload["security_advisory"];
+            let vulnerability = &advisory["vulnerabilities"][0];
+            json!({
+                "severity": severity(&advisory["severity"]),
+                "package": text(&vulnerability["package"]["name"]),
+                "ecosystem": text(&vulnerability["package"]["ecosystem"]),
+                "summary": text(&advisory["summary"]),
+                "ghsa_id": text(&advisory["ghsa_id"]),
+                "cve_id": text(&advisory["cve_id"]),
+                "url": text(&advisory["references"][0]["url"]),
+                "vulnerable_range": text(&vulnerability["vulnerable_version_range"]),
+                "patched_version": text(&vulnerability["first_patched_version"]["identifier"]),
+            })
+        }
+        _ => return None,
+    };
+
+    let mut alert = alert;
+    alert["source"] = event.into();
+    alert["action"] = action.into();
+    alert["repo"] = repo.into();
+    Some(alert)
+}
@@ -0,0 +1,24 @@
+use reqwest::blocking::Client;
+
+use crate::server::slack_post_internal;
+use crate::types::EnvInfo;
+
+/// Raise an alert for the maintainers of this instance
+///
+/// Alerts are always logged, and are also posted to the `ALERT_CHANNEL` on Slack if one was
+/// configured.
+///
+/// # Arguments
+///
+/// * `env` - Environment variables
+/// * `http` - The shared http client, see `Services::http`
+/// * `kind` - A short, dot separated name for what happened, e.g. `auth.lockout`
+/// * `message` - A human readable description of what happened
+pub fn raise_alert(env: &EnvInfo, http: &Client, kind: &str, message: String) {
+    log_event!("alert", kind = kind, message = message);
+
+    if let Some(channel) = &env.alert_channel {
+        let text = format!(":rotating_light: [{}] {}", kind, message);
+        slack_post_internal(http, &env.slack_token, channel.clone(), text);
+    }
+}
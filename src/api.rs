@@ -0,0 +1,628 @@
+use std::path::PathBuf;
+
+use rocket::http::Status;
+use rocket::response::{self, Responder, Response};
+use rocket::{Request, State};
+use rocket_contrib::json::Json;
+
+use serde::de::DeserializeOwned;
+use serde_json::{json, Map, Value};
+
+use crate::admin;
+use crate::auth::AuthHeader;
+use crate::cleanup;
+use crate::codecheck;
+use crate::completions;
+use crate::dryrun;
+use crate::flags;
+use crate::handler_logs;
+use crate::history;
+use crate::libraries;
+use crate::logging::CorrelationId;
+use crate::reminders;
+use crate::search;
+use crate::secrets;
+use crate::server::{self, Collection};
+use crate::services::Services;
+use crate::stats;
+use crate::storage::Storage;
+use crate::types::{ApiKeyInfo, EnvInfo, FailureKind, Handler, UserResponse};
+
+/// What a request to `/api/v1` gets back
+///
+/// Successes are the data itself, as json, or nothing with a 204. Failures have the status which
+/// fits them, and a body of `{"error": {"code": ..., "message": ...}}`, see `status_of`.
+#[derive(Debug)]
+pub struct ApiResponse {
+    pub status: Status,
+    pub body: Option<Value>,
+}
+
+impl ApiResponse {
+    fn error(status: Status, code: &str, message: String) -> ApiResponse {
+        ApiResponse {
+            status,
+            body: Some(json!({ "error": { "code": code, "message": message } })),
+        }
+    }
+}
+
+impl<'r> Responder<'r> for ApiResponse {
+    fn respond_to(self, request: &Request) -> response::Result<'r> {
+        let mut response = match self.body {
+            Some(body) => Json(body).respond_to(request)?,
+            None => Response::new(),
+        };
+        response.set_status(self.status);
+        Ok(response)
+    }
+}
+
+/// The http status, and the code clients can match on, of a failure of a legacy endpoint
+///
+/// # Arguments
+///
+/// * `kind` - What kind of failure it is
+pub fn status_of(kind: FailureKind) -> (Status, &'static str) {
+    match kind {
+        FailureKind::Invalid => (Status::BadRequest, "invalid_request"),
+        FailureKind::Unauthorized => (Status::Unauthorized, "unauthorized"),
+        FailureKind::LockedOut => (Status::TooManyRequests, "locked_out"),
+        FailureKind::RateLimited => (Status::TooManyRequests, "rate_limited"),
+        FailureKind::Forbidden => (Status::Forbidden, "forbidden"),
+        FailureKind::ReadOnly => (Status::ServiceUnavailable, "read_only"),
+        FailureKind::NotFound => (Status::NotFound, "not_found"),
+        FailureKind::Conflict => (Status::Conflict, "conflict"),
+        // Only `upsert_handler` fails this way, and it answers with the handler it saved instead
+        FailureKind::WarmUpFailed => (Status::InternalServerError, "warmup_failed"),
+        FailureKind::Internal => (Status::InternalServerError, "internal"),
+    }
+}
+
+/// Turn the response of a legacy endpoint into an `ApiResponse`
+///
+/// Data which is json, i.e. an object or an array, is sent as it is. Other data, e.g. a uri or a
+/// key, is sent as a json string.
+///
+/// # Arguments
+///
+/// * `response` - What the legacy endpoint returned
+/// * `created` - Whether a success created something, for a 201
+fn v1(response: Json<UserResponse>, created: bool) -> ApiResponse {
+    let response = response.0;
+    if !response.status {
+        let cause = response.data.unwrap_or_default();
+        let (status, code) = status_of(response.kind);
+        return ApiResponse::error(status, code, cause);
+    }
+
+    let status = if created { Status::Created } else { Status::Ok };
+    match response.data {
+        None => ApiResponse {
+            status: Status::NoContent,
+            body: None,
+        },
+        Some(data) => {
+            let body = Some(&data)
+                .filter(|d| d.starts_with('{') || d.starts_with('['))
+                .map(|d| serde_json::from_str(d).ok())
+                .flatten()
+                .unwrap_or(Value::String(data));
+            ApiResponse {
+                status,
+                body: Some(body),
+            }
+        }
+    }
+}
+
+/// Build the request of a legacy endpoint from the body of a request, and its path and query
+///
+/// Fields from the path and query win over those in the body. Keys come from the
+/// `Authorization` header, so the body never needs one.
+///
+/// # Arguments
+///
+/// * `body` - The json body, if any. It must be an object
+/// * `fields` - The fields taken from the path and query
+fn request<T: DeserializeOwned>(
+    body: Option<Json<Value>>,
+    fields: Vec<(&str, Value)>,
+) -> Result<Json<T>, ApiResponse> {
+    let mut map = match body.map(|b| b.0) {
+        None => Map::new(),
+        Some(Value::Object(map)) => map,
+        Some(_) => {
+            let cause = "The body must be a json object".to_string();
+            return Err(ApiResponse::error(
+                Status::BadRequest,
+                "invalid_request",
+                cause,
+            ));
+        }
+    };
+    for (name, value) in fields {
+        map.insert(name.to_string(), value);
+    }
+    serde_json::from_value(Value::Object(map))
+        .map(Json)
+        .map_err(|e| ApiResponse::error(Status::BadRequest, "invalid_request", e.to_string()))
+}
+
+/// A uri given as the rest of a path, e.g. `payments/deploy`
+fn uri_of(path: PathBuf) -> Value {
+    Value::String(path.to_string_lossy().into_owned())
+}
+
+/// The handlers the API Key can see, see `server::list_handlers`
+#[get("/handlers")]
+pub fn list_handlers(
+    auth: AuthHeader,
+    api_keys: Collection<String, ApiKeyInfo>,
+    handlers: Collection<String, Handler>,
+) -> ApiResponse {
+    v1(server::list_handlers(auth, api_keys, handlers, None), false)
+}
+
+/// The code and metadata of a handler, see `server::find_handler`
+#[get("/handlers/<uri..>")]
+pub fn find_handler(
+    auth: AuthHeader,
+    api_keys: Collection<String, ApiKeyInfo>,
+    handlers: Collection<String, Handler>,
+    uri: PathBuf,
+) -> ApiResponse {
+    match request(None, vec![("uri", uri_of(uri))]) {
+        Ok(data) => v1(server::find_handler(auth, api_keys, handlers, data), false),
+        Err(response) => response,
+    }
+}
+
+/// Create or update a handler, returning the uri it was saved at, see `server::upsert_handler`
+///
+/// A handler which was saved, but whose warm-up failed, is still a success. It is answered with
+/// `{"uri": ..., "warmup_error": ...}`, since only the warm-up needs fixing.
+#[allow(clippy::too_many_arguments)]
+#[put("/handlers/<uri..>", data = "<body>")]
+pub fn upsert_handler(
+    auth: AuthHeader,
+    env: State<EnvInfo>,
+    storage: State<Storage>,
+    services: State<Services>,
+    api_keys: Collection<String, ApiKeyInfo>,
+    handlers: Collection<String, Handler>,
+    uri: PathBuf,
+    body: Option<Json<Value>>,
+) -> ApiResponse {
+    match request(body, vec![("uri", uri_of(uri))]) {
+        Ok(data) => {
+            let (response, upserted) =
+                server::upsert(auth, env, storage, services, api_keys, handlers, data.0);
+            match upserted.uri {
+                Some(uri) if response.kind == FailureKind::WarmUpFailed && !response.status => {
+                    let status = if upserted.created {
+                        Status::Created
+                    } else {
+                        Status::Ok
+                    };
+                    let body = json!({ "uri": uri, "warmup_error": response.data });
+                    ApiResponse {
+                        status,
+                        body: Some(body),
+                    }
+                }
+                _ => v1(Json(response), upserted.created),
+            }
+        }
+        Err(response) => response,
+    }
+}
+
+/// Archive a handler, see `cleanup::archive_handler`
+#[delete("/handlers/<uri..>")]
+pub fn archive_handler(
+    auth: AuthHeader,
+    env: State<EnvInfo>,
+    storage: State<Storage>,
+    services: State<Services>,
+    api_keys: Collection<String, ApiKeyInfo>,
+    handlers: Collection<String, Handler>,
+    uri: PathBuf,
+) -> ApiResponse {
+    match request(None, vec![("uri", uri_of(uri))]) {
+        Ok(data) => v1(
+            cleanup::archive_handler(auth, env, storage, services, api_keys, handlers, data),
+            false,
+        ),
+        Err(response) => response,
+    }
+}
+
+/// Restore an archived handler, see `cleanup::restore_handler`
+#[post("/archived_handlers/<uri..>")]
+pub fn restore_handler(
+    auth: AuthHeader,
+    env: State<EnvInfo>,
+    storage: State<Storage>,
+    services: State<Services>,
+    api_keys: Collection<String, ApiKeyInfo>,
+    handlers: Collection<String, Handler>,
+    uri: PathBuf,
+) -> ApiResponse {
+    match request(None, vec![("uri", uri_of(uri))]) {
+        Ok(data) => v1(
+            cleanup::restore_handler(auth, env, storage, services, api_keys, handlers, data),
+            false,
+        ),
+        Err(response) => response,
+    }
+}
+
+/// The previous revisions of a handler, see `history::handler_history`
+#[get("/history/<uri..>")]
+pub fn handler_history(
+    auth: AuthHeader,
+    api_keys: Collection<String, ApiKeyInfo>,
+    handlers: Collection<String, Handler>,
+    uri: PathBuf,
+) -> ApiResponse {
+    match request(None, vec![("uri", uri_of(uri))]) {
+        Ok(data) => v1(
+            history::handler_history(auth, api_keys, handlers, data),
+            false,
+        ),
+        Err(response) => response,
+    }
+}
+
+/// Restore the revision of a handler at the `index` in the body, see `history::rollback_handler`
+#[allow(clippy::too_many_arguments)]
+#[post("/history/<uri..>", data = "<body>")]
+pub fn rollback_handler(
+    auth: AuthHeader,
+    env: State<EnvInfo>,
+    storage: State<Storage>,
+    services: State<Services>,
+    api_keys: Collection<String, ApiKeyInfo>,
+    handlers: Collection<String, Handler>,
+    uri: PathBuf,
+    body: Option<Json<Value>>,
+) -> ApiResponse {
+    match request(body, vec![("uri", uri_of(uri))]) {
+        Ok(data) => v1(
+            history::rollback_handler(auth, env, storage, services, api_keys, handlers, data),
+            false,
+        ),
+        Err(response) => response,
+    }
+}
+
+/// The recent log entries of a handler, see `handler_logs::handler_logs`
+#[get("/logs/<uri..>?<limit>")]
+pub fn handler_logs(
+    auth: AuthHeader,
+    services: State<Services>,
+    api_keys: Collection<String, ApiKeyInfo>,
+    handlers: Collection<String, Handler>,
+    uri: PathBuf,
+    limit: Option<usize>,
+) -> ApiResponse {
+    match request(None, vec![("uri", uri_of(uri)), ("limit", json!(limit))]) {
+        Ok(data) => v1(
+            handler_logs::handler_logs(auth, services, api_keys, handlers, data),
+            false,
+        ),
+        Err(response) => response,
+    }
+}
+
+/// Run a handler, or some code, without saving anything, see `dryrun::test_handler`
+#[post("/test_handler", data = "<body>")]
+pub fn test_handler(
+    auth: AuthHeader,
+    id: CorrelationId,
+    env: State<EnvInfo>,
+    services: State<Services>,
+    api_keys: Collection<String, ApiKeyInfo>,
+    handlers: Collection<String, Handler>,
+    body: Option<Json<Value>>,
+) -> ApiResponse {
+    match request(body, vec![]) {
+        Ok(data) => v1(
+            dryrun::test_handler(auth, id, env, services, api_keys, handlers, data),
+            false,
+        ),
+        Err(response) => response,
+    }
+}
+
+/// Check some code without saving it, see `codecheck::check_code`
+#[post("/check_code", data = "<body>")]
+pub fn check_code(
+    auth: AuthHeader,
+    api_keys: Collection<String, ApiKeyInfo>,
+    body: Option<Json<Value>>,
+) -> ApiResponse {
+    match request(body, vec![]) {
+        Ok(data) => v1(codecheck::check_code(auth, api_keys, data), false),
+        Err(response) => response,
+    }
+}
+
+/// The handlers mentioning the `query`, see `search::search_handlers`
+#[get("/search?<query>")]
+pub fn search_handlers(
+    auth: AuthHeader,
+    env: State<EnvInfo>,
+    api_keys: Collection<String, ApiKeyInfo>,
+    handlers: Collection<String, Handler>,
+    query: String,
+) -> ApiResponse {
+    match request(None, vec![("query", Value::String(query))]) {
+        Ok(data) => v1(
+            search::search_handlers(auth, env, api_keys, handlers, data),
+            false,
+        ),
+        Err(response) => response,
+    }
+}
+
+/// The handlers which haven't been called in `days`, see `cleanup::dead_handlers`
+#[get("/dead_handlers?<days>")]
+pub fn dead_handlers(
+    auth: AuthHeader,
+    env: State<EnvInfo>,
+    services: State<Services>,
+    api_keys: Collection<String, ApiKeyInfo>,
+    handlers: Collection<String, Handler>,
+    days: Option<u64>,
+) -> ApiResponse {
+    match request(None, vec![("days", json!(days))]) {
+        Ok(data) => v1(
+            cleanup::dead_handlers(auth, env, services, api_keys, handlers, data),
+            false,
+        ),
+        Err(response) => response,
+    }
+}
+
+/// How often the API Key's handlers are called, see `stats::handler_stats`
+#[get("/stats")]
+pub fn handler_stats(
+    auth: AuthHeader,
+    services: State<Services>,
+    api_keys: Collection<String, ApiKeyInfo>,
+    handlers: Collection<String, Handler>,
+) -> ApiResponse {
+    v1(
+        stats::handler_stats(auth, services, api_keys, handlers, None),
+        false,
+    )
+}
+
+/// What handlers of the API Key can refer to, see `completions::completions`
+#[get("/completions")]
+pub fn list_completions(
+    auth: AuthHeader,
+    env: State<EnvInfo>,
+    services: State<Services>,
+    api_keys: Collection<String, ApiKeyInfo>,
+    handlers: Collection<String, Handler>,
+) -> ApiResponse {
+    v1(
+        completions::completions(auth, env, services, api_keys, handlers, None),
+        false,
+    )
+}
+
+/// Store the secret `value` in the body, see `secrets::set_secret`
+#[put("/secrets/<name>", data = "<body>")]
+pub fn set_secret(
+    auth: AuthHeader,
+    env: State<EnvInfo>,
+    services: State<Services>,
+    api_keys: Collection<String, ApiKeyInfo>,
+    name: String,
+    body: Option<Json<Value>>,
+) -> ApiResponse {
+    match request(body, vec![("name", Value::String(name))]) {
+        Ok(data) => v1(
+            secrets::set_secret(auth, env, services, api_keys, data),
+            false,
+        ),
+        Err(response) => response,
+    }
+}
+
+/// Remove a secret, see `secrets::delete_secret`
+#[delete("/secrets/<name>")]
+pub fn delete_secret(
+    auth: AuthHeader,
+    env: State<EnvInfo>,
+    services: State<Services>,
+    api_keys: Collection<String, ApiKeyInfo>,
+    name: String,
+) -> ApiResponse {
+    match request(None, vec![("name", Value::String(name))]) {
+        Ok(data) => v1(
+            secrets::delete_secret(auth, env, services, api_keys, data),
+            false,
+        ),
+        Err(response) => response,
+    }
+}
+
+/// Create or change a feature flag, with the rules in the body, see `flags::set_flag`
+#[put("/flags/<name>", data = "<body>")]
+pub fn set_flag(
+    auth: AuthHeader,
+    env: State<EnvInfo>,
+    services: State<Services>,
+    api_keys: Collection<String, ApiKeyInfo>,
+    name: String,
+    body: Option<Json<Value>>,
+) -> ApiResponse {
+    match request(body, vec![("name", Value::String(name))]) {
+        Ok(data) => v1(flags::set_flag(auth, env, services, api_keys, data), false),
+        Err(response) => response,
+    }
+}
+
+/// Create or update a module, with the `code` in the body, see `libraries::upsert_module`
+#[put("/modules/<name>", data = "<body>")]
+pub fn upsert_module(
+    auth: AuthHeader,
+    env: State<EnvInfo>,
+    services: State<Services>,
+    api_keys: Collection<String, ApiKeyInfo>,
+    name: String,
+    body: Option<Json<Value>>,
+) -> ApiResponse {
+    match request(body, vec![("name", Value::String(name))]) {
+        Ok(data) => v1(
+            libraries::upsert_module(auth, env, services, api_keys, data),
+            false,
+        ),
+        Err(response) => response,
+    }
+}
+
+/// The pending reminders of the API Key's handlers, see `reminders::list_reminders`
+#[get("/reminders")]
+pub fn list_reminders(
+    auth: AuthHeader,
+    services: State<Services>,
+    api_keys: Collection<String, ApiKeyInfo>,
+    handlers: Collection<String, Handler>,
+) -> ApiResponse {
+    v1(
+        reminders::list_reminders(auth, services, api_keys, handlers, None),
+        false,
+    )
+}
+
+/// Cancel a pending reminder, see `reminders::cancel_reminder`
+#[delete("/reminders/<id>")]
+pub fn cancel_reminder(
+    auth: AuthHeader,
+    env: State<EnvInfo>,
+    services: State<Services>,
+    api_keys: Collection<String, ApiKeyInfo>,
+    handlers: Collection<String, Handler>,
+    id: String,
+) -> ApiResponse {
+    match request(None, vec![("id", Value::String(id))]) {
+        Ok(data) => v1(
+            reminders::cancel_reminder(auth, env, services, api_keys, handlers, data),
+            false,
+        ),
+        Err(response) => response,
+    }
+}
+
+/// Every API Key, by its hash. Needs the admin key, see `admin::list_keys`
+#[get("/keys")]
+pub fn list_keys(
+    auth: AuthHeader,
+    env: State<EnvInfo>,
+    api_keys: Collection<String, ApiKeyInfo>,
+) -> ApiResponse {
+    v1(admin::list_keys(auth, env, api_keys, None), false)
+}
+
+/// Issue a new API Key, returning it. Needs the admin key, see `admin::create_key`
+#[post("/keys", data = "<body>")]
+pub fn create_key(
+    auth: AuthHeader,
+    env: State<EnvInfo>,
+    storage: State<Storage>,
+    api_keys: Collection<String, ApiKeyInfo>,
+    body: Option<Json<Value>>,
+) -> ApiResponse {
+    match request(body, vec![]) {
+        Ok(data) => v1(admin::create_key(auth, env, storage, api_keys, data), true),
+        Err(response) => response,
+    }
+}
+
+/// Change an API Key. Needs the admin key, see `admin::update_key`
+#[patch("/keys/<key_hash>", data = "<body>")]
+pub fn update_key(
+    auth: AuthHeader,
+    env: State<EnvInfo>,
+    storage: State<Storage>,
+    api_keys: Collection<String, ApiKeyInfo>,
+    key_hash: String,
+    body: Option<Json<Value>>,
+) -> ApiResponse {
+    match request(body, vec![("key_hash", Value::String(key_hash))]) {
+        Ok(data) => v1(admin::update_key(auth, env, storage, api_keys, data), false),
+        Err(response) => response,
+    }
+}
+
+/// Revoke an API Key. Needs the admin key, see `admin::revoke_key`
+#[delete("/keys/<key_hash>")]
+pub fn revoke_key(
+    auth: AuthHeader,
+    env: State<EnvInfo>,
+    storage: State<Storage>,
+    api_keys: Collection<String, ApiKeyInfo>,
+    key_hash: String,
+) -> ApiResponse {
+    match request(None, vec![("key_hash", Value::String(key_hash))]) {
+        Ok(data) => v1(admin::revoke_key(auth, env, storage, api_keys, data), false),
+        Err(response) => response,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn failures_get_the_status_of_their_kind() {
+        let failure = UserResponse::failure_of(FailureKind::NotFound, "Unknown key".into());
+        let response = v1(Json(failure), false);
+        assert_eq!(response.status, Status::NotFound);
+        let body = response.body.unwrap();
+        assert_eq!(body["error"]["code"], "not_found");
+        assert_eq!(body["error"]["message"], "Unknown key");
+
+        assert_eq!(status_of(FailureKind::LockedOut).0, Status::TooManyRequests);
+        assert_eq!(status_of(FailureKind::Forbidden).0, Status::Forbidden);
+        assert_eq!(
+            status_of(FailureKind::ReadOnly).0,
+            Status::ServiceUnavailable
+        );
+    }
+
+    #[test]
+    fn the_wording_of_a_failure_does_not_pick_its_status() {
+        let failure = UserResponse::failure("Unknown handler uri, or Invalid API Key".into());
+        assert_eq!(v1(Json(failure), false).status, Status::BadRequest);
+    }
+
+    #[test]
+    fn successes_which_created_something_are_201() {
+        let created = v1(Json(UserResponse::success_with_data("a/b".into())), true);
+        assert_eq!(created.status, Status::Created);
+        assert_eq!(created.body, Some(json!("a/b")));
+
+        let updated = v1(Json(UserResponse::success_with_data("a/b".into())), false);
+        assert_eq!(updated.status, Status::Ok);
+    }
+
+    #[test]
+    fn json_data_is_sent_as_json() {
+        let response = v1(
+            Json(UserResponse::success_with_data("[1, 2]".into())),
+            false,
+        );
+        assert_eq!(response.body, Some(json!([1, 2])));
+
+        let response = v1(Json(UserResponse::success()), false);
+        assert_eq!(response.status, Status::NoContent);
+        assert_eq!(response.body, None);
+    }
+}
@@ -0,0 +1,333 @@
+use std::collections::{BTreeMap, HashMap};
+use std::ops::DerefMut;
+
+use rocket::State;
+use rocket_contrib::json::Json;
+
+use serde_json::json;
+use sha2::{Digest, Sha256};
+
+use crate::auth::{check_auth, check_scope, describe_key, hash_key, AuthHeader};
+use crate::changes::check_unprotected;
+use crate::namespaces::{check_uri, namespace_of, resolve_uri};
+use crate::server::{build_handler, settle_owners, warm_up_handler, Collection};
+use crate::services::Services;
+use crate::signing::check_signature;
+use crate::storage::Storage;
+use crate::types::{
+    ApiKeyInfo, ApplyPlan, ApplyRequest, EnvInfo, FailureKind, Handler, HandlerSpec, UserResponse,
+    WRITE_SCOPE,
+};
+
+/// Whether saving a handler as it is declared would change it
+///
+/// # Arguments
+///
+/// * `current` - The handler as it is
+/// * `declared` - The handler as the document declares it
+fn differs(current: &Handler, declared: &Handler) -> bool {
+    // Windows, subscriptions and relays can't be compared directly, but serialize the same
+    // when equal
+    current.code.raw != declared.code.raw
+        || current.warmup != declared.warmup
+        || current.description != declared.description
+        || current.tags != declared.tags
+        || current.max_operations != declared.max_operations
+        || current.timeout_ms != declared.timeout_ms
+        || current.runbook != declared.runbook
+        || current.co_owners != declared.co_owners
+        || current.protected != declared.protected
+        || current.public_status != declared.public_status
+        || json!(current.active_window) != json!(declared.active_window)
+        || json!(current.subscription) != json!(declared.subscription)
+        || json!(current.relay) != json!(declared.relay)
+}
+
+/// Build every handler a document declares, and work out what saving them would change
+///
+/// Nothing is changed. Every handler is checked as `/upsert_handler` would, so a plan which is
+/// returned can be applied in full.
+///
+/// # Arguments
+///
+/// * `env` - Environment variables, for the ceilings on limits
+/// * `owner` - The hash of the Client's API Key
+/// * `namespace` - The Client's namespace, see `namespaces::namespace_of`
+/// * `api_keys` - The Client API keys, by hash, to check co-owners against
+/// * `map` - The handlers, by their uris
+/// * `document` - The handlers the Client declared, by name or uri
+fn make_plan(
+    env: &EnvInfo,
+    owner: &str,
+    namespace: &str,
+    api_keys: &HashMap<String, ApiKeyInfo>,
+    map: &HashMap<String, Handler>,
+    document: BTreeMap<String, HandlerSpec>,
+) -> Result<(ApplyPlan, BTreeMap<String, Handler>), String> {
+    let mut declared = BTreeMap::new();
+    let mut signed = BTreeMap::new();
+    for (name, mut spec) in document {
+        let uri = resolve_uri(&name, owner, namespace, map);
+        match map.get(&uri) {
+            Some(h) if !h.owned_by(owner) => {
+                return Err(format!("A handler with uri {} already exists", uri))
+            }
+            Some(_) => {}
+            None => check_uri(&uri, namespace)?,
+        }
+        if declared.contains_key(&uri) {
+            return Err(format!("{} is declared more than once", uri));
+        }
+        let co_owners = spec.co_owners.take();
+        let signature = spec.signature.take();
+        let handler = build_handler(env, uri.clone(), owner.to_string(), spec)
+            .and_then(|mut h| settle_owners(&mut h, map.get(&uri), co_owners, api_keys).map(|_| h))
+            .map_err(|e| format!("{}: {}", uri, e))?;
+        let protected = handler.protected || map.get(&uri).map_or(false, |h| h.protected);
+        let code = &handler.code.raw;
+        if let Some(fingerprint) =
+            check_signature(api_keys.get(owner), protected, code, signature.as_deref())
+                .map_err(|e| format!("{}: {}", uri, e))?
+        {
+            signed.insert(uri.clone(), fingerprint);
+        }
+        declared.insert(uri, handler);
+    }
+
+    let mut plan = ApplyPlan::default();
+    for (uri, handler) in &declared {
+        match map.get(uri) {
+            None => plan.create.push(uri.clone()),
+            Some(current) if differs(current, handler) => plan.update.push(uri.clone()),
+            Some(_) => plan.unchanged.push(uri.clone()),
+        }
+    }
+    signed.retain(|uri, _| !plan.unchanged.contains(uri));
+    plan.signed = signed;
+    // Only the handlers the API Key owns itself are its to delete, not those it co-owns
+    plan.delete = map
+        .iter()
+        .filter(|(uri, h)| h.api_key == owner && !declared.contains_key(*uri))
+        .map(|(uri, _)| uri.clone())
+        .collect();
+    plan.delete.sort();
+
+    // Protected handlers only change once a change is approved, which a plan can't wait for
+    for uri in plan.update.iter().chain(&plan.delete) {
+        check_unprotected(&map[uri])?;
+    }
+
+    Ok((plan, declared))
+}
+
+/// Identify a plan, by what it changes, what it was made from, and when the handlers it covers
+/// were last saved, so a plan made before someone else saved one of them can't be applied
+///
+/// # Arguments
+///
+/// * `plan` - The plan, without its fingerprint
+/// * `document` - The document it was made from, serialized
+/// * `map` - The handlers, by their uris
+fn fingerprint(
+    plan: &ApplyPlan,
+    document: &serde_json::Value,
+    map: &HashMap<String, Handler>,
+) -> String {
+    let saved_at = plan
+        .update
+        .iter()
+        .chain(&plan.delete)
+        .chain(&plan.unchanged)
+        .map(|uri| (uri.clone(), map.get(uri).map(|h| h.saved_at).unwrap_or(0)))
+        .collect::<BTreeMap<String, u64>>();
+    let summary = json!({
+        "create": plan.create,
+        "update": plan.update,
+        "delete": plan.delete,
+        "document": document,
+        "saved_at": saved_at,
+    });
+    Sha256::digest(summary.to_string().as_bytes())
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+/// Rocket Endpoint which makes the handlers of an API Key what a document declares, e.g. one
+/// kept in a git repository alongside the code it deploys
+///
+/// Handlers are declared by name or uri, as for `/upsert_handler`, with the same fields. The
+/// document is the whole desired state: the API Key's handlers it doesn't declare are archived,
+/// and can be brought back with `/restore_handler`. Handlers it co-owns may be declared too, but
+/// are left alone if they aren't. Plans which would change protected handlers are refused.
+///
+/// Without `confirm`, the plan is returned: which handlers would be created, updated, deleted or
+/// left as they are, and its `fingerprint`. Sending the same document again with that fingerprint
+/// as `confirm` applies it. If the document, or any of the handlers it covers, changed in the
+/// meantime the fingerprint won't match, and nothing is applied. Every handler is checked before
+/// anything changes, so a plan is applied in full or not at all.
+///
+/// Schedules are declared with each handler's `active_window`. Routes, e.g. which handlers
+/// answer Slack or GitHub, follow from uris, and the rest of the instance's configuration comes
+/// from its environment variables, so neither is part of the document.
+///
+/// # Arguments
+///
+/// * `auth` - The API Key from the `Authorization` header, if any
+/// * `env` - Environment variables
+/// * `storage` - Where handlers are saved
+/// * `services` - The stateful subsystems, for archiving and warm-ups
+/// * `api_keys` - A reference to the collection of Client API keys, used to check for auth
+/// * `handlers` - A reference to the collection of User created handlers, indexed by their uris
+/// * `post_data` - The document, and the fingerprint of the plan to apply, if any
+#[post("/apply", data = "<post_data>")]
+pub fn apply(
+    auth: AuthHeader,
+    env: State<EnvInfo>,
+    storage: State<Storage>,
+    services: State<Services>,
+    api_keys: Collection<String, ApiKeyInfo>,
+    handlers: Collection<String, Handler>,
+    post_data: Json<ApplyRequest>,
+) -> Json<UserResponse> {
+    let data = post_data.0;
+    let key = auth.key_or(&data.api_key);
+
+    if let Err(cause) = auth.verify(&key, check_auth(&key, &api_keys), "Invalid API Key") {
+        return Json(UserResponse::from(cause));
+    }
+    if let Err(cause) = check_scope(&key, &api_keys, WRITE_SCOPE) {
+        return Json(UserResponse::from(cause));
+    }
+    if env.read_only && data.confirm.is_some() {
+        return Json(UserResponse::read_only());
+    }
+
+    // Planning and applying under the same lock means nothing changes in between
+    let mut guard = handlers.write().unwrap();
+    let map = guard.deref_mut();
+
+    let owner = hash_key(&key);
+    let keys = api_keys.read().unwrap();
+    let namespace = namespace_of(&owner, &keys);
+    let document = json!(data.handlers);
+    let planned = make_plan(&env, &owner, &namespace, &keys, map, data.handlers);
+    drop(keys);
+    let (mut plan, declared) = match planned {
+        Ok(planned) => planned,
+        Err(cause) => return Json(UserResponse::failure(cause)),
+    };
+    plan.fingerprint = fingerprint(&plan, &document, map);
+
+    match data.confirm {
+        None => {}
+        Some(confirm) if confirm == plan.fingerprint => {
+            let changed = plan
+                .create
+                .iter()
+                .chain(&plan.update)
+                .chain(&plan.delete)
+                .cloned()
+                .collect::<Vec<String>>();
+
+            for uri in &plan.delete {
+                if let Some(handler) = map.remove(uri) {
+                    services.cleanup.archive(uri, handler);
+                }
+            }
+            for (uri, mut handler) in declared {
+                if plan.unchanged.contains(&uri) {
+                    continue;
+                }
+                if let Some(previous) = map.remove(&uri) {
+                    handler.supersede(previous);
+                }
+                map.insert(uri, handler);
+            }
+
+            if let Err(e) = storage.save_handlers(map, &changed) {
+                log_event!("db.save_error", storage = storage.describe(), error = e);
+                return Json(UserResponse::failure_of(
+                    FailureKind::Internal,
+                    "Server error while saving db".into(),
+                ));
+            }
+            plan.applied = true;
+            log_event!(
+                "audit.apply",
+                key = describe_key(&owner, &api_keys),
+                created = plan.create.len(),
+                updated = plan.update.len(),
+                deleted = plan.delete.len(),
+                signed_by = plan
+                    .signed
+                    .iter()
+                    .map(|(uri, fingerprint)| format!("{}={}", uri, fingerprint))
+                    .collect::<Vec<String>>()
+                    .join(","),
+            );
+
+            // The handlers are saved either way, like with `/upsert_handler`
+            for uri in plan.create.iter().chain(&plan.update) {
+                if let Some(handler) = map.get(uri).filter(|h| h.warmup) {
+                    if let Err(e) = warm_up_handler(&env, &services, handler) {
+                        log_event!("handler.warmup_error", handler = uri, error = e);
+                    }
+                }
+            }
+        }
+        Some(_) => {
+            return Json(UserResponse::failure(
+                "The plan changed since it was made, review it again before applying it".into(),
+            ))
+        }
+    }
+
+    Json(
+        UserResponse::success_with_raw(plan)
+            .unwrap_or_else(|| UserResponse::failure("Unable to describe the plan".into())),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::envelope::Subscription;
+
+    use super::*;
+
+    fn handler(code: &str) -> Handler {
+        Handler::new("deploy".into(), "owner".into(), code.into()).unwrap()
+    }
+
+    #[test]
+    fn differs_ignores_what_a_document_does_not_declare() {
+        let current = handler("fn handle(v) { v }");
+        let mut declared = handler("fn handle(v) { v }");
+        declared.saved_at = current.saved_at + 60;
+        declared.created_at = current.created_at + 60;
+        declared.api_key = "someone else".into();
+        assert!(!differs(&current, &declared));
+    }
+
+    #[test]
+    fn differs_sees_changes_to_code_and_settings() {
+        let current = handler("fn handle(v) { v }");
+        assert!(differs(&current, &handler("fn handle(v) { v + 1 }")));
+
+        let mut described = current.clone();
+        described.description = Some("Deploys things".into());
+        assert!(differs(&current, &described));
+
+        let mut limited = current.clone();
+        limited.timeout_ms = Some(500);
+        assert!(differs(&current, &limited));
+
+        let mut subscribed = current.clone();
+        subscribed.subscription = Some(Subscription {
+            sources: vec!["github".into()],
+            mentions: Vec::new(),
+        });
+        assert!(differs(&current, &subscribed));
+        assert!(!differs(&subscribed, &subscribed.clone()));
+    }
+}
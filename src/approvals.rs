@@ -0,0 +1,225 @@
+use rhai::{Array, Dynamic, ImmutableString, Map, Module};
+
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::clock::unix_now;
+use crate::services::Services;
+use crate::slack::slack_api;
+use crate::storage::new_id;
+use crate::types::EnvInfo;
+
+/// The action ids of the buttons on approval messages
+pub const APPROVE_ACTION: &str = "majordomo_approve";
+pub const DENY_ACTION: &str = "majordomo_deny";
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ApprovalStatus {
+    Pending,
+    Approved,
+    Denied,
+}
+
+/// A request for someone to approve something, e.g. a deploy, made by a handler
+///
+/// Once decided, the requesting handler's `on_approval` function is invoked with the decision.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Approval {
+    pub id: String,
+    /// The uri of the handler which requested the approval, and which gets the decision
+    pub handler: String,
+    /// The channel the request was posted in
+    pub channel: String,
+    /// The Slack user ids allowed to decide. Anyone in the channel may, if empty
+    pub approvers: Vec<String>,
+    /// What is being approved
+    pub summary: String,
+    pub status: ApprovalStatus,
+    /// The Slack user id of whoever decided
+    pub decided_by: Option<String>,
+    /// When the approval was requested, as a unix timestamp
+    pub created_at: u64,
+}
+
+impl Approval {
+    /// The decision, as passed to `on_approval`
+    pub fn to_map(&self) -> Map {
+        let mut map = Map::new();
+        map.insert("approval_id".into(), Dynamic::from(self.id.clone()));
+        map.insert(
+            "approved".into(),
+            Dynamic::from(self.status == ApprovalStatus::Approved),
+        );
+        map.insert(
+            "decided_by".into(),
+            Dynamic::from(self.decided_by.clone().unwrap_or_default()),
+        );
+        map.insert("summary".into(), Dynamic::from(self.summary.clone()));
+        map
+    }
+}
+
+/// Register the approval functions available to clients
+///
+/// * `request_approval(channel, approvers, summary)` posts a message with approve/deny buttons
+///   and returns the approval id. The decision is passed to the handler's `on_approval(decision)`
+/// * `approval_status(approval_id)` returns `"pending"`, `"approved"` or `"denied"`
+///
+/// # Arguments
+///
+/// * `module` - The module to register the functions in
+/// * `env` - Environment variables
+/// * `services` - Where approvals are stored
+/// * `handler_addr` - The uri of the handler the functions are for
+pub fn register(module: &mut Module, env: &EnvInfo, services: &Services, handler_addr: &str) {
+    let client = services.http.clone();
+    let slack_token = env.slack_token.clone();
+    let approvals = services.approvals.clone();
+    let addr = handler_addr.to_string();
+    let request_approval =
+        move |channel: ImmutableString, approvers: Array, summary: ImmutableString| {
+            let approvers = approvers
+                .into_iter()
+                .map(|a| a.to_string())
+                .collect::<Vec<String>>();
+
+            let approval = Approval {
+                id: new_id(),
+                handler: addr.clone(),
+                channel: channel.to_string(),
+                approvers,
+                summary: summary.to_string(),
+                status: ApprovalStatus::Pending,
+                decided_by: None,
+                created_at: unix_now(),
+            };
+
+            slack_api(
+                &client,
+                &slack_token,
+                "chat.postMessage",
+                &request_message(&approval),
+            )?;
+
+            log_event!(
+                "approval.request",
+                handler = addr,
+                approval = approval.id,
+                channel = channel,
+            );
+
+            let id = approval.id.clone();
+            approvals.update(|map| map.insert(id.clone(), approval));
+            Ok(id)
+        };
+
+    let approvals = services.approvals.clone();
+    let approval_status = move |id: ImmutableString| match approvals.read().get(id.as_str()) {
+        Some(approval) => Ok(serde_json::to_value(approval.status)
+            .ok()
+            .and_then(|v| v.as_str().map(String::from))
+            .unwrap_or_default()),
+        None => Err(format!("Unknown approval {}", id).into()),
+    };
+
+    module.set_fn_3("request_approval", request_approval);
+    module.set_fn_1("approval_status", approval_status);
+}
+
+/// The Slack message asking for an approval
+fn request_message(approval: &Approval) -> serde_json::Value {
+    let approvers = if approval.approvers.is_empty() {
+        "anyone in this channel".to_string()
+    } else {
+        approval
+            .approvers
+            .iter()
+            .map(|a| format!("<@{}>", a))
+            .collect::<Vec<String>>()
+            .join(", ")
+    };
+
+    json!({
+        "channel": approval.channel,
+        "text": format!("Approval requested: {}", approval.summary),
+        "blocks": [
+            {
+                "type": "section",
+                "text": {
+                    "type": "mrkdwn",
+                    "text": format!("*Approval requested*\n{}\n_Can be decided by {}_", approval.summary, approvers)
+                }
+            },
+            {
+                "type": "actions",
+                "elements": [
+                    {
+                        "type": "button",
+                        "style": "primary",
+                        "text": { "type": "plain_text", "text": "Approve" },
+                        "action_id": APPROVE_ACTION,
+                        "value": approval.id
+                    },
+                    {
+                        "type": "button",
+                        "style": "danger",
+                        "text": { "type": "plain_text", "text": "Deny" },
+                        "action_id": DENY_ACTION,
+                        "value": approval.id
+                    }
+                ]
+            }
+        ]
+    })
+}
+
+/// Record someone's decision on an approval
+///
+/// Returns the decided approval, or why the decision was refused
+///
+/// # Arguments
+///
+/// * `services` - Where approvals are stored
+/// * `approval_id` - The approval being decided
+/// * `user` - The Slack user id of whoever is deciding
+/// * `approve` - Whether they approved or denied
+pub fn decide(
+    services: &Services,
+    approval_id: &str,
+    user: &str,
+    approve: bool,
+) -> Result<Approval, String> {
+    services.approvals.update(|map| {
+        let approval = map
+            .get_mut(approval_id)
+            .ok_or("This approval no longer exists")?;
+
+        if approval.status != ApprovalStatus::Pending {
+            return Err(format!(
+                "This was already decided by <@{}>",
+                approval.decided_by.clone().unwrap_or_default()
+            ));
+        }
+
+        if !approval.approvers.is_empty() && !approval.approvers.iter().any(|a| a == user) {
+            return Err("You are not one of the approvers of this request".into());
+        }
+
+        approval.status = if approve {
+            ApprovalStatus::Approved
+        } else {
+            ApprovalStatus::Denied
+        };
+        approval.decided_by = Some(user.to_string());
+
+        log_event!(
+            "approval.decide",
+            approval = approval_id,
+            user = user,
+            approved = approve,
+        );
+
+        Ok(approval.clone())
+    })
+}
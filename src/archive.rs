@@ -0,0 +1,336 @@
+use std::fs::{self, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use flate2::read::MultiGzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+
+use rhai::de::from_dynamic;
+use rhai::{Dynamic, Map};
+
+use rocket::http::{ContentType, Status};
+use rocket::State;
+use rocket_contrib::json::Json;
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::auth::{check_auth, check_scope, hash_key, AuthHeader};
+use crate::clock::{unix_now, utc_date};
+use crate::responses::{CustomResponse, Reply};
+use crate::server::Collection;
+use crate::services::Services;
+use crate::types::{
+    ApiKeyInfo, ArchiveSearchRequest, EnvInfo, Failure, FailureKind, Handler, UserResponse,
+    READ_SCOPE,
+};
+
+/// How many events a search returns, if it doesn't say
+const DEFAULT_LIMIT: usize = 100;
+
+/// The most events a search may return
+const MAX_LIMIT: usize = 1_000;
+
+/// The most events an export may return
+const MAX_EXPORT: usize = 100_000;
+
+/// An event a handler ran against, as archived
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ArchivedEvent {
+    /// When the handler ran, as a unix timestamp
+    pub at: u64,
+    /// The correlation id of the run
+    pub id: String,
+    pub handler: String,
+    pub payload: String,
+    /// The context the handler was called with, if any, as json
+    pub context: Option<Value>,
+}
+
+/// Every event the selected handlers run against, kept indefinitely for compliance and
+/// reprocessing, unlike their logs and history
+///
+/// Each handler has a directory in `ARCHIVE_DIR`, with a file per UTC day,
+/// `YYYY-MM-DD.jsonl.gz`. Files are only ever appended to, a gzip member per event, so an
+/// interrupted write loses at most that event, and the files can be shipped elsewhere, e.g. to
+/// S3, with any tool which syncs directories. Majordomo never deletes them.
+pub struct Archive {
+    dir: PathBuf,
+    /// The uris of the handlers to archive, or `*` for all of them
+    handlers: Vec<String>,
+    /// Held while appending, so events from concurrent runs don't interleave
+    lock: Mutex<()>,
+}
+
+/// The name of a handler's directory. Uris may contain anything, so everything but letters,
+/// digits, `-` and `_` is percent encoded
+fn dir_name(handler: &str) -> String {
+    handler
+        .bytes()
+        .map(|b| match b {
+            b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'-' | b'_' => (b as char).to_string(),
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+impl Archive {
+    /// Get ready to archive the handlers the environment selects
+    pub fn open(env: &EnvInfo) -> Archive {
+        Archive {
+            dir: PathBuf::from(&env.archive_dir),
+            handlers: env.archive_handlers.clone(),
+            lock: Mutex::new(()),
+        }
+    }
+
+    /// Whether a handler's events are archived
+    pub fn archives(&self, handler: &str) -> bool {
+        self.handlers.iter().any(|h| h == "*" || h == handler)
+    }
+
+    /// Archive an event a handler is about to run against, if it is one of those archived.
+    /// Failures are logged, rather than failing the run
+    ///
+    /// # Arguments
+    ///
+    /// * `id` - The correlation id of the run
+    /// * `handler` - The uri of the handler
+    /// * `payload` - The data passed to the handler
+    /// * `context` - The context passed to the handler, if any
+    /// * `now` - The current unix timestamp
+    pub fn record(&self, id: &str, handler: &str, payload: &str, context: &Option<Map>, now: u64) {
+        if !self.archives(handler) {
+            return;
+        }
+
+        let event = ArchivedEvent {
+            at: now,
+            id: id.into(),
+            handler: handler.into(),
+            payload: payload.into(),
+            context: context
+                .as_ref()
+                .map(|c| from_dynamic::<Value>(&Dynamic::from(c.clone())).ok())
+                .flatten(),
+        };
+        if let Err(e) = self.append(&event) {
+            log_event!("archive.error", id = id, handler = handler, error = e);
+        }
+    }
+
+    /// Append an event to the file of its handler and day
+    fn append(&self, event: &ArchivedEvent) -> Result<(), String> {
+        let mut line = serde_json::to_vec(event).map_err(|e| e.to_string())?;
+        line.push(b'\n');
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&line).map_err(|e| e.to_string())?;
+        let member = encoder.finish().map_err(|e| e.to_string())?;
+
+        let dir = self.dir.join(dir_name(&event.handler));
+        let path = dir.join(format!("{}.jsonl.gz", utc_date(event.at)));
+        let _guard = self.lock.lock().unwrap();
+        fs::create_dir_all(&dir).map_err(|e| e.to_string())?;
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .map_err(|e| e.to_string())?;
+        file.write_all(&member).map_err(|e| e.to_string())
+    }
+
+    /// Find the archived events of a handler, oldest first
+    ///
+    /// # Arguments
+    ///
+    /// * `handler` - The uri of the handler
+    /// * `since`, `until` - The times the events must be within, inclusive, as unix timestamps
+    /// * `contains` - Text the payload or context must contain, if any
+    /// * `limit` - The most events to return
+    pub fn search(
+        &self,
+        handler: &str,
+        since: u64,
+        until: u64,
+        contains: Option<&str>,
+        limit: usize,
+    ) -> Vec<ArchivedEvent> {
+        let dir = self.dir.join(dir_name(handler));
+        // Events are never archived in the future, give or take the clock changing
+        let (first, last) = (utc_date(since), utc_date(until.min(unix_now() + 86_400)));
+
+        // The dates sort the same as text, so the files can be picked by name
+        let mut days = fs::read_dir(&dir)
+            .map(|entries| {
+                entries
+                    .filter_map(|e| e.ok())
+                    .filter_map(|e| e.file_name().into_string().ok())
+                    .filter(|name| name.ends_with(".jsonl.gz"))
+                    .filter(|name| {
+                        let day = name.trim_end_matches(".jsonl.gz");
+                        day >= first.as_str() && day <= last.as_str()
+                    })
+                    .collect::<Vec<String>>()
+            })
+            .unwrap_or_default();
+        days.sort();
+
+        let matches = |event: &ArchivedEvent| {
+            event.at >= since
+                && event.at <= until
+                && contains
+                    .map(|text| {
+                        event.payload.contains(text)
+                            || event
+                                .context
+                                .as_ref()
+                                .map(|c| c.to_string().contains(text))
+                                .unwrap_or(false)
+                    })
+                    .unwrap_or(true)
+        };
+
+        let mut found = Vec::new();
+        for day in days {
+            let path = dir.join(&day);
+            for event in read_day(&path).into_iter().filter(|e| matches(e)) {
+                if found.len() >= limit {
+                    return found;
+                }
+                found.push(event);
+            }
+        }
+        found
+    }
+}
+
+/// Read the events archived in a day's file
+///
+/// A file whose last event was cut short, e.g. by a crash, is read up to that event.
+fn read_day(path: &Path) -> Vec<ArchivedEvent> {
+    let file = match fs::File::open(path) {
+        Ok(file) => file,
+        Err(_) => return Vec::new(),
+    };
+    BufReader::new(MultiGzDecoder::new(file))
+        .lines()
+        .take_while(|line| line.is_ok())
+        .filter_map(|line| line.ok())
+        .filter_map(|line| serde_json::from_str::<ArchivedEvent>(&line).ok())
+        .collect()
+}
+
+/// Check the API Key may read the archive of the handler it asks about
+///
+/// # Arguments
+///
+/// * `auth` - The API Key from the `Authorization` header, if any
+/// * `api_keys` - A reference to the collection of Client API keys, used to check for auth
+/// * `handlers` - A reference to the collection of User created handlers, indexed by their uris
+/// * `data` - The search
+fn check_access(
+    auth: &AuthHeader,
+    api_keys: &Collection<String, ApiKeyInfo>,
+    handlers: &Collection<String, Handler>,
+    data: &ArchiveSearchRequest,
+) -> Result<(), Failure> {
+    let key = auth.key_or(&data.api_key);
+    auth.verify(&key, check_auth(&key, api_keys), "Invalid API Key")?;
+    check_scope(&key, api_keys, READ_SCOPE)?;
+
+    match handlers.read().unwrap().get(&data.uri) {
+        Some(h) if h.owned_by(&hash_key(&key)) => Ok(()),
+        Some(_) => Err(Failure::new(
+            FailureKind::Unauthorized,
+            "Invalid API Key".into(),
+        )),
+        None => Err(Failure::new(
+            FailureKind::NotFound,
+            "Unknown handler uri".into(),
+        )),
+    }
+}
+
+/// Rocket Endpoint which searches the archived events of a handler, oldest first
+///
+/// # Arguments
+///
+/// * `auth` - The API Key from the `Authorization` header, if any
+/// * `services` - Where the archive is kept
+/// * `api_keys` - A reference to the collection of Client API keys, used to check for auth
+/// * `handlers` - A reference to the collection of User created handlers, indexed by their uris
+/// * `post_data` - The uri of the handler, which must be owned by the API Key, and what to find
+#[post("/archive_search", data = "<post_data>")]
+pub fn archive_search(
+    auth: AuthHeader,
+    services: State<Services>,
+    api_keys: Collection<String, ApiKeyInfo>,
+    handlers: Collection<String, Handler>,
+    post_data: Json<ArchiveSearchRequest>,
+) -> Json<UserResponse> {
+    let data = post_data.0;
+    if let Err(cause) = check_access(&auth, &api_keys, &handlers, &data) {
+        return Json(UserResponse::from(cause));
+    }
+
+    let limit = data.limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT);
+    let events = services.archive.search(
+        &data.uri,
+        data.since.unwrap_or(0),
+        data.until.unwrap_or(u64::MAX),
+        data.contains.as_deref(),
+        limit,
+    );
+    Json(
+        UserResponse::success_with_raw(events)
+            .unwrap_or_else(|| UserResponse::failure("Unable to list events".into())),
+    )
+}
+
+/// Rocket Endpoint which exports the archived events of a handler as json lines, oldest first,
+/// e.g. to replay them against a new version of the handler
+///
+/// Takes the same search as `archive_search`, but returns up to 100,000 events, ignoring
+/// `limit`. Failures are returned as a `UserResponse`, like everywhere else.
+///
+/// # Arguments
+///
+/// * `auth` - The API Key from the `Authorization` header, if any
+/// * `services` - Where the archive is kept
+/// * `api_keys` - A reference to the collection of Client API keys, used to check for auth
+/// * `handlers` - A reference to the collection of User created handlers, indexed by their uris
+/// * `post_data` - The uri of the handler, which must be owned by the API Key, and what to find
+#[post("/archive_export", data = "<post_data>")]
+pub fn archive_export(
+    auth: AuthHeader,
+    services: State<Services>,
+    api_keys: Collection<String, ApiKeyInfo>,
+    handlers: Collection<String, Handler>,
+    post_data: Json<ArchiveSearchRequest>,
+) -> Reply {
+    let data = post_data.0;
+    if let Err(cause) = check_access(&auth, &api_keys, &handlers, &data) {
+        return Reply::Wrapped(Json(UserResponse::from(cause)));
+    }
+
+    let events = services.archive.search(
+        &data.uri,
+        data.since.unwrap_or(0),
+        data.until.unwrap_or(u64::MAX),
+        data.contains.as_deref(),
+        MAX_EXPORT,
+    );
+    let body = events
+        .iter()
+        .filter_map(|e| serde_json::to_string(e).ok())
+        .map(|line| line + "\n")
+        .collect::<String>();
+    Reply::Custom(CustomResponse {
+        status: Status::Ok,
+        content_type: ContentType::new("application", "x-ndjson"),
+        headers: Vec::new(),
+        body,
+    })
+}
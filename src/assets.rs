@@ -0,0 +1,212 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::io::{Cursor, Write};
+use std::time::SystemTime;
+
+use flate2::write::GzEncoder;
+use flate2::Compression;
+
+use httpdate::HttpDate;
+
+use rocket::http::{ContentType, Status};
+use rocket::response::{self, Responder};
+use rocket::{Request, Response};
+
+/// A file which is compiled into the binary and served as is, e.g. the frontend
+pub struct StaticAsset {
+    /// The contents of the file
+    body: &'static [u8],
+    /// The contents of the file, gzipped ahead of time
+    gzipped: Vec<u8>,
+    content_type: ContentType,
+    /// A quoted hash of the contents, which changes whenever the contents do
+    etag: String,
+    /// The contents are baked into the binary, so they last changed at the latest when we started
+    last_modified: SystemTime,
+}
+
+impl StaticAsset {
+    pub fn new(body: &'static str, content_type: ContentType) -> StaticAsset {
+        let mut hasher = DefaultHasher::new();
+        body.hash(&mut hasher);
+
+        // If compression fails for some reason, we'll just always serve the plain body
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        let gzipped = match encoder.write_all(body.as_bytes()) {
+            Ok(_) => encoder.finish().unwrap_or_default(),
+            Err(_) => Vec::new(),
+        };
+
+        StaticAsset {
+            body: body.as_bytes(),
+            gzipped,
+            content_type,
+            etag: format!("\"{:016x}\"", hasher.finish()),
+            last_modified: SystemTime::now(),
+        }
+    }
+
+    /// Whether the client already has the current version of this asset, according to the
+    /// `If-None-Match` and `If-Modified-Since` headers of its request.
+    /// `If-None-Match` takes precedence if both are present.
+    fn is_fresh(&self, request: &Request) -> bool {
+        if let Some(tags) = request.headers().get_one("If-None-Match") {
+            return tags
+                .split(',')
+                .map(|t| t.trim().trim_start_matches("W/"))
+                .any(|t| t == "*" || t == self.etag);
+        }
+
+        match request
+            .headers()
+            .get_one("If-Modified-Since")
+            .map(|date| httpdate::parse_http_date(date).ok())
+            .flatten()
+        {
+            // HTTP dates only have second precision
+            Some(since) => SystemTime::from(HttpDate::from(self.last_modified)) <= since,
+            None => false,
+        }
+    }
+}
+
+/// All the static assets we serve, indexed by their file name
+pub struct Assets(HashMap<&'static str, StaticAsset>);
+
+impl Assets {
+    pub fn load() -> Assets {
+        let mut assets = HashMap::new();
+        assets.insert(
+            "site.html",
+            StaticAsset::new(include_str!("site.html"), ContentType::HTML),
+        );
+        assets.insert(
+            "error_page.html",
+            StaticAsset::new(include_str!("error_page.html"), ContentType::HTML),
+        );
+        assets.insert(
+            "suggestion-box.js",
+            StaticAsset::new(include_str!("suggestion-box.js"), ContentType::JavaScript),
+        );
+        Assets(assets)
+    }
+
+    /// Prepare a response serving the asset with the given name
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - The file name of the asset. Must be one registered in `Assets::load`
+    /// * `cache_control` - The value of the `Cache-Control` header to send along with it
+    pub fn serve(&self, name: &str, cache_control: String) -> Served {
+        Served {
+            asset: &self.0[name],
+            cache_control,
+        }
+    }
+}
+
+/// The weight a coding is given in an `Accept-Encoding` header, e.g. `gzip;q=0.5`
+///
+/// Codings without a `q` parameter have a weight of 1. A weight which doesn't parse counts as
+/// 0, so that we never send something the client may not understand.
+fn weight(params: &str) -> f32 {
+    params
+        .split(';')
+        .map(|p| p.trim())
+        .find(|p| p.get(..2).map_or(false, |k| k.eq_ignore_ascii_case("q=")))
+        .map(|q| q[2..].trim().parse::<f32>().unwrap_or(0.0))
+        .unwrap_or(1.0)
+}
+
+/// Whether the `Accept-Encoding` headers of a request allow a gzipped body
+///
+/// `gzip;q=0` is a refusal, even if `*` is accepted. Without a mention of gzip, it is accepted
+/// if `*` is, with a weight above 0.
+///
+/// # Arguments
+///
+/// * `accept_encoding` - The values of the request's `Accept-Encoding` headers
+fn accepts_gzip<'h, I: IntoIterator<Item = &'h str>>(accept_encoding: I) -> bool {
+    let mut gzip = None;
+    let mut any = None;
+    for coding in accept_encoding.into_iter().flat_map(|v| v.split(',')) {
+        let (name, params) = match coding.find(';') {
+            Some(i) => (coding[..i].trim(), &coding[i + 1..]),
+            None => (coding.trim(), ""),
+        };
+        if name.eq_ignore_ascii_case("gzip") || name.eq_ignore_ascii_case("x-gzip") {
+            gzip = Some(weight(params));
+        } else if name == "*" {
+            any = Some(weight(params));
+        }
+    }
+    gzip.or(any).map_or(false, |q| q > 0.0)
+}
+
+/// Rocket Responder for a static asset
+///
+/// Answers with a `304 Not Modified` if the client's copy is still current, and compresses the
+/// body if the client accepts gzip.
+pub struct Served<'a> {
+    asset: &'a StaticAsset,
+    cache_control: String,
+}
+
+impl<'r, 'a: 'r> Responder<'r> for Served<'a> {
+    fn respond_to(self, request: &Request) -> response::Result<'r> {
+        let mut response = Response::build();
+        response
+            .raw_header("ETag", self.asset.etag.clone())
+            .raw_header(
+                "Last-Modified",
+                httpdate::fmt_http_date(self.asset.last_modified),
+            )
+            .raw_header("Cache-Control", self.cache_control)
+            .raw_header("Vary", "Accept-Encoding");
+
+        if self.asset.is_fresh(request) {
+            return response.status(Status::NotModified).ok();
+        }
+
+        response.header(self.asset.content_type.clone());
+        if accepts_gzip(request.headers().get("Accept-Encoding")) && !self.asset.gzipped.is_empty()
+        {
+            response
+                .raw_header("Content-Encoding", "gzip")
+                .sized_body(Cursor::new(self.asset.gzipped.as_slice()));
+        } else {
+            response.sized_body(Cursor::new(self.asset.body));
+        }
+
+        response.ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn gzip_is_accepted_when_listed_or_covered_by_a_wildcard() {
+        assert!(accepts_gzip(vec!["gzip"]));
+        assert!(accepts_gzip(vec!["deflate, GZIP;q=0.5"]));
+        assert!(accepts_gzip(vec!["br", "gzip ; Q=1.0"]));
+        assert!(accepts_gzip(vec!["x-gzip"]));
+        assert!(accepts_gzip(vec!["gzip;é"]));
+        assert!(accepts_gzip(vec!["br;q=1, *;q=0.1"]));
+    }
+
+    #[test]
+    fn gzip_is_refused_when_weighted_zero_or_not_listed() {
+        assert!(!accepts_gzip(Vec::new()));
+        assert!(!accepts_gzip(vec![""]));
+        assert!(!accepts_gzip(vec!["gzip;q=0"]));
+        assert!(!accepts_gzip(vec!["gzip;q=0.000, *"]));
+        assert!(!accepts_gzip(vec!["*", "gzip; q=0"]));
+        assert!(!accepts_gzip(vec!["br, deflate"]));
+        assert!(!accepts_gzip(vec!["gzipped"]));
+        assert!(!accepts_gzip(vec!["*;q=0"]));
+        assert!(!accepts_gzip(vec!["gzip;q=soon"]));
+    }
+}
@@ -0,0 +1,870 @@
+use std::collections::{HashMap, HashSet};
+use std::io::Read;
+use std::net::IpAddr;
+use std::ops::Deref;
+use std::sync::{Arc, Mutex, RwLock};
+
+use reqwest::blocking::Client;
+
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+
+use rocket::data::{self, FromDataSimple};
+use rocket::http::Status;
+use rocket::request::{self, FormItems, FromRequest};
+use rocket::{Data, Outcome, Request, State};
+
+use crate::alerts::raise_alert;
+use crate::clock::unix_now;
+use crate::ratelimit::Throttle;
+use crate::services::Services;
+use crate::types::{ApiKeyInfo, EnvInfo, Failure, FailureKind, Handler, KeyFormat, SCOPES};
+
+/// How many failed authentication attempts a source may make within `FAILURE_WINDOW_SECS`
+const MAX_FAILURES: u32 = 5;
+
+/// The window, in seconds, over which failed authentication attempts are counted
+const FAILURE_WINDOW_SECS: u64 = 600;
+
+/// How long, in seconds, a source is locked out for once it exceeds `MAX_FAILURES`
+const LOCKOUT_SECS: u64 = 900;
+
+/// The fewest characters an imported key may have, as many as a generated key, see
+/// `check_imported_key`
+const MIN_IMPORTED_KEY_LEN: usize = 32;
+
+/// The fewest different characters an imported key may have. 32 random hex characters have
+/// fewer only about once in 28 million keys
+const MIN_IMPORTED_KEY_CHARS: usize = 8;
+
+/// The failed authentication attempts of a single source, i.e. an IP or a key, see `sources`
+struct FailureRecord {
+    /// How many attempts failed since `window_start`
+    failures: u32,
+    window_start: u64,
+    /// The source is locked out until this unix timestamp
+    locked_until: u64,
+}
+
+/// Tracks failed authentication attempts, so that key guessing gets throttled
+///
+/// Failures are counted both per source IP and per key, so that guessing is slowed down whether
+/// it comes from one machine, or from many machines going after the same key.
+#[derive(Default)]
+pub struct Lockouts(Mutex<HashMap<String, FailureRecord>>);
+
+/// The sources an authentication attempt counts against, see `Lockouts`
+///
+/// Keys are only identified by their hash, so that no part of them is kept around, or shown in
+/// alerts, and so that keys which merely look alike are counted apart.
+///
+/// # Arguments
+///
+/// * `ip` - The IP the attempt came from, if known
+/// * `key` - The key that was presented, possibly empty
+fn sources(ip: Option<IpAddr>, key: &str) -> Vec<String> {
+    let mut sources = Vec::new();
+    if let Some(ip) = ip {
+        sources.push(format!("ip:{}", ip));
+    }
+    if !key.is_empty() {
+        sources.push(format!("key:{}", hash_key(key)));
+    }
+    sources
+}
+
+impl Lockouts {
+    /// The remaining lockout, in seconds, of the most locked out of the given sources, if any
+    fn locked_for(&self, sources: &[String], now: u64) -> Option<u64> {
+        let records = self.0.lock().unwrap();
+        sources
+            .iter()
+            .filter_map(|source| records.get(source))
+            .filter(|record| record.locked_until > now)
+            .map(|record| record.locked_until - now)
+            .max()
+    }
+
+    /// Record a failed attempt by each of the given sources
+    /// Returns the sources which just got locked out because of it
+    fn record_failure(&self, sources: &[String], now: u64) -> Vec<String> {
+        let mut records = self.0.lock().unwrap();
+
+        // Don't let a scan from many IPs grow this forever
+        if records.len() > 10_000 {
+            records
+                .retain(|_, r| r.locked_until > now || r.window_start + FAILURE_WINDOW_SECS > now);
+        }
+
+        let mut locked = Vec::new();
+        for source in sources {
+            let record = records.entry(source.clone()).or_insert(FailureRecord {
+                failures: 0,
+                window_start: now,
+                locked_until: 0,
+            });
+
+            if record.window_start + FAILURE_WINDOW_SECS <= now {
+                record.failures = 0;
+                record.window_start = now;
+            }
+
+            record.failures += 1;
+            if record.failures >= MAX_FAILURES && record.locked_until <= now {
+                record.locked_until = now + LOCKOUT_SECS;
+                locked.push(source.clone());
+            }
+        }
+
+        locked
+    }
+}
+
+/// Rocket Request Guard which reads a key from an `Authorization: Bearer <key>` header, and
+/// enforces lockouts on failed authentication attempts
+///
+/// This never fails: requests without the header have no `key`, so that routes can fall back to
+/// the older `api_key`/`admin_key` body fields. Prefer the header, as it keeps keys out of
+/// request bodies, and works with standard HTTP tooling.
+pub struct AuthHeader<'a> {
+    /// The key from the header, if any
+    pub key: Option<String>,
+    /// The IP the request came from, if known
+    pub ip: Option<IpAddr>,
+    lockouts: &'a Lockouts,
+    env: &'a EnvInfo,
+    /// The shared http client, to raise alerts with
+    http: &'a Client,
+    api_keys: &'a RwLock<HashMap<String, ApiKeyInfo>>,
+    throttle: Throttle<'a>,
+}
+
+impl AuthHeader<'_> {
+    /// The key from the header if present, otherwise the one from the body
+    ///
+    /// # Arguments
+    ///
+    /// * `body_key` - The key provided in the request body, possibly empty
+    pub fn key_or(&self, body_key: &str) -> String {
+        match &self.key {
+            Some(key) => key.clone(),
+            None => body_key.to_string(),
+        }
+    }
+
+    /// Decide the outcome of an authentication attempt, taking lockouts into account
+    ///
+    /// Locked out sources are refused even if their key is valid, so that they learn nothing.
+    /// Failures count towards a lockout, and an alert is raised whenever a source gets locked out.
+    /// Valid Client API keys are also held to their rate limit, see `ApiKeyInfo::rate_limit`.
+    ///
+    /// # Arguments
+    ///
+    /// * `key` - The key that was presented
+    /// * `valid` - Whether the key is valid for this request
+    /// * `cause` - The failure to report if the key is invalid, e.g. "Invalid API Key"
+    pub fn verify(&self, key: &str, valid: bool, cause: &str) -> Result<(), Failure> {
+        let now = unix_now();
+        let sources = sources(self.ip, key);
+
+        if let Some(remaining) = self.lockouts.locked_for(&sources, now) {
+            return Err(Failure::new(
+                FailureKind::LockedOut,
+                format!(
+                    "Too many failed attempts, try again in {} seconds",
+                    remaining
+                ),
+            ));
+        }
+
+        if valid {
+            // The admin key isn't a Client API key, and isn't rate limited
+            let hash = hash_key(key);
+            let limit = self
+                .api_keys
+                .read()
+                .unwrap()
+                .get(&hash)
+                .map(|info| info.rate_limit.unwrap_or(self.env.key_rate_limit));
+            if let Some(limit) = limit {
+                self.throttle
+                    .check(&format!("key:{}", hash), limit)
+                    .map_err(|e| Failure::new(FailureKind::RateLimited, e))?;
+            }
+            return Ok(());
+        }
+
+        log_event!(
+            "auth.failure",
+            ip = self.ip.map(|ip| ip.to_string()).unwrap_or_default(),
+            key = &hash_key(key)[..8],
+        );
+
+        for source in self.lockouts.record_failure(&sources, now) {
+            raise_alert(
+                self.env,
+                self.http,
+                "auth.lockout",
+                format!(
+                    "Locked out {} for {}s after {} failed authentication attempts",
+                    source, LOCKOUT_SECS, MAX_FAILURES
+                ),
+            );
+        }
+
+        Err(Failure::new(FailureKind::Unauthorized, cause.to_string()))
+    }
+}
+
+impl<'a, 'r> FromRequest<'a, 'r> for AuthHeader<'a> {
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<AuthHeader<'a>, ()> {
+        let lockouts = match request.guard::<State<Lockouts>>() {
+            Outcome::Success(lockouts) => lockouts.inner(),
+            _ => return Outcome::Failure((Status::InternalServerError, ())),
+        };
+        let env = match request.guard::<State<EnvInfo>>() {
+            Outcome::Success(env) => env.inner(),
+            _ => return Outcome::Failure((Status::InternalServerError, ())),
+        };
+        let http = match request.guard::<State<Services>>() {
+            Outcome::Success(services) => &services.inner().http,
+            _ => return Outcome::Failure((Status::InternalServerError, ())),
+        };
+        let api_keys = match request.guard::<State<Arc<RwLock<HashMap<String, ApiKeyInfo>>>>>() {
+            Outcome::Success(api_keys) => api_keys.inner().deref(),
+            _ => return Outcome::Failure((Status::InternalServerError, ())),
+        };
+        let throttle = match request.guard::<Throttle>() {
+            Outcome::Success(throttle) => throttle,
+            _ => return Outcome::Failure((Status::InternalServerError, ())),
+        };
+
+        let key = request
+            .headers()
+            .get_one("Authorization")
+            .map(|value| value.trim())
+            .filter(|value| value.len() > 7 && value[..7].eq_ignore_ascii_case("bearer "))
+            .map(|value| value[7..].trim().to_string())
+            .filter(|key| !key.is_empty());
+
+        Outcome::Success(AuthHeader {
+            key,
+            ip: request.client_ip(),
+            lockouts,
+            env,
+            http,
+            api_keys,
+            throttle,
+        })
+    }
+}
+
+/// Compute if a client is authorized or not, i.e. if their key is one we know about, and it
+/// is currently usable. See `ApiKeyInfo::is_usable`
+///
+/// # Arguments
+///
+/// * `key` - The API Key the client presented
+/// * `api_keys` - The collection of Client API keys, by hash
+pub fn check_auth(key: &str, api_keys: &RwLock<HashMap<String, ApiKeyInfo>>) -> bool {
+    let guard = api_keys.read().unwrap();
+    match guard.get(&hash_key(key)) {
+        Some(info) => info.is_usable(unix_now()),
+        None => false,
+    }
+}
+
+/// Compute if a client's key has been given a scope, see `ApiKeyInfo::allows`
+/// Only call this once the key is known to be valid, see `check_auth`
+///
+/// # Arguments
+///
+/// * `key` - The API Key the client presented
+/// * `api_keys` - The collection of Client API keys, by hash
+/// * `scope` - The scope the request needs, e.g. `READ_SCOPE`
+pub fn check_scope(
+    key: &str,
+    api_keys: &RwLock<HashMap<String, ApiKeyInfo>>,
+    scope: &str,
+) -> Result<(), Failure> {
+    let guard = api_keys.read().unwrap();
+    match guard.get(&hash_key(key)) {
+        Some(info) if info.allows(scope) => Ok(()),
+        _ => Err(Failure::new(
+            FailureKind::Forbidden,
+            format!("This API Key lacks the {} scope", scope),
+        )),
+    }
+}
+
+/// Check that every scope an admin asked to give a key exists
+///
+/// # Arguments
+///
+/// * `scopes` - The scopes, see `SCOPES`
+pub fn check_scopes(scopes: &[String]) -> Result<(), String> {
+    match scopes.iter().find(|s| !SCOPES.contains(&s.as_str())) {
+        Some(unknown) => Err(format!(
+            "Unknown scope {}, expected one of {}",
+            unknown,
+            SCOPES.join(", ")
+        )),
+        None => Ok(()),
+    }
+}
+
+/// Compute if a request carries the admin key
+/// Always false if no admin key was configured
+pub fn check_admin(key: &str, env: &EnvInfo) -> bool {
+    match &env.admin_key {
+        Some(admin_key) => constant_time_eq(admin_key.as_bytes(), key.as_bytes()),
+        None => false,
+    }
+}
+
+/// Compare two byte strings in time which only depends on their lengths, not their contents
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b.iter()).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Hash an API Key, as a lowercase hex string, for when we need to identify a key without
+/// revealing it
+///
+/// Keys are only ever stored hashed, both in the collection of Client API keys and as the owner
+/// of a handler, so a leaked backup doesn't give away any keys. The hash is neither salted nor
+/// slow, which keeps lookups cheap, so it only protects keys too random to guess: generated keys
+/// are 128 random bits, and `check_imported_key` refuses imported keys which aren't random enough.
+pub fn hash_key(key: &str) -> String {
+    Sha256::digest(key.as_bytes())
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+/// Check that a key an admin imported is hard enough to guess for `hash_key` to protect it
+///
+/// Imported keys must be at least as long as generated ones, and not be made of just a few
+/// characters, e.g. `aaaa...` or `0101...`. Random hex keys pass, such as those of
+/// `openssl rand -hex 16`.
+///
+/// # Arguments
+///
+/// * `key` - The key, as imported
+pub fn check_imported_key(key: &str) -> Result<(), String> {
+    let distinct = key.chars().collect::<HashSet<char>>().len();
+    if key.chars().count() < MIN_IMPORTED_KEY_LEN || distinct < MIN_IMPORTED_KEY_CHARS {
+        return Err(format!(
+            "Imported keys must be random, with at least {} characters, and {} different ones",
+            MIN_IMPORTED_KEY_LEN, MIN_IMPORTED_KEY_CHARS
+        ));
+    }
+    Ok(())
+}
+
+/// Hash the keys which are still stored in plain text, from before keys were hashed at rest,
+/// as recorded by `ApiKeyInfo::key_format`
+/// Returns the keys which changed, both as they were and as they are now, for saving
+///
+/// # Arguments
+///
+/// * `api_keys` - The collection of Client API keys, as loaded
+pub fn hash_plain_keys(api_keys: &mut HashMap<String, ApiKeyInfo>) -> Vec<String> {
+    let plain = api_keys
+        .iter()
+        .filter(|(_, info)| info.key_format == KeyFormat::Plain)
+        .map(|(key, _)| key.clone())
+        .collect::<Vec<String>>();
+
+    let mut changed = Vec::new();
+    for key in plain {
+        if let Some(mut info) = api_keys.remove(&key) {
+            let hash = hash_key(&key);
+            info.key_format = KeyFormat::Hashed;
+            api_keys.insert(hash.clone(), info);
+            changed.push(key);
+            changed.push(hash);
+        }
+    }
+    changed
+}
+
+/// Hash the keys of handler owners which are still stored in plain text, as recorded by
+/// `Handler::owner_format`, like `hash_plain_keys`
+/// Returns the uris of the handlers which changed
+///
+/// # Arguments
+///
+/// * `handlers` - The handlers, as loaded or synced from another instance
+pub fn hash_plain_owners(handlers: &mut HashMap<String, Handler>) -> Vec<String> {
+    handlers
+        .values_mut()
+        .filter(|h| h.owner_format == KeyFormat::Plain)
+        .map(|h| {
+            h.api_key = hash_key(&h.api_key);
+            h.owner_format = KeyFormat::Hashed;
+            h.uri.clone()
+        })
+        .collect()
+}
+
+/// Describe the owner of a key for logs and alerts, without revealing the key
+///
+/// This is the label and contact if we have them, followed by a short prefix of the key's hash,
+/// e.g. `frontend team <U012AB3CD> (3f2a9c1e)`.
+///
+/// # Arguments
+///
+/// * `hash` - The hash of the API Key to describe, see `hash_key`
+/// * `api_keys` - The collection of Client API keys, by hash
+pub fn describe_key(hash: &str, api_keys: &RwLock<HashMap<String, ApiKeyInfo>>) -> String {
+    let guard = api_keys.read().unwrap();
+
+    match guard.get(hash) {
+        Some(info) => {
+            let mut description = String::new();
+            if let Some(label) = &info.label {
+                description.push_str(label);
+                description.push(' ');
+            }
+            if let Some(contact) = &info.contact {
+                description.push_str(&format!("<{}> ", contact));
+            }
+            description.push_str(&format!("({})", &hash[..8]));
+            description
+        }
+        None => format!("unknown key ({})", &hash[..8]),
+    }
+}
+
+/// How old, in seconds, a signed Slack request may be before we refuse it as a possible replay
+const SLACK_MAX_AGE_SECS: u64 = 300;
+
+/// The most we read of a signed request body, e.g. from Slack or GitHub, in bytes
+const SIGNED_BODY_LIMIT: u64 = 1024 * 1024;
+
+/// HMAC, as described in RFC 2104, with a hash whose blocks are 64 bytes, e.g. SHA-256
+fn hmac<D: Digest>(key: &[u8], message: &[u8]) -> Vec<u8> {
+    const BLOCK_SIZE: usize = 64;
+
+    let mut block = [0u8; BLOCK_SIZE];
+    if key.len() > BLOCK_SIZE {
+        let hashed = D::digest(key);
+        block[..hashed.len()].copy_from_slice(&hashed);
+    } else {
+        block[..key.len()].copy_from_slice(key);
+    }
+
+    let mut inner = D::new();
+    inner.input(block.iter().map(|b| b ^ 0x36).collect::<Vec<u8>>());
+    inner.input(message);
+
+    let mut outer = D::new();
+    outer.input(block.iter().map(|b| b ^ 0x5c).collect::<Vec<u8>>());
+    outer.input(inner.result());
+    outer.result().to_vec()
+}
+
+/// Check the signature Slack attached to a request, see
+/// https://api.slack.com/authentication/verifying-requests-from-slack
+///
+/// # Arguments
+///
+/// * `secret` - The signing secret of our Slack app
+/// * `timestamp` - The `X-Slack-Request-Timestamp` header
+/// * `signature` - The `X-Slack-Signature` header
+/// * `body` - The raw request body
+/// * `now` - The current unix timestamp
+pub fn check_slack_signature(
+    secret: &str,
+    timestamp: &str,
+    signature: &str,
+    body: &str,
+    now: u64,
+) -> bool {
+    let sent_at = match timestamp.parse::<u64>() {
+        Ok(t) => t,
+        Err(_) => return false,
+    };
+    // The timestamp is whatever the sender says, so the difference mustn't overflow
+    if now.max(sent_at) - now.min(sent_at) > SLACK_MAX_AGE_SECS {
+        return false;
+    }
+
+    let base = format!("v0:{}:{}", timestamp, body);
+    let expected: String = hmac::<Sha256>(secret.as_bytes(), base.as_bytes())
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect();
+    constant_time_eq(format!("v0={}", expected).as_bytes(), signature.as_bytes())
+}
+
+/// Read the body of a request whose signature we are about to check, along with our config
+fn read_signed_body<'a>(
+    request: &'a Request,
+    data: Data,
+) -> Result<(State<'a, EnvInfo>, String), (Status, String)> {
+    let mut body = String::new();
+    data.open()
+        .take(SIGNED_BODY_LIMIT)
+        .read_to_string(&mut body)
+        .map_err(|e| (Status::BadRequest, e.to_string()))?;
+
+    match request.guard::<State<EnvInfo>>() {
+        Outcome::Success(env) => Ok((env, body)),
+        _ => Err((Status::InternalServerError, "no config".into())),
+    }
+}
+
+/// Rocket data guard for requests from Slack: the raw body, once its signature checks out
+///
+/// Requests without a valid `X-Slack-Signature` fail with a 401, so forged events never reach a
+/// handler. If no signing secret is configured, every request is let through, as before.
+pub struct SlackBody(pub String);
+
+impl FromDataSimple for SlackBody {
+    type Error = String;
+
+    fn from_data(request: &Request, data: Data) -> data::Outcome<SlackBody, String> {
+        let (env, body) = match read_signed_body(request, data) {
+            Ok(read) => read,
+            Err(failure) => return Outcome::Failure(failure),
+        };
+
+        if let Some(secret) = &env.slack_signing_secret {
+            let header = |name| request.headers().get_one(name).unwrap_or_default();
+            let timestamp = header("X-Slack-Request-Timestamp");
+            let signature = header("X-Slack-Signature");
+
+            if !check_slack_signature(secret, timestamp, signature, &body, unix_now()) {
+                log_event!(
+                    "slack.signature_invalid",
+                    path = request.uri().path(),
+                    ip = request
+                        .client_ip()
+                        .map(|ip| ip.to_string())
+                        .unwrap_or_default(),
+                );
+                return Outcome::Failure((Status::Unauthorized, "Invalid Slack signature".into()));
+            }
+        }
+
+        Outcome::Success(SlackBody(body))
+    }
+}
+
+/// Check the signature GitHub attached to a webhook delivery, see
+/// https://docs.github.com/en/developers/webhooks-and-events/securing-your-webhooks
+///
+/// # Arguments
+///
+/// * `secret` - The secret the webhook was set up with
+/// * `signature` - The `X-Hub-Signature-256` header
+/// * `body` - The raw request body
+pub fn check_github_signature(secret: &str, signature: &str, body: &str) -> bool {
+    let expected: String = hmac::<Sha256>(secret.as_bytes(), body.as_bytes())
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect();
+    constant_time_eq(
+        format!("sha256={}", expected).as_bytes(),
+        signature.as_bytes(),
+    )
+}
+
+/// Rocket data guard for webhook deliveries from GitHub: the raw body, once its signature
+/// checks out
+///
+/// Unlike Slack events, which predate signature checks, webhooks are refused outright when no
+/// secret is configured.
+pub struct GithubBody(pub String);
+
+impl FromDataSimple for GithubBody {
+    type Error = String;
+
+    fn from_data(request: &Request, data: Data) -> data::Outcome<GithubBody, String> {
+        let (env, body) = match read_signed_body(request, data) {
+            Ok(read) => read,
+            Err(failure) => return Outcome::Failure(failure),
+        };
+
+        let secret = match &env.github_webhook_secret {
+            Some(secret) => secret,
+            None => {
+                return Outcome::Failure((
+                    Status::NotFound,
+                    "GitHub webhooks are not configured".into(),
+                ))
+            }
+        };
+
+        let signature = request
+            .headers()
+            .get_one("X-Hub-Signature-256")
+            .unwrap_or_default();
+        if !check_github_signature(secret, signature, &body) {
+            log_event!(
+                "github.signature_invalid",
+                path = request.uri().path(),
+                ip = request
+                    .client_ip()
+                    .map(|ip| ip.to_string())
+                    .unwrap_or_default(),
+            );
+            return Outcome::Failure((Status::Unauthorized, "Invalid GitHub signature".into()));
+        }
+
+        Outcome::Success(GithubBody(body))
+    }
+}
+
+/// Check the signature Twilio attached to a webhook request, see
+/// https://www.twilio.com/docs/usage/security#validating-requests
+///
+/// # Arguments
+///
+/// * `auth_token` - The auth token of our Twilio account
+/// * `url` - The full url Twilio requested, including any query string
+/// * `params` - The parameters of the form Twilio posted
+/// * `signature` - The `X-Twilio-Signature` header
+pub fn check_twilio_signature(
+    auth_token: &str,
+    url: &str,
+    params: &[(String, String)],
+    signature: &str,
+) -> bool {
+    let mut sorted = params.iter().collect::<Vec<&(String, String)>>();
+    sorted.sort();
+
+    let mut signed = url.to_string();
+    for (key, value) in sorted {
+        signed.push_str(key);
+        signed.push_str(value);
+    }
+    let expected = base64::encode(hmac::<Sha1>(auth_token.as_bytes(), signed.as_bytes()));
+    constant_time_eq(expected.as_bytes(), signature.as_bytes())
+}
+
+/// Rocket data guard for webhooks from Twilio: the parameters of the posted form, once its
+/// signature checks out
+///
+/// Requests without a valid `X-Twilio-Signature` fail with a 401. Twilio signs the url it
+/// requested, which proxies may hide from us, so it is rebuilt from `TWILIO_WEBHOOK_URL` if set,
+/// and from the `Host` header otherwise. If no auth token is configured, every request fails
+/// with a 404, as `/twilio` is disabled.
+pub struct TwilioBody(pub Vec<(String, String)>);
+
+impl FromDataSimple for TwilioBody {
+    type Error = String;
+
+    fn from_data(request: &Request, data: Data) -> data::Outcome<TwilioBody, String> {
+        let (env, body) = match read_signed_body(request, data) {
+            Ok(read) => read,
+            Err(failure) => return Outcome::Failure(failure),
+        };
+
+        let auth_token = match &env.twilio_auth_token {
+            Some(token) => token,
+            None => {
+                return Outcome::Failure((
+                    Status::NotFound,
+                    "Twilio webhooks are not configured".into(),
+                ))
+            }
+        };
+
+        let url = match &env.twilio_webhook_url {
+            Some(base) => format!("{}{}", base.trim_end_matches('/'), request.uri()),
+            None => format!(
+                "https://{}{}",
+                request.headers().get_one("Host").unwrap_or_default(),
+                request.uri()
+            ),
+        };
+        let params = FormItems::from(body.as_str())
+            .map(|item| item.key_value_decoded())
+            .collect::<Vec<(String, String)>>();
+
+        let signature = request
+            .headers()
+            .get_one("X-Twilio-Signature")
+            .unwrap_or_default();
+        if !check_twilio_signature(auth_token, &url, &params, signature) {
+            log_event!(
+                "twilio.signature_invalid",
+                path = request.uri().path(),
+                ip = request
+                    .client_ip()
+                    .map(|ip| ip.to_string())
+                    .unwrap_or_default(),
+            );
+            return Outcome::Failure((Status::Unauthorized, "Invalid Twilio signature".into()));
+        }
+
+        Outcome::Success(TwilioBody(params))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECRET: &str = "8f742231b10e8888abcd99yyyzzz85a5";
+    const BODY: &str = "token=xyz&text=hello";
+    const SENT_AT: u64 = 1531420618;
+
+    /// Whether `BODY`, as signed with `SECRET` at `SENT_AT`, checks out
+    fn accepted(secret: &str, timestamp: &str, body: &str, now: u64) -> bool {
+        // The HMAC-SHA256 of `v0:1531420618:token=xyz&text=hello` under `SECRET`
+        let signature = "v0=8e0a0e7ec0e1bc5e3b8a8be0bd6ff5b4a39eb5742e4cd10894d0ea9e351c6a4a";
+        check_slack_signature(secret, timestamp, signature, body, now)
+    }
+
+    #[test]
+    fn slack_signature_accepts_a_fresh_signed_body() {
+        let timestamp = SENT_AT.to_string();
+        assert!(accepted(SECRET, &timestamp, BODY, SENT_AT + 10));
+        assert!(accepted(SECRET, &timestamp, BODY, SENT_AT - 10));
+    }
+
+    #[test]
+    fn slack_signature_refuses_a_changed_body_or_secret() {
+        let timestamp = SENT_AT.to_string();
+        assert!(!accepted(SECRET, &timestamp, "token=xyz&text=bye", SENT_AT));
+        assert!(!accepted("another secret", &timestamp, BODY, SENT_AT));
+    }
+
+    #[test]
+    fn slack_signature_refuses_stale_and_absurd_timestamps() {
+        let timestamp = SENT_AT.to_string();
+        assert!(!accepted(
+            SECRET,
+            &timestamp,
+            BODY,
+            SENT_AT + SLACK_MAX_AGE_SECS + 1
+        ));
+        for timestamp in &["18446744073709551615", "-9223372036854775808", "soon", ""] {
+            assert!(!accepted(SECRET, timestamp, BODY, SENT_AT));
+        }
+        assert!(!accepted(SECRET, "0", BODY, u64::MAX));
+    }
+
+    #[test]
+    fn sources_hold_no_part_of_the_key() {
+        let ip = "10.0.0.1".parse().ok();
+        let guessed = sources(ip, "abcdef123456");
+        assert_eq!(guessed[0], "ip:10.0.0.1");
+        assert_eq!(guessed[1], format!("key:{}", hash_key("abcdef123456")));
+        assert!(guessed.iter().all(|s| !s.contains("abcdef")));
+        assert!(sources(None, "").is_empty());
+    }
+
+    #[test]
+    fn lockouts_lock_out_a_source_after_too_many_failures() {
+        let lockouts = Lockouts::default();
+        let guessed = sources("10.0.0.1".parse().ok(), "abcdef123456");
+        for _ in 1..MAX_FAILURES {
+            assert!(lockouts.record_failure(&guessed, SENT_AT).is_empty());
+        }
+        assert_eq!(lockouts.record_failure(&guessed, SENT_AT), guessed);
+        assert_eq!(lockouts.locked_for(&guessed, SENT_AT), Some(LOCKOUT_SECS));
+        assert_eq!(lockouts.locked_for(&guessed, SENT_AT + LOCKOUT_SECS), None);
+    }
+
+    #[test]
+    fn lockouts_of_a_key_spare_keys_which_look_alike() {
+        let lockouts = Lockouts::default();
+        let guessed = sources(None, "abcdef123456");
+        for _ in 0..MAX_FAILURES {
+            lockouts.record_failure(&guessed, SENT_AT);
+        }
+        assert!(lockouts.locked_for(&guessed, SENT_AT).is_some());
+
+        let alike = sources("10.0.0.2".parse().ok(), "abcdef654321");
+        assert_eq!(lockouts.locked_for(&alike, SENT_AT), None);
+        let same_key = sources("10.0.0.2".parse().ok(), "abcdef123456");
+        assert!(lockouts.locked_for(&same_key, SENT_AT).is_some());
+    }
+
+    #[test]
+    fn plain_keys_are_told_apart_by_their_format_rather_than_their_shape() {
+        // As `openssl rand -hex 32` makes them, so just like a hash
+        let plain = "9f86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08";
+        let hashed = hash_key("a key saved since keys are hashed");
+        let mut api_keys = HashMap::new();
+        let info = ApiKeyInfo {
+            key_format: KeyFormat::Plain,
+            ..ApiKeyInfo::default()
+        };
+        api_keys.insert(plain.to_string(), info);
+        api_keys.insert(hashed.clone(), ApiKeyInfo::default());
+
+        assert_eq!(
+            hash_plain_keys(&mut api_keys),
+            vec![plain.to_string(), hash_key(plain)]
+        );
+        assert_eq!(api_keys[&hash_key(plain)].key_format, KeyFormat::Hashed);
+        assert!(api_keys.contains_key(&hashed));
+        assert!(hash_plain_keys(&mut api_keys).is_empty());
+    }
+
+    #[test]
+    fn imported_keys_must_be_random() {
+        assert!(check_imported_key(&crate::admin::generate_key()).is_ok());
+        assert!(check_imported_key("9f86d081884c7d659a2feaa0c55ad015").is_ok());
+        assert!(check_imported_key("hunter2").is_err());
+        assert!(check_imported_key(&"a".repeat(64)).is_err());
+        assert!(check_imported_key(&"01".repeat(32)).is_err());
+    }
+
+    #[test]
+    fn github_signature_matches_githubs_example() {
+        let secret = "It's a Secret to Everybody";
+        let signature = "sha256=757107ea0eb2509fc211221cce984b8a37570b6d7586c22c46f4379c8b043e17";
+        assert!(check_github_signature(secret, signature, "Hello, World!"));
+        assert!(!check_github_signature(secret, signature, "Hello, World?"));
+        assert!(!check_github_signature("", signature, "Hello, World!"));
+        assert!(!check_github_signature(
+            secret,
+            &signature[7..],
+            "Hello, World!"
+        ));
+        assert!(!check_github_signature(secret, "", "Hello, World!"));
+    }
+
+    #[test]
+    fn twilio_signature_matches_twilios_example() {
+        let url = "https://mycompany.com/myapp.php?foo=1&bar=2";
+        // In the order Twilio happened to post them, which is not the order they are signed in
+        let params = [
+            ("To", "+18005551212"),
+            ("Caller", "+12349013030"),
+            ("Digits", "1234"),
+            ("From", "+12349013030"),
+            ("CallSid", "CA1234567890ABCDE"),
+        ]
+        .iter()
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect::<Vec<(String, String)>>();
+        let signature = "0/KCTR6DLpKmkAf8muzZqo1nDgQ=";
+
+        assert!(check_twilio_signature("12345", url, &params, signature));
+        assert!(!check_twilio_signature("54321", url, &params, signature));
+        assert!(!check_twilio_signature(
+            "12345",
+            "https://mycompany.com/myapp.php",
+            &params,
+            signature
+        ));
+        assert!(!check_twilio_signature(
+            "12345",
+            url,
+            &params[1..],
+            signature
+        ));
+    }
+}
@@ -0,0 +1,145 @@
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+
+use reqwest::blocking::Client;
+
+use rhai::{Array, EvalAltResult, ImmutableString, Module, INT};
+
+use serde_json::json;
+
+use crate::logging::CorrelationId;
+use crate::services::Services;
+use crate::slack::slack_api;
+use crate::types::EnvInfo;
+
+/// The pause between two broadcast messages. Slack allows about one message per second
+const PACE: Duration = Duration::from_millis(1100);
+
+/// How long to back off when Slack says we are sending too much anyway
+const RATE_LIMITED_BACKOFF: Duration = Duration::from_secs(30);
+
+/// How many times to retry a message which was rate limited
+const MAX_RETRIES: usize = 3;
+
+/// The most channels a single broadcast may target
+const MAX_CHANNELS: usize = 500;
+
+/// A message waiting to be posted to one channel
+struct Delivery {
+    id: CorrelationId,
+    handler: String,
+    channel: String,
+    message: String,
+}
+
+/// A queue of messages to post to Slack, worked through at a pace Slack accepts
+///
+/// Messages are posted by a single background thread, in the order they were queued.
+pub struct Broadcaster {
+    queue: Mutex<Sender<Delivery>>,
+}
+
+impl Broadcaster {
+    /// Start the thread which posts the queued messages
+    ///
+    /// # Arguments
+    ///
+    /// * `env` - Environment variables
+    /// * `client` - The http client to post with
+    pub fn start(env: &EnvInfo, client: Client) -> Broadcaster {
+        let (sender, receiver) = mpsc::channel();
+        let slack_token = env.slack_token.clone();
+
+        let spawned = thread::Builder::new()
+            .name("broadcaster".into())
+            .spawn(move || deliver_all(&client, &slack_token, receiver));
+        if let Err(e) = spawned {
+            log_event!("broadcast.start_error", error = e);
+        }
+
+        Broadcaster {
+            queue: Mutex::new(sender),
+        }
+    }
+
+    fn enqueue(&self, delivery: Delivery) -> bool {
+        self.queue.lock().unwrap().send(delivery).is_ok()
+    }
+}
+
+/// Post every message which is queued, until the queue is dropped
+fn deliver_all(client: &Client, slack_token: &str, receiver: Receiver<Delivery>) {
+    for delivery in receiver {
+        let body = json!({ "channel": delivery.channel, "text": delivery.message });
+
+        let mut attempts = 0;
+        let result = loop {
+            attempts += 1;
+            match slack_api(client, slack_token, "chat.postMessage", &body) {
+                Err(e) if e == "ratelimited" && attempts <= MAX_RETRIES => {
+                    thread::sleep(RATE_LIMITED_BACKOFF)
+                }
+                result => break result,
+            }
+        };
+
+        log_event!(
+            "broadcast.deliver",
+            id = delivery.id.0,
+            handler = delivery.handler,
+            channel = delivery.channel,
+            ok = result.is_ok(),
+        );
+        thread::sleep(PACE);
+    }
+}
+
+/// Register the broadcast functions available to clients
+///
+/// * `slack_broadcast(channels, message)` queues the message for each of the channels, and
+///   returns how many were queued. Messages go out in the background, about one per second
+///
+/// # Arguments
+///
+/// * `module` - The module to register the functions in
+/// * `services` - Where the queue lives
+/// * `id` - The correlation id of the request the handler is serving
+/// * `handler_addr` - The uri of the handler the functions are for
+pub fn register(module: &mut Module, services: &Services, id: &CorrelationId, handler_addr: &str) {
+    let broadcaster = services.broadcaster.clone();
+    let addr = handler_addr.to_string();
+    let cid = id.clone();
+    let slack_broadcast = move |channels: Array,
+                                message: ImmutableString|
+          -> Result<INT, Box<EvalAltResult>> {
+        if channels.len() > MAX_CHANNELS {
+            return Err(format!("A broadcast may target at most {} channels", MAX_CHANNELS).into());
+        }
+
+        log_event!(
+            "broadcast.queue",
+            id = cid.0,
+            handler = addr,
+            channels = channels.len()
+        );
+
+        let mut queued = 0;
+        for channel in channels {
+            let delivery = Delivery {
+                id: cid.clone(),
+                handler: addr.clone(),
+                channel: channel.to_string(),
+                message: message.to_string(),
+            };
+            if !broadcaster.enqueue(delivery) {
+                return Err("The broadcast queue is not running".into());
+            }
+            queued += 1;
+        }
+        Ok(queued)
+    };
+
+    module.set_fn_2("slack_broadcast", slack_broadcast);
+}
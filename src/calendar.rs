@@ -0,0 +1,257 @@
+use std::collections::HashMap;
+use std::ops::Deref;
+
+use rhai::de::from_dynamic;
+use rhai::{Dynamic, EvalAltResult, ImmutableString, Module, INT};
+
+use rocket::http::ContentType;
+use rocket::response::content::Content;
+use rocket::State;
+
+use serde::{Deserialize, Serialize};
+
+use crate::clock::{unix_now, utc_date, utc_datetime};
+use crate::server::Collection;
+use crate::services::Services;
+use crate::storage::new_id;
+use crate::types::Handler;
+
+/// How many events a single handler's calendar may hold
+const MAX_EVENTS: usize = 1000;
+
+/// The longest a line of an ICS file may be, in bytes, not counting the line break
+const MAX_LINE_BYTES: usize = 75;
+
+/// An event a handler published to its calendar
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Event {
+    /// Identifies the event, so it can be updated or removed later. Generated, if not given
+    #[serde(default)]
+    pub uid: String,
+    /// The title of the event
+    pub summary: String,
+    /// When the event starts, as a unix timestamp
+    pub start: u64,
+    /// When the event ends, as a unix timestamp. Events without an end take no time, or, if
+    /// they last all day, the one day
+    #[serde(default)]
+    pub end: Option<u64>,
+    /// Whether the event lasts all day, on the UTC dates of `start` and `end`
+    #[serde(default)]
+    pub all_day: bool,
+    #[serde(default)]
+    pub description: Option<String>,
+    #[serde(default)]
+    pub location: Option<String>,
+    /// When the event was last published, as a unix timestamp
+    #[serde(default)]
+    pub published_at: u64,
+}
+
+/// The events of a handler, indexed by their uid
+pub type Calendar = HashMap<String, Event>;
+
+/// A unix timestamp as an ICS date, `YYYYMMDD`
+fn ics_date(unix: u64) -> String {
+    utc_date(unix).replace('-', "")
+}
+
+/// A unix timestamp as an ICS date and time in UTC, `YYYYMMDDTHHMMSSZ`
+fn ics_time(unix: u64) -> String {
+    utc_datetime(unix).replace('-', "").replace(':', "")
+}
+
+/// Escape text for use as an ICS property value
+fn escape_text(text: &str) -> String {
+    text.replace('\\', "\\\\")
+        .replace(';', "\\;")
+        .replace(',', "\\,")
+        .replace("\r\n", "\\n")
+        .replace('\n', "\\n")
+        .replace('\r', "")
+}
+
+/// Add a content line to an ICS file, folding it so no line is longer than `MAX_LINE_BYTES`
+///
+/// Continuation lines start with a space, which doesn't count towards the content, and lines
+/// are only folded between characters, never inside one.
+///
+/// # Arguments
+///
+/// * `ics` - The file so far
+/// * `line` - The content line, without a line break
+fn push_line(ics: &mut String, line: &str) {
+    let mut length = 0;
+    for c in line.chars() {
+        if length + c.len_utf8() > MAX_LINE_BYTES {
+            ics.push_str("\r\n ");
+            length = 1;
+        }
+        ics.push(c);
+        length += c.len_utf8();
+    }
+    ics.push_str("\r\n");
+}
+
+/// Render a calendar as an ICS file
+///
+/// Events are listed in the order they start, so the feed is stable between fetches.
+///
+/// # Arguments
+///
+/// * `handler_addr` - The uri of the handler the calendar belongs to, used as its name
+/// * `calendar` - The events to list
+fn render(handler_addr: &str, calendar: &Calendar) -> String {
+    let mut events = calendar.values().collect::<Vec<&Event>>();
+    events.sort_by(|a, b| a.start.cmp(&b.start).then_with(|| a.uid.cmp(&b.uid)));
+
+    let mut ics = String::new();
+    push_line(&mut ics, "BEGIN:VCALENDAR");
+    push_line(&mut ics, "VERSION:2.0");
+    push_line(&mut ics, "PRODID:-//Majordomo//Handler Calendar//EN");
+    push_line(&mut ics, "CALSCALE:GREGORIAN");
+    push_line(
+        &mut ics,
+        &format!("X-WR-CALNAME:{}", escape_text(handler_addr)),
+    );
+
+    for event in events {
+        push_line(&mut ics, "BEGIN:VEVENT");
+        push_line(
+            &mut ics,
+            &format!(
+                "UID:{}@{}",
+                escape_text(&event.uid),
+                escape_text(handler_addr)
+            ),
+        );
+        push_line(
+            &mut ics,
+            &format!("DTSTAMP:{}", ics_time(event.published_at)),
+        );
+        if event.all_day {
+            // The end date is exclusive, so a one day event ends the day after it starts
+            let last_day = event.end.unwrap_or(event.start).max(event.start);
+            push_line(
+                &mut ics,
+                &format!("DTSTART;VALUE=DATE:{}", ics_date(event.start)),
+            );
+            push_line(
+                &mut ics,
+                &format!("DTEND;VALUE=DATE:{}", ics_date(last_day + 86_400)),
+            );
+        } else {
+            let end = event.end.unwrap_or(event.start).max(event.start);
+            push_line(&mut ics, &format!("DTSTART:{}", ics_time(event.start)));
+            push_line(&mut ics, &format!("DTEND:{}", ics_time(end)));
+        }
+        push_line(
+            &mut ics,
+            &format!("SUMMARY:{}", escape_text(&event.summary)),
+        );
+        if let Some(description) = &event.description {
+            push_line(
+                &mut ics,
+                &format!("DESCRIPTION:{}", escape_text(description)),
+            );
+        }
+        if let Some(location) = &event.location {
+            push_line(&mut ics, &format!("LOCATION:{}", escape_text(location)));
+        }
+        push_line(&mut ics, "END:VEVENT");
+    }
+
+    push_line(&mut ics, "END:VCALENDAR");
+    ics
+}
+
+/// Register the calendar functions available to clients
+///
+/// Each handler has its own calendar, served at `/h/<uri>/calendar.ics`, which calendar apps can
+/// subscribe to.
+///
+/// * `calendar_publish(event)` adds an event, or replaces the one with the same `uid`, and
+///   returns its uid. Events are maps with a `summary` and a `start`, and optionally an `end`,
+///   both unix timestamps, a `description`, a `location`, a `uid`, and `all_day`
+/// * `calendar_remove(uid)` removes an event, returning whether there was one
+/// * `calendar_clear()` removes every event, returning how many there were, e.g. before
+///   publishing a rebuilt schedule
+///
+/// # Arguments
+///
+/// * `module` - The module to register the functions in
+/// * `services` - Where calendars are stored
+/// * `handler_addr` - The uri of the handler the functions are for, i.e. the calendar
+pub fn register(module: &mut Module, services: &Services, handler_addr: &str) {
+    let calendars = services.calendars.clone();
+    let addr = handler_addr.to_string();
+    let calendar_publish = move |event: Dynamic| -> Result<String, Box<EvalAltResult>> {
+        let mut event: Event = from_dynamic(&event)?;
+        if event.summary.trim().is_empty() {
+            return Err("Events need a summary".into());
+        }
+        if event.uid.is_empty() {
+            event.uid = new_id();
+        }
+        event.published_at = unix_now();
+
+        let uid = event.uid.clone();
+        calendars.update(|map| {
+            let calendar = map.entry(addr.clone()).or_default();
+            if calendar.len() >= MAX_EVENTS && !calendar.contains_key(&event.uid) {
+                return Err(format!("A calendar may hold at most {} events", MAX_EVENTS));
+            }
+            calendar.insert(event.uid.clone(), event);
+            Ok(())
+        })?;
+        Ok(uid)
+    };
+
+    let calendars = services.calendars.clone();
+    let addr = handler_addr.to_string();
+    let calendar_remove = move |uid: ImmutableString| {
+        Ok(calendars.update(|map| {
+            map.get_mut(&addr)
+                .map(|calendar| calendar.remove(uid.as_str()).is_some())
+                .unwrap_or(false)
+        }))
+    };
+
+    let calendars = services.calendars.clone();
+    let addr = handler_addr.to_string();
+    let calendar_clear =
+        move || Ok(calendars.update(|map| map.remove(&addr).map(|c| c.len()).unwrap_or(0)) as INT);
+
+    module.set_fn_1("calendar_publish", calendar_publish);
+    module.set_fn_1("calendar_remove", calendar_remove);
+    module.set_fn_0("calendar_clear", calendar_clear);
+}
+
+/// Rocket Endpoint which serves the calendar of a handler as an ICS feed
+///
+/// Like invoking handlers, this needs no API Key, so calendar apps can subscribe to it. Handlers
+/// which haven't published anything have an empty calendar.
+///
+/// # Arguments
+///
+/// * `services` - Where calendars are stored
+/// * `handlers` - A reference to the collection of User created handlers, indexed by their uris
+/// * `handler_addr` - The uri of the handler whose calendar to serve
+#[get("/h/<handler_addr>/calendar.ics")]
+pub fn calendar_feed(
+    services: State<Services>,
+    handlers: Collection<String, Handler>,
+    handler_addr: String,
+) -> Option<Content<String>> {
+    if !handlers.read().unwrap().deref().contains_key(&handler_addr) {
+        return None;
+    }
+
+    let calendars = services.calendars.read();
+    let empty = Calendar::new();
+    let calendar = calendars.get(&handler_addr).unwrap_or(&empty);
+    Some(Content(
+        ContentType::new("text", "calendar"),
+        render(&handler_addr, calendar),
+    ))
+}
@@ -0,0 +1,384 @@
+use std::collections::HashMap;
+use std::ops::DerefMut;
+use std::sync::RwLock;
+
+use rocket::State;
+use rocket_contrib::json::Json;
+
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::auth::{check_auth, check_scope, describe_key, hash_key, AuthHeader};
+use crate::clock::unix_now;
+use crate::server::{build_handler, settle_owners, warm_up_handler, Collection};
+use crate::services::Services;
+use crate::slack::slack_api;
+use crate::storage::{new_id, Storage};
+use crate::types::{
+    APIKeyRequest, ApiKeyInfo, DecideChangeRequest, EnvInfo, Handler, HandlerSpec, UserResponse,
+    READ_SCOPE, WRITE_SCOPE,
+};
+
+/// The action ids of the buttons on the messages asking for a change to be approved
+pub const APPROVE_CHANGE_ACTION: &str = "majordomo_approve_change";
+pub const REJECT_CHANGE_ACTION: &str = "majordomo_reject_change";
+
+/// A change to a protected handler, which only goes live once another of its owners approves it
+///
+/// Changes are forgotten once decided, their decision being in the audit log.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PendingChange {
+    pub id: String,
+    /// The uri of the handler
+    pub uri: String,
+    /// The hash of the API Key which proposed the change. It can't approve it itself
+    pub proposed_by: String,
+    /// The handler as it would be after the change
+    pub spec: HandlerSpec,
+    /// When the handler was last saved as the change was proposed, so a change proposed against
+    /// an older version of it can't be approved
+    pub base_saved_at: u64,
+    /// The fingerprint of the signing key which signed the code, if it was signed, see
+    /// `signing::check_signature`
+    #[serde(default)]
+    pub signed_by: Option<String>,
+    /// When the change was proposed, as a unix timestamp
+    pub created_at: u64,
+}
+
+/// Refuse to change a protected handler other than by proposing a change, e.g. when rolling it
+/// back or archiving it
+///
+/// # Arguments
+///
+/// * `handler` - The handler about to be changed
+pub fn check_unprotected(handler: &Handler) -> Result<(), String> {
+    if handler.protected {
+        return Err(format!(
+            "{} is protected, changes to it must be proposed with /upsert_handler and approved",
+            handler.uri
+        ));
+    }
+    Ok(())
+}
+
+/// Record a change to a protected handler, and ask for it to be approved in
+/// `CHANGE_APPROVAL_CHANNEL`, if there is one
+///
+/// # Arguments
+///
+/// * `env` - Environment variables
+/// * `services` - Where pending changes are kept
+/// * `api_keys` - The collection of Client API keys, to describe who proposed the change
+/// * `handler` - The handler as it is
+/// * `proposed_by` - The hash of the API Key proposing the change
+/// * `spec` - The handler as it would be after the change
+/// * `signed_by` - The fingerprint of the signing key which signed its code, if it was signed
+pub fn propose(
+    env: &EnvInfo,
+    services: &Services,
+    api_keys: &RwLock<HashMap<String, ApiKeyInfo>>,
+    handler: &Handler,
+    proposed_by: &str,
+    spec: HandlerSpec,
+    signed_by: Option<String>,
+) -> PendingChange {
+    let change = PendingChange {
+        id: new_id(),
+        uri: handler.uri.clone(),
+        proposed_by: proposed_by.to_string(),
+        spec,
+        base_saved_at: handler.saved_at,
+        signed_by,
+        created_at: unix_now(),
+    };
+    services
+        .changes
+        .update(|map| map.insert(change.id.clone(), change.clone()));
+
+    let proposer = describe_key(proposed_by, api_keys);
+    log_event!(
+        "audit.change_proposed",
+        handler = change.uri,
+        change = change.id,
+        key = proposer,
+        signed_by = change.signed_by.as_deref().unwrap_or("unsigned"),
+    );
+
+    if let Some(channel) = &env.change_channel {
+        let message = request_message(channel, &change, handler, &proposer);
+        if let Err(e) = slack_api(
+            &services.http,
+            &env.slack_token,
+            "chat.postMessage",
+            &message,
+        ) {
+            log_event!("change.post_error", change = change.id, error = e);
+        }
+    }
+    change
+}
+
+/// The Slack message asking for a change to be approved
+fn request_message(
+    channel: &str,
+    change: &PendingChange,
+    handler: &Handler,
+    proposer: &str,
+) -> serde_json::Value {
+    let lines = |code: &str| code.lines().count();
+    let signed = match &change.signed_by {
+        Some(fingerprint) => format!("signed with `{}`", fingerprint),
+        None => "unsigned".to_string(),
+    };
+    let summary = format!(
+        "*Change proposed to `{}`* by {}\nIts code goes from {} to {} lines, {}",
+        change.uri,
+        proposer,
+        lines(&handler.code.raw),
+        lines(&change.spec.code),
+        signed
+    );
+
+    json!({
+        "channel": channel,
+        "text": format!("Change proposed to {}", change.uri),
+        "blocks": [
+            {
+                "type": "section",
+                "text": { "type": "mrkdwn", "text": summary }
+            },
+            {
+                "type": "actions",
+                "elements": [
+                    {
+                        "type": "button",
+                        "style": "primary",
+                        "text": { "type": "plain_text", "text": "Approve" },
+                        "action_id": APPROVE_CHANGE_ACTION,
+                        "value": change.id
+                    },
+                    {
+                        "type": "button",
+                        "style": "danger",
+                        "text": { "type": "plain_text", "text": "Reject" },
+                        "action_id": REJECT_CHANGE_ACTION,
+                        "value": change.id
+                    }
+                ]
+            }
+        ]
+    })
+}
+
+/// Approve or reject a pending change, putting it live if it was approved
+///
+/// Changes must be decided by another owner or co-owner of the handler than the one which
+/// proposed them. A change stays pending if it couldn't be saved, so it can be approved again.
+/// Returns the decided change, or why the decision was refused
+///
+/// # Arguments
+///
+/// * `env` - Environment variables
+/// * `storage` - Where handlers are saved
+/// * `services` - Where pending changes are kept, and for the warm-up
+/// * `api_keys` - The collection of Client API keys, by hash
+/// * `handlers` - The handlers, by their uris
+/// * `change_id` - The change being decided
+/// * `decided_by` - The hash of the API Key deciding
+/// * `approve` - Whether the change was approved or rejected
+#[allow(clippy::too_many_arguments)]
+pub fn decide(
+    env: &EnvInfo,
+    storage: &Storage,
+    services: &Services,
+    api_keys: &RwLock<HashMap<String, ApiKeyInfo>>,
+    handlers: &RwLock<HashMap<String, Handler>>,
+    change_id: &str,
+    decided_by: &str,
+    approve: bool,
+) -> Result<PendingChange, String> {
+    let mut guard = handlers.write().unwrap();
+    let map = guard.deref_mut();
+    let change = services
+        .changes
+        .read()
+        .get(change_id)
+        .cloned()
+        .ok_or_else(|| format!("No pending change with id {}", change_id))?;
+
+    let current = match map.get(&change.uri) {
+        Some(h) if !h.owned_by(decided_by) => {
+            return Err(format!(
+                "Only the owners of {} may decide changes to it",
+                change.uri
+            ))
+        }
+        Some(h) => h,
+        None => {
+            services.changes.update(|m| m.remove(change_id));
+            return Err(format!("{} no longer exists", change.uri));
+        }
+    };
+    if change.proposed_by == decided_by {
+        return Err(
+            "Changes must be decided by another API Key than the one which proposed them".into(),
+        );
+    }
+
+    services.changes.update(|m| m.remove(change_id));
+    let decider = describe_key(decided_by, api_keys);
+    if !approve {
+        log_event!(
+            "audit.change_rejected",
+            handler = change.uri,
+            change = change.id,
+            key = decider,
+        );
+        return Ok(change);
+    }
+
+    if current.saved_at != change.base_saved_at {
+        return Err(format!(
+            "{} changed since this change was proposed, it must be proposed again",
+            change.uri
+        ));
+    }
+    if !current.owned_by(&change.proposed_by) {
+        return Err("Whoever proposed this change no longer owns the handler".into());
+    }
+    let mut spec = change.spec.clone();
+    let co_owners = spec.co_owners.take();
+    let mut handler = build_handler(env, change.uri.clone(), change.proposed_by.clone(), spec)?;
+    settle_owners(
+        &mut handler,
+        Some(current),
+        co_owners,
+        &api_keys.read().unwrap(),
+    )?;
+    handler.supersede(current.clone());
+    let previous = map.insert(change.uri.clone(), handler);
+
+    if let Err(e) = storage.save_handlers(map, &[change.uri.clone()]) {
+        log_event!("db.save_error", storage = storage.describe(), error = e);
+        if let Some(previous) = previous {
+            map.insert(change.uri.clone(), previous);
+        }
+        services
+            .changes
+            .update(|m| m.insert(change.id.clone(), change));
+        return Err("Server error while saving db".into());
+    }
+    log_event!(
+        "audit.change_approved",
+        handler = change.uri,
+        change = change.id,
+        key = decider,
+        signed_by = change.signed_by.as_deref().unwrap_or("unsigned"),
+    );
+
+    // The change is live either way, like with `/upsert_handler`, which also doesn't warm up
+    // under the write lock
+    drop(guard);
+    let guard = handlers.read().unwrap();
+    if let Some(handler) = guard.get(&change.uri).filter(|h| h.warmup) {
+        if let Err(e) = warm_up_handler(env, services, handler) {
+            log_event!("handler.warmup_error", handler = change.uri, error = e);
+        }
+    }
+    Ok(change)
+}
+
+/// Rocket Endpoint which lists the pending changes to the handlers an API Key owns or co-owns
+///
+/// # Arguments
+///
+/// * `auth` - The API Key from the `Authorization` header, if any
+/// * `services` - Where pending changes are kept
+/// * `api_keys` - A reference to the collection of Client API keys, used to check for auth
+/// * `handlers` - A reference to the collection of User created handlers
+/// * `post_data` - The API Key, if not in the header
+#[post("/list_changes", data = "<post_data>")]
+pub fn list_changes(
+    auth: AuthHeader,
+    services: State<Services>,
+    api_keys: Collection<String, ApiKeyInfo>,
+    handlers: Collection<String, Handler>,
+    post_data: Option<Json<APIKeyRequest>>,
+) -> Json<UserResponse> {
+    let key = auth.key_or(&post_data.map(|d| d.0.api_key).unwrap_or_default());
+
+    if let Err(cause) = auth.verify(&key, check_auth(&key, &api_keys), "Invalid API Key") {
+        return Json(UserResponse::from(cause));
+    }
+    if let Err(cause) = check_scope(&key, &api_keys, READ_SCOPE) {
+        return Json(UserResponse::from(cause));
+    }
+
+    let owner = hash_key(&key);
+    let handlers = handlers.read().unwrap();
+    let mut changes = services
+        .changes
+        .read()
+        .values()
+        .filter(|c| handlers.get(&c.uri).map_or(false, |h| h.owned_by(&owner)))
+        .cloned()
+        .collect::<Vec<PendingChange>>();
+    changes.sort_by_key(|c| c.created_at);
+
+    Json(
+        UserResponse::success_with_raw(changes)
+            .unwrap_or_else(|| UserResponse::failure("Unable to list changes".into())),
+    )
+}
+
+/// Rocket Endpoint which approves or rejects a pending change to a protected handler, see
+/// `decide`
+///
+/// # Arguments
+///
+/// * `auth` - The API Key from the `Authorization` header, if any
+/// * `env` - Environment variables
+/// * `storage` - Where handlers are saved
+/// * `services` - Where pending changes are kept
+/// * `api_keys` - A reference to the collection of Client API keys, used to check for auth
+/// * `handlers` - A reference to the collection of User created handlers
+/// * `post_data` - The id of the change, and the decision
+#[post("/decide_change", data = "<post_data>")]
+pub fn decide_change(
+    auth: AuthHeader,
+    env: State<EnvInfo>,
+    storage: State<Storage>,
+    services: State<Services>,
+    api_keys: Collection<String, ApiKeyInfo>,
+    handlers: Collection<String, Handler>,
+    post_data: Json<DecideChangeRequest>,
+) -> Json<UserResponse> {
+    let data = post_data.0;
+    let key = auth.key_or(&data.api_key);
+
+    if let Err(cause) = auth.verify(&key, check_auth(&key, &api_keys), "Invalid API Key") {
+        return Json(UserResponse::from(cause));
+    }
+    if let Err(cause) = check_scope(&key, &api_keys, WRITE_SCOPE) {
+        return Json(UserResponse::from(cause));
+    }
+    if env.read_only {
+        return Json(UserResponse::read_only());
+    }
+
+    let decided = decide(
+        &env,
+        &storage,
+        &services,
+        &api_keys,
+        &handlers,
+        &data.id,
+        &hash_key(&key),
+        data.approve,
+    );
+    match decided {
+        Ok(_) => Json(UserResponse::success()),
+        Err(cause) => Json(UserResponse::failure(cause)),
+    }
+}
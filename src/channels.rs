@@ -0,0 +1,43 @@
+use std::collections::HashMap;
+use std::sync::RwLock;
+use std::time::{Duration, Instant};
+
+/// How long a channel's name is trusted for. Renames Slack tells us about are picked up at once,
+/// see `slack_redirector`, so this only bounds how stale a name can get if an event was missed
+const NAME_TTL: Duration = Duration::from_secs(60 * 60);
+
+/// The names of Slack channels, by their id, so that routing a message doesn't cost a call to
+/// `conversations.info` every time
+#[derive(Default)]
+pub struct ChannelNames {
+    names: RwLock<HashMap<String, (String, Instant)>>,
+}
+
+impl ChannelNames {
+    /// The name of a channel, unless it isn't known, or was looked up too long ago
+    ///
+    /// # Arguments
+    ///
+    /// * `channel` - The id of the channel
+    pub fn get(&self, channel: &str) -> Option<String> {
+        self.names
+            .read()
+            .unwrap()
+            .get(channel)
+            .filter(|(_, at)| at.elapsed() < NAME_TTL)
+            .map(|(name, _)| name.clone())
+    }
+
+    /// Remember the name of a channel, e.g. after looking it up, or when it was renamed
+    ///
+    /// # Arguments
+    ///
+    /// * `channel` - The id of the channel
+    /// * `name` - Its name
+    pub fn insert(&self, channel: &str, name: String) {
+        let mut names = self.names.write().unwrap();
+        // Names which expired are only dropped here, and there are never many channels
+        names.retain(|_, (_, at)| at.elapsed() < NAME_TTL);
+        names.insert(channel.to_string(), (name, Instant::now()));
+    }
+}
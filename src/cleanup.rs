@@ -0,0 +1,483 @@
+use std::collections::{BTreeMap, HashMap};
+use std::ops::DerefMut;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+
+use rocket::State;
+use rocket_contrib::json::Json;
+
+use serde::Serialize;
+
+use crate::auth::{check_admin, check_auth, check_scope, describe_key, hash_key, AuthHeader};
+use crate::changes::check_unprotected;
+use crate::clock::{unix_now, utc_date};
+use crate::server::{slack_post_internal, Collection};
+use crate::services::Services;
+use crate::stats::Stats;
+use crate::storage::{JsonStore, Storage};
+use crate::types::{
+    ApiKeyInfo, DeadHandlersRequest, EnvInfo, FailureKind, FindHandlerRequest, Handler,
+    UserResponse, READ_SCOPE, WRITE_SCOPE,
+};
+
+/// How often owners are told about their dead handlers, at most, in seconds
+const CHECK_INTERVAL_SECS: u64 = 24 * 60 * 60;
+
+/// How many days a handler must be idle to be dead, unless `DEAD_HANDLER_DAYS` says otherwise
+pub const DEFAULT_DAYS: u64 = 90;
+
+/// The most handlers listed in a single message to an owner
+const MAX_LISTED: usize = 20;
+
+/// Handlers nobody uses anymore, and those their owners have archived
+///
+/// A handler is dead if it hasn't run, or been saved, in `EnvInfo::dead_handler_days`. Its
+/// owner is told once a day, by a Slack DM to whoever activated their API Key, and can archive
+/// it from the dashboard, or with `/archive_handler`. Archived handlers stop being served, but
+/// are kept, code, history and all, until `/restore_handler` brings them back.
+pub struct Cleanup {
+    /// When the owner of each dead handler was last told about it, by its uri
+    notices: JsonStore<u64>,
+    /// The archived handlers, by their uri
+    archived: JsonStore<Handler>,
+    /// When owners were last told, as a unix timestamp. 0 if not since we started
+    checked_at: AtomicU64,
+}
+
+impl Cleanup {
+    /// Open the notices and archived handlers saved at these paths, or empty ones
+    pub fn open(notices_path: String, archived_path: String) -> Cleanup {
+        Cleanup {
+            notices: JsonStore::open(notices_path),
+            archived: JsonStore::open(archived_path),
+            checked_at: AtomicU64::new(0),
+        }
+    }
+
+    /// Keep a handler which was taken out of service, until it is restored
+    ///
+    /// # Arguments
+    ///
+    /// * `uri` - The uri it was served at
+    /// * `handler` - The handler
+    pub fn archive(&self, uri: &str, handler: Handler) {
+        self.archived
+            .update(|archived| archived.insert(uri.to_string(), handler));
+        self.notices.update(|notices| notices.remove(uri));
+    }
+}
+
+/// A handler which hasn't run in a while
+#[derive(Debug, Serialize)]
+pub struct DeadHandler {
+    pub uri: String,
+    pub description: Option<String>,
+    /// How many times it has run, ever
+    pub invocations: u64,
+    /// When it last ran, as a unix timestamp. 0 if never
+    pub last_invoked_at: u64,
+    /// When it was last saved, as a unix timestamp. 0 if unknown
+    pub updated_at: u64,
+    /// How many days it has been since it last ran, or was saved, whichever was later
+    pub idle_days: u64,
+    /// Who owns it, see `auth::describe_key`. Only given to the admin
+    pub owner: Option<String>,
+    /// The hash of the API Key which owns it
+    #[serde(skip)]
+    owner_hash: String,
+}
+
+/// Find the handlers which haven't run, or been saved, in `days`, least recently active first
+///
+/// Replicas don't share their stats with the primary, so handlers only ever called on replicas
+/// look dead to it.
+///
+/// # Arguments
+///
+/// * `handlers` - The handlers to look through
+/// * `stats` - How often each handler has run
+/// * `days` - How long a handler must be idle to be dead
+/// * `now` - The current unix timestamp
+pub fn find_dead<'a, I: Iterator<Item = &'a Handler>>(
+    handlers: I,
+    stats: &Stats,
+    days: u64,
+    now: u64,
+) -> Vec<DeadHandler> {
+    let mut dead = handlers
+        .filter_map(|h| {
+            let s = stats.get(&h.uri);
+            let active_at = s.last_invoked_at.max(h.saved_at).max(h.created_at);
+            let idle_days = now.saturating_sub(active_at) / (24 * 60 * 60);
+            if idle_days < days {
+                return None;
+            }
+            Some(DeadHandler {
+                uri: h.uri.clone(),
+                description: h.description.clone(),
+                invocations: s.invocations,
+                last_invoked_at: s.last_invoked_at,
+                updated_at: h.saved_at,
+                idle_days,
+                owner: None,
+                owner_hash: h.api_key.clone(),
+            })
+        })
+        .collect::<Vec<DeadHandler>>();
+    dead.sort_by(|a, b| b.idle_days.cmp(&a.idle_days).then(a.uri.cmp(&b.uri)));
+    dead
+}
+
+/// The Slack user to tell about the handlers of an API Key: whoever activated it, or its
+/// contact, if that is a Slack user id
+fn slack_user(info: &ApiKeyInfo) -> Option<String> {
+    let is_user_id = |c: &String| {
+        c.len() > 1
+            && (c.starts_with('U') || c.starts_with('W'))
+            && c.chars().all(|c| c.is_ascii_alphanumeric())
+    };
+    info.activated_by
+        .clone()
+        .or_else(|| info.contact.clone().filter(is_user_id))
+}
+
+/// Describe dead handlers to their owner
+///
+/// # Arguments
+///
+/// * `dead` - The owner's dead handlers
+/// * `days` - How long a handler must be idle to be dead
+fn message(dead: &[&DeadHandler], days: u64) -> String {
+    let mut lines = vec![format!(
+        "These handlers of yours haven't run in at least {} days:",
+        days
+    )];
+    for handler in dead.iter().take(MAX_LISTED) {
+        let last_ran = match handler.last_invoked_at {
+            0 => "never ran".to_string(),
+            at => format!("last ran {}", utc_date(at)),
+        };
+        lines.push(format!("• `{}` ({})", handler.uri, last_ran));
+    }
+    if dead.len() > MAX_LISTED {
+        lines.push(format!("…and {} more", dead.len() - MAX_LISTED));
+    }
+    lines.push(
+        "If they are no longer needed, please archive them from the Majordomo dashboard. \
+         Archived handlers can be restored at any time."
+            .into(),
+    );
+    lines.join("\n")
+}
+
+/// Tell owners about their dead handlers, once a day. Run by the scheduler
+///
+/// Each handler is only mentioned once per stretch of idleness: if it runs or is saved, and
+/// then goes idle again, its owner is told again. Owners whose API Key has no Slack user are
+/// skipped, and told once they have one.
+///
+/// # Arguments
+///
+/// * `env` - Environment variables
+/// * `services` - Where the stats and notices are kept
+/// * `handlers` - The handlers
+/// * `api_keys` - The Client API keys, to find the owners of handlers
+/// * `now` - The current unix timestamp
+pub fn tick(
+    env: &EnvInfo,
+    services: &Services,
+    handlers: &Arc<RwLock<HashMap<String, Handler>>>,
+    api_keys: &Arc<RwLock<HashMap<String, ApiKeyInfo>>>,
+    now: u64,
+) {
+    let cleanup = &services.cleanup;
+    let checked_at = cleanup.checked_at.load(Ordering::Relaxed);
+    if env.dead_handler_days == 0 || now.saturating_sub(checked_at) < CHECK_INTERVAL_SECS {
+        return;
+    }
+    cleanup.checked_at.store(now, Ordering::Relaxed);
+
+    let dead = {
+        let guard = handlers.read().unwrap();
+        cleanup
+            .notices
+            .update(|notices| notices.retain(|uri, _| guard.contains_key(uri)));
+        find_dead(guard.values(), &services.stats, env.dead_handler_days, now)
+    };
+
+    // Only what became dead since the owner was last told
+    let notices = cleanup.notices.read().clone();
+    let mut by_owner = BTreeMap::<String, Vec<&DeadHandler>>::new();
+    for handler in &dead {
+        let active_at = handler.last_invoked_at.max(handler.updated_at);
+        if notices
+            .get(&handler.uri)
+            .map(|at| *at < active_at)
+            .unwrap_or(true)
+        {
+            by_owner
+                .entry(handler.owner_hash.clone())
+                .or_default()
+                .push(handler);
+        }
+    }
+
+    for (owner, dead) in by_owner {
+        let user = api_keys
+            .read()
+            .unwrap()
+            .get(&owner)
+            .map(slack_user)
+            .flatten();
+        let user = match user {
+            Some(user) => user,
+            None => {
+                log_event!("cleanup.no_contact", handlers = dead.len());
+                continue;
+            }
+        };
+
+        let text = message(&dead, env.dead_handler_days);
+        if slack_post_internal(&services.http, &env.slack_token, user, text) {
+            cleanup.notices.update(|notices| {
+                for handler in &dead {
+                    notices.insert(handler.uri.clone(), now);
+                }
+            });
+            log_event!("cleanup.notified", handlers = dead.len());
+        }
+    }
+}
+
+/// Rocket Endpoint which lists the handlers of the API Key which haven't run, or been saved, in a
+/// while, least recently active first, see `find_dead`
+///
+/// The admin key lists every dead handler, along with its owner.
+///
+/// # Arguments
+///
+/// * `auth` - The API Key, or admin key, from the `Authorization` header, if any
+/// * `env` - Environment variables
+/// * `services` - Where the stats are kept
+/// * `api_keys` - A reference to the collection of Client API keys, used to check for auth
+/// * `handlers` - A reference to the collection of User created handlers, indexed by their uris
+/// * `post_data` - How many days a handler must be idle, `DEAD_HANDLER_DAYS`, or `DEFAULT_DAYS`
+///   if that is 0, when omitted
+#[post("/dead_handlers", data = "<post_data>")]
+pub fn dead_handlers(
+    auth: AuthHeader,
+    env: State<EnvInfo>,
+    services: State<Services>,
+    api_keys: Collection<String, ApiKeyInfo>,
+    handlers: Collection<String, Handler>,
+    post_data: Json<DeadHandlersRequest>,
+) -> Json<UserResponse> {
+    let data = post_data.0;
+    let key = auth.key_or(&data.api_key);
+
+    let admin = check_admin(&key, &env);
+    if let Err(cause) = auth.verify(
+        &key,
+        admin || check_auth(&key, &api_keys),
+        "Invalid API Key",
+    ) {
+        return Json(UserResponse::from(cause));
+    }
+    if !admin {
+        if let Err(cause) = check_scope(&key, &api_keys, READ_SCOPE) {
+            return Json(UserResponse::from(cause));
+        }
+    }
+
+    let configured = Some(env.dead_handler_days).filter(|d| *d > 0);
+    let days = match data.days.or(configured).unwrap_or(DEFAULT_DAYS) {
+        0 => return Json(UserResponse::failure("days must be at least 1".into())),
+        days => days,
+    };
+
+    let owner = hash_key(&key);
+    let guard = handlers.read().unwrap();
+    let owned = guard.values().filter(|h| admin || h.owned_by(&owner));
+    let mut dead = find_dead(owned, &services.stats, days, unix_now());
+    if admin {
+        for handler in &mut dead {
+            handler.owner = Some(describe_key(&handler.owner_hash, &api_keys));
+        }
+    }
+
+    Json(
+        UserResponse::success_with_raw(dead)
+            .unwrap_or_else(|| UserResponse::failure("Unable to list handlers".into())),
+    )
+}
+
+/// Rocket Endpoint which archives a handler: it stops being served, and its uri is free to be
+/// reused, but it is kept, code, history and all, so `/restore_handler` can bring it back
+///
+/// What the handler set up elsewhere, e.g. its key-value store, is left alone, though its stale
+/// issue sweeps and uptime checks are dropped the next time they come due.
+///
+/// # Arguments
+///
+/// * `auth` - The API Key from the `Authorization` header, if any
+/// * `env` - Environment variables
+/// * `storage` - Where the handlers are saved
+/// * `services` - Where archived handlers are kept
+/// * `api_keys` - A reference to the collection of Client API keys, used to check for auth
+/// * `handlers` - A reference to the collection of User created handlers, indexed by their uris
+/// * `post_data` - The uri of the handler. Must be owned by the API Key
+#[post("/archive_handler", data = "<post_data>")]
+pub fn archive_handler(
+    auth: AuthHeader,
+    env: State<EnvInfo>,
+    storage: State<Storage>,
+    services: State<Services>,
+    api_keys: Collection<String, ApiKeyInfo>,
+    handlers: Collection<String, Handler>,
+    post_data: Json<FindHandlerRequest>,
+) -> Json<UserResponse> {
+    if env.read_only {
+        return Json(UserResponse::read_only());
+    }
+
+    let data = post_data.0;
+    let key = auth.key_or(&data.api_key);
+
+    if let Err(cause) = auth.verify(&key, check_auth(&key, &api_keys), "Invalid API Key") {
+        return Json(UserResponse::from(cause));
+    }
+    if let Err(cause) = check_scope(&key, &api_keys, WRITE_SCOPE) {
+        return Json(UserResponse::from(cause));
+    }
+
+    let mut guard = handlers.write().unwrap();
+    let map = guard.deref_mut();
+
+    match map.get(&data.uri) {
+        Some(h) if h.owned_by(&hash_key(&key)) => {
+            if let Err(cause) = check_unprotected(h) {
+                return Json(UserResponse::failure_of(FailureKind::Conflict, cause));
+            }
+        }
+        Some(_) => {
+            return Json(UserResponse::failure_of(
+                FailureKind::Unauthorized,
+                "Invalid API Key".into(),
+            ))
+        }
+        None => {
+            return Json(UserResponse::failure_of(
+                FailureKind::NotFound,
+                "Unknown handler uri".into(),
+            ))
+        }
+    }
+
+    let handler = map.remove(&data.uri).unwrap();
+    if let Err(e) = storage.save_handlers(map, &[data.uri.clone()]) {
+        log_event!("db.save_error", storage = storage.describe(), error = e);
+        map.insert(data.uri.clone(), handler);
+        return Json(UserResponse::failure_of(
+            FailureKind::Internal,
+            "Server error while saving db".into(),
+        ));
+    }
+
+    services.cleanup.archive(&data.uri, handler);
+
+    log_event!(
+        "audit.archive_handler",
+        handler = data.uri,
+        key = describe_key(&hash_key(&key), &api_keys),
+    );
+    Json(UserResponse::success())
+}
+
+/// Rocket Endpoint which brings back a handler archived with `/archive_handler`, as it was. Its
+/// owner isn't told it is dead again until it has run, or been saved, since
+///
+/// # Arguments
+///
+/// * `auth` - The API Key from the `Authorization` header, if any
+/// * `env` - Environment variables
+/// * `storage` - Where the handlers are saved
+/// * `services` - Where archived handlers are kept
+/// * `api_keys` - A reference to the collection of Client API keys, used to check for auth
+/// * `handlers` - A reference to the collection of User created handlers, indexed by their uris
+/// * `post_data` - The uri of the handler. Must be owned by the API Key, and not taken since
+#[post("/restore_handler", data = "<post_data>")]
+pub fn restore_handler(
+    auth: AuthHeader,
+    env: State<EnvInfo>,
+    storage: State<Storage>,
+    services: State<Services>,
+    api_keys: Collection<String, ApiKeyInfo>,
+    handlers: Collection<String, Handler>,
+    post_data: Json<FindHandlerRequest>,
+) -> Json<UserResponse> {
+    if env.read_only {
+        return Json(UserResponse::read_only());
+    }
+
+    let data = post_data.0;
+    let key = auth.key_or(&data.api_key);
+
+    if let Err(cause) = auth.verify(&key, check_auth(&key, &api_keys), "Invalid API Key") {
+        return Json(UserResponse::from(cause));
+    }
+    if let Err(cause) = check_scope(&key, &api_keys, WRITE_SCOPE) {
+        return Json(UserResponse::from(cause));
+    }
+
+    let mut guard = handlers.write().unwrap();
+    let map = guard.deref_mut();
+    if map.contains_key(&data.uri) {
+        let cause = format!("A handler with uri {} already exists", data.uri);
+        return Json(UserResponse::failure_of(FailureKind::Conflict, cause));
+    }
+
+    let archived = &services.cleanup.archived;
+    let owned = archived
+        .read()
+        .get(&data.uri)
+        .map(|h| h.owned_by(&hash_key(&key)));
+    match owned {
+        Some(true) => {}
+        Some(_) => {
+            return Json(UserResponse::failure_of(
+                FailureKind::Unauthorized,
+                "Invalid API Key".into(),
+            ))
+        }
+        None => {
+            return Json(UserResponse::failure_of(
+                FailureKind::NotFound,
+                "Unknown archived handler uri".into(),
+            ))
+        }
+    }
+    let handler = archived.update(|archived| archived.remove(&data.uri).unwrap());
+
+    map.insert(data.uri.clone(), handler);
+    if let Err(e) = storage.save_handlers(map, &[data.uri.clone()]) {
+        log_event!("db.save_error", storage = storage.describe(), error = e);
+        let handler = map.remove(&data.uri).unwrap();
+        archived.update(|archived| archived.insert(data.uri.clone(), handler));
+        return Json(UserResponse::failure_of(
+            FailureKind::Internal,
+            "Server error while saving db".into(),
+        ));
+    }
+    // It was most likely dead when it was archived, and its owner knows
+    services
+        .cleanup
+        .notices
+        .update(|notices| notices.insert(data.uri.clone(), unix_now()));
+
+    log_event!(
+        "audit.restore_handler",
+        handler = data.uri,
+        key = describe_key(&hash_key(&key), &api_keys),
+    );
+    Json(UserResponse::success())
+}
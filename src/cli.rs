@@ -0,0 +1,246 @@
+use std::collections::HashMap;
+use std::fs;
+use std::process;
+
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+
+use rhai::Engine;
+
+use serde_json::Value;
+
+use crate::admin::generate_key;
+use crate::auth::{check_scopes, hash_key, hash_plain_owners};
+use crate::namespaces::check_namespace;
+use crate::storage::Storage;
+use crate::types::{ApiKeyInfo, Handler};
+
+/// The command line interface, with `serve` being what runs when no subcommand is given
+pub fn app() -> App<'static, 'static> {
+    App::new("majordomo")
+        .version(env!("CARGO_PKG_VERSION"))
+        .about("Runs handlers in response to Slack, GitHub and HTTP events")
+        .setting(AppSettings::VersionlessSubcommands)
+        .subcommand(SubCommand::with_name("serve").about("Start the server (the default)"))
+        .subcommand(
+            SubCommand::with_name("validate")
+                .about("Check that every handler in a handlers file compiles")
+                .arg(
+                    Arg::with_name("path")
+                        .help("The handlers file, e.g. handlers.json")
+                        .required(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("export")
+                .about("Write the stored handlers out as json")
+                .arg(
+                    Arg::with_name("out")
+                        .help("Where to write them. Standard output, if not given"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("import")
+                .about("Add the handlers in a json file, replacing any with the same uri")
+                .arg(
+                    Arg::with_name("path")
+                        .help("A file written by export")
+                        .required(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("gen-key")
+                .about("Mint a new API Key, and print it")
+                .arg(
+                    Arg::with_name("label")
+                        .long("label")
+                        .takes_value(true)
+                        .help("A human readable name for the key"),
+                )
+                .arg(
+                    Arg::with_name("contact")
+                        .long("contact")
+                        .takes_value(true)
+                        .help("How to reach the owner of the key"),
+                )
+                .arg(
+                    Arg::with_name("scopes")
+                        .long("scopes")
+                        .takes_value(true)
+                        .help("Any of invoke, read and write, comma separated. All, if not given"),
+                )
+                .arg(
+                    Arg::with_name("namespace")
+                        .long("namespace")
+                        .takes_value(true)
+                        .help("The team prefix the key's handlers are saved under"),
+                ),
+        )
+}
+
+/// Print an error and exit, for the commands below
+fn fail(message: String) -> ! {
+    eprintln!("{}", message);
+    process::exit(1)
+}
+
+/// Read a handlers file as raw json, so each handler can be looked at on its own. Loading it as
+/// handlers fails as a whole at the first one which doesn't compile
+///
+/// # Arguments
+///
+/// * `path` - The handlers file
+fn read_raw(path: &str) -> HashMap<String, Value> {
+    let data = fs::read_to_string(path)
+        .unwrap_or_else(|e| fail(format!("Unable to read {}: {}", path, e)));
+    serde_json::from_str(&data)
+        .unwrap_or_else(|e| fail(format!("{} is not a json object of handlers: {}", path, e)))
+}
+
+/// Compile every handler in a handlers file, and report those which don't. Exits with 1 if any
+/// don't
+///
+/// # Arguments
+///
+/// * `path` - The handlers file
+pub fn validate(path: &str) {
+    let raw = read_raw(path);
+    let mut uris = raw.keys().collect::<Vec<&String>>();
+    uris.sort();
+
+    let engine = Engine::new();
+    let mut failed = 0;
+    for uri in uris {
+        let code = match raw[uri].get("code").and_then(Value::as_str) {
+            Some(code) => code,
+            None => {
+                println!("{}: no code", uri);
+                failed += 1;
+                continue;
+            }
+        };
+        if let Err(e) = engine.compile(code) {
+            println!("{}: {}", uri, e);
+            failed += 1;
+        }
+    }
+
+    println!("{} of {} Handlers compile", raw.len() - failed, raw.len());
+    if failed > 0 {
+        process::exit(1);
+    }
+}
+
+/// Write the stored handlers out as json, in the format `import` and the json backend read
+///
+/// # Arguments
+///
+/// * `storage` - Where the handlers are saved
+/// * `out` - The file to write them to. Standard output, if None
+pub fn export(storage: &Storage, out: Option<&str>) {
+    let handlers = storage.load_handlers().unwrap_or_else(|| {
+        fail(format!(
+            "Unable to load handlers from {}",
+            storage.describe()
+        ))
+    });
+    let json = serde_json::to_string_pretty(&handlers)
+        .unwrap_or_else(|e| fail(format!("Unable to write handlers: {}", e)));
+
+    match out {
+        Some(path) => {
+            fs::write(path, json)
+                .unwrap_or_else(|e| fail(format!("Unable to write {}: {}", path, e)));
+            eprintln!("Exported {} Handlers to {}", handlers.len(), path);
+        }
+        None => println!("{}", json),
+    }
+}
+
+/// Add the handlers in a json file to the stored ones, replacing any with the same uri
+///
+/// Nothing is imported unless every handler in the file compiles. The server only reads
+/// handlers when it starts, or, for replicas, when they change, so a running primary should be
+/// restarted afterwards.
+///
+/// # Arguments
+///
+/// * `storage` - Where the handlers are saved
+/// * `path` - The file to import, e.g. one written by `export`
+pub fn import(storage: &Storage, path: &str) {
+    let mut imported = HashMap::new();
+    let mut failed = 0;
+    for (uri, value) in read_raw(path) {
+        match serde_json::from_value::<Handler>(value) {
+            Ok(handler) => {
+                imported.insert(uri, handler);
+            }
+            Err(e) => {
+                println!("{}: {}", uri, e);
+                failed += 1;
+            }
+        }
+    }
+    if failed > 0 {
+        fail(format!(
+            "{} Handlers are invalid, so none were imported",
+            failed
+        ));
+    }
+    hash_plain_owners(&mut imported);
+
+    let mut handlers = storage.load_handlers().unwrap_or_default();
+    let uris = imported.keys().cloned().collect::<Vec<String>>();
+    handlers.extend(imported);
+    storage
+        .save_handlers(&handlers, &uris)
+        .unwrap_or_else(|e| fail(format!("Unable to save handlers: {}", e)));
+    println!(
+        "Imported {} Handlers into {}",
+        uris.len(),
+        storage.describe()
+    );
+}
+
+/// Mint a new API Key, save its hash, and print it. This is the only time it is shown
+///
+/// # Arguments
+///
+/// * `storage` - Where the api keys are saved
+/// * `args` - The `gen-key` arguments
+pub fn gen_key(storage: &Storage, args: &ArgMatches) {
+    let scopes = args
+        .value_of("scopes")
+        .unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .filter(|s| !s.is_empty())
+        .map(String::from)
+        .collect::<Vec<String>>();
+    if let Err(cause) = check_scopes(&scopes) {
+        fail(cause);
+    }
+
+    let mut api_keys = storage.load_api_keys().unwrap_or_default();
+    let key = generate_key();
+    let hash = hash_key(&key);
+    let namespace = args.value_of("namespace").map(String::from);
+    if let Some(namespace) = &namespace {
+        if let Err(cause) = check_namespace(namespace, &hash, &api_keys) {
+            fail(cause);
+        }
+    }
+    api_keys.insert(
+        hash.clone(),
+        ApiKeyInfo {
+            label: args.value_of("label").map(String::from),
+            contact: args.value_of("contact").map(String::from),
+            scopes,
+            namespace,
+            ..ApiKeyInfo::default()
+        },
+    );
+    storage
+        .save_api_keys(&api_keys, &[hash])
+        .unwrap_or_else(|e| fail(format!("Unable to save api keys: {}", e)));
+    println!("{}", key);
+}
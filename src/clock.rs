@@ -0,0 +1,85 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// The current time, in seconds since the unix epoch
+/// All timestamps Majordomo stores or accepts are in this format
+pub fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// The UTC calendar date of a unix timestamp, as `YYYY-MM-DD`
+///
+/// # Arguments
+///
+/// * `unix` - The timestamp
+pub fn utc_date(unix: u64) -> String {
+    // The calculation in `unix_from_utc`, backwards
+    let days = unix / 86_400 + 719_468;
+    let era = days / 146_097;
+    let day_of_era = days % 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1_460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let m = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * m + 2) / 5 + 1;
+    let month = if m < 10 { m + 3 } else { m - 9 };
+    let year = era * 400 + year_of_era + if month <= 2 { 1 } else { 0 };
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
+
+/// The UTC date and time of a unix timestamp, as RFC 3339, e.g. `2020-10-01T12:30:00Z`
+///
+/// # Arguments
+///
+/// * `unix` - The timestamp
+pub fn utc_datetime(unix: u64) -> String {
+    let secs = unix % 86_400;
+    format!(
+        "{}T{:02}:{:02}:{:02}Z",
+        utc_date(unix),
+        secs / 3_600,
+        secs % 3_600 / 60,
+        secs % 60
+    )
+}
+
+/// Convert a UTC calendar date and time to a unix timestamp
+/// Returns `None` if the date or time does not exist, or is before the epoch
+///
+/// # Arguments
+///
+/// * `year`, `month`, `day` - The date, with months and days counted from 1
+/// * `hour`, `minute`, `second` - The time of day, on a 24 hour clock
+pub fn unix_from_utc(
+    year: u64,
+    month: u64,
+    day: u64,
+    hour: u64,
+    minute: u64,
+    second: u64,
+) -> Option<u64> {
+    let leap = (year % 4 == 0 && year % 100 != 0) || year % 400 == 0;
+    let month_days = match month {
+        1 | 3 | 5 | 7 | 8 | 10 | 12 => 31,
+        4 | 6 | 9 | 11 => 30,
+        2 if leap => 29,
+        2 => 28,
+        _ => return None,
+    };
+    if year < 1970 || day == 0 || day > month_days || hour > 23 || minute > 59 || second > 59 {
+        return None;
+    }
+
+    // Days since the epoch, counting years as starting in March so leap days come last
+    let (y, m) = if month <= 2 {
+        (year - 1, month + 9)
+    } else {
+        (year, month - 3)
+    };
+    let era_days = 365 * y + y / 4 - y / 100 + y / 400;
+    let days = era_days + (153 * m + 2) / 5 + day - 1 - 719_468;
+
+    Some(days * 86_400 + hour * 3_600 + minute * 60 + second)
+}
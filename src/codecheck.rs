@@ -0,0 +1,133 @@
+use rhai::{Engine, ParseError, AST};
+
+use rocket_contrib::json::Json;
+
+use serde::Serialize;
+
+use crate::auth::{check_auth, check_scope, AuthHeader};
+use crate::server::Collection;
+use crate::types::{arities, ApiKeyInfo, CheckCodeRequest, UserResponse, READ_SCOPE};
+
+/// The functions majordomo calls on handlers besides `handle`, and how many parameters they take
+pub const HOOKS: [(&str, usize); 5] = [
+    ("on_approval", 1),
+    ("on_event", 1),
+    ("on_flag_change", 1),
+    ("on_stale", 1),
+    ("on_uptime_change", 1),
+];
+
+/// Something about a piece of code, and where it is, if it is anywhere in particular
+#[derive(Debug, Serialize)]
+pub struct Diagnostic {
+    /// The line, counting from 1
+    pub line: Option<usize>,
+    /// The column, counting from 1
+    pub column: Option<usize>,
+    pub message: String,
+}
+
+impl Diagnostic {
+    fn general(message: String) -> Diagnostic {
+        Diagnostic {
+            line: None,
+            column: None,
+            message,
+        }
+    }
+}
+
+/// What checking a piece of code found
+#[derive(Debug, Serialize)]
+pub struct CodeCheck {
+    /// Whether the code compiles, and so could be saved
+    pub ok: bool,
+    /// Why the code doesn't compile. Rhai stops at the first error, so there is at most one
+    pub errors: Vec<Diagnostic>,
+    /// Things which are likely mistakes, but don't stop the code from being saved
+    pub warnings: Vec<Diagnostic>,
+}
+
+/// Describe why code doesn't compile
+fn error(e: ParseError) -> Diagnostic {
+    let ParseError(kind, position) = e;
+    Diagnostic {
+        line: position.line(),
+        column: position.position(),
+        message: kind.to_string(),
+    }
+}
+
+/// Look for likely mistakes in code which compiles
+///
+/// # Arguments
+///
+/// * `ast` - The compiled code
+fn warnings(ast: &AST) -> Vec<Diagnostic> {
+    let mut warnings = Vec::new();
+    let handle = arities(ast, "handle");
+    if handle.is_empty() {
+        warnings.push(Diagnostic::general(
+            "No handle function is defined, so requests to the handler will fail".into(),
+        ));
+    } else if !handle.iter().any(|p| *p == 1 || *p == 2) {
+        warnings.push(Diagnostic::general(
+            "handle should take one parameter, handle(payload), or two, handle(payload, context)"
+                .into(),
+        ));
+    }
+
+    for (hook, expected) in HOOKS.iter() {
+        let defined = arities(ast, hook);
+        if !defined.is_empty() && !defined.contains(expected) {
+            warnings.push(Diagnostic::general(format!(
+                "{} should take {} parameter, so it will never be called",
+                hook, expected
+            )));
+        }
+    }
+    warnings
+}
+
+/// Rocket Endpoint which checks whether code would compile, and looks for likely mistakes,
+/// without saving anything
+///
+/// # Arguments
+///
+/// * `auth` - The API Key from the `Authorization` header, if any
+/// * `api_keys` - A reference to the collection of Client API keys, used to check for auth
+/// * `post_data` - The code to check
+#[post("/check_code", data = "<post_data>")]
+pub fn check_code(
+    auth: AuthHeader,
+    api_keys: Collection<String, ApiKeyInfo>,
+    post_data: Json<CheckCodeRequest>,
+) -> Json<UserResponse> {
+    let data = post_data.0;
+    let key = auth.key_or(&data.api_key);
+
+    if let Err(cause) = auth.verify(&key, check_auth(&key, &api_keys), "Invalid API Key") {
+        return Json(UserResponse::from(cause));
+    }
+    if let Err(cause) = check_scope(&key, &api_keys, READ_SCOPE) {
+        return Json(UserResponse::from(cause));
+    }
+
+    // Compiled like upsert_handler does, so whatever passes here can be saved
+    let check = match Engine::new().compile(&data.code) {
+        Ok(ast) => CodeCheck {
+            ok: true,
+            errors: Vec::new(),
+            warnings: warnings(&ast),
+        },
+        Err(e) => CodeCheck {
+            ok: false,
+            errors: vec![error(e)],
+            warnings: Vec::new(),
+        },
+    };
+    Json(
+        UserResponse::success_with_raw(check)
+            .unwrap_or_else(|| UserResponse::failure("Unable to describe the check".into())),
+    )
+}
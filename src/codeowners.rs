@@ -0,0 +1,151 @@
+/// The places GitHub looks for a CODEOWNERS file, in the order it looks
+pub const LOCATIONS: [&str; 3] = [".github/CODEOWNERS", "CODEOWNERS", "docs/CODEOWNERS"];
+
+/// A line of a CODEOWNERS file: the files a pattern matches are owned by its owners
+#[derive(Debug, Clone)]
+pub struct Rule {
+    pub pattern: String,
+    /// Users as `@login`, teams as `@org/team`, or email addresses. Empty if the files matched
+    /// deliberately have no owner
+    pub owners: Vec<String>,
+}
+
+/// Read the rules of a CODEOWNERS file, skipping comments and blank lines
+///
+/// # Arguments
+///
+/// * `text` - The contents of the file
+pub fn parse(text: &str) -> Vec<Rule> {
+    text.lines()
+        .map(|line| line.splitn(2, " #").next().unwrap_or_default().trim())
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .filter_map(|line| {
+            let mut parts = line.split_whitespace();
+            parts.next().map(|pattern| Rule {
+                pattern: pattern.to_string(),
+                owners: parts.map(String::from).collect(),
+            })
+        })
+        .collect()
+}
+
+/// The owners of a file. As with GitHub, the last rule which matches wins
+///
+/// # Arguments
+///
+/// * `rules` - The rules of the CODEOWNERS file
+/// * `path` - The path of the file, relative to the root of the repository
+pub fn owners_of<'a>(rules: &'a [Rule], path: &str) -> &'a [String] {
+    rules
+        .iter()
+        .rev()
+        .find(|rule| matches(&rule.pattern, path.trim_start_matches('/')))
+        .map(|rule| rule.owners.as_slice())
+        .unwrap_or_default()
+}
+
+/// Whether a CODEOWNERS pattern matches a file, following the gitignore rules it is based on
+///
+/// Patterns with a slash anywhere but at the end are relative to the root, others match at any
+/// depth. Patterns ending in a slash only match directories. A pattern which matches a directory
+/// matches everything in it.
+///
+/// # Arguments
+///
+/// * `pattern` - The pattern from a CODEOWNERS rule
+/// * `path` - The path of the file, relative to the root of the repository
+pub fn matches(pattern: &str, path: &str) -> bool {
+    let directory_only = pattern.ends_with('/');
+    let pattern = pattern.trim_end_matches('/');
+    let pattern = if pattern.contains('/') {
+        pattern.trim_start_matches('/').to_string()
+    } else {
+        format!("**/{}", pattern)
+    };
+
+    let contents = format!("{}/**", pattern);
+    (!directory_only && glob(pattern.as_bytes(), path.as_bytes()))
+        || glob(contents.as_bytes(), path.as_bytes())
+}
+
+/// Match a path against a glob, where `*` and `?` stay within a directory and `**` does not
+///
+/// # Arguments
+///
+/// * `pattern` - The glob
+/// * `path` - The path to match
+fn glob(pattern: &[u8], path: &[u8]) -> bool {
+    match pattern {
+        [] => path.is_empty(),
+        [b'*', b'*', rest @ ..] => {
+            // `**/` may also stand for no directories at all
+            let none = match rest {
+                [b'/', after @ ..] => glob(after, path),
+                _ => false,
+            };
+            none || (0..=path.len()).any(|i| glob(rest, &path[i..]))
+        }
+        [b'*', rest @ ..] => (0..=path.len())
+            .take_while(|&i| i == 0 || path[i - 1] != b'/')
+            .any(|i| glob(rest, &path[i..])),
+        [b'?', rest @ ..] => match path {
+            [c, after @ ..] if *c != b'/' => glob(rest, after),
+            _ => false,
+        },
+        [c, rest @ ..] => match path {
+            [d, after @ ..] if c == d => glob(rest, after),
+            _ => false,
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CODEOWNERS: &str = "\
+# Everything else
+*       @org/everyone
+
+/docs/  @docs-team # the docs site
+*.rs    @rustaceans someone@example.com
+/build/logs/
+";
+
+    #[test]
+    fn parse_skips_comments_and_blank_lines() {
+        let rules = parse(CODEOWNERS);
+        let patterns = rules
+            .iter()
+            .map(|r| r.pattern.as_str())
+            .collect::<Vec<&str>>();
+        assert_eq!(patterns, vec!["*", "/docs/", "*.rs", "/build/logs/"]);
+        assert_eq!(rules[1].owners, vec!["@docs-team"]);
+        assert_eq!(rules[2].owners, vec!["@rustaceans", "someone@example.com"]);
+        assert!(rules[3].owners.is_empty());
+    }
+
+    #[test]
+    fn the_last_matching_rule_wins() {
+        let rules = parse(CODEOWNERS);
+        assert_eq!(owners_of(&rules, "README.md"), ["@org/everyone"]);
+        assert_eq!(owners_of(&rules, "/docs/index.md"), ["@docs-team"]);
+        assert_eq!(owners_of(&rules, "docs/src/main.rs")[0], "@rustaceans");
+        assert!(owners_of(&rules, "build/logs/today.log").is_empty());
+        assert!(owners_of(&[], "README.md").is_empty());
+    }
+
+    #[test]
+    fn patterns_follow_gitignore_rules() {
+        assert!(matches("*.rs", "src/deep/main.rs"));
+        assert!(!matches("/*.rs", "src/main.rs"));
+        assert!(matches("/*.rs", "main.rs"));
+        assert!(matches("docs/*", "docs/index.md"));
+        assert!(matches("docs/**/index.md", "docs/index.md"));
+        assert!(matches("docs/**/index.md", "docs/api/v1/index.md"));
+        assert!(matches("logs/", "app/logs/today.log"));
+        assert!(!matches("logs/", "app/logs"));
+        assert!(matches("file?.txt", "file1.txt"));
+        assert!(!matches("file?.txt", "file/.txt"));
+    }
+}
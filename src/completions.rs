@@ -0,0 +1,291 @@
+use std::collections::BTreeSet;
+
+use rocket::State;
+use rocket_contrib::json::Json;
+
+use serde::Serialize;
+
+use crate::auth::{check_auth, check_scope, hash_key, AuthHeader};
+use crate::codecheck::HOOKS;
+use crate::server::Collection;
+use crate::services::Services;
+use crate::types::{APIKeyRequest, ApiKeyInfo, EnvInfo, Handler, UserResponse, READ_SCOPE};
+
+/// The source of every file which registers functions available to clients, by the name of its
+/// module. Their functions are read from the doc comments of their `register` functions, see
+/// `parse`, so the completions say what the documentation does. New modules must be added here
+const SOURCES: [(&str, &str); 24] = [
+    ("approvals", include_str!("approvals.rs")),
+    ("broadcast", include_str!("broadcast.rs")),
+    ("calendar", include_str!("calendar.rs")),
+    ("crosspost", include_str!("crosspost.rs")),
+    ("feed", include_str!("feed.rs")),
+    ("flags", include_str!("flags.rs")),
+    ("github", include_str!("github.rs")),
+    ("http_client", include_str!("http_client.rs")),
+    ("invoke", include_str!("invoke.rs")),
+    ("json", include_str!("json.rs")),
+    ("k8s", include_str!("k8s.rs")),
+    ("kv", include_str!("kv.rs")),
+    ("metrics", include_str!("metrics.rs")),
+    ("oncall", include_str!("oncall.rs")),
+    ("polls", include_str!("polls.rs")),
+    ("probes", include_str!("probes.rs")),
+    ("releases", include_str!("releases.rs")),
+    ("reminders", include_str!("reminders.rs")),
+    ("remote", include_str!("remote.rs")),
+    ("secrets", include_str!("secrets.rs")),
+    ("server", include_str!("server.rs")),
+    ("slack", include_str!("slack.rs")),
+    ("stale", include_str!("stale.rs")),
+    ("uptime", include_str!("uptime.rs")),
+];
+
+/// A function handlers can call
+#[derive(Debug, Clone, Serialize)]
+pub struct Function {
+    pub name: String,
+    /// The names of its parameters, as documented
+    pub params: Vec<String>,
+    pub arity: usize,
+    /// What it does, as documented
+    pub doc: String,
+    /// The module which registers it, e.g. `slack`
+    pub module: &'static str,
+}
+
+/// A function majordomo calls on handlers which define it
+#[derive(Debug, Serialize)]
+pub struct Hook {
+    pub name: String,
+    pub arity: usize,
+}
+
+/// Everything a handler of the API Key can refer to, for editors to complete
+#[derive(Debug, Serialize)]
+pub struct Completions {
+    /// The functions handlers can call, sorted by name and arity
+    pub functions: Vec<Function>,
+    /// The functions handlers can define for majordomo to call, including `handle`
+    pub hooks: Vec<Hook>,
+    /// The names of the API Key's secrets, for `secret_get`
+    pub secrets: Vec<String>,
+    /// The names of the API Key's modules, for `import`
+    pub modules: Vec<String>,
+    /// The names of the feature flags, for `flag_enabled` and friends
+    pub flags: Vec<String>,
+    /// The names of the commands the API Key's handlers may run, for `remote_run`
+    pub remote_commands: Vec<String>,
+    /// The domains handlers may make http requests to
+    pub http_allowlist: Vec<String>,
+}
+
+/// Read a documented signature, e.g. `slack_post(channel, message)`, into its name and parameters
+fn signature(text: &str) -> Option<(String, Vec<String>)> {
+    let is_ident = |s: &str| !s.is_empty() && s.chars().all(|c| c.is_alphanumeric() || c == '_');
+    let open = text.find('(')?;
+    let name = &text[..open];
+    let params = text[open + 1..]
+        .strip_suffix(')')?
+        .split(',')
+        .map(str::trim)
+        .filter(|p| !p.is_empty())
+        .collect::<Vec<&str>>();
+    // Examples, e.g. `dns_lookup("example.com", "MX")`, aren't signatures
+    if !is_ident(name) || !params.iter().all(|p| is_ident(p)) {
+        return None;
+    }
+    Some((name.into(), params.into_iter().map(String::from).collect()))
+}
+
+/// Find the functions documented in the bullets of a doc comment, before its `# Arguments`
+///
+/// Each bullet starts with a signature in backticks, followed by what the function does, e.g.
+/// `` * `kv_get(key)` returns the stored value ``. Other signatures of the same function which the
+/// bullet mentions are overloads, e.g. `remote_run(name)` alongside `remote_run(name, args)`.
+///
+/// # Arguments
+///
+/// * `module` - The module the doc comment is in
+/// * `doc` - The lines of the doc comment, without their `///`
+fn bullets(module: &'static str, doc: &[&str]) -> Vec<Function> {
+    let mut items = Vec::<String>::new();
+    let mut open = false;
+    for line in doc {
+        if line.starts_with("# Arguments") {
+            break;
+        }
+        if let Some(item) = line.strip_prefix("* `") {
+            items.push(format!("`{}", item));
+            open = true;
+        } else if open && line.starts_with("  ") {
+            let last = items.last_mut().unwrap();
+            last.push(' ');
+            last.push_str(line.trim());
+        } else {
+            open = false;
+        }
+    }
+
+    let mut functions = Vec::new();
+    for item in items {
+        let spans = item.split('`').skip(1).step_by(2).collect::<Vec<&str>>();
+        let (name, params) = match spans.first().map(|s| signature(s)).flatten() {
+            Some(signature) => signature,
+            None => continue,
+        };
+        let doc = item.splitn(3, '`').nth(2).unwrap_or("").trim().to_string();
+
+        let mut arities = BTreeSet::new();
+        arities.insert(params.len());
+        functions.push(Function {
+            name: name.clone(),
+            arity: params.len(),
+            params,
+            doc: doc.clone(),
+            module,
+        });
+        for (other, params) in spans[1..].iter().filter_map(|s| signature(s)) {
+            if other == name && arities.insert(params.len()) {
+                functions.push(Function {
+                    name: other,
+                    arity: params.len(),
+                    params,
+                    doc: doc.clone(),
+                    module,
+                });
+            }
+        }
+    }
+    functions
+}
+
+/// Find the functions a module registers, from the doc comments of its `register` functions, and
+/// of `server::build_engine`
+///
+/// # Arguments
+///
+/// * `module` - The name of the module
+/// * `source` - Its source
+fn parse(module: &'static str, source: &str) -> Vec<Function> {
+    let mut functions = Vec::new();
+    let mut doc = Vec::new();
+    for line in source.lines().map(str::trim_start) {
+        if let Some(text) = line.strip_prefix("///") {
+            doc.push(text.strip_prefix(' ').unwrap_or(text));
+            continue;
+        }
+        if line.starts_with("pub fn register") || line.starts_with("pub fn build_engine") {
+            functions.extend(bullets(module, &doc));
+        }
+        // Attributes may come between a doc comment and its function
+        if !line.starts_with("#[") {
+            doc.clear();
+        }
+    }
+    functions
+}
+
+/// Every function handlers can call, sorted by name and arity
+pub fn functions() -> Vec<Function> {
+    let mut functions = SOURCES
+        .iter()
+        .flat_map(|(module, source)| parse(*module, *source))
+        .collect::<Vec<Function>>();
+    functions.sort_by(|a, b| a.name.cmp(&b.name).then(a.arity.cmp(&b.arity)));
+    functions.dedup_by(|a, b| a.name == b.name && a.arity == b.arity);
+    functions
+}
+
+/// Rocket Endpoint which describes everything handlers of the API Key can refer to, for the
+/// autocomplete of editors: the functions they can call, with their parameters and docs, the
+/// hooks they can define, and the names of the secrets, modules, flags and commands they can use
+///
+/// # Arguments
+///
+/// * `auth` - The API Key from the `Authorization` header, if any
+/// * `env` - Environment variables, for the commands and domains handlers may use
+/// * `services` - Where secrets, modules and flags are stored
+/// * `api_keys` - A reference to the collection of Client API keys, used to check for auth
+/// * `handlers` - A reference to the collection of User created handlers, indexed by their uris
+/// * `post_data` - The API Key, unless it is in the header
+#[post("/completions", data = "<post_data>")]
+pub fn completions(
+    auth: AuthHeader,
+    env: State<EnvInfo>,
+    services: State<Services>,
+    api_keys: Collection<String, ApiKeyInfo>,
+    handlers: Collection<String, Handler>,
+    post_data: Option<Json<APIKeyRequest>>,
+) -> Json<UserResponse> {
+    let key = auth.key_or(&post_data.map(|d| d.0.api_key).unwrap_or_default());
+
+    if let Err(cause) = auth.verify(&key, check_auth(&key, &api_keys), "Invalid API Key") {
+        return Json(UserResponse::from(cause));
+    }
+    if let Err(cause) = check_scope(&key, &api_keys, READ_SCOPE) {
+        return Json(UserResponse::from(cause));
+    }
+
+    let owner = hash_key(&key);
+    let mut hooks = vec![
+        Hook {
+            name: "handle".into(),
+            arity: 1,
+        },
+        Hook {
+            name: "handle".into(),
+            arity: 2,
+        },
+    ];
+    hooks.extend(HOOKS.iter().map(|(name, arity)| Hook {
+        name: name.to_string(),
+        arity: *arity,
+    }));
+
+    let mut modules = services
+        .libraries
+        .read()
+        .get(&owner)
+        .map(|libraries| libraries.keys().cloned().collect::<Vec<String>>())
+        .unwrap_or_default();
+    modules.sort();
+
+    let mut flags = services
+        .flags
+        .read()
+        .keys()
+        .cloned()
+        .collect::<Vec<String>>();
+    flags.sort();
+
+    // Commands any handler may run, or which name one of the API Key's handlers
+    let owned = handlers
+        .read()
+        .unwrap()
+        .values()
+        .filter(|h| h.owned_by(&owner))
+        .map(|h| h.uri.clone())
+        .collect::<BTreeSet<String>>();
+    let mut remote_commands = env
+        .remote_commands
+        .iter()
+        .filter(|(_, c)| c.handlers.is_empty() || c.handlers.iter().any(|h| owned.contains(h)))
+        .map(|(name, _)| name.clone())
+        .collect::<Vec<String>>();
+    remote_commands.sort();
+
+    let completions = Completions {
+        functions: functions(),
+        hooks,
+        secrets: services.secrets.names(&owner),
+        modules,
+        flags,
+        remote_commands,
+        http_allowlist: env.http_allowlist.clone(),
+    };
+    Json(
+        UserResponse::success_with_raw(completions)
+            .unwrap_or_else(|| UserResponse::failure("Unable to list completions".into())),
+    )
+}
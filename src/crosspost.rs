@@ -0,0 +1,429 @@
+use std::collections::HashMap;
+
+use reqwest::blocking::Client;
+
+use rhai::{EvalAltResult, ImmutableString, Module};
+
+use serde_json::{json, Value};
+
+use crate::clock::utc_date;
+use crate::github::{check_repo, rest_post};
+use crate::logging::CorrelationId;
+use crate::services::Services;
+use crate::slack::{slack_api_get, thread_messages};
+use crate::types::EnvInfo;
+
+/// The longest title an issue filed from a thread gets, in characters
+const MAX_TITLE_CHARS: usize = 80;
+
+/// The longest body an issue filed from a thread gets, in characters. GitHub refuses bodies
+/// over 65536
+const MAX_BODY_CHARS: usize = 60_000;
+
+/// Undo the escaping Slack applies to `&`, `<` and `>` in message text
+fn unescape(text: &str) -> String {
+    text.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&amp;", "&")
+}
+
+/// Convert a `<...>` token of mrkdwn, i.e. a link, a mention or a special command, to markdown
+///
+/// Mentions become inline code, e.g. `` `@alice` ``, so they don't notify whichever GitHub user
+/// happens to have the same name.
+///
+/// # Arguments
+///
+/// * `token` - The token, without the angle brackets
+/// * `names` - The names of Slack users, by id
+fn convert_token(token: &str, names: &HashMap<String, String>) -> String {
+    let mut parts = token.splitn(2, '|');
+    let target = parts.next().unwrap_or_default();
+    let label = parts.next();
+
+    if let Some(id) = target.strip_prefix('@') {
+        let name = names.get(id).map(String::as_str).or(label).unwrap_or(id);
+        format!("`@{}`", name.trim_start_matches('@'))
+    } else if let Some(channel) = target.strip_prefix('#') {
+        format!("#{}", label.unwrap_or(channel))
+    } else if target.starts_with("!subteam^") {
+        format!("`{}`", label.unwrap_or("@group"))
+    } else if let Some(command) = target.strip_prefix('!') {
+        // `<!here>`, `<!channel>`, or `<!date^...|fallback>` and the like
+        match label {
+            Some(label) => label.to_string(),
+            None => format!("`@{}`", command),
+        }
+    } else {
+        match label {
+            Some(label) => format!("[{}]({})", label, target),
+            None => target.to_string(),
+        }
+    }
+}
+
+/// Convert every `<...>` token in some mrkdwn, see `convert_token`
+fn convert_tokens(text: &str, names: &HashMap<String, String>) -> String {
+    let mut converted = String::new();
+    let mut rest = text;
+    while let Some(start) = rest.find('<') {
+        let end = match rest[start..].find('>') {
+            Some(end) => start + end,
+            None => break,
+        };
+        converted.push_str(&rest[..start]);
+        converted.push_str(&convert_token(&rest[start + 1..end], names));
+        rest = &rest[end + 1..];
+    }
+    converted.push_str(rest);
+    converted
+}
+
+/// Replace a mrkdwn formatting marker with its markdown equivalent, e.g. `*bold*` with
+/// `**bold**`
+///
+/// As in Slack, markers only count at the edges of words, and never span lines, so `2*3*4` is
+/// left alone. `<...>` tokens are skipped over, as urls are full of such characters.
+///
+/// # Arguments
+///
+/// * `text` - The mrkdwn
+/// * `marker` - The mrkdwn marker, e.g. `*`
+/// * `replacement` - What to replace it with, e.g. `**`
+fn convert_marker(text: &str, marker: char, replacement: &str) -> String {
+    let chars = text.chars().collect::<Vec<char>>();
+    let mut converted = String::new();
+    let mut i = 0;
+
+    while i < chars.len() {
+        if chars[i] == '<' {
+            let end = chars[i..]
+                .iter()
+                .position(|&c| c == '>')
+                .map(|p| i + p + 1)
+                .unwrap_or(chars.len());
+            converted.extend(&chars[i..end]);
+            i = end;
+            continue;
+        }
+
+        let opens = chars[i] == marker
+            && (i == 0 || !chars[i - 1].is_alphanumeric())
+            && chars
+                .get(i + 1)
+                .map(|c| !c.is_whitespace() && *c != marker)
+                .unwrap_or(false);
+        let close = if opens {
+            (i + 2..chars.len())
+                .take_while(|&j| chars[j] != '\n')
+                .find(|&j| {
+                    chars[j] == marker
+                        && !chars[j - 1].is_whitespace()
+                        && chars
+                            .get(j + 1)
+                            .map(|c| !c.is_alphanumeric())
+                            .unwrap_or(true)
+                })
+        } else {
+            None
+        };
+
+        match close {
+            Some(j) => {
+                converted.push_str(replacement);
+                converted.extend(&chars[i + 1..j]);
+                converted.push_str(replacement);
+                i = j + 1;
+            }
+            None => {
+                converted.push(chars[i]);
+                i += 1;
+            }
+        }
+    }
+    converted
+}
+
+/// Convert Slack's mrkdwn to GitHub flavoured markdown
+///
+/// Bold and strikethrough change markers, links and mentions are rewritten, and Slack's escaping
+/// is undone. Italics, quotes and lists are the same in both. Code is left as it is.
+///
+/// # Arguments
+///
+/// * `text` - The mrkdwn, as Slack sent it
+/// * `names` - The names of Slack users, by id, for mentions
+fn to_markdown(text: &str, names: &HashMap<String, String>) -> String {
+    let inline = |text: &str| {
+        text.split('`')
+            .enumerate()
+            .map(|(i, part)| {
+                if i % 2 == 1 {
+                    format!("`{}`", unescape(part))
+                } else {
+                    let part = convert_marker(part, '*', "**");
+                    let part = convert_marker(&part, '~', "~~");
+                    unescape(&convert_tokens(&part, names))
+                }
+            })
+            .collect::<String>()
+    };
+
+    // Slack code blocks may start and end mid-line, markdown ones may not
+    text.split("```")
+        .enumerate()
+        .map(|(i, part)| {
+            if i % 2 == 1 {
+                format!("\n```\n{}\n```\n", unescape(part).trim_matches('\n'))
+            } else {
+                inline(part)
+            }
+        })
+        .collect::<String>()
+}
+
+/// The ids of the users mentioned in some mrkdwn
+fn mentioned_users(text: &str) -> Vec<String> {
+    text.split("<@")
+        .skip(1)
+        .filter_map(|rest| rest.split(|c| c == '>' || c == '|').next())
+        .map(String::from)
+        .collect()
+}
+
+/// Look up the names of Slack users, as they appear in Slack: their display name, or their real
+/// name if they have no display name. Users who can't be found are left out
+///
+/// # Arguments
+///
+/// * `client` - A reqwest HTTP "client" to make the requests
+/// * `token` - The slack token to authenticate with
+/// * `ids` - The ids of the users
+fn user_names(client: &Client, token: &str, ids: &[String]) -> HashMap<String, String> {
+    let mut names = HashMap::new();
+    for id in ids {
+        let resp = match slack_api_get(client, token, "users.info", &[("user", id.as_str())]) {
+            Ok(resp) => resp,
+            Err(_) => continue,
+        };
+        let user = &resp["user"];
+        let name = [
+            &user["profile"]["display_name"],
+            &user["real_name"],
+            &user["name"],
+        ]
+        .iter()
+        .filter_map(|n| n.as_str())
+        .find(|n| !n.is_empty())
+        .map(String::from);
+        if let Some(name) = name {
+            names.insert(id.clone(), name);
+        }
+    }
+    names
+}
+
+/// When a message was posted, e.g. `2021-03-04 05:06 UTC`
+///
+/// # Arguments
+///
+/// * `ts` - The ts of the message, which starts with a unix timestamp
+fn posted_at(ts: &str) -> String {
+    let unix = ts
+        .splitn(2, '.')
+        .next()
+        .unwrap_or_default()
+        .parse::<u64>()
+        .unwrap_or_default();
+    format!(
+        "{} {:02}:{:02} UTC",
+        utc_date(unix),
+        unix % 86_400 / 3_600,
+        unix % 3_600 / 60
+    )
+}
+
+/// Describe a thread as the title and body of an issue
+///
+/// The title is the first line of the parent message. The body links back to the thread, then
+/// has each message in turn, headed by who posted it and when, with links to its files.
+///
+/// # Arguments
+///
+/// * `messages` - The messages of the thread, as Slack describes them, the parent first
+/// * `names` - The names of Slack users, by id
+/// * `permalink` - A link to the thread, if we have one
+fn render_issue(
+    messages: &[Value],
+    names: &HashMap<String, String>,
+    permalink: Option<&str>,
+) -> (String, String) {
+    let text = |m: &Value| m["text"].as_str().unwrap_or_default().to_string();
+
+    let first_line = messages
+        .first()
+        .map(|m| to_markdown(&text(m), names))
+        .unwrap_or_default()
+        .lines()
+        .map(str::trim)
+        .find(|l| !l.is_empty())
+        .unwrap_or("Slack thread")
+        .to_string();
+    let mut title = first_line.chars().take(MAX_TITLE_CHARS).collect::<String>();
+    if first_line.chars().count() > MAX_TITLE_CHARS {
+        title.push('…');
+    }
+
+    let mut body = match permalink {
+        Some(link) => format!("_Cross-posted from a [Slack thread]({})._\n", link),
+        None => "_Cross-posted from a Slack thread._\n".to_string(),
+    };
+    for message in messages {
+        let user = message["user"].as_str().unwrap_or_default();
+        let author = names
+            .get(user)
+            .map(String::as_str)
+            .or_else(|| message["username"].as_str())
+            .filter(|a| !a.is_empty())
+            .unwrap_or(if user.is_empty() { "bot" } else { user });
+        body.push_str(&format!(
+            "\n**{}** · {}\n\n{}\n",
+            author,
+            posted_at(message["ts"].as_str().unwrap_or_default()),
+            to_markdown(&text(message), names).trim()
+        ));
+
+        let files = message["files"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .map(|f| {
+                format!(
+                    "[{}]({})",
+                    f["name"].as_str().unwrap_or("file"),
+                    f["permalink"].as_str().unwrap_or_default()
+                )
+            })
+            .collect::<Vec<String>>();
+        if !files.is_empty() {
+            body.push_str(&format!("\n_Attached: {}_\n", files.join(", ")));
+        }
+    }
+
+    if body.chars().count() > MAX_BODY_CHARS {
+        body = body.chars().take(MAX_BODY_CHARS).collect();
+        body.push_str("\n\n_The rest of the thread was cut off._\n");
+    }
+    (title, body)
+}
+
+/// File a GitHub issue from a Slack thread, returning the url of the issue
+///
+/// # Arguments
+///
+/// * `client` - A reqwest HTTP "client" to make the requests
+/// * `slack_token` - The slack token to read the thread with
+/// * `github_token` - The github token to file the issue with
+/// * `channel` - The id of the channel the thread is in
+/// * `thread_ts` - The ts of the parent message of the thread
+/// * `repo` - The full name of the repository, `<owner>/<repo>`
+fn file_issue(
+    client: &Client,
+    slack_token: &str,
+    github_token: &str,
+    channel: &str,
+    thread_ts: &str,
+    repo: &str,
+) -> Result<String, String> {
+    check_repo(repo)?;
+
+    let messages = thread_messages(client, slack_token, channel, thread_ts)?;
+    if messages.is_empty() {
+        return Err(format!("No thread {} in {}", thread_ts, channel));
+    }
+
+    let mut users = messages
+        .iter()
+        .filter_map(|m| m["user"].as_str())
+        .map(String::from)
+        .collect::<Vec<String>>();
+    for message in &messages {
+        users.extend(mentioned_users(
+            message["text"].as_str().unwrap_or_default(),
+        ));
+    }
+    users.sort();
+    users.dedup();
+    let names = user_names(client, slack_token, &users);
+
+    let permalink = slack_api_get(
+        client,
+        slack_token,
+        "chat.getPermalink",
+        &[("channel", channel), ("message_ts", thread_ts)],
+    )
+    .ok()
+    .and_then(|resp| resp["permalink"].as_str().map(String::from));
+
+    let (title, body) = render_issue(&messages, &names, permalink.as_deref());
+    let path = format!("/repos/{}/issues", repo);
+    let issue = rest_post(
+        client,
+        github_token,
+        &path,
+        &json!({ "title": title, "body": body }),
+    )?;
+    Ok(issue["html_url"].as_str().unwrap_or_default().to_string())
+}
+
+/// Register the cross-posting functions available to clients
+///
+/// * `thread_to_issue(channel, thread_ts, repo)` files a GitHub issue from a Slack thread, and
+///   returns its url. The issue is titled after the first line of the thread, and has every
+///   message of it, converted to markdown and attributed to its author, with a link back to
+///   the thread
+///
+/// # Arguments
+///
+/// * `module` - The module to register the functions in
+/// * `env` - Environment variables
+/// * `services` - The shared http client
+/// * `id` - The correlation id to attach to every log line
+/// * `handler_addr` - The uri of the handler the functions are for
+pub fn register(
+    module: &mut Module,
+    env: &EnvInfo,
+    services: &Services,
+    id: &CorrelationId,
+    handler_addr: &str,
+) {
+    let client = services.http.clone();
+    let slack_token = env.slack_token.clone();
+    let github_token = env.github_token.clone();
+    let addr = handler_addr.to_string();
+    let cid = id.clone();
+    let thread_to_issue = move |channel: ImmutableString,
+                                thread_ts: ImmutableString,
+                                repo: ImmutableString|
+          -> Result<String, Box<EvalAltResult>> {
+        log_event!(
+            "crosspost.thread_to_issue",
+            id = cid.0,
+            handler = addr,
+            channel = channel,
+            thread_ts = thread_ts,
+            repo = repo,
+        );
+        file_issue(
+            &client,
+            &slack_token,
+            &github_token,
+            &channel,
+            &thread_ts,
+            &repo,
+        )
+        .map_err(Into::into)
+    };
+
+    module.set_fn_3("thread_to_issue", thread_to_issue);
+}
@@ -0,0 +1,338 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::ops::Deref;
+
+use reqwest::Url;
+
+use rocket::State;
+use rocket_contrib::json::Json;
+
+use serde::Serialize;
+
+use crate::auth::{check_admin, AuthHeader};
+use crate::server::Collection;
+use crate::types::{AdminRequest, EnvInfo, Handler, UserResponse};
+
+/// What functions need, by the start of their names, e.g. `slack_` functions use the Slack
+/// token. Functions can need more than one thing
+const CAPABILITIES: [(&str, &str); 17] = [
+    ("slack_", "slack"),
+    ("mention", "slack"),
+    ("request_approval", "slack"),
+    ("poll_create", "slack"),
+    ("remind", "slack"),
+    ("thread_to_issue", "slack"),
+    ("thread_to_issue", "github"),
+    ("github_", "github"),
+    ("http_", "http"),
+    ("uptime_check", "http"),
+    ("tls_cert_expiry", "network"),
+    ("dns_lookup", "network"),
+    ("k8s_", "k8s"),
+    ("remote_run", "remote"),
+    ("secret_", "secrets"),
+    ("statsd_", "metrics"),
+    ("invoke", "invoke"),
+];
+
+/// The functions which take a Slack channel, or a list of them, and which argument it is
+const CHANNEL_ARGS: [(&str, usize); 11] = [
+    ("slack_post", 0),
+    ("slack_post_blocks", 0),
+    ("slack_post_thread", 0),
+    ("slack_broadcast", 0),
+    ("slack_thread_replies", 0),
+    ("slack_invite", 0),
+    ("slack_set_topic", 0),
+    ("request_approval", 0),
+    ("remind", 0),
+    ("poll_create", 0),
+    ("thread_to_issue", 0),
+];
+
+/// The functions which take a url or host, and which argument it is
+const HOST_ARGS: [(&str, usize); 5] = [
+    ("http_get", 0),
+    ("http_post", 0),
+    ("uptime_check", 1),
+    ("tls_cert_expiry", 0),
+    ("dns_lookup", 0),
+];
+
+/// Words which are followed by brackets, but aren't calls
+const KEYWORDS: [&str; 6] = ["if", "while", "for", "switch", "return", "throw"];
+
+/// A piece of code, as far as finding calls is concerned
+#[derive(Debug, PartialEq)]
+enum Token {
+    Ident(String),
+    Str(String),
+    Punct(char),
+}
+
+/// Split code into tokens, dropping comments, whitespace and anything else calls aren't made of
+fn tokenize(code: &str) -> Vec<Token> {
+    let mut tokens = Vec::new();
+    let mut chars = code.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '/' if chars.peek() == Some(&'/') => {
+                while chars.peek().map(|c| *c != '\n').unwrap_or(false) {
+                    chars.next();
+                }
+            }
+            '/' if chars.peek() == Some(&'*') => {
+                chars.next();
+                let mut last = ' ';
+                for c in &mut chars {
+                    if last == '*' && c == '/' {
+                        break;
+                    }
+                    last = c;
+                }
+            }
+            '"' => {
+                let mut text = String::new();
+                while let Some(c) = chars.next() {
+                    match c {
+                        '"' => break,
+                        '\\' => match chars.next() {
+                            Some('n') => text.push('\n'),
+                            Some('t') => text.push('\t'),
+                            Some(c) => text.push(c),
+                            None => break,
+                        },
+                        c => text.push(c),
+                    }
+                }
+                tokens.push(Token::Str(text));
+            }
+            '\'' => {
+                // A character, which is never a channel or a repository
+                while let Some(c) = chars.next() {
+                    match c {
+                        '\'' => break,
+                        '\\' => {
+                            chars.next();
+                        }
+                        _ => {}
+                    }
+                }
+            }
+            c if c.is_alphabetic() || c == '_' => {
+                let mut ident = c.to_string();
+                while let Some(c) = chars.peek().filter(|c| c.is_alphanumeric() || **c == '_') {
+                    ident.push(*c);
+                    chars.next();
+                }
+                tokens.push(Token::Ident(ident));
+            }
+            c if c.is_whitespace() => {}
+            c => tokens.push(Token::Punct(c)),
+        }
+    }
+    tokens
+}
+
+/// A call to a function, and the string literals each argument consists of, if any
+#[derive(Debug)]
+struct Call {
+    name: String,
+    /// The strings in each argument, e.g. both channels of `["#a", "#b"]`. Empty for arguments
+    /// which are worked out as the handler runs
+    args: Vec<Vec<String>>,
+}
+
+/// Find the calls code makes, to functions it doesn't define itself
+///
+/// # Arguments
+///
+/// * `code` - The code
+fn calls(code: &str) -> Vec<Call> {
+    let tokens = tokenize(code);
+    let defined = tokens
+        .windows(2)
+        .filter_map(|w| match (&w[0], &w[1]) {
+            (Token::Ident(f), Token::Ident(name)) if f == "fn" => Some(name.clone()),
+            _ => None,
+        })
+        .collect::<BTreeSet<String>>();
+
+    let mut calls = Vec::new();
+    for (i, token) in tokens.iter().enumerate() {
+        let name = match (token, tokens.get(i + 1)) {
+            (Token::Ident(name), Some(Token::Punct('('))) => name,
+            _ => continue,
+        };
+        let after_dot_or_fn = match i.checked_sub(1).map(|p| &tokens[p]) {
+            Some(Token::Punct('.')) => true,
+            Some(Token::Ident(f)) => f == "fn",
+            _ => false,
+        };
+        if after_dot_or_fn || defined.contains(name) || KEYWORDS.contains(&name.as_str()) {
+            continue;
+        }
+
+        // Split the arguments at the commas which aren't nested in anything
+        let mut args = vec![Vec::new()];
+        let mut literal = vec![true];
+        let mut depth = 0;
+        for token in &tokens[i + 2..] {
+            match token {
+                Token::Punct('(') | Token::Punct('[') | Token::Punct('{') => {
+                    depth += 1;
+                    *literal.last_mut().unwrap() &= *token == Token::Punct('[') && depth == 1;
+                }
+                Token::Punct(')') | Token::Punct(']') | Token::Punct('}') if depth == 0 => break,
+                Token::Punct(')') | Token::Punct(']') | Token::Punct('}') => depth -= 1,
+                Token::Punct(',') if depth == 0 => {
+                    args.push(Vec::new());
+                    literal.push(true);
+                }
+                Token::Punct(',') if depth == 1 => {}
+                Token::Str(text) => args.last_mut().unwrap().push(text.clone()),
+                _ => *literal.last_mut().unwrap() = false,
+            }
+        }
+        let args = args
+            .into_iter()
+            .zip(literal)
+            .map(|(strings, literal)| if literal { strings } else { Vec::new() })
+            .collect();
+        calls.push(Call {
+            name: name.clone(),
+            args,
+        });
+    }
+    calls
+}
+
+/// What a handler depends on
+#[derive(Debug, Default, Serialize)]
+pub struct Dependencies {
+    /// The functions it calls, besides its own
+    pub functions: BTreeSet<String>,
+    /// What those functions need, e.g. `slack`, `github` or `http`, see `CAPABILITIES`
+    pub capabilities: BTreeSet<String>,
+    /// The Slack channels it names
+    pub channels: BTreeSet<String>,
+    /// The GitHub repositories it names, as `owner/repo`
+    pub repos: BTreeSet<String>,
+    /// The hosts it names, for http requests, probes and checks
+    pub hosts: BTreeSet<String>,
+}
+
+/// Work out what a handler depends on, from its code
+///
+/// Only what the code spells out can be found: channels, repositories and hosts passed in
+/// variables, or built as the handler runs, are missed.
+///
+/// # Arguments
+///
+/// * `code` - The code of the handler
+pub fn analyze(code: &str) -> Dependencies {
+    let mut deps = Dependencies::default();
+    for call in calls(code) {
+        let arg = |index: usize| call.args.get(index).cloned().unwrap_or_default();
+
+        for (prefix, capability) in CAPABILITIES.iter() {
+            if call.name.starts_with(prefix) {
+                deps.capabilities.insert(capability.to_string());
+            }
+        }
+        if let Some((_, index)) = CHANNEL_ARGS.iter().find(|(f, _)| *f == call.name) {
+            deps.channels.extend(arg(*index));
+        }
+        if let Some((_, index)) = HOST_ARGS.iter().find(|(f, _)| *f == call.name) {
+            deps.hosts.extend(arg(*index).into_iter().map(|a| {
+                Url::parse(&a)
+                    .ok()
+                    .and_then(|u| u.host_str().map(String::from))
+                    .unwrap_or(a)
+            }));
+        }
+        if call.name.starts_with("github_") || call.name == "thread_to_issue" {
+            let repos = call.args.iter().flatten().filter(|a| {
+                let parts = a.split('/').collect::<Vec<&str>>();
+                parts.len() == 2 && parts.iter().all(|p| !p.is_empty() && !p.contains(' '))
+            });
+            deps.repos.extend(repos.cloned());
+        }
+        deps.functions.insert(call.name);
+    }
+    deps
+}
+
+/// What every handler depends on, and the handlers which depend on each thing
+#[derive(Debug, Serialize)]
+pub struct Report {
+    /// What each handler depends on, by uri
+    pub handlers: BTreeMap<String, Dependencies>,
+    /// The handlers which need each capability
+    pub capabilities: BTreeMap<String, Vec<String>>,
+    /// The handlers which name each channel
+    pub channels: BTreeMap<String, Vec<String>>,
+    /// The handlers which name each repository
+    pub repos: BTreeMap<String, Vec<String>>,
+    /// The handlers which name each host
+    pub hosts: BTreeMap<String, Vec<String>>,
+}
+
+/// Index handlers by what they depend on
+fn invert<'a, F: Fn(&'a Dependencies) -> &'a BTreeSet<String>>(
+    handlers: &'a BTreeMap<String, Dependencies>,
+    field: F,
+) -> BTreeMap<String, Vec<String>> {
+    let mut index = BTreeMap::<String, Vec<String>>::new();
+    for (uri, deps) in handlers {
+        for item in field(deps) {
+            index.entry(item.clone()).or_default().push(uri.clone());
+        }
+    }
+    index
+}
+
+/// Rocket Endpoint which reports what every handler depends on, e.g. to know which handlers
+/// break before rotating a token or renaming a channel, see `analyze`
+///
+/// # Arguments
+///
+/// * `auth` - The admin key from the `Authorization` header, if any
+/// * `env` - Environment variables
+/// * `handlers` - A reference to the collection of User created handlers, indexed by their uris
+/// * `post_data` - Must contain the admin key, unless it was passed in the header
+#[post("/admin/dependencies", data = "<post_data>")]
+pub fn dependencies(
+    auth: AuthHeader,
+    env: State<EnvInfo>,
+    handlers: Collection<String, Handler>,
+    post_data: Option<Json<AdminRequest>>,
+) -> Json<UserResponse> {
+    let admin_key = auth.key_or(&post_data.map(|d| d.0.admin_key).unwrap_or_default());
+
+    if let Err(cause) = auth.verify(
+        &admin_key,
+        check_admin(&admin_key, &env),
+        "Invalid admin key",
+    ) {
+        return Json(UserResponse::from(cause));
+    }
+
+    let analyzed = handlers
+        .read()
+        .unwrap()
+        .deref()
+        .iter()
+        .map(|(uri, h)| (uri.clone(), analyze(&h.code.raw)))
+        .collect::<BTreeMap<String, Dependencies>>();
+    let report = Report {
+        capabilities: invert(&analyzed, |d| &d.capabilities),
+        channels: invert(&analyzed, |d| &d.channels),
+        repos: invert(&analyzed, |d| &d.repos),
+        hosts: invert(&analyzed, |d| &d.hosts),
+        handlers: analyzed,
+    };
+    Json(
+        UserResponse::success_with_raw(report)
+            .unwrap_or_else(|| UserResponse::failure("Unable to describe dependencies".into())),
+    )
+}
@@ -0,0 +1,304 @@
+use std::sync::{Arc, Mutex};
+
+use rhai::de::from_dynamic;
+use rhai::{Array, Dynamic, Engine, ImmutableString, Map, RegisterFn, Scope, INT};
+
+use rocket::State;
+use rocket_contrib::json::Json;
+
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::auth::{check_auth, check_scope, hash_key, AuthHeader};
+use crate::logging::CorrelationId;
+use crate::server::{build_engine, limit_engine, Collection};
+use crate::services::Services;
+use crate::types::{
+    ApiKeyInfo, EnvInfo, FailureKind, GithubIssueCommentResponse, GithubIssueCreateResponse,
+    Handler, TestHandlerRequest, UserResponse, WRITE_SCOPE,
+};
+
+/// Where inline code runs, followed by the hash of its API Key, since it has no uri of its own
+const INLINE_URI: &str = "dry-run";
+
+/// A call a handler made to a mocked integration during a dry run
+#[derive(Debug, Clone, Serialize)]
+pub struct MockCall {
+    /// The name of the function, e.g. `slack_post`
+    pub function: String,
+    /// What it was called with, as text
+    pub args: Vec<String>,
+}
+
+/// How a dry run went
+#[derive(Debug, Serialize)]
+pub struct DryRun {
+    /// What the handler returned, if it succeeded. Anything but a string is shown as json
+    pub result: Option<String>,
+    /// Why the handler failed, if it did
+    pub error: Option<String>,
+    /// The calls the handler made to mocked integrations, in order
+    pub calls: Vec<MockCall>,
+}
+
+/// The calls made during a dry run, shared with the mocks recording them
+pub type Transcript = Arc<Mutex<Vec<MockCall>>>;
+
+/// Record a call in the transcript
+fn record(transcript: &Transcript, function: &str, args: Vec<String>) {
+    transcript.lock().unwrap().push(MockCall {
+        function: function.into(),
+        args,
+    });
+}
+
+/// Replace the integrations which reach people, i.e. post to Slack or open GitHub issues, with
+/// mocks which only record that they were called
+///
+/// Functions registered with the engine take precedence over those in its packages, so these
+/// shadow the real ones from `build_engine`. The mocks return what the real functions would on
+/// success.
+///
+/// # Arguments
+///
+/// * `engine` - The engine the handler will run in
+/// * `transcript` - Where calls are recorded
+pub fn mock_integrations(engine: &mut Engine, transcript: &Transcript) {
+    let calls = transcript.clone();
+    engine.register_fn(
+        "slack_post",
+        move |channel: ImmutableString, message: ImmutableString| {
+            record(&calls, "slack_post", vec![channel.into(), message.into()]);
+            true
+        },
+    );
+
+    let calls = transcript.clone();
+    engine.register_fn(
+        "slack_post_thread",
+        move |channel: ImmutableString, thread_ts: ImmutableString, message: ImmutableString| {
+            let args = vec![channel.into(), thread_ts.into(), message.into()];
+            record(&calls, "slack_post_thread", args);
+        },
+    );
+
+    let calls = transcript.clone();
+    engine.register_fn(
+        "slack_broadcast",
+        move |channels: Array, message: ImmutableString| {
+            let count = channels.len() as INT;
+            let channels = channels
+                .iter()
+                .map(|c| c.to_string())
+                .collect::<Vec<String>>();
+            record(
+                &calls,
+                "slack_broadcast",
+                vec![channels.join(","), message.into()],
+            );
+            count
+        },
+    );
+
+    let calls = transcript.clone();
+    engine.register_fn(
+        "github_issue_create",
+        move |repo: ImmutableString, title: ImmutableString, body: ImmutableString| {
+            let response = GithubIssueCreateResponse {
+                html_url: format!("https://github.com/{}/issues/0", repo),
+                title: title.to_string(),
+                id: 0,
+            };
+            record(
+                &calls,
+                "github_issue_create",
+                vec![repo.into(), title.into(), body.into()],
+            );
+            response
+        },
+    );
+
+    let calls = transcript.clone();
+    engine.register_fn(
+        "github_issue_create",
+        move |repo: ImmutableString,
+              title: ImmutableString,
+              body: ImmutableString,
+              options: Map| {
+            let response = GithubIssueCreateResponse {
+                html_url: format!("https://github.com/{}/issues/0", repo),
+                title: title.to_string(),
+                id: 0,
+            };
+            let options = describe(Dynamic::from(options));
+            let args = vec![repo.into(), title.into(), body.into(), options];
+            record(&calls, "github_issue_create", args);
+            response
+        },
+    );
+
+    let calls = transcript.clone();
+    engine.register_fn(
+        "github_issue_comment",
+        move |repo: ImmutableString, number: INT, body: ImmutableString| {
+            let response = GithubIssueCommentResponse {
+                html_url: format!(
+                    "https://github.com/{}/issues/{}#issuecomment-0",
+                    repo, number
+                ),
+                body: body.to_string(),
+                id: 0,
+            };
+            let args = vec![repo.into(), number.to_string(), body.into()];
+            record(&calls, "github_issue_comment", args);
+            response
+        },
+    );
+
+    let calls = transcript.clone();
+    engine.register_fn(
+        "github_issue_close",
+        move |repo: ImmutableString, number: INT| {
+            record(
+                &calls,
+                "github_issue_close",
+                vec![repo.into(), number.to_string()],
+            );
+        },
+    );
+
+    let calls = transcript.clone();
+    engine.register_fn(
+        "github_add_labels",
+        move |repo: ImmutableString, number: INT, labels: Array| {
+            let labels = labels
+                .iter()
+                .map(|l| l.to_string())
+                .collect::<Vec<String>>();
+            let args = vec![repo.into(), number.to_string(), labels.join(",")];
+            record(&calls, "github_add_labels", args);
+        },
+    );
+
+    let calls = transcript.clone();
+    engine.register_fn("debug_println", move |message: ImmutableString| {
+        record(&calls, "debug_println", vec![message.into()]);
+    });
+}
+
+/// Describe what a handler returned
+pub fn describe(value: Dynamic) -> String {
+    if value.is::<ImmutableString>() {
+        return value.to_string();
+    }
+    from_dynamic::<Value>(&value)
+        .map(|v| v.to_string())
+        .unwrap_or_else(|_| value.to_string())
+}
+
+/// Rocket Endpoint which runs a handler without letting it reach anyone, to try it out
+///
+/// `slack_post`, `slack_post_thread`, `slack_broadcast`, `github_issue_create`,
+/// `github_issue_comment`, `github_issue_close`, `github_add_labels` and `debug_println` are
+/// replaced with mocks, and the calls made to them are returned along with
+/// what the handler returned. Everything else, e.g. the key-value store, is real, so this needs
+/// the write scope. Dry runs are not counted in the handler's stats or logs.
+///
+/// # Arguments
+///
+/// * `auth` - The API Key from the `Authorization` header, if any
+/// * `id` - The correlation id of the request
+/// * `env` - Environment variables
+/// * `services` - The stateful subsystems available to handlers
+/// * `api_keys` - A reference to the collection of Client API keys, used to check for auth
+/// * `handlers` - A reference to the collection of User created handlers, indexed by their uris
+/// * `post_data` - The handler, or code, to try out and the payload to pass it
+#[post("/test_handler", data = "<post_data>")]
+pub fn test_handler(
+    auth: AuthHeader,
+    id: CorrelationId,
+    env: State<EnvInfo>,
+    services: State<Services>,
+    api_keys: Collection<String, ApiKeyInfo>,
+    handlers: Collection<String, Handler>,
+    post_data: Json<TestHandlerRequest>,
+) -> Json<UserResponse> {
+    let data = post_data.0;
+    let key = auth.key_or(&data.api_key);
+
+    if let Err(cause) = auth.verify(&key, check_auth(&key, &api_keys), "Invalid API Key") {
+        return Json(UserResponse::from(cause));
+    }
+    if let Err(cause) = check_scope(&key, &api_keys, WRITE_SCOPE) {
+        return Json(UserResponse::from(cause));
+    }
+
+    // Whatever runs does so as the handler at `uri`, e.g. with its key-value namespace. Inline
+    // code without one runs at an address of the API Key's own
+    let owner = hash_key(&key);
+    let handler_addr = match &data.uri {
+        Some(uri) => uri.clone(),
+        None => format!("{}/{}", INLINE_URI, owner),
+    };
+    let guard = handlers.read().unwrap();
+    let saved = match guard.get(&handler_addr) {
+        Some(h) if h.owned_by(&owner) => Some(h),
+        Some(_) => {
+            return Json(UserResponse::failure_of(
+                FailureKind::Unauthorized,
+                "Invalid API Key".into(),
+            ))
+        }
+        None => None,
+    };
+
+    let inline;
+    let handler = match (&data.code, saved) {
+        (Some(code), _) => match Handler::new(handler_addr.clone(), owner.clone(), code.clone()) {
+            Ok(handler) => {
+                inline = handler;
+                &inline
+            }
+            Err(e) => return Json(UserResponse::failure(format!("Error parsing code: {}", e))),
+        },
+        (None, Some(handler)) => handler,
+        (None, None) if data.uri.is_some() => {
+            return Json(UserResponse::failure_of(
+                FailureKind::NotFound,
+                "Unknown handler uri".into(),
+            ))
+        }
+        (None, None) => {
+            return Json(UserResponse::failure(
+                "Either a uri or code must be given".into(),
+            ))
+        }
+    };
+
+    let transcript = Transcript::default();
+    let mut engine = build_engine(&env, &services, &id, &handler_addr, &owner);
+    mock_integrations(&mut engine, &transcript);
+    limit_engine(&mut engine, &env, handler);
+    let mut scope = Scope::new();
+    let result: Result<Dynamic, _> =
+        engine.call_fn(&mut scope, &handler.code.ast, "handle", (data.post_data,));
+
+    log_event!("handler.dry_run", id = id.0, handler = handler_addr);
+    let calls = transcript.lock().unwrap().clone();
+    let run = match result {
+        Ok(value) => DryRun {
+            result: Some(describe(value)),
+            error: None,
+            calls,
+        },
+        Err(e) => DryRun {
+            result: None,
+            error: Some(e.to_string()),
+            calls,
+        },
+    };
+    Json(
+        UserResponse::success_with_raw(run)
+            .unwrap_or_else(|| UserResponse::failure("Unable to describe the run".into())),
+    )
+}
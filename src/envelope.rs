@@ -0,0 +1,394 @@
+use std::thread;
+
+use rhai::ser::to_dynamic;
+use rhai::{Array, Dynamic, Map, Scope};
+
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::logging::CorrelationId;
+use crate::server::run_function;
+use crate::services::Services;
+use crate::types::{EnvInfo, Handler};
+
+/// Where events come from
+pub const SOURCES: [&str; 4] = ["github", "slack", "sms", "webhook"];
+
+/// How many links are picked out of an event's text
+const MAX_LINKS: usize = 20;
+
+/// An event from any source, in the same shape, so one handler can follow a topic wherever it
+/// comes up
+#[derive(Debug, Clone, Serialize)]
+pub struct Envelope {
+    /// Where the event came from, one of `SOURCES`
+    pub source: String,
+    /// What kind of event it is, as the source calls it, e.g. `message` or `pull_request`
+    pub kind: String,
+    /// Who caused the event, e.g. a Slack user id, a GitHub login or a phone number. Empty if
+    /// unknown
+    pub actor: String,
+    /// What the event says, e.g. the message, or the title and body of an issue
+    pub text: String,
+    /// The urls the event mentions, or points to
+    pub links: Vec<String>,
+    /// The event as the source sent it
+    pub raw: Value,
+    /// The hash of the only API Key whose handlers may see the event, if it isn't shared with
+    /// everyone. Requests to a handler's url are private to the handler's owner
+    #[serde(skip)]
+    pub owner: Option<String>,
+}
+
+/// Pick the urls out of some text. Slack wraps them as `<url|label>`, so that is taken apart
+fn find_links(text: &str) -> Vec<String> {
+    let mut links = Vec::new();
+    for word in text.split(|c: char| c.is_whitespace() || "<>|\"'()".contains(c)) {
+        let word = word.trim_end_matches(|c| ".,;:!?".contains(c));
+        if (word.starts_with("http://") || word.starts_with("https://"))
+            && !links.iter().any(|l| l == word)
+        {
+            links.push(word.to_string());
+        }
+        if links.len() >= MAX_LINKS {
+            break;
+        }
+    }
+    links
+}
+
+impl Envelope {
+    /// A message in a Slack channel
+    ///
+    /// # Arguments
+    ///
+    /// * `user` - The id of the user who sent it
+    /// * `text` - The message
+    /// * `raw` - The event, as passed to the channel's handler
+    pub fn slack(user: &str, text: &str, raw: Value) -> Envelope {
+        Envelope {
+            source: "slack".into(),
+            kind: "message".into(),
+            actor: user.into(),
+            text: text.into(),
+            links: find_links(text),
+            raw,
+            owner: None,
+        }
+    }
+
+    /// A GitHub webhook delivery. The text is whatever people wrote, e.g. the title and body of
+    /// an issue, a comment, or the messages of pushed commits
+    ///
+    /// # Arguments
+    ///
+    /// * `event` - The kind of event, e.g. `issues`
+    /// * `payload` - The delivery
+    pub fn github(event: &str, payload: &Value) -> Envelope {
+        let mut text = Vec::new();
+        let mut links = Vec::new();
+        for object in &["issue", "pull_request", "comment", "review", "release"] {
+            let object = &payload[object];
+            for field in &["title", "name", "body"] {
+                if let Some(value) = object[field].as_str().filter(|v| !v.is_empty()) {
+                    text.push(value.to_string());
+                }
+            }
+            if let Some(url) = object["html_url"].as_str() {
+                links.push(url.to_string());
+            }
+        }
+        if let Some(commits) = payload["commits"].as_array() {
+            text.extend(
+                commits
+                    .iter()
+                    .filter_map(|c| c["message"].as_str())
+                    .map(String::from),
+            );
+        }
+        if let Some(url) = payload["compare"].as_str() {
+            links.push(url.to_string());
+        }
+
+        let text = text.join("\n");
+        for link in find_links(&text) {
+            if !links.contains(&link) {
+                links.push(link);
+            }
+        }
+        links.truncate(MAX_LINKS);
+
+        Envelope {
+            source: "github".into(),
+            kind: event.into(),
+            actor: payload["sender"]["login"].as_str().unwrap_or("").into(),
+            text,
+            links,
+            raw: payload.clone(),
+            owner: None,
+        }
+    }
+
+    /// A text message, or call, from Twilio
+    ///
+    /// # Arguments
+    ///
+    /// * `call` - Whether it was a call
+    /// * `from` - The number it came from
+    /// * `text` - The message, or what the caller said
+    /// * `raw` - The parameters Twilio posted, as passed to the number's handler
+    pub fn sms(call: bool, from: &str, text: &str, raw: Value) -> Envelope {
+        Envelope {
+            source: "sms".into(),
+            kind: if call { "call" } else { "message" }.into(),
+            actor: from.into(),
+            text: text.into(),
+            links: find_links(text),
+            raw,
+            owner: None,
+        }
+    }
+
+    /// A request to a handler's url, e.g. from another service's webhooks, or a mail provider
+    /// which forwards incoming email
+    ///
+    /// # Arguments
+    ///
+    /// * `handler_addr` - The uri of the handler the request was for
+    /// * `owner` - The hash of the API Key which owns that handler
+    /// * `payload` - The body of the request, or its query string if it was a GET
+    pub fn webhook(handler_addr: &str, owner: &str, payload: &str) -> Envelope {
+        let body = serde_json::from_str::<Value>(payload).unwrap_or_else(|_| json!(payload));
+        Envelope {
+            source: "webhook".into(),
+            kind: handler_addr.into(),
+            actor: String::new(),
+            text: payload.into(),
+            links: find_links(payload),
+            raw: json!({ "handler": handler_addr, "body": body }),
+            owner: Some(owner.into()),
+        }
+    }
+
+    /// Describe the envelope for handlers
+    fn to_map(&self) -> Map {
+        let links = self
+            .links
+            .iter()
+            .cloned()
+            .map(Dynamic::from)
+            .collect::<Array>();
+        let mut map = Map::new();
+        map.insert("source".into(), Dynamic::from(self.source.clone()));
+        map.insert("kind".into(), Dynamic::from(self.kind.clone()));
+        map.insert("actor".into(), Dynamic::from(self.actor.clone()));
+        map.insert("text".into(), Dynamic::from(self.text.clone()));
+        map.insert("links".into(), Dynamic::from(links));
+        map.insert(
+            "raw".into(),
+            to_dynamic(&self.raw).unwrap_or_else(|_| Dynamic::from(())),
+        );
+        map
+    }
+}
+
+/// Which events a handler follows, regardless of where they come from
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Subscription {
+    /// The sources to follow, any of `SOURCES`. All of them, if empty
+    #[serde(default)]
+    pub sources: Vec<String>,
+    /// Words or phrases, any of which the text or links of an event must mention, ignoring
+    /// case, e.g. the name of a service. Every event, if empty
+    #[serde(default)]
+    pub mentions: Vec<String>,
+}
+
+impl Subscription {
+    /// Check the subscription makes sense, before it is saved
+    pub fn check(&self) -> Result<(), String> {
+        match self.sources.iter().find(|s| !SOURCES.contains(&s.as_str())) {
+            Some(source) => Err(format!(
+                "Unknown source {}, expected any of {}",
+                source,
+                SOURCES.join(", ")
+            )),
+            None => Ok(()),
+        }
+    }
+
+    /// Whether an event is one the handler follows
+    ///
+    /// # Arguments
+    ///
+    /// * `owner` - The hash of the API Key which owns the handler
+    /// * `envelope` - The event
+    pub fn matches(&self, owner: &str, envelope: &Envelope) -> bool {
+        if envelope.owner.as_ref().map(|o| o != owner).unwrap_or(false) {
+            return false;
+        }
+        let source = self.sources.is_empty() || self.sources.contains(&envelope.source);
+        let text = envelope.text.to_lowercase();
+        let mentioned = self.mentions.is_empty()
+            || self.mentions.iter().map(|m| m.to_lowercase()).any(|m| {
+                text.contains(&m) || envelope.links.iter().any(|l| l.to_lowercase().contains(&m))
+            });
+        source && mentioned
+    }
+}
+
+/// Pass an event on to every handler subscribed to it, as `on_event(envelope)`, where
+/// `envelope` is a map of the `Envelope` fields
+///
+/// Sources call this once whichever handler the event was addressed to has run, so subscribers
+/// never see an event before it has been handled. They then run in the background, so they can't
+/// hold up the source, which may be waiting on a response.
+///
+/// # Arguments
+///
+/// * `env` - Environment variables
+/// * `services` - The stateful subsystems available to handlers, and the handlers themselves
+/// * `id` - The correlation id of the request the event came in with
+/// * `envelope` - The event
+pub fn fan_in(env: &EnvInfo, services: &Services, id: &CorrelationId, envelope: Envelope) {
+    // Most events have no subscribers, and shouldn't pay for a thread
+    let subscribed = services.handlers.read().unwrap().values().any(|h| {
+        h.subscription
+            .as_ref()
+            .map(|s| s.matches(&h.api_key, &envelope))
+            .unwrap_or(false)
+    });
+    if !subscribed {
+        return;
+    }
+
+    let env = env.clone();
+    let services = services.clone();
+    let id = id.clone();
+    thread::spawn(move || {
+        // Copied, so the handlers aren't locked while subscribers run, which may take a while,
+        // and may need the handlers themselves
+        let subscribers = services
+            .handlers
+            .read()
+            .unwrap()
+            .iter()
+            .filter(|(_, h)| {
+                h.defines("on_event", 1)
+                    && h.subscription
+                        .as_ref()
+                        .map(|s| s.matches(&h.api_key, &envelope))
+                        .unwrap_or(false)
+            })
+            .map(|(addr, h)| (addr.clone(), h.clone()))
+            .collect::<Vec<(String, Handler)>>();
+
+        let event = envelope.to_map();
+        for (addr, handler) in &subscribers {
+            log_event!(
+                "envelope.deliver",
+                id = id.0,
+                handler = addr,
+                source = envelope.source
+            );
+            let args = (event.clone(),);
+            let ast = &handler.code.ast;
+            let result = run_function(
+                &env,
+                &services,
+                &id,
+                addr,
+                handler,
+                None,
+                "on_event",
+                |engine| engine.call_fn(&mut Scope::new(), ast, "on_event", args),
+            );
+            if let Err(e) = result {
+                log_event!("handler.error", id = id.0, handler = addr, error = e);
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn subscription(sources: &[&str], mentions: &[&str]) -> Subscription {
+        Subscription {
+            sources: sources.iter().map(|s| s.to_string()).collect(),
+            mentions: mentions.iter().map(|m| m.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn find_links_takes_slack_markup_and_punctuation_apart() {
+        let text = "See <https://status.example.com|the status page>, or (http://wiki/Payments).\n\
+                    https://status.example.com again, and ftp://not.a.link";
+        assert_eq!(
+            find_links(text),
+            vec!["https://status.example.com", "http://wiki/Payments"]
+        );
+        assert!(find_links("https").is_empty());
+    }
+
+    #[test]
+    fn find_links_keeps_at_most_max_links() {
+        let text = (0..MAX_LINKS + 5)
+            .map(|i| format!("https://example.com/{}", i))
+            .collect::<Vec<String>>()
+            .join(" ");
+        assert_eq!(find_links(&text).len(), MAX_LINKS);
+    }
+
+    #[test]
+    fn github_envelopes_gather_text_and_links() {
+        let payload = json!({
+            "sender": { "login": "octocat" },
+            "issue": {
+                "title": "Payments are down",
+                "body": "Since https://deploy.example.com/42",
+                "html_url": "https://github.com/org/repo/issues/1"
+            }
+        });
+        let envelope = Envelope::github("issues", &payload);
+        assert_eq!(envelope.actor, "octocat");
+        assert_eq!(
+            envelope.text,
+            "Payments are down\nSince https://deploy.example.com/42"
+        );
+        assert_eq!(
+            envelope.links,
+            vec![
+                "https://github.com/org/repo/issues/1",
+                "https://deploy.example.com/42"
+            ]
+        );
+    }
+
+    #[test]
+    fn subscriptions_match_sources_and_mentions() {
+        let message = Envelope::slack("U1", "Is PAYMENTS down?", Value::Null);
+        assert!(subscription(&[], &[]).matches("owner", &message));
+        assert!(subscription(&["slack"], &["payments"]).matches("owner", &message));
+        assert!(!subscription(&["github"], &["payments"]).matches("owner", &message));
+        assert!(!subscription(&[], &["search"]).matches("owner", &message));
+
+        let linked = Envelope::sms(
+            false,
+            "+15550100",
+            "https://payments.example.com",
+            Value::Null,
+        );
+        assert!(subscription(&["sms"], &["Payments"]).matches("owner", &linked));
+    }
+
+    #[test]
+    fn webhooks_only_reach_their_owners_subscriptions() {
+        let webhook = Envelope::webhook("deploy", "owner", "{\"service\":\"payments\"}");
+        assert!(subscription(&[], &["payments"]).matches("owner", &webhook));
+        assert!(!subscription(&[], &["payments"]).matches("someone else", &webhook));
+        assert!(subscription(&["webhook", "sms"], &[]).check().is_ok());
+        assert!(subscription(&["email"], &[]).check().is_err());
+    }
+}
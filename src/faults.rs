@@ -0,0 +1,242 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::thread;
+use std::time::Duration;
+
+use rand::Rng;
+
+use rocket::State;
+use rocket_contrib::json::Json;
+
+use serde::{Deserialize, Serialize};
+
+use crate::auth::{check_admin, AuthHeader};
+use crate::clock::unix_now;
+use crate::services::Services;
+use crate::types::{AdminRequest, EnvInfo, InjectFaultRequest, UserResponse};
+
+/// The integrations faults can be injected into
+pub const INTEGRATIONS: [&str; 3] = ["github", "http", "slack"];
+
+/// The longest a fault may be injected for, so one which was forgotten about stops on its own
+const MAX_MINUTES: u64 = 24 * 60;
+
+/// The longest a call may be slowed down by, in milliseconds. Handlers can't be stopped while
+/// they wait on a call, so this is kept well under anything a handler would time out on
+const MAX_DELAY_MS: u64 = 30_000;
+
+/// Failures and delays injected into the calls handlers make to an integration, so their
+/// authors can see their retries and fallbacks work before a real outage does
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Fault {
+    /// How many of the calls fail, in percent
+    pub fail_percent: u32,
+    /// How many of the calls are slowed down, in percent
+    pub slow_percent: u32,
+    /// How long slowed calls are held up, in milliseconds
+    pub delay_ms: u64,
+    /// Only the calls of these handlers, by uri. Every handler's, if empty
+    pub handlers: Vec<String>,
+    /// When the fault stops on its own, as a unix timestamp
+    pub expires_at: u64,
+}
+
+thread_local! {
+    /// The faults of the handlers running on this thread, by integration, innermost last
+    ///
+    /// Invoked handlers run on the thread of their caller, each with their own faults.
+    static ARMED: RefCell<Vec<HashMap<String, Fault>>> = RefCell::new(Vec::new());
+}
+
+/// Keeps the faults of a handler injected into the calls made on this thread while it runs,
+/// taking them off again however the run ends
+pub struct Armed;
+
+impl Drop for Armed {
+    fn drop(&mut self) {
+        ARMED.with(|a| a.borrow_mut().pop());
+    }
+}
+
+/// Inject the faults which apply to a handler into the calls made on this thread, until the
+/// returned guard is dropped. Calls made outside of handlers are never faulted
+///
+/// # Arguments
+///
+/// * `services` - Where faults are kept
+/// * `handler_addr` - The uri of the handler about to run
+pub fn arm(services: &Services, handler_addr: &str) -> Armed {
+    let now = unix_now();
+    let faults = services
+        .faults
+        .read()
+        .iter()
+        .filter(|(_, f)| f.expires_at > now)
+        .filter(|(_, f)| f.handlers.is_empty() || f.handlers.iter().any(|h| h == handler_addr))
+        .map(|(integration, f)| (integration.clone(), f.clone()))
+        .collect::<HashMap<String, Fault>>();
+    ARMED.with(|a| a.borrow_mut().push(faults));
+    Armed
+}
+
+/// Fail or slow down a call to an integration, if a fault is injected into it for the handler
+/// running on this thread, see `arm`
+///
+/// # Arguments
+///
+/// * `integration` - One of `INTEGRATIONS`
+pub fn inject(integration: &str) -> Result<(), String> {
+    let fault = ARMED.with(|a| {
+        a.borrow()
+            .last()
+            .map(|faults| faults.get(integration).cloned())
+            .flatten()
+    });
+    let fault = match fault {
+        Some(fault) => fault,
+        None => return Ok(()),
+    };
+
+    let mut rng = rand::thread_rng();
+    if rng.gen_range(0, 100) < fault.slow_percent {
+        log_event!(
+            "faults.delay",
+            integration = integration,
+            ms = fault.delay_ms
+        );
+        thread::sleep(Duration::from_millis(fault.delay_ms));
+    }
+    if rng.gen_range(0, 100) < fault.fail_percent {
+        log_event!("faults.failure", integration = integration);
+        return Err(format!("Injected failure of this {} call", integration));
+    }
+    Ok(())
+}
+
+/// Rocket Endpoint which injects failures and delays into the calls handlers make to an
+/// integration, or stops doing so
+///
+/// A percentage of the calls to `github`, `http` or `slack` fail with an error, and a percentage
+/// are slowed down, for the calls of every handler, or only of some. Faults stop on their own
+/// after `minutes`, or right away if it is 0. Calls majordomo makes itself are never faulted.
+///
+/// # Arguments
+///
+/// * `auth` - The admin key from the `Authorization` header, if any
+/// * `env` - Environment variables
+/// * `services` - Where faults are kept
+/// * `post_data` - The integration, and what to inject into it
+#[post("/admin/inject_fault", data = "<post_data>")]
+pub fn inject_fault(
+    auth: AuthHeader,
+    env: State<EnvInfo>,
+    services: State<Services>,
+    post_data: Json<InjectFaultRequest>,
+) -> Json<UserResponse> {
+    let data = post_data.0;
+    let admin_key = auth.key_or(&data.admin_key);
+
+    if let Err(cause) = auth.verify(
+        &admin_key,
+        check_admin(&admin_key, &env),
+        "Invalid admin key",
+    ) {
+        return Json(UserResponse::from(cause));
+    }
+    if env.read_only {
+        return Json(UserResponse::read_only());
+    }
+
+    if !INTEGRATIONS.contains(&data.integration.as_str()) {
+        return Json(UserResponse::failure(format!(
+            "Unknown integration {}, expected one of {}",
+            data.integration,
+            INTEGRATIONS.join(", ")
+        )));
+    }
+    if data.fail_percent > 100 || data.slow_percent > 100 {
+        return Json(UserResponse::failure(
+            "fail_percent and slow_percent must be between 0 and 100".into(),
+        ));
+    }
+    if data.delay_ms > MAX_DELAY_MS {
+        return Json(UserResponse::failure(format!(
+            "delay_ms must be at most {}",
+            MAX_DELAY_MS
+        )));
+    }
+    if data.minutes > MAX_MINUTES {
+        return Json(UserResponse::failure(format!(
+            "Faults may be injected for at most {} minutes",
+            MAX_MINUTES
+        )));
+    }
+
+    if data.minutes == 0 {
+        services
+            .faults
+            .update(|faults| faults.remove(&data.integration));
+        log_event!("audit.fault_cleared", integration = data.integration);
+        return Json(UserResponse::success());
+    }
+
+    let integration = data.integration;
+    let fault = Fault {
+        fail_percent: data.fail_percent,
+        slow_percent: data.slow_percent,
+        delay_ms: data.delay_ms,
+        handlers: data.handlers,
+        expires_at: unix_now() + data.minutes * 60,
+    };
+    log_event!(
+        "audit.fault_injected",
+        integration = integration,
+        fail_percent = fault.fail_percent,
+        slow_percent = fault.slow_percent,
+        delay_ms = fault.delay_ms,
+        minutes = data.minutes,
+    );
+    services
+        .faults
+        .update(|faults| faults.insert(integration, fault));
+    Json(UserResponse::success())
+}
+
+/// Rocket Endpoint which lists the faults being injected, by integration
+///
+/// # Arguments
+///
+/// * `auth` - The admin key from the `Authorization` header, if any
+/// * `env` - Environment variables
+/// * `services` - Where faults are kept
+/// * `post_data` - Must contain the admin key, unless it was passed in the header
+#[post("/admin/list_faults", data = "<post_data>")]
+pub fn list_faults(
+    auth: AuthHeader,
+    env: State<EnvInfo>,
+    services: State<Services>,
+    post_data: Option<Json<AdminRequest>>,
+) -> Json<UserResponse> {
+    let admin_key = auth.key_or(&post_data.map(|d| d.0.admin_key).unwrap_or_default());
+
+    if let Err(cause) = auth.verify(
+        &admin_key,
+        check_admin(&admin_key, &env),
+        "Invalid admin key",
+    ) {
+        return Json(UserResponse::from(cause));
+    }
+
+    let now = unix_now();
+    let faults = services
+        .faults
+        .read()
+        .iter()
+        .filter(|(_, f)| f.expires_at > now)
+        .map(|(integration, f)| (integration.clone(), f.clone()))
+        .collect::<HashMap<String, Fault>>();
+    Json(
+        UserResponse::success_with_raw(faults)
+            .unwrap_or_else(|| UserResponse::failure("Unable to list faults".into())),
+    )
+}
@@ -0,0 +1,204 @@
+use std::ops::Deref;
+
+use rhai::de::from_dynamic;
+use rhai::{Dynamic, EvalAltResult, ImmutableString, Module, INT};
+
+use rocket::http::ContentType;
+use rocket::response::content::Content;
+use rocket::State;
+
+use serde::{Deserialize, Serialize};
+
+use crate::clock::{unix_now, utc_datetime};
+use crate::server::Collection;
+use crate::services::Services;
+use crate::storage::new_id;
+use crate::twilio::escape_xml;
+use crate::types::Handler;
+
+/// How many entries a handler's feed keeps. The oldest are dropped to make room for new ones
+const MAX_ENTRIES: usize = 100;
+
+/// An entry a handler appended to its feed
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Entry {
+    /// Identifies the entry, so it can be updated or removed later. Generated, if not given
+    #[serde(default)]
+    pub id: String,
+    pub title: String,
+    /// The body of the entry, as plain text
+    #[serde(default)]
+    pub content: Option<String>,
+    /// Where the entry links to, e.g. a release or a Slack thread
+    #[serde(default)]
+    pub link: Option<String>,
+    #[serde(default)]
+    pub author: Option<String>,
+    /// When the entry was first appended, as a unix timestamp
+    #[serde(default)]
+    pub published_at: u64,
+    /// When the entry was last appended, as a unix timestamp
+    #[serde(default)]
+    pub updated_at: u64,
+}
+
+/// The entries of a handler, oldest first
+pub type Feed = Vec<Entry>;
+
+/// Render a feed as an Atom document, newest entries first
+///
+/// # Arguments
+///
+/// * `handler_addr` - The uri of the handler the feed belongs to, used as its title
+/// * `feed` - The entries to list
+fn render(handler_addr: &str, feed: &[Entry]) -> String {
+    let feed_id = format!("urn:majordomo:feed:{}", handler_addr);
+    let updated = feed.iter().map(|e| e.updated_at).max().unwrap_or(0);
+
+    let mut xml = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+    xml.push_str("<feed xmlns=\"http://www.w3.org/2005/Atom\">\n");
+    xml.push_str(&format!("  <id>{}</id>\n", escape_xml(&feed_id)));
+    xml.push_str(&format!("  <title>{}</title>\n", escape_xml(handler_addr)));
+    xml.push_str(&format!("  <updated>{}</updated>\n", utc_datetime(updated)));
+    xml.push_str("  <author><name>Majordomo</name></author>\n");
+
+    for entry in feed.iter().rev() {
+        xml.push_str("  <entry>\n");
+        xml.push_str(&format!(
+            "    <id>{}:{}</id>\n",
+            escape_xml(&feed_id),
+            escape_xml(&entry.id)
+        ));
+        xml.push_str(&format!(
+            "    <title>{}</title>\n",
+            escape_xml(&entry.title)
+        ));
+        xml.push_str(&format!(
+            "    <published>{}</published>\n",
+            utc_datetime(entry.published_at)
+        ));
+        xml.push_str(&format!(
+            "    <updated>{}</updated>\n",
+            utc_datetime(entry.updated_at)
+        ));
+        if let Some(author) = &entry.author {
+            xml.push_str(&format!(
+                "    <author><name>{}</name></author>\n",
+                escape_xml(author)
+            ));
+        }
+        if let Some(link) = &entry.link {
+            xml.push_str(&format!("    <link href=\"{}\"/>\n", escape_xml(link)));
+        }
+        if let Some(content) = &entry.content {
+            xml.push_str(&format!(
+                "    <content type=\"text\">{}</content>\n",
+                escape_xml(content)
+            ));
+        }
+        xml.push_str("  </entry>\n");
+    }
+
+    xml.push_str("</feed>\n");
+    xml
+}
+
+/// Register the feed functions available to clients
+///
+/// Each handler has its own feed, served as Atom at `/h/<uri>/feed.xml`, which feed readers can
+/// subscribe to, e.g. for a changelog or announcements.
+///
+/// * `feed_append(entry)` adds an entry, or updates the one with the same `id`, and returns its
+///   id. Entries are maps with a `title`, and optionally `content`, a `link`, an `author` and an
+///   `id`. Only the latest `MAX_ENTRIES` are kept
+/// * `feed_remove(id)` removes an entry, returning whether there was one
+/// * `feed_clear()` removes every entry, returning how many there were
+///
+/// # Arguments
+///
+/// * `module` - The module to register the functions in
+/// * `services` - Where feeds are stored
+/// * `handler_addr` - The uri of the handler the functions are for, i.e. the feed
+pub fn register(module: &mut Module, services: &Services, handler_addr: &str) {
+    let feeds = services.feeds.clone();
+    let addr = handler_addr.to_string();
+    let feed_append = move |entry: Dynamic| -> Result<String, Box<EvalAltResult>> {
+        let mut entry: Entry = from_dynamic(&entry)?;
+        if entry.title.trim().is_empty() {
+            return Err("Entries need a title".into());
+        }
+        if entry.id.is_empty() {
+            entry.id = new_id();
+        }
+        entry.updated_at = unix_now();
+        entry.published_at = entry.updated_at;
+
+        let id = entry.id.clone();
+        feeds.update(|map| {
+            let feed = map.entry(addr.clone()).or_default();
+            // Updated entries keep their place, and when they were first published
+            match feed.iter_mut().find(|e| e.id == entry.id) {
+                Some(existing) => {
+                    entry.published_at = existing.published_at;
+                    *existing = entry;
+                }
+                None => feed.push(entry),
+            }
+            if feed.len() > MAX_ENTRIES {
+                let excess = feed.len() - MAX_ENTRIES;
+                feed.drain(..excess);
+            }
+        });
+        Ok(id)
+    };
+
+    let feeds = services.feeds.clone();
+    let addr = handler_addr.to_string();
+    let feed_remove = move |id: ImmutableString| {
+        Ok(feeds.update(|map| match map.get_mut(&addr) {
+            Some(feed) => {
+                let before = feed.len();
+                feed.retain(|e| e.id != id.as_str());
+                feed.len() < before
+            }
+            None => false,
+        }))
+    };
+
+    let feeds = services.feeds.clone();
+    let addr = handler_addr.to_string();
+    let feed_clear =
+        move || Ok(feeds.update(|map| map.remove(&addr).map(|f| f.len()).unwrap_or(0)) as INT);
+
+    module.set_fn_1("feed_append", feed_append);
+    module.set_fn_1("feed_remove", feed_remove);
+    module.set_fn_0("feed_clear", feed_clear);
+}
+
+/// Rocket Endpoint which serves the feed of a handler as Atom
+///
+/// Like invoking handlers, this needs no API Key, so feed readers can subscribe to it. Handlers
+/// which haven't appended anything have an empty feed.
+///
+/// # Arguments
+///
+/// * `services` - Where feeds are stored
+/// * `handlers` - A reference to the collection of User created handlers, indexed by their uris
+/// * `handler_addr` - The uri of the handler whose feed to serve
+#[get("/h/<handler_addr>/feed.xml")]
+pub fn feed_xml(
+    services: State<Services>,
+    handlers: Collection<String, Handler>,
+    handler_addr: String,
+) -> Option<Content<String>> {
+    if !handlers.read().unwrap().deref().contains_key(&handler_addr) {
+        return None;
+    }
+
+    let feeds = services.feeds.read();
+    let feed = feeds.get(&handler_addr).map(Vec::as_slice).unwrap_or(&[]);
+    Some(Content(
+        ContentType::new("application", "atom+xml"),
+        render(&handler_addr, feed),
+    ))
+}
@@ -0,0 +1,388 @@
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::sync::RwLock;
+
+use rhai::de::from_dynamic;
+use rhai::{Array, Dynamic, EvalAltResult, ImmutableString, Map, Module, Scope, INT};
+
+use rocket::State;
+use rocket_contrib::json::Json;
+
+use serde::{Deserialize, Serialize};
+
+use sha2::{Digest, Sha256};
+
+use crate::auth::{check_auth, check_scope, hash_key, AuthHeader};
+use crate::clock::unix_now;
+use crate::logging::CorrelationId;
+use crate::server::{run_function, Collection};
+use crate::services::Services;
+use crate::storage::JsonStore;
+use crate::types::{ApiKeyInfo, EnvInfo, Handler, SetFlagRequest, UserResponse, WRITE_SCOPE};
+
+/// How many flags a single API Key may own
+const MAX_FLAGS: usize = 200;
+
+/// The longest the name of a flag may be
+const MAX_NAME_CHARS: usize = 64;
+
+/// How many users a flag may list as always or never on
+const MAX_USERS: usize = 1000;
+
+/// The rules of a flag, as handlers and clients set them
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Rules {
+    /// Whether the flag is on at all. Off flags are off for everyone
+    #[serde(default)]
+    pub enabled: bool,
+    /// The percentage of users the flag is on for, 0 to 100. The same users stay in the
+    /// rollout as it grows
+    #[serde(default)]
+    pub rollout_percent: u8,
+    /// Users the flag is always on for, while it is enabled
+    #[serde(default)]
+    pub allow: Vec<String>,
+    /// Users the flag is never on for
+    #[serde(default)]
+    pub deny: Vec<String>,
+}
+
+/// A feature flag, which other services ask majordomo about, see `flag_eval`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Flag {
+    /// The hash of the API Key which owns the flag. Only it, and its handlers, may change it
+    pub owner: String,
+    pub rules: Rules,
+    /// When the flag was last changed, as a unix timestamp
+    pub updated_at: u64,
+    /// Counts changes, so that every one is announced, see `tick`
+    pub version: u64,
+    /// The version handlers were last told about
+    pub notified_version: u64,
+}
+
+impl Rules {
+    /// Whether the flag is on for a user, or for everyone, if there is no user
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - The name of the flag, so each flag rolls out to different users
+    /// * `user` - Who is asking, if anyone in particular
+    pub fn evaluate(&self, name: &str, user: Option<&str>) -> bool {
+        if !self.enabled {
+            return false;
+        }
+        let user = match user {
+            Some(user) => user,
+            None => return self.rollout_percent >= 100,
+        };
+        if self.deny.iter().any(|u| u == user) {
+            return false;
+        }
+        if self.allow.iter().any(|u| u == user) {
+            return true;
+        }
+
+        let digest = Sha256::digest(format!("{}:{}", name, user).as_bytes());
+        let bucket = u64::from_be_bytes(digest[..8].try_into().unwrap()) % 100;
+        bucket < self.rollout_percent as u64
+    }
+
+    fn check(&self) -> Result<(), String> {
+        if self.rollout_percent > 100 {
+            return Err("The rollout must be a percentage, 0 to 100".into());
+        }
+        if self.allow.len() + self.deny.len() > MAX_USERS {
+            return Err(format!("A flag may list at most {} users", MAX_USERS));
+        }
+        Ok(())
+    }
+
+    fn to_map(&self) -> Map {
+        let users = |users: &[String]| {
+            users
+                .iter()
+                .map(|u| Dynamic::from(u.clone()))
+                .collect::<Array>()
+        };
+
+        let mut map = Map::new();
+        map.insert("enabled".into(), Dynamic::from(self.enabled));
+        map.insert(
+            "rollout_percent".into(),
+            Dynamic::from(self.rollout_percent as INT),
+        );
+        map.insert("allow".into(), Dynamic::from(users(&self.allow)));
+        map.insert("deny".into(), Dynamic::from(users(&self.deny)));
+        map
+    }
+}
+
+/// Check the name of a flag, so that it can be put in a url as is
+fn check_name(name: &str) -> Result<(), String> {
+    let valid = !name.is_empty()
+        && name.chars().count() <= MAX_NAME_CHARS
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-' || c == '.');
+    if valid {
+        Ok(())
+    } else {
+        Err(format!(
+            "Flag names must be 1 to {} letters, digits, '_', '-' or '.'",
+            MAX_NAME_CHARS
+        ))
+    }
+}
+
+/// Create or change a flag
+///
+/// # Arguments
+///
+/// * `flags` - Where flags are stored
+/// * `owner` - The hash of the API Key setting the flag
+/// * `name` - The name of the flag
+/// * `rules` - Its new rules
+fn set(flags: &JsonStore<Flag>, owner: &str, name: &str, rules: Rules) -> Result<(), String> {
+    check_name(name)?;
+    rules.check()?;
+
+    flags.update(|map| match map.get_mut(name) {
+        Some(flag) if flag.owner != owner => {
+            Err(format!("The flag {} belongs to another API Key", name))
+        }
+        Some(flag) => {
+            flag.rules = rules;
+            flag.updated_at = unix_now();
+            flag.version += 1;
+            Ok(())
+        }
+        None => {
+            if map.values().filter(|f| f.owner == owner).count() >= MAX_FLAGS {
+                return Err(format!("An API Key may own at most {} flags", MAX_FLAGS));
+            }
+            let flag = Flag {
+                owner: owner.to_string(),
+                rules,
+                updated_at: unix_now(),
+                version: 1,
+                notified_version: 0,
+            };
+            map.insert(name.to_string(), flag);
+            Ok(())
+        }
+    })
+}
+
+/// Register the feature flag functions available to clients
+///
+/// Flags belong to the API Key which owns the handler, so handlers of other keys can read them,
+/// but not change them.
+///
+/// * `flag_set(name, rules)` creates or changes a flag. `rules` is a map of whether the flag is
+///   `enabled`, the `rollout_percent` of users it is on for, and arrays of users it is always
+///   on for, `allow`, and never on for, `deny`
+/// * `flag_get(name)` returns the rules of a flag, as above, or `()` if there is no such flag
+/// * `flag_enabled(name, user)` returns whether a flag is on for a user. Unknown flags are off
+/// * `flag_delete(name)` deletes a flag. Returns whether there was one
+///
+/// # Arguments
+///
+/// * `module` - The module to register the functions in
+/// * `services` - Where flags are stored
+/// * `handler_addr` - The uri of the handler the functions are for
+/// * `owner` - The hash of the API Key which owns the handler
+pub fn register(module: &mut Module, services: &Services, handler_addr: &str, owner: &str) {
+    let flags = services.flags.clone();
+    let addr = handler_addr.to_string();
+    let key = owner.to_string();
+    let flag_set = move |name: ImmutableString, rules: Map| -> Result<(), Box<EvalAltResult>> {
+        let rules: Rules = from_dynamic(&Dynamic::from(rules))?;
+        set(&flags, &key, &name, rules)?;
+        log_event!("flag.set", handler = addr, flag = name);
+        Ok(())
+    };
+
+    let flags = services.flags.clone();
+    let flag_get = move |name: ImmutableString| {
+        Ok(match flags.read().get(name.as_str()) {
+            Some(flag) => Dynamic::from(flag.rules.to_map()),
+            None => Dynamic::from(()),
+        })
+    };
+
+    let flags = services.flags.clone();
+    let flag_enabled = move |name: ImmutableString, user: ImmutableString| {
+        Ok(flags
+            .read()
+            .get(name.as_str())
+            .map(|f| f.rules.evaluate(&name, Some(&user)))
+            .unwrap_or(false))
+    };
+
+    let flags = services.flags.clone();
+    let addr = handler_addr.to_string();
+    let key = owner.to_string();
+    let flag_delete = move |name: ImmutableString| -> Result<bool, Box<EvalAltResult>> {
+        let deleted = flags.update(|map| match map.get(name.as_str()) {
+            Some(flag) if flag.owner != key => {
+                Err(format!("The flag {} belongs to another API Key", name))
+            }
+            Some(_) => Ok(map.remove(name.as_str()).is_some()),
+            None => Ok(false),
+        })?;
+        log_event!("flag.delete", handler = addr, flag = name);
+        Ok(deleted)
+    };
+
+    module.set_fn_2("flag_set", flag_set);
+    module.set_fn_1("flag_get", flag_get);
+    module.set_fn_2("flag_enabled", flag_enabled);
+    module.set_fn_1("flag_delete", flag_delete);
+}
+
+/// Rocket Endpoint which creates or changes a flag, for clients which aren't handlers
+///
+/// # Arguments
+///
+/// * `auth` - The API Key from the `Authorization` header, if any
+/// * `env` - Environment variables
+/// * `services` - Where flags are stored
+/// * `api_keys` - A reference to the collection of Client API keys, used to check for auth
+/// * `post_data` - The name of the flag, and its rules
+#[post("/set_flag", data = "<post_data>")]
+pub fn set_flag(
+    auth: AuthHeader,
+    env: State<EnvInfo>,
+    services: State<Services>,
+    api_keys: Collection<String, ApiKeyInfo>,
+    post_data: Json<SetFlagRequest>,
+) -> Json<UserResponse> {
+    let data = post_data.0;
+    let key = auth.key_or(&data.api_key);
+
+    if let Err(cause) = auth.verify(&key, check_auth(&key, &api_keys), "Invalid API Key") {
+        return Json(UserResponse::from(cause));
+    }
+    if let Err(cause) = check_scope(&key, &api_keys, WRITE_SCOPE) {
+        return Json(UserResponse::from(cause));
+    }
+
+    if env.read_only {
+        return Json(UserResponse::read_only());
+    }
+
+    let owner = hash_key(&key);
+    match set(&services.flags, &owner, &data.name, data.rules) {
+        Ok(_) => {
+            log_event!("audit.flag_set", key = &owner[..8], flag = data.name);
+            Json(UserResponse::success())
+        }
+        Err(cause) => Json(UserResponse::failure(cause)),
+    }
+}
+
+/// How a flag evaluated for a user
+#[derive(Debug, Serialize)]
+pub struct Evaluation {
+    pub flag: String,
+    pub user: Option<String>,
+    pub enabled: bool,
+}
+
+/// Rocket Endpoint which tells other services whether a flag is on for a user
+///
+/// Like invoking handlers, this needs no API Key, so any service can ask. Without a user, the
+/// flag is only on if it is enabled for everyone. Unknown flags are 404s.
+///
+/// # Arguments
+///
+/// * `services` - Where flags are stored
+/// * `name` - The name of the flag
+/// * `user` - Who is asking, e.g. a user id
+#[get("/flags/<name>?<user>")]
+pub fn flag_eval(
+    services: State<Services>,
+    name: String,
+    user: Option<String>,
+) -> Option<Json<UserResponse>> {
+    let enabled = services
+        .flags
+        .read()
+        .get(&name)?
+        .rules
+        .evaluate(&name, user.as_deref());
+
+    let evaluation = Evaluation {
+        flag: name,
+        user,
+        enabled,
+    };
+    UserResponse::success_with_raw(evaluation).map(Json)
+}
+
+/// Tell handlers about the flags which changed since the last tick. Run by the scheduler
+///
+/// Every handler of the flag's owner which defines `on_flag_change(flag)` is called, with a map
+/// of the flag's `name` and rules, as `flag_get` returns them.
+///
+/// # Arguments
+///
+/// * `env` - Environment variables
+/// * `services` - Where flags are stored
+/// * `handlers` - The handlers to tell about changes
+pub fn tick(env: &EnvInfo, services: &Services, handlers: &RwLock<HashMap<String, Handler>>) {
+    // Most ticks have nothing to do, and shouldn't save the flags for nothing
+    let changed = services
+        .flags
+        .read()
+        .values()
+        .any(|f| f.version != f.notified_version);
+    if !changed {
+        return;
+    }
+
+    let changes = services.flags.update(|map| {
+        map.iter_mut()
+            .filter(|(_, f)| f.version != f.notified_version)
+            .map(|(name, f)| {
+                f.notified_version = f.version;
+                (name.clone(), f.clone())
+            })
+            .collect::<Vec<(String, Flag)>>()
+    });
+
+    for (name, flag) in changes {
+        let id = CorrelationId::generate();
+        let mut event = flag.rules.to_map();
+        event.insert("name".into(), Dynamic::from(name.clone()));
+
+        let guard = handlers.read().unwrap();
+        let watchers = guard
+            .values()
+            .filter(|h| h.api_key == flag.owner && h.defines("on_flag_change", 1));
+        for handler in watchers {
+            let args = (event.clone(),);
+            let ast = &handler.code.ast;
+            let result = run_function(
+                env,
+                services,
+                &id,
+                &handler.uri,
+                handler,
+                None,
+                "on_flag_change",
+                |engine| engine.call_fn(&mut Scope::new(), ast, "on_flag_change", args),
+            );
+            if let Err(e) = result {
+                log_event!(
+                    "handler.error",
+                    id = id.0,
+                    handler = handler.uri,
+                    flag = name,
+                    error = e
+                );
+            }
+        }
+    }
+}
@@ -0,0 +1,1277 @@
+use std::collections::HashMap;
+use std::ops::Deref;
+use std::sync::RwLock;
+
+use reqwest::blocking::{Client, RequestBuilder, Response};
+use reqwest::header::{HeaderMap, HeaderValue, ACCEPT, AUTHORIZATION, CONTENT_TYPE, USER_AGENT};
+use reqwest::StatusCode;
+
+use rhai::ser::to_dynamic;
+use rhai::{Array, Dynamic, EvalAltResult, ImmutableString, Map, Module, INT};
+
+use rocket::request::{self, FromRequest};
+use rocket::{Outcome, Request, State};
+
+use rocket_contrib::json::Json;
+
+use serde_json::{json, Value};
+
+use crate::advisories;
+use crate::auth::GithubBody;
+use crate::codeowners;
+use crate::envelope::{self, Envelope};
+use crate::faults;
+use crate::logging::CorrelationId;
+use crate::server::{run_handler, Collection};
+use crate::services::Services;
+use crate::types::{EnvInfo, GithubIssueCommentResponse, Handler, PathRoute, UserResponse};
+
+/// The kind of event a webhook delivery is about, e.g. `push`, from the `X-GitHub-Event` header
+pub struct GithubEvent(pub String);
+
+impl<'a, 'r> FromRequest<'a, 'r> for GithubEvent {
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<GithubEvent, ()> {
+        match request.headers().get_one("X-GitHub-Event") {
+            Some(event) if !event.is_empty() => Outcome::Success(GithubEvent(event.to_lowercase())),
+            _ => Outcome::Forward(()),
+        }
+    }
+}
+
+/// The uri of the handler which receives a kind of event for a repository,
+/// `github-<owner>-<repo>-<event>`
+///
+/// # Arguments
+///
+/// * `full_name` - The full name of the repository, `<owner>/<repo>`
+/// * `event` - The kind of event
+pub fn handler_uri(full_name: &str, event: &str) -> String {
+    format!("github-{}-{}", full_name.replace('/', "-"), event).to_lowercase()
+}
+
+/// Run a query or mutation against the GitHub GraphQL API
+///
+/// Returns the `data` of the response, or the first error GitHub reported
+///
+/// # Arguments
+///
+/// * `client` - A reqwest HTTP "client" to make the request
+/// * `token` - The github token to authenticate with. Never seen by Clients
+/// * `query` - The GraphQL document
+/// * `variables` - The variables the document refers to
+pub fn graphql(
+    client: &Client,
+    token: &str,
+    query: &str,
+    variables: Value,
+) -> Result<Value, String> {
+    if token == "no-github" {
+        return Err("github is not configured".into());
+    }
+    faults::inject("github")?;
+
+    let mut headers = HeaderMap::new();
+    headers.insert(
+        AUTHORIZATION,
+        format!("bearer {}", token)
+            .parse()
+            .map_err(|_| "invalid github token".to_string())?,
+    );
+    headers.insert(USER_AGENT, HeaderValue::from_static("dti-majordomo"));
+
+    let text = client
+        .post("https://api.github.com/graphql")
+        .headers(headers)
+        .body(json!({ "query": query, "variables": variables }).to_string())
+        .send()
+        .and_then(Response::text)
+        .map_err(|e| e.to_string())?;
+    let mut resp: Value = serde_json::from_str(&text).map_err(|e| e.to_string())?;
+
+    match resp["errors"].get(0) {
+        Some(error) => {
+            let error = error["message"]
+                .as_str()
+                .unwrap_or("unknown_error")
+                .to_string();
+            log_event!("github.graphql_error", error = error);
+            Err(error)
+        }
+        None if resp["data"].is_object() => Ok(resp["data"].take()),
+        None => Err(resp["message"]
+            .as_str()
+            .unwrap_or("unknown_error")
+            .to_string()),
+    }
+}
+
+/// The most members `github_org_members` returns
+const MAX_ORG_MEMBERS: usize = 1000;
+
+/// GitHub lists at most 3000 files of a pull request, 100 at a time
+const MAX_PULL_FILE_PAGES: usize = 30;
+
+/// Send a request to the GitHub REST API, returning the status and body of the response
+///
+/// # Arguments
+///
+/// * `token` - The github token to authenticate with. Never seen by Clients
+/// * `request` - The request, without any authentication
+fn rest_send(token: &str, request: RequestBuilder) -> Result<(StatusCode, String), String> {
+    if token == "no-github" {
+        return Err("github is not configured".into());
+    }
+    faults::inject("github")?;
+
+    let mut headers = HeaderMap::new();
+    headers.insert(
+        AUTHORIZATION,
+        format!("token {}", token)
+            .parse()
+            .map_err(|_| "invalid github token".to_string())?,
+    );
+    headers.insert(USER_AGENT, HeaderValue::from_static("dti-majordomo"));
+
+    let resp = request.headers(headers).send().map_err(|e| e.to_string())?;
+    let status = resp.status();
+    let text = resp.text().map_err(|e| e.to_string())?;
+    Ok((status, text))
+}
+
+/// Parse a response of the GitHub REST API
+///
+/// Returns the parsed response, or the message GitHub gave for refusing the request
+///
+/// # Arguments
+///
+/// * `path` - The path of the API resource, for the logs
+/// * `status` - The status of the response
+/// * `text` - The body of the response
+fn rest_parse(path: &str, status: StatusCode, text: &str) -> Result<Value, String> {
+    // Some successful responses, e.g. 204s, have no body at all
+    let body: Value = match text.trim() {
+        "" if status.is_success() => Value::Null,
+        text => serde_json::from_str(text).map_err(|e| e.to_string())?,
+    };
+
+    if status.is_success() {
+        Ok(body)
+    } else {
+        let error = body["message"]
+            .as_str()
+            .unwrap_or("unknown_error")
+            .to_string();
+        log_event!("github.api_error", path = path, error = error);
+        Err(error)
+    }
+}
+
+/// Make a GET request to the GitHub REST API, e.g. `/repos/<owner>/<repo>`
+///
+/// Returns the parsed response, or the message GitHub gave for refusing the request
+///
+/// # Arguments
+///
+/// * `client` - A reqwest HTTP "client" to make the request
+/// * `token` - The github token to authenticate with. Never seen by Clients
+/// * `path` - The path of the API resource
+/// * `query` - Any query parameters
+pub fn rest_get(
+    client: &Client,
+    token: &str,
+    path: &str,
+    query: &[(&str, String)],
+) -> Result<Value, String> {
+    let request = client
+        .get(&format!("https://api.github.com{}", path))
+        .query(query);
+    let (status, text) = rest_send(token, request)?;
+    rest_parse(path, status, &text)
+}
+
+/// Make a POST request to the GitHub REST API
+///
+/// Returns the parsed response, or the message GitHub gave for refusing the request
+///
+/// # Arguments
+///
+/// * `client` - A reqwest HTTP "client" to make the request
+/// * `token` - The github token to authenticate with. Never seen by Clients
+/// * `path` - The path of the API resource
+/// * `body` - The body of the request, sent as json
+pub fn rest_post(client: &Client, token: &str, path: &str, body: &Value) -> Result<Value, String> {
+    let request = client
+        .post(&format!("https://api.github.com{}", path))
+        .header(CONTENT_TYPE, "application/json")
+        .body(body.to_string());
+    let (status, text) = rest_send(token, request)?;
+    rest_parse(path, status, &text)
+}
+
+/// Make a PATCH request to the GitHub REST API
+///
+/// Returns the parsed response, or the message GitHub gave for refusing the request
+///
+/// # Arguments
+///
+/// * `client` - A reqwest HTTP "client" to make the request
+/// * `token` - The github token to authenticate with. Never seen by Clients
+/// * `path` - The path of the API resource
+/// * `body` - The body of the request, sent as json
+pub fn rest_patch(client: &Client, token: &str, path: &str, body: &Value) -> Result<Value, String> {
+    let request = client
+        .patch(&format!("https://api.github.com{}", path))
+        .header(CONTENT_TYPE, "application/json")
+        .body(body.to_string());
+    let (status, text) = rest_send(token, request)?;
+    rest_parse(path, status, &text)
+}
+
+/// Search issues and pull requests, returning the first 100 matches, most recently updated first
+///
+/// # Arguments
+///
+/// * `client` - A reqwest HTTP "client" to make the request
+/// * `token` - The github token to authenticate with
+/// * `query` - A GitHub search query, e.g. `repo:octo/widgets is:issue is:open label:bug`
+pub fn search_issues(client: &Client, token: &str, query: &str) -> Result<Vec<Value>, String> {
+    let params = [
+        ("q", query.to_string()),
+        ("sort", "updated".to_string()),
+        ("per_page", "100".to_string()),
+    ];
+    let mut resp = rest_get(client, token, "/search/issues", &params)?;
+    match resp["items"].take() {
+        Value::Array(items) => Ok(items),
+        _ => Ok(Vec::new()),
+    }
+}
+
+/// An issue or pull request from the REST API, as a map of the parts handlers usually care about:
+/// its `repo`, `number`, `title`, `url`, `author`, `state`, `labels`, `created_at` and
+/// `updated_at`, and whether it is a `pull_request`
+///
+/// # Arguments
+///
+/// * `issue` - The issue, as GitHub sent it
+pub fn issue_map(issue: &Value) -> Map {
+    let text = |value: &Value| Dynamic::from(value.as_str().unwrap_or_default().to_string());
+    let labels = issue["labels"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(|l| l["name"].as_str().map(|n| Dynamic::from(n.to_string())))
+        .collect::<Array>();
+    // Search results only link to their repository
+    let repo = issue["repository_url"]
+        .as_str()
+        .unwrap_or_default()
+        .trim_start_matches("https://api.github.com/repos/")
+        .to_string();
+
+    let mut map = Map::new();
+    map.insert("repo".into(), Dynamic::from(repo));
+    map.insert(
+        "number".into(),
+        Dynamic::from(issue["number"].as_i64().unwrap_or_default() as INT),
+    );
+    map.insert("title".into(), text(&issue["title"]));
+    map.insert("url".into(), text(&issue["html_url"]));
+    map.insert("author".into(), text(&issue["user"]["login"]));
+    map.insert("state".into(), text(&issue["state"]));
+    map.insert("labels".into(), Dynamic::from(labels));
+    map.insert("created_at".into(), text(&issue["created_at"]));
+    map.insert("updated_at".into(), text(&issue["updated_at"]));
+    map.insert(
+        "pull_request".into(),
+        Dynamic::from(issue["pull_request"].is_object()),
+    );
+    map
+}
+
+/// Add labels to an issue or pull request
+///
+/// # Arguments
+///
+/// * `client` - A reqwest HTTP "client" to make the request
+/// * `token` - The github token to authenticate with
+/// * `repo` - The full name of the repository, `<owner>/<repo>`
+/// * `number` - The number of the issue
+/// * `labels` - The labels to add. Labels which don't exist yet are created
+pub fn add_labels(
+    client: &Client,
+    token: &str,
+    repo: &str,
+    number: INT,
+    labels: &[String],
+) -> Result<(), String> {
+    check_repo(repo)?;
+    let path = format!("/repos/{}/issues/{}/labels", repo, number);
+    rest_post(client, token, &path, &json!({ "labels": labels })).map(|_| ())
+}
+
+/// Comment on an issue or pull request
+///
+/// # Arguments
+///
+/// * `client` - A reqwest HTTP "client" to make the request
+/// * `token` - The github token to authenticate with
+/// * `repo` - The full name of the repository, `<owner>/<repo>`
+/// * `number` - The number of the issue
+/// * `body` - The comment, as markdown
+pub fn comment_issue(
+    client: &Client,
+    token: &str,
+    repo: &str,
+    number: INT,
+    body: &str,
+) -> Result<GithubIssueCommentResponse, String> {
+    check_repo(repo)?;
+    let path = format!("/repos/{}/issues/{}/comments", repo, number);
+    let comment = rest_post(client, token, &path, &json!({ "body": body }))?;
+    serde_json::from_value(comment).map_err(|e| e.to_string())
+}
+
+/// Close an issue or pull request
+///
+/// # Arguments
+///
+/// * `client` - A reqwest HTTP "client" to make the request
+/// * `token` - The github token to authenticate with
+/// * `repo` - The full name of the repository, `<owner>/<repo>`
+/// * `number` - The number of the issue
+pub fn close_issue(client: &Client, token: &str, repo: &str, number: INT) -> Result<(), String> {
+    check_repo(repo)?;
+    let path = format!("/repos/{}/issues/{}", repo, number);
+    rest_patch(client, token, &path, &json!({ "state": "closed" })).map(|_| ())
+}
+
+/// Fetch a file from a repository, or None if it doesn't exist
+///
+/// # Arguments
+///
+/// * `client` - A reqwest HTTP "client" to make the request
+/// * `token` - The github token to authenticate with
+/// * `repo` - The full name of the repository, `<owner>/<repo>`
+/// * `path` - The path of the file in the repository
+/// * `git_ref` - The branch, tag or commit to fetch the file at
+pub fn fetch_file(
+    client: &Client,
+    token: &str,
+    repo: &str,
+    path: &str,
+    git_ref: &str,
+) -> Result<Option<String>, String> {
+    check_repo(repo)?;
+    if path.split('/').any(|part| part == "..") || path.contains(['?', '#'].as_ref()) {
+        return Err(format!("Invalid path {}", path));
+    }
+
+    let api_path = format!("/repos/{}/contents/{}", repo, path.trim_start_matches('/'));
+    let request = client
+        .get(&format!("https://api.github.com{}", api_path))
+        .header(ACCEPT, "application/vnd.github.v3.raw")
+        .query(&[("ref", git_ref)]);
+
+    match rest_send(token, request)? {
+        (status, _) if status == StatusCode::NOT_FOUND => Ok(None),
+        (status, text) if status.is_success() => Ok(Some(text)),
+        (status, text) => rest_parse(&api_path, status, &text).map(|_| None),
+    }
+}
+
+/// Make sure a repository name can be put into a REST API path as it is
+///
+/// # Arguments
+///
+/// * `repo` - The full name of the repository, which should be `<owner>/<repo>`
+pub fn check_repo(repo: &str) -> Result<(), String> {
+    if repo.split('/').count() != 2 || repo.contains("..") || repo.contains(['?', '#'].as_ref()) {
+        return Err(format!("Expected <owner>/<repo>, not {}", repo));
+    }
+    Ok(())
+}
+
+/// Make sure a branch, tag or commit can be put into a REST API path as it is
+///
+/// # Arguments
+///
+/// * `reference` - The branch, tag or commit
+pub fn check_ref(reference: &str) -> Result<(), String> {
+    if reference.is_empty() || reference.contains("..") || reference.contains(['?', '#'].as_ref()) {
+        return Err(format!("Invalid ref {}", reference));
+    }
+    Ok(())
+}
+
+/// Look up a repository, as a map of the parts handlers usually care about
+///
+/// # Arguments
+///
+/// * `client` - A reqwest HTTP "client" to make the request
+/// * `token` - The github token to authenticate with
+/// * `repo` - The full name of the repository, `<owner>/<repo>`
+fn repo_info(client: &Client, token: &str, repo: &str) -> Result<Map, String> {
+    check_repo(repo)?;
+
+    let info = rest_get(client, token, &format!("/repos/{}", repo), &[])
+        .map_err(|e| format!("Unable to find {}: {}", repo, e))?;
+
+    let text = |key: &str| Dynamic::from(info[key].as_str().unwrap_or_default().to_string());
+    let flag = |key: &str| Dynamic::from(info[key].as_bool().unwrap_or(false));
+
+    let mut map = Map::new();
+    map.insert("name".into(), text("name"));
+    map.insert("full_name".into(), text("full_name"));
+    map.insert("description".into(), text("description"));
+    map.insert("default_branch".into(), text("default_branch"));
+    map.insert("url".into(), text("html_url"));
+    map.insert("private".into(), flag("private"));
+    map.insert("archived".into(), flag("archived"));
+    map.insert("fork".into(), flag("fork"));
+    Ok(map)
+}
+
+/// Compare two commits of a repository, e.g. the `before` and `after` of a push
+///
+/// Returns a map with the `status` of `head` relative to `base` (e.g. `"ahead"`), `ahead_by`,
+/// `behind_by`, a `url` to the comparison, the `commits` between them, oldest first, as maps of
+/// `sha`, `short_sha`, `author` and the first line of the `message`, and the changed `files`,
+/// as maps of `filename`, `status`, `additions` and `deletions`. GitHub lists at most 250
+/// commits and 300 files.
+///
+/// # Arguments
+///
+/// * `client` - A reqwest HTTP "client" to make the request
+/// * `token` - The github token to authenticate with
+/// * `repo` - The full name of the repository, `<owner>/<repo>`
+/// * `base` - The commit, branch or tag to compare from
+/// * `head` - The commit, branch or tag to compare to
+fn compare(
+    client: &Client,
+    token: &str,
+    repo: &str,
+    base: &str,
+    head: &str,
+) -> Result<Map, String> {
+    check_repo(repo)?;
+    check_ref(base)?;
+    check_ref(head)?;
+
+    let path = format!("/repos/{}/compare/{}...{}", repo, base, head);
+    let resp = rest_get(client, token, &path, &[])?;
+
+    let commits = resp["commits"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .map(|c| {
+            let sha = c["sha"].as_str().unwrap_or_default().to_string();
+            let message = c["commit"]["message"].as_str().unwrap_or_default();
+            let author = c["author"]["login"]
+                .as_str()
+                .or_else(|| c["commit"]["author"]["name"].as_str())
+                .unwrap_or_default();
+
+            let mut map = Map::new();
+            map.insert(
+                "short_sha".into(),
+                Dynamic::from(sha.chars().take(7).collect::<String>()),
+            );
+            map.insert("sha".into(), Dynamic::from(sha));
+            map.insert(
+                "message".into(),
+                Dynamic::from(message.lines().next().unwrap_or_default().to_string()),
+            );
+            map.insert("author".into(), Dynamic::from(author.to_string()));
+            Dynamic::from(map)
+        })
+        .collect::<Array>();
+
+    let files = resp["files"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .map(|f| {
+            let mut map = Map::new();
+            map.insert(
+                "filename".into(),
+                Dynamic::from(f["filename"].as_str().unwrap_or_default().to_string()),
+            );
+            map.insert(
+                "status".into(),
+                Dynamic::from(f["status"].as_str().unwrap_or_default().to_string()),
+            );
+            map.insert(
+                "additions".into(),
+                Dynamic::from(f["additions"].as_i64().unwrap_or_default()),
+            );
+            map.insert(
+                "deletions".into(),
+                Dynamic::from(f["deletions"].as_i64().unwrap_or_default()),
+            );
+            Dynamic::from(map)
+        })
+        .collect::<Array>();
+
+    let mut map = Map::new();
+    map.insert(
+        "status".into(),
+        Dynamic::from(resp["status"].as_str().unwrap_or_default().to_string()),
+    );
+    map.insert(
+        "ahead_by".into(),
+        Dynamic::from(resp["ahead_by"].as_i64().unwrap_or_default()),
+    );
+    map.insert(
+        "behind_by".into(),
+        Dynamic::from(resp["behind_by"].as_i64().unwrap_or_default()),
+    );
+    map.insert(
+        "url".into(),
+        Dynamic::from(resp["html_url"].as_str().unwrap_or_default().to_string()),
+    );
+    map.insert("commits".into(), Dynamic::from(commits));
+    map.insert("files".into(), Dynamic::from(files));
+    Ok(map)
+}
+
+/// List the logins of the members of an organization, up to `MAX_ORG_MEMBERS` of them
+///
+/// Which members are visible depends on the token: without access to the organization, only
+/// its public members are listed.
+///
+/// # Arguments
+///
+/// * `client` - A reqwest HTTP "client" to make the requests
+/// * `token` - The github token to authenticate with
+/// * `org` - The login of the organization
+fn org_members(client: &Client, token: &str, org: &str) -> Result<Vec<String>, String> {
+    if org.is_empty() || org.contains('/') {
+        return Err(format!("Invalid organization {}", org));
+    }
+
+    let mut members = Vec::new();
+    for page in 1.. {
+        let query = [("per_page", "100".to_string()), ("page", page.to_string())];
+        let resp = rest_get(client, token, &format!("/orgs/{}/members", org), &query)?;
+        let logins = resp
+            .as_array()
+            .map(|list| {
+                list.iter()
+                    .filter_map(|m| m["login"].as_str().map(String::from))
+                    .collect::<Vec<String>>()
+            })
+            .unwrap_or_default();
+
+        let last_page = logins.len() < 100;
+        members.extend(logins);
+        if last_page || members.len() >= MAX_ORG_MEMBERS {
+            break;
+        }
+    }
+
+    members.truncate(MAX_ORG_MEMBERS);
+    Ok(members)
+}
+
+/// Find the node ids of a repository and one of its discussion categories, which is what
+/// `createDiscussion` wants rather than their names
+///
+/// # Arguments
+///
+/// * `client` - A reqwest HTTP "client" to make the request
+/// * `token` - The github token to authenticate with
+/// * `repo` - The full name of the repository, `<owner>/<repo>`
+/// * `category` - The name of the category, case insensitive
+fn discussion_ids(
+    client: &Client,
+    token: &str,
+    repo: &str,
+    category: &str,
+) -> Result<(String, String), String> {
+    let mut parts = repo.splitn(2, '/');
+    let (owner, name) = match (parts.next(), parts.next()) {
+        (Some(owner), Some(name)) => (owner, name),
+        _ => return Err(format!("Expected <owner>/<repo>, not {}", repo)),
+    };
+
+    let query = "query($owner: String!, $name: String!) {
+        repository(owner: $owner, name: $name) {
+            id
+            discussionCategories(first: 100) { nodes { id name } }
+        }
+    }";
+    let data = graphql(
+        client,
+        token,
+        query,
+        json!({ "owner": owner, "name": name }),
+    )?;
+    let repository = &data["repository"];
+
+    let repo_id = repository["id"]
+        .as_str()
+        .ok_or_else(|| format!("Unknown repository {}", repo))?;
+    let category_id = repository["discussionCategories"]["nodes"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .find(|c| {
+            c["name"]
+                .as_str()
+                .map(|n| n.eq_ignore_ascii_case(category))
+                .unwrap_or(false)
+        })
+        .and_then(|c| c["id"].as_str())
+        .ok_or_else(|| format!("{} has no discussion category {}", repo, category))?;
+
+    Ok((repo_id.to_string(), category_id.to_string()))
+}
+
+/// Work out who owns the files a pull request changes, from the CODEOWNERS file of the branch it
+/// is going into, the way GitHub does when it requests reviews itself
+///
+/// Returns the owners as they are written in CODEOWNERS, e.g. `@login` or `@org/team`, in the
+/// order they were found. The author of the pull request is left out, as they can't review it.
+///
+/// # Arguments
+///
+/// * `client` - A reqwest HTTP "client" to make the requests
+/// * `token` - The github token to authenticate with
+/// * `repo` - The full name of the repository, `<owner>/<repo>`
+/// * `number` - The number of the pull request
+fn code_owners(
+    client: &Client,
+    token: &str,
+    repo: &str,
+    number: INT,
+) -> Result<Vec<String>, String> {
+    check_repo(repo)?;
+
+    let pull_path = format!("/repos/{}/pulls/{}", repo, number);
+    let pull = rest_get(client, token, &pull_path, &[])?;
+    let base = pull["base"]["ref"].as_str().unwrap_or("HEAD");
+    let author = format!("@{}", pull["user"]["login"].as_str().unwrap_or_default());
+
+    let mut rules = Vec::new();
+    for location in &codeowners::LOCATIONS {
+        if let Some(text) = fetch_file(client, token, repo, location, base)? {
+            rules = codeowners::parse(&text);
+            break;
+        }
+    }
+    if rules.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let mut owners: Vec<String> = Vec::new();
+    for path in pull_files(client, token, repo, number)? {
+        for owner in codeowners::owners_of(&rules, &path) {
+            if !owners.contains(owner) && !owner.eq_ignore_ascii_case(&author) {
+                owners.push(owner.clone());
+            }
+        }
+    }
+
+    Ok(owners)
+}
+
+/// List the paths of the files a pull request changes
+///
+/// # Arguments
+///
+/// * `client` - A reqwest HTTP "client" to make the requests
+/// * `token` - The github token to authenticate with
+/// * `repo` - The full name of the repository, `<owner>/<repo>`
+/// * `number` - The number of the pull request
+fn pull_files(
+    client: &Client,
+    token: &str,
+    repo: &str,
+    number: INT,
+) -> Result<Vec<String>, String> {
+    check_repo(repo)?;
+
+    let path = format!("/repos/{}/pulls/{}/files", repo, number);
+    let mut paths = Vec::new();
+    for page in 1..=MAX_PULL_FILE_PAGES {
+        let query = [("per_page", "100".to_string()), ("page", page.to_string())];
+        let resp = rest_get(client, token, &path, &query)?;
+        let files = resp.as_array().cloned().unwrap_or_default();
+
+        paths.extend(
+            files
+                .iter()
+                .filter_map(|f| f["filename"].as_str().map(String::from)),
+        );
+
+        if files.len() < 100 {
+            break;
+        }
+    }
+
+    Ok(paths)
+}
+
+/// The paths a push or pull request event changes, or None for any other event
+///
+/// Pushes list their changes commit by commit, but GitHub only includes the first 20 commits.
+/// Pull requests don't list them at all, so they are fetched.
+///
+/// # Arguments
+///
+/// * `client` - A reqwest HTTP "client" to make the requests
+/// * `token` - The github token to authenticate with
+/// * `event` - The kind of event
+/// * `payload` - The payload of the delivery
+fn changed_paths(
+    client: &Client,
+    token: &str,
+    event: &str,
+    payload: &Value,
+) -> Option<Vec<String>> {
+    let mut paths = match event {
+        "push" => payload["commits"]
+            .as_array()?
+            .iter()
+            .flat_map(|c| {
+                ["added", "removed", "modified"]
+                    .iter()
+                    .flat_map(move |kind| c[*kind].as_array().cloned().unwrap_or_default())
+            })
+            .filter_map(|p| p.as_str().map(String::from))
+            .collect::<Vec<String>>(),
+        "pull_request" => {
+            let repo = payload["repository"]["full_name"].as_str()?;
+            let number = payload["number"].as_i64()?;
+            match pull_files(client, token, repo, number as INT) {
+                Ok(paths) => paths,
+                Err(e) => {
+                    log_event!("github.changed_paths_error", repo = repo, error = e);
+                    return None;
+                }
+            }
+        }
+        _ => return None,
+    };
+
+    paths.sort();
+    paths.dedup();
+    Some(paths)
+}
+
+/// The handlers an event is fanned out to by `GITHUB_PATH_ROUTES`, each with the changed paths
+/// which matched its pattern
+///
+/// # Arguments
+///
+/// * `env` - Environment variables, for the routes
+/// * `client` - A reqwest HTTP "client" to fetch the changes of pull requests
+/// * `event` - The kind of event
+/// * `payload` - The payload of the delivery
+fn path_routes(
+    env: &EnvInfo,
+    client: &Client,
+    event: &str,
+    payload: &Value,
+) -> Vec<(String, Vec<String>)> {
+    let repo = payload["repository"]["full_name"]
+        .as_str()
+        .unwrap_or_default()
+        .to_lowercase();
+    let routes = env
+        .github_path_routes
+        .iter()
+        .filter(|r| r.repo == repo)
+        .collect::<Vec<&PathRoute>>();
+    if routes.is_empty() {
+        return Vec::new();
+    }
+
+    let paths = match changed_paths(client, &env.github_token, event, payload) {
+        Some(paths) => paths,
+        None => return Vec::new(),
+    };
+
+    let mut targets: Vec<(String, Vec<String>)> = Vec::new();
+    for route in routes {
+        let matched = paths
+            .iter()
+            .filter(|p| codeowners::matches(&route.pattern, p))
+            .cloned()
+            .collect::<Vec<String>>();
+        if matched.is_empty() {
+            continue;
+        }
+
+        match targets.iter_mut().find(|(uri, _)| *uri == route.handler) {
+            Some((_, existing)) => {
+                existing.extend(matched);
+                existing.sort();
+                existing.dedup();
+            }
+            None => targets.push((route.handler.clone(), matched)),
+        }
+    }
+    targets
+}
+
+/// Ask people and teams to review a pull request
+///
+/// # Arguments
+///
+/// * `client` - A reqwest HTTP "client" to make the request
+/// * `token` - The github token to authenticate with
+/// * `repo` - The full name of the repository, `<owner>/<repo>`
+/// * `number` - The number of the pull request
+/// * `reviewers` - Logins, and teams as `org/team`. A leading `@` is fine, so the owners from
+///   CODEOWNERS can be passed as they are. Email addresses can't be asked, and are skipped
+fn request_review(
+    client: &Client,
+    token: &str,
+    repo: &str,
+    number: INT,
+    reviewers: &[String],
+) -> Result<(), String> {
+    check_repo(repo)?;
+
+    let mut users = Vec::new();
+    let mut teams = Vec::new();
+    for reviewer in reviewers {
+        let reviewer = reviewer.trim().trim_start_matches('@');
+        if reviewer.is_empty() || reviewer.contains('@') {
+            continue;
+        }
+        match reviewer.splitn(2, '/').nth(1) {
+            Some(team) => teams.push(team.to_string()),
+            None => users.push(reviewer.to_string()),
+        }
+    }
+
+    if users.is_empty() && teams.is_empty() {
+        return Ok(());
+    }
+
+    let path = format!("/repos/{}/pulls/{}/requested_reviewers", repo, number);
+    let body = json!({ "reviewers": users, "team_reviewers": teams });
+    rest_post(client, token, &path, &body).map(|_| ())
+}
+
+/// Register the GitHub functions available to clients
+///
+/// * `github_repo_info(repo)` looks up a repository, returning a map with its `name`,
+///   `full_name`, `description`, `default_branch`, `url`, and whether it is `private`,
+///   `archived` or a `fork`. Fails if the repository doesn't exist or can't be seen
+/// * `github_org_members(org)` lists the logins of the members of an organization
+/// * `github_compare(repo, base, head)` lists the commits and changed files between two
+///   commits, see `compare`
+/// * `github_search_issues(query)` searches issues and pull requests, e.g.
+///   `"repo:octo/widgets is:issue is:open label:bug"`, returning up to 100 maps as described by
+///   `issue_map`
+/// * `github_code_owners(repo, number)` lists the owners of the files a pull request changes,
+///   according to CODEOWNERS, see `code_owners`
+/// * `github_request_review(repo, number, reviewers)` asks people and teams to review a pull
+///   request, see `request_review`
+/// * `github_discussion_create(repo, category, title, body)` starts a discussion and returns a
+///   map with its `id`, `number` and `url`
+/// * `github_project_add_item(project, content_id)` adds an issue, pull request or draft to a
+///   project, given their node ids, and returns the id of the new project item
+/// * `github_issue_comment(repo, number, body)` comments on an issue or pull request, and
+///   returns the comment, with its `url`, `id` and `body`
+/// * `github_issue_close(repo, number)` closes an issue or pull request
+/// * `github_add_labels(repo, number, labels)` adds labels to an issue or pull request. Labels
+///   which don't exist yet are created
+///
+/// # Arguments
+///
+/// * `module` - The module to register the functions in
+/// * `env` - Environment variables
+/// * `services` - The shared http client
+/// * `id` - The correlation id to attach to every log line
+/// * `handler_addr` - The uri of the handler the functions are for
+pub fn register(
+    module: &mut Module,
+    env: &EnvInfo,
+    services: &Services,
+    id: &CorrelationId,
+    handler_addr: &str,
+) {
+    let client = services.http.clone();
+    let github_token = env.github_token.clone();
+    let addr = handler_addr.to_string();
+    let cid = id.clone();
+    let github_discussion_create = move |repo: ImmutableString,
+                                         category: ImmutableString,
+                                         title: ImmutableString,
+                                         body: ImmutableString|
+          -> Result<Map, Box<EvalAltResult>> {
+        log_event!(
+            "github.discussion_create",
+            id = cid.0,
+            handler = addr,
+            repo = repo,
+            title = title,
+        );
+
+        let (repo_id, category_id) = discussion_ids(&client, &github_token, &repo, &category)?;
+        let mutation = "mutation($repo: ID!, $category: ID!, $title: String!, $body: String!) {
+            createDiscussion(input: {
+                repositoryId: $repo, categoryId: $category, title: $title, body: $body
+            }) {
+                discussion { id number url }
+            }
+        }";
+        let variables = json!({
+            "repo": repo_id,
+            "category": category_id,
+            "title": title.as_str(),
+            "body": body.as_str(),
+        });
+        let data = graphql(&client, &github_token, mutation, variables)?;
+
+        let discussion = &data["createDiscussion"]["discussion"];
+        let mut map = Map::new();
+        map.insert(
+            "id".into(),
+            Dynamic::from(discussion["id"].as_str().unwrap_or_default().to_string()),
+        );
+        map.insert(
+            "number".into(),
+            Dynamic::from(discussion["number"].as_i64().unwrap_or_default()),
+        );
+        map.insert(
+            "url".into(),
+            Dynamic::from(discussion["url"].as_str().unwrap_or_default().to_string()),
+        );
+        Ok(map)
+    };
+
+    let client = services.http.clone();
+    let github_token = env.github_token.clone();
+    let addr = handler_addr.to_string();
+    let cid = id.clone();
+    let github_project_add_item = move |project: ImmutableString,
+                                        content_id: ImmutableString|
+          -> Result<String, Box<EvalAltResult>> {
+        log_event!(
+            "github.project_add_item",
+            id = cid.0,
+            handler = addr,
+            project = project,
+            content = content_id,
+        );
+
+        let mutation = "mutation($project: ID!, $content: ID!) {
+            addProjectV2ItemById(input: { projectId: $project, contentId: $content }) {
+                item { id }
+            }
+        }";
+        let variables = json!({ "project": project.as_str(), "content": content_id.as_str() });
+        let data = graphql(&client, &github_token, mutation, variables)?;
+
+        Ok(data["addProjectV2ItemById"]["item"]["id"]
+            .as_str()
+            .unwrap_or_default()
+            .to_string())
+    };
+
+    let client = services.http.clone();
+    let github_token = env.github_token.clone();
+    let addr = handler_addr.to_string();
+    let cid = id.clone();
+    let github_repo_info = move |repo: ImmutableString| -> Result<Map, Box<EvalAltResult>> {
+        log_event!("github.repo_info", id = cid.0, handler = addr, repo = repo);
+        repo_info(&client, &github_token, &repo).map_err(Into::into)
+    };
+
+    let client = services.http.clone();
+    let github_token = env.github_token.clone();
+    let addr = handler_addr.to_string();
+    let cid = id.clone();
+    let github_org_members = move |org: ImmutableString| -> Result<Array, Box<EvalAltResult>> {
+        log_event!("github.org_members", id = cid.0, handler = addr, org = org);
+        let members = org_members(&client, &github_token, &org)?;
+        Ok(members.into_iter().map(Dynamic::from).collect())
+    };
+
+    let client = services.http.clone();
+    let github_token = env.github_token.clone();
+    let addr = handler_addr.to_string();
+    let cid = id.clone();
+    let github_compare = move |repo: ImmutableString,
+                               base: ImmutableString,
+                               head: ImmutableString|
+          -> Result<Map, Box<EvalAltResult>> {
+        log_event!(
+            "github.compare",
+            id = cid.0,
+            handler = addr,
+            repo = repo,
+            base = base,
+            head = head,
+        );
+        compare(&client, &github_token, &repo, &base, &head).map_err(Into::into)
+    };
+
+    let client = services.http.clone();
+    let github_token = env.github_token.clone();
+    let addr = handler_addr.to_string();
+    let cid = id.clone();
+    let github_code_owners =
+        move |repo: ImmutableString, number: INT| -> Result<Array, Box<EvalAltResult>> {
+            log_event!(
+                "github.code_owners",
+                id = cid.0,
+                handler = addr,
+                repo = repo,
+                number = number,
+            );
+            let owners = code_owners(&client, &github_token, &repo, number)?;
+            Ok(owners.into_iter().map(Dynamic::from).collect())
+        };
+
+    let client = services.http.clone();
+    let github_token = env.github_token.clone();
+    let addr = handler_addr.to_string();
+    let cid = id.clone();
+    let github_request_review = move |repo: ImmutableString,
+                                      number: INT,
+                                      reviewers: Array|
+          -> Result<(), Box<EvalAltResult>> {
+        let reviewers = reviewers
+            .into_iter()
+            .map(|r| r.to_string())
+            .collect::<Vec<String>>();
+        log_event!(
+            "github.request_review",
+            id = cid.0,
+            handler = addr,
+            repo = repo,
+            number = number,
+            reviewers = reviewers.join(","),
+        );
+        request_review(&client, &github_token, &repo, number, &reviewers).map_err(Into::into)
+    };
+
+    let client = services.http.clone();
+    let github_token = env.github_token.clone();
+    let addr = handler_addr.to_string();
+    let cid = id.clone();
+    let github_search_issues = move |query: ImmutableString| -> Result<Array, Box<EvalAltResult>> {
+        log_event!(
+            "github.search_issues",
+            id = cid.0,
+            handler = addr,
+            query = query
+        );
+        let issues = search_issues(&client, &github_token, &query)?;
+        Ok(issues
+            .iter()
+            .map(|issue| Dynamic::from(issue_map(issue)))
+            .collect())
+    };
+
+    let client = services.http.clone();
+    let github_token = env.github_token.clone();
+    let addr = handler_addr.to_string();
+    let cid = id.clone();
+    let github_issue_comment = move |repo: ImmutableString,
+                                     number: INT,
+                                     body: ImmutableString|
+          -> Result<GithubIssueCommentResponse, Box<EvalAltResult>> {
+        log_event!(
+            "github.issue_comment",
+            id = cid.0,
+            handler = addr,
+            repo = repo,
+            number = number,
+        );
+        comment_issue(&client, &github_token, &repo, number, &body).map_err(Into::into)
+    };
+
+    let client = services.http.clone();
+    let github_token = env.github_token.clone();
+    let addr = handler_addr.to_string();
+    let cid = id.clone();
+    let github_issue_close =
+        move |repo: ImmutableString, number: INT| -> Result<(), Box<EvalAltResult>> {
+            log_event!(
+                "github.issue_close",
+                id = cid.0,
+                handler = addr,
+                repo = repo,
+                number = number,
+            );
+            close_issue(&client, &github_token, &repo, number).map_err(Into::into)
+        };
+
+    let client = services.http.clone();
+    let github_token = env.github_token.clone();
+    let addr = handler_addr.to_string();
+    let cid = id.clone();
+    let github_add_labels = move |repo: ImmutableString,
+                                  number: INT,
+                                  labels: Array|
+          -> Result<(), Box<EvalAltResult>> {
+        let labels = labels
+            .into_iter()
+            .map(|l| l.to_string())
+            .collect::<Vec<String>>();
+        log_event!(
+            "github.add_labels",
+            id = cid.0,
+            handler = addr,
+            repo = repo,
+            number = number,
+            labels = labels.join(","),
+        );
+        add_labels(&client, &github_token, &repo, number, &labels).map_err(Into::into)
+    };
+
+    module.set_fn_1("github_repo_info", github_repo_info);
+    module.set_fn_1("github_search_issues", github_search_issues);
+    module.set_fn_2("github_code_owners", github_code_owners);
+    module.set_fn_3("github_request_review", github_request_review);
+    module.set_fn_3("github_compare", github_compare);
+    module.set_fn_1("github_org_members", github_org_members);
+    module.set_fn_4("github_discussion_create", github_discussion_create);
+    module.set_fn_2("github_project_add_item", github_project_add_item);
+    module.set_fn_3("github_issue_comment", github_issue_comment);
+    module.set_fn_2("github_issue_close", github_issue_close);
+    module.set_fn_3("github_add_labels", github_add_labels);
+}
+
+/// Rocket Endpoint which receives GitHub webhook deliveries, and passes them on to handlers
+///
+/// Deliveries are routed to `github-<owner>-<repo>-<event>`, e.g. `github-octo-widgets-push`,
+/// which is called as `handle(payload)` with the payload as a json string, or as
+/// `handle(payload, event)` with the payload parsed into a map, if it defines that. Deliveries
+/// nobody handles are acknowledged and dropped.
+///
+/// Security alerts from every repository go to the single `github-security` handler instead,
+/// with the payload normalized as described by `advisories::normalize`.
+///
+/// Pushes and pull requests are also fanned out by the paths they change, according to
+/// `GITHUB_PATH_ROUTES`, so each team of a monorepo can have a handler for its part of it. Those
+/// handlers get the same payload, and a map with `changed_paths` added, listing the paths
+/// which matched. The response is that of the repository's own handler, if there is one.
+///
+/// # Arguments
+///
+/// * `id` - The correlation id of the request, attached to every log line
+/// * `env` - Environment variables
+/// * `services` - The stateful subsystems available to handlers
+/// * `handlers` - A reference to the collection of User created handlers, indexed by their uris
+/// * `event` - The kind of event
+/// * `body` - The payload of the delivery. Its signature has been checked
+#[post("/github_webhook", data = "<body>")]
+pub fn github_webhook(
+    id: CorrelationId,
+    env: State<EnvInfo>,
+    services: State<Services>,
+    handlers: Collection<String, Handler>,
+    event: GithubEvent,
+    body: GithubBody,
+) -> Json<UserResponse> {
+    let payload: Value = match serde_json::from_str(&body.0) {
+        Ok(payload) => payload,
+        Err(e) => return Json(UserResponse::failure(format!("Invalid payload: {}", e))),
+    };
+
+    // Sent when the webhook is set up, nobody needs to hear about it
+    if event.0 == "ping" {
+        return Json(UserResponse::success());
+    }
+
+    // Subscribers hear about the delivery once its own handlers have run
+    let envelope = Envelope::github(&event.0, &payload);
+    let response = deliver(&id, &env, &services, &handlers, &event.0, body.0, payload);
+    envelope::fan_in(&env, &services, &id, envelope);
+    response
+}
+
+/// Pass a delivery on to the handler of its repository, and to those of the paths it changes,
+/// see `github_webhook`. Returns the response of the repository's own handler
+///
+/// # Arguments
+///
+/// * `id` - The correlation id of the request, attached to every log line
+/// * `env` - Environment variables
+/// * `services` - The stateful subsystems available to handlers
+/// * `handlers` - The User created handlers, indexed by their uris
+/// * `event` - The kind of event
+/// * `body` - The payload of the delivery, as it was sent
+/// * `payload` - The payload, parsed
+fn deliver(
+    id: &CorrelationId,
+    env: &EnvInfo,
+    services: &Services,
+    handlers: &RwLock<HashMap<String, Handler>>,
+    event: &str,
+    body: String,
+    payload: Value,
+) -> Json<UserResponse> {
+    let (addr, data, payload) = match advisories::normalize(event, &payload) {
+        Some(alert) => (advisories::HANDLER.to_string(), alert.to_string(), alert),
+        None => match payload["repository"]["full_name"].as_str() {
+            Some(repo) => (handler_uri(repo, event), body, payload),
+            None => return Json(UserResponse::failure("Event has no repository".into())),
+        },
+    };
+
+    let context = to_dynamic(&payload)
+        .ok()
+        .and_then(|d| d.try_cast::<Map>())
+        .unwrap_or_default();
+
+    let routed = path_routes(env, &services.http, event, &payload);
+
+    let guard = handlers.read().unwrap();
+    let map = guard.deref();
+
+    for (uri, paths) in routed {
+        let handler = match map.get(&uri) {
+            Some(handler) => handler,
+            None => {
+                log_event!("github.unhandled", id = id.0, handler = uri);
+                continue;
+            }
+        };
+
+        let mut context = context.clone();
+        let paths = paths.into_iter().map(Dynamic::from).collect::<Array>();
+        context.insert("changed_paths".into(), Dynamic::from(paths));
+
+        let result = run_handler(
+            env,
+            services,
+            id,
+            &uri,
+            handler,
+            data.clone(),
+            Some(context),
+        );
+        if let Err(e) = result {
+            log_event!("github.handler_error", id = id.0, handler = uri, error = e);
+        }
+    }
+
+    let handler = match map.get(&addr) {
+        Some(handler) => handler,
+        None => {
+            log_event!("github.unhandled", id = id.0, handler = addr);
+            return Json(UserResponse::success());
+        }
+    };
+
+    match run_handler(env, services, id, &addr, handler, data, Some(context)) {
+        Ok(res) => Json(UserResponse::success_with_data(res)),
+        Err(e) => {
+            log_event!("github.handler_error", id = id.0, handler = addr, error = e);
+            Json(UserResponse::failure("Error running client code!".into()))
+        }
+    }
+}
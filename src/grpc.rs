@@ -0,0 +1,181 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, RwLock};
+use std::thread;
+
+use tokio::runtime::Runtime;
+use tokio::sync::mpsc;
+use tokio::task;
+
+use tonic::transport::Server;
+use tonic::{Request, Response, Status, Streaming};
+
+use crate::envelope;
+use crate::logging::CorrelationId;
+use crate::ratelimit::{RateLimits, RetryAfter, Throttle};
+use crate::responses::Reply;
+use crate::server::{dispatch, webhook_envelope};
+use crate::services::Services;
+use crate::types::{EnvInfo, Handler};
+
+/// The service generated from `proto/majordomo.proto`
+pub mod proto {
+    tonic::include_proto!("majordomo");
+}
+
+use proto::handlers_server::{Handlers, HandlersServer};
+use proto::{InvokeRequest, InvokeResponse};
+
+/// How many answers of a stream may wait for the client to read them, before handlers stop
+/// being called for it
+const STREAM_BUFFER: usize = 64;
+
+/// The gRPC service which calls handlers, like `/h/<handler>` does
+///
+/// Calls are held to the same rate limits as http requests, but are counted separately.
+#[derive(Clone)]
+pub struct Invoker {
+    env: Arc<EnvInfo>,
+    services: Services,
+    handlers: Arc<RwLock<HashMap<String, Handler>>>,
+    limits: Arc<RateLimits>,
+}
+
+impl Invoker {
+    /// Call a handler, blocking until it has run
+    ///
+    /// # Arguments
+    ///
+    /// * `request` - Which handler to call, and with what
+    fn run(&self, request: InvokeRequest) -> InvokeResponse {
+        let id = match request.request_id.as_str() {
+            id if !id.is_empty() && id.len() <= 64 => CorrelationId(id.into()),
+            _ => CorrelationId::generate(),
+        };
+        log_event!("grpc.invoke", id = id.0, handler = request.handler);
+
+        // Like over http, requests to handlers which don't exist aren't passed on to subscribers
+        let envelope = webhook_envelope(&self.handlers, &request.handler, &request.payload);
+
+        let retry_after = RetryAfter::default();
+        let throttle = Throttle::new(&self.limits, &retry_after);
+        let reply = dispatch(
+            &id,
+            &self.env,
+            &self.services,
+            &self.handlers,
+            &throttle,
+            request.handler,
+            request.payload,
+            None,
+        );
+        if let Some(envelope) = envelope {
+            envelope::fan_in(&self.env, &self.services, &id, envelope);
+        }
+
+        let retry_after = retry_after.seconds();
+        match reply {
+            Reply::Wrapped(json) => InvokeResponse {
+                status: json.0.status,
+                data: json.0.data.unwrap_or_default(),
+                http_status: if retry_after > 0 { 429 } else { 200 },
+                content_type: String::new(),
+                request_id: id.0,
+                retry_after,
+            },
+            Reply::Custom(custom) => InvokeResponse {
+                status: custom.status.code < 400,
+                data: custom.body,
+                http_status: u32::from(custom.status.code),
+                content_type: custom.content_type.to_string(),
+                request_id: id.0,
+                retry_after,
+            },
+        }
+    }
+
+    /// Call a handler on a thread which may block, since handlers do
+    async fn run_blocking(&self, request: InvokeRequest) -> Result<InvokeResponse, Status> {
+        let invoker = self.clone();
+        task::spawn_blocking(move || invoker.run(request))
+            .await
+            .map_err(|e| Status::internal(e.to_string()))
+    }
+}
+
+#[tonic::async_trait]
+impl Handlers for Invoker {
+    async fn invoke(
+        &self,
+        request: Request<InvokeRequest>,
+    ) -> Result<Response<InvokeResponse>, Status> {
+        let response = self.run_blocking(request.into_inner()).await?;
+        Ok(Response::new(response))
+    }
+
+    type InvokeStreamStream = mpsc::Receiver<Result<InvokeResponse, Status>>;
+
+    async fn invoke_stream(
+        &self,
+        request: Request<Streaming<InvokeRequest>>,
+    ) -> Result<Response<Self::InvokeStreamStream>, Status> {
+        let mut requests = request.into_inner();
+        let (mut answers, stream) = mpsc::channel(STREAM_BUFFER);
+        let invoker = self.clone();
+        tokio::spawn(async move {
+            loop {
+                let answer = match requests.message().await {
+                    Ok(Some(request)) => invoker.run_blocking(request).await,
+                    Ok(None) => break,
+                    Err(status) => Err(status),
+                };
+                // Stop once the stream fails, or the client stops reading
+                let failed = answer.is_err();
+                if answers.send(answer).await.is_err() || failed {
+                    break;
+                }
+            }
+        });
+        Ok(Response::new(stream))
+    }
+}
+
+/// Serve the gRPC service in the background, on its own port
+///
+/// # Arguments
+///
+/// * `port` - The port to listen on
+/// * `env` - Environment variables
+/// * `services` - The stateful subsystems available to handlers
+/// * `handlers` - The User created handlers, indexed by their uris
+pub fn start(
+    port: u16,
+    env: EnvInfo,
+    services: Services,
+    handlers: Arc<RwLock<HashMap<String, Handler>>>,
+) {
+    let invoker = Invoker {
+        env: Arc::new(env),
+        services,
+        handlers,
+        limits: Arc::new(RateLimits::default()),
+    };
+    let addr = SocketAddr::from(([0, 0, 0, 0], port));
+
+    thread::spawn(move || {
+        let mut runtime = match Runtime::new() {
+            Ok(runtime) => runtime,
+            Err(e) => {
+                println!("Warning! Unable to start the gRPC server: {}", e);
+                return;
+            }
+        };
+        println!("Serving gRPC on {}", addr);
+        let server = Server::builder()
+            .add_service(HandlersServer::new(invoker))
+            .serve(addr);
+        if let Err(e) = runtime.block_on(server) {
+            println!("Warning! The gRPC server stopped: {}", e);
+        }
+    });
+}
@@ -0,0 +1,140 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+
+use rocket::State;
+
+use rocket_contrib::json::Json;
+
+use serde::Serialize;
+
+use crate::auth::{check_auth, check_scope, hash_key, AuthHeader};
+use crate::clock::unix_now;
+use crate::server::Collection;
+use crate::services::Services;
+use crate::types::{
+    ApiKeyInfo, FailureKind, Handler, HandlerLogsRequest, UserResponse, READ_SCOPE,
+};
+
+/// How many entries are kept for each handler. Older ones are dropped
+pub const MAX_ENTRIES: usize = 200;
+
+/// How many entries `/handler_logs` returns, unless asked for fewer
+const DEFAULT_LIMIT: usize = 50;
+
+/// Messages longer than this many characters are cut short
+const MAX_MESSAGE_CHARS: usize = 2000;
+
+/// Something a handler did, as shown to its owner
+#[derive(Debug, Clone, Serialize)]
+pub struct LogEntry {
+    /// When it happened, as a unix timestamp
+    pub at: u64,
+    /// The correlation id of the request the handler was running for
+    pub id: String,
+    /// `debug` for `debug_println`, `invocation` for a successful run, `error` for a failed one
+    pub kind: String,
+    pub message: String,
+}
+
+/// The recent log entries of every handler, kept in memory only
+///
+/// Each handler gets its own ring buffer of `MAX_ENTRIES`, so a noisy handler can't push out
+/// the entries of the others.
+#[derive(Default)]
+pub struct HandlerLogs(Mutex<HashMap<String, VecDeque<LogEntry>>>);
+
+impl HandlerLogs {
+    /// Add an entry to a handler's log
+    ///
+    /// # Arguments
+    ///
+    /// * `handler` - The uri of the handler
+    /// * `id` - The correlation id of the request the handler is running for
+    /// * `kind` - What sort of entry this is, see `LogEntry`
+    /// * `message` - What happened
+    pub fn record(&self, handler: &str, id: &str, kind: &str, message: String) {
+        let message = if message.chars().count() > MAX_MESSAGE_CHARS {
+            message.chars().take(MAX_MESSAGE_CHARS).collect::<String>() + "..."
+        } else {
+            message
+        };
+
+        let mut logs = self.0.lock().unwrap();
+        let entries = logs.entry(handler.to_string()).or_default();
+        if entries.len() >= MAX_ENTRIES {
+            entries.pop_front();
+        }
+        entries.push_back(LogEntry {
+            at: unix_now(),
+            id: id.to_string(),
+            kind: kind.to_string(),
+            message,
+        });
+    }
+
+    /// The most recent entries of a handler's log, oldest first
+    ///
+    /// # Arguments
+    ///
+    /// * `handler` - The uri of the handler
+    /// * `limit` - How many entries to return at most
+    pub fn recent(&self, handler: &str, limit: usize) -> Vec<LogEntry> {
+        match self.0.lock().unwrap().get(handler) {
+            Some(entries) => {
+                let skip = entries.len().saturating_sub(limit);
+                entries.iter().skip(skip).cloned().collect()
+            }
+            None => Vec::new(),
+        }
+    }
+}
+
+/// Rocket Endpoint which returns the recent log entries of a handler, oldest first
+///
+/// Logs are kept in memory, so they start over whenever the server restarts, and each instance
+/// only has the logs of the invocations it served.
+///
+/// # Arguments
+///
+/// * `auth` - The API Key from the `Authorization` header, if any
+/// * `services` - Where the logs are kept
+/// * `api_keys` - A reference to the collection of Client API keys, used to check for auth
+/// * `handlers` - A reference to the collection of User created handlers, indexed by their uris
+/// * `post_data` - The uri of the handler, which must be owned by the API Key, and optionally
+///                 how many entries to return
+#[post("/handler_logs", data = "<post_data>")]
+pub fn handler_logs(
+    auth: AuthHeader,
+    services: State<Services>,
+    api_keys: Collection<String, ApiKeyInfo>,
+    handlers: Collection<String, Handler>,
+    post_data: Json<HandlerLogsRequest>,
+) -> Json<UserResponse> {
+    let data = post_data.0;
+    let key = auth.key_or(&data.api_key);
+
+    if let Err(cause) = auth.verify(&key, check_auth(&key, &api_keys), "Invalid API Key") {
+        return Json(UserResponse::from(cause));
+    }
+    if let Err(cause) = check_scope(&key, &api_keys, READ_SCOPE) {
+        return Json(UserResponse::from(cause));
+    }
+
+    match handlers.read().unwrap().get(&data.uri) {
+        Some(h) if h.owned_by(&hash_key(&key)) => {
+            let limit = data.limit.unwrap_or(DEFAULT_LIMIT).min(MAX_ENTRIES);
+            Json(
+                UserResponse::success_with_raw(services.logs.recent(&data.uri, limit))
+                    .unwrap_or_else(|| UserResponse::failure("Unable to list logs".into())),
+            )
+        }
+        Some(_) => Json(UserResponse::failure_of(
+            FailureKind::Unauthorized,
+            "Invalid API Key".into(),
+        )),
+        None => Json(UserResponse::failure_of(
+            FailureKind::NotFound,
+            "Unknown handler uri".into(),
+        )),
+    }
+}
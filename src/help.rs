@@ -0,0 +1,121 @@
+use std::collections::{BTreeMap, HashMap};
+
+use rocket_contrib::json::Json;
+
+use crate::server::Collection;
+use crate::slack::{ACTION_PREFIX, EVENT_PREFIX};
+use crate::types::{Handler, UserResponse};
+
+/// Render the list of things Majordomo can do, from the descriptions and tags of its handlers
+///
+/// Channel handlers (`slack-...`) are listed as commands for their channel, slash command
+/// handlers (`slash-...`) as slash commands, and everything else as an endpoint. Handlers are
+/// grouped by their first tag. Those tagged `hidden` are omitted, as are those which handle Slack
+/// events and interactions.
+/// The result is formatted for Slack, but reads fine as plain text.
+///
+/// # Arguments
+///
+/// * `handlers` - The handlers to describe
+pub fn render_help(handlers: &HashMap<String, Handler>) -> String {
+    // BTreeMaps, so that the output is sorted
+    let mut channels: BTreeMap<&str, BTreeMap<&str, &Handler>> = BTreeMap::new();
+    let mut commands: BTreeMap<&str, BTreeMap<&str, &Handler>> = BTreeMap::new();
+    let mut endpoints: BTreeMap<&str, BTreeMap<&str, &Handler>> = BTreeMap::new();
+
+    for (uri, handler) in handlers {
+        // Handlers of Slack events and interactions aren't something to ask for
+        let reactive = uri.starts_with(ACTION_PREFIX) || uri.starts_with(EVENT_PREFIX);
+        if reactive || handler.tags.iter().any(|t| t == "hidden") {
+            continue;
+        }
+
+        let group = handler.tags.first().map(String::as_str).unwrap_or("other");
+        let section = if uri.starts_with("slack-") {
+            &mut channels
+        } else if uri.starts_with("slash-") {
+            &mut commands
+        } else {
+            &mut endpoints
+        };
+        section
+            .entry(group)
+            .or_insert_with(BTreeMap::new)
+            .insert(uri, handler);
+    }
+
+    let mut text = String::from("*Here's what I can do:*\n");
+
+    if !channels.is_empty() {
+        text.push_str("\n*Channel commands* (mention me in the channel)\n");
+        for (group, handlers) in &channels {
+            text.push_str(&format!("_{}_\n", group));
+            for (uri, handler) in handlers {
+                text.push_str(&format!(
+                    "• #{}: {}\n",
+                    &uri["slack-".len()..],
+                    describe(handler)
+                ));
+            }
+        }
+    }
+
+    if !commands.is_empty() {
+        text.push_str("\n*Slash commands* (type them in any channel)\n");
+        for (group, handlers) in &commands {
+            text.push_str(&format!("_{}_\n", group));
+            for (uri, handler) in handlers {
+                text.push_str(&format!(
+                    "• `{}`: {}\n",
+                    &uri["slash-".len()..],
+                    describe(handler)
+                ));
+            }
+        }
+    }
+
+    if !endpoints.is_empty() {
+        text.push_str("\n*Endpoints* (POST to `/h/<name>`)\n");
+        for (group, handlers) in &endpoints {
+            text.push_str(&format!("_{}_\n", group));
+            for (uri, handler) in handlers {
+                text.push_str(&format!("• `{}`: {}\n", uri, describe(handler)));
+            }
+        }
+    }
+
+    if channels.is_empty() && commands.is_empty() && endpoints.is_empty() {
+        text.push_str("Nothing yet! Ask a maintainer to set up a handler.\n");
+    }
+
+    text
+}
+
+fn describe(handler: &Handler) -> &str {
+    handler
+        .description
+        .as_ref()
+        .map(String::as_str)
+        .unwrap_or("(no description)")
+}
+
+/// Rocket Endpoint which lists what Majordomo can do. See `render_help`
+///
+/// This takes precedence over any handler with the uri `help`.
+///
+/// # Arguments
+///
+/// * `handlers` - A reference to the collection of User created handlers, indexed by their uris
+#[get("/h/help")]
+pub fn help_get(handlers: Collection<String, Handler>) -> Json<UserResponse> {
+    Json(UserResponse::success_with_data(render_help(
+        &handlers.read().unwrap(),
+    )))
+}
+
+/// Rocket Endpoint which lists what Majordomo can do, for clients which POST to every handler
+/// See `help_get`
+#[post("/h/help")]
+pub fn help_post(handlers: Collection<String, Handler>) -> Json<UserResponse> {
+    help_get(handlers)
+}
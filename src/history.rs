@@ -0,0 +1,151 @@
+use std::ops::DerefMut;
+
+use rocket::State;
+
+use rocket_contrib::json::Json;
+
+use crate::auth::{check_auth, check_scope, describe_key, hash_key, AuthHeader};
+use crate::changes::check_unprotected;
+use crate::server::{warm_up_handler, Collection};
+use crate::services::Services;
+use crate::storage::Storage;
+use crate::types::{
+    ApiKeyInfo, EnvInfo, FailureKind, FindHandlerRequest, Handler, RollbackHandlerRequest,
+    UserResponse, READ_SCOPE, WRITE_SCOPE,
+};
+
+/// Rocket Endpoint which lists the previous revisions of a handler, most recent first
+///
+/// The position of a revision in the list is the index to pass to `/rollback_handler`.
+///
+/// # Arguments
+///
+/// * `auth` - The API Key from the `Authorization` header, if any
+/// * `api_keys` - A reference to the collection of Client API keys, used to check for auth
+/// * `handlers` - A reference to the collection of User created handlers, indexed by their uris
+/// * `post_data` - The uri of the handler. Must be owned by the API Key
+#[post("/handler_history", data = "<post_data>")]
+pub fn handler_history(
+    auth: AuthHeader,
+    api_keys: Collection<String, ApiKeyInfo>,
+    handlers: Collection<String, Handler>,
+    post_data: Json<FindHandlerRequest>,
+) -> Json<UserResponse> {
+    let data = post_data.0;
+    let key = auth.key_or(&data.api_key);
+
+    if let Err(cause) = auth.verify(&key, check_auth(&key, &api_keys), "Invalid API Key") {
+        return Json(UserResponse::from(cause));
+    }
+    if let Err(cause) = check_scope(&key, &api_keys, READ_SCOPE) {
+        return Json(UserResponse::from(cause));
+    }
+
+    let guard = handlers.read().unwrap();
+    match guard.get(&data.uri) {
+        Some(h) if h.owned_by(&hash_key(&key)) => Json(
+            UserResponse::success_with_raw(&h.history)
+                .unwrap_or_else(|| UserResponse::failure("Unable to list revisions".into())),
+        ),
+        Some(_) => Json(UserResponse::failure_of(
+            FailureKind::Unauthorized,
+            "Invalid API Key".into(),
+        )),
+        None => Json(UserResponse::failure_of(
+            FailureKind::NotFound,
+            "Unknown handler uri".into(),
+        )),
+    }
+}
+
+/// Rocket Endpoint which restores a previous revision of a handler
+///
+/// Only the code is restored; the description, tags, etc. are left as they are. The code being
+/// replaced is added to the history, so the rollback can be undone the same way. Protected
+/// handlers can't be rolled back, their changes must be approved, see `changes`.
+///
+/// # Arguments
+///
+/// * `auth` - The API Key from the `Authorization` header, if any
+/// * `env` - Environment variables
+/// * `storage` - Where the handlers are saved
+/// * `services` - The stateful subsystems available to handlers, for the warm-up
+/// * `api_keys` - A reference to the collection of Client API keys, used to check for auth
+/// * `handlers` - A reference to the collection of User created handlers, indexed by their uris
+/// * `post_data` - The uri of the handler, and which revision to restore
+#[post("/rollback_handler", data = "<post_data>")]
+pub fn rollback_handler(
+    auth: AuthHeader,
+    env: State<EnvInfo>,
+    storage: State<Storage>,
+    services: State<Services>,
+    api_keys: Collection<String, ApiKeyInfo>,
+    handlers: Collection<String, Handler>,
+    post_data: Json<RollbackHandlerRequest>,
+) -> Json<UserResponse> {
+    if env.read_only {
+        return Json(UserResponse::read_only());
+    }
+
+    let data = post_data.0;
+    let key = auth.key_or(&data.api_key);
+
+    if let Err(cause) = auth.verify(&key, check_auth(&key, &api_keys), "Invalid API Key") {
+        return Json(UserResponse::from(cause));
+    }
+    if let Err(cause) = check_scope(&key, &api_keys, WRITE_SCOPE) {
+        return Json(UserResponse::from(cause));
+    }
+
+    let mut guard = handlers.write().unwrap();
+    let map = guard.deref_mut();
+
+    let handler = match map.get_mut(&data.uri) {
+        Some(h) if h.owned_by(&hash_key(&key)) => h,
+        Some(_) => {
+            return Json(UserResponse::failure_of(
+                FailureKind::Unauthorized,
+                "Invalid API Key".into(),
+            ))
+        }
+        None => {
+            return Json(UserResponse::failure_of(
+                FailureKind::NotFound,
+                "Unknown handler uri".into(),
+            ))
+        }
+    };
+
+    if let Err(cause) = check_unprotected(handler) {
+        return Json(UserResponse::failure_of(FailureKind::Conflict, cause));
+    }
+    if let Err(cause) = handler.rollback(data.index) {
+        return Json(UserResponse::failure(cause));
+    }
+
+    if let Err(e) = storage.save_handlers(map, &[data.uri.clone()]) {
+        log_event!("db.save_error", storage = storage.describe(), error = e);
+        return Json(UserResponse::failure_of(
+            FailureKind::Internal,
+            "Server error while saving db".into(),
+        ));
+    }
+
+    log_event!(
+        "audit.rollback",
+        handler = data.uri,
+        index = data.index,
+        key = describe_key(&hash_key(&key), &api_keys),
+    );
+
+    match map.get(&data.uri).filter(|h| h.warmup) {
+        Some(handler) => match warm_up_handler(&env, &services, handler) {
+            Ok(_) => Json(UserResponse::success()),
+            Err(e) => Json(UserResponse::failure(format!(
+                "Handler rolled back, but warm-up failed: {}",
+                e
+            ))),
+        },
+        None => Json(UserResponse::success()),
+    }
+}
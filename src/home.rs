@@ -0,0 +1,304 @@
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::sync::Mutex;
+use std::thread;
+
+use serde_json::{json, Value};
+
+use crate::clock::{unix_now, utc_datetime};
+use crate::logging::CorrelationId;
+use crate::services::Services;
+use crate::slack::{open_dm, slack_api, Interaction};
+use crate::slash::{command_uri, dispatch, run_command, SlashCommand};
+use crate::types::{EnvInfo, Handler, SlackAppHomeOpened};
+
+/// The action id of the buttons of the home tab which run a command
+pub const HOME_RUN_ACTION: &str = "majordomo_home_run";
+
+/// How many of a user's commands are remembered for their home tab
+const MAX_RECENT: usize = 10;
+
+/// How many commands the home tab lists. Slack refuses views of more than 100 blocks
+const MAX_COMMANDS: usize = 60;
+
+/// Slack refuses buttons whose value is longer than this
+const MAX_VALUE_CHARS: usize = 2000;
+
+/// What a user typed is cut short past this many characters, on the home tab
+const MAX_SHOWN_CHARS: usize = 200;
+
+/// A slash command someone ran
+#[derive(Debug, Clone)]
+pub struct RecentCommand {
+    /// The name of the command, see `slash::dispatch`
+    pub name: String,
+    /// What the user typed after the name
+    pub text: String,
+    /// When it ran, as a unix timestamp
+    pub at: u64,
+    /// Whether its handler succeeded
+    pub ok: bool,
+}
+
+/// The slash commands each Slack user recently ran, by their user id, kept in memory only
+///
+/// Running the same command again moves it to the top rather than listing it twice.
+#[derive(Default)]
+pub struct RecentCommands(Mutex<HashMap<String, VecDeque<RecentCommand>>>);
+
+impl RecentCommands {
+    /// Remember that a user ran a command
+    ///
+    /// # Arguments
+    ///
+    /// * `user` - The Slack user id of whoever ran it
+    /// * `name` - The name of the command
+    /// * `text` - What they typed after the name
+    /// * `ok` - Whether its handler succeeded
+    pub fn record(&self, user: &str, name: &str, text: &str, ok: bool) {
+        let mut recent = self.0.lock().unwrap();
+        let commands = recent.entry(user.to_string()).or_default();
+        commands.retain(|c| c.name != name || c.text != text);
+        if commands.len() >= MAX_RECENT {
+            commands.pop_front();
+        }
+        commands.push_back(RecentCommand {
+            name: name.to_string(),
+            text: text.to_string(),
+            at: unix_now(),
+            ok,
+        });
+    }
+
+    /// The commands a user recently ran, most recent first
+    ///
+    /// # Arguments
+    ///
+    /// * `user` - The Slack user id
+    pub fn recent(&self, user: &str) -> Vec<RecentCommand> {
+        match self.0.lock().unwrap().get(user) {
+            Some(commands) => commands.iter().rev().cloned().collect(),
+            None => Vec::new(),
+        }
+    }
+}
+
+/// Cut what a user typed short, for showing it
+fn shorten(text: &str) -> String {
+    if text.chars().count() > MAX_SHOWN_CHARS {
+        text.chars().take(MAX_SHOWN_CHARS).collect::<String>() + "..."
+    } else {
+        text.to_string()
+    }
+}
+
+/// A section of the home tab, with a button which runs a command if it fits in one
+fn command_section(text: String, button: &str, command: &str) -> Value {
+    let mut section = json!({
+        "type": "section",
+        "text": { "type": "mrkdwn", "text": text }
+    });
+    if !command.is_empty() && command.chars().count() <= MAX_VALUE_CHARS {
+        section["accessory"] = json!({
+            "type": "button",
+            "text": { "type": "plain_text", "text": button },
+            "action_id": HOME_RUN_ACTION,
+            "value": command
+        });
+    }
+    section
+}
+
+/// The home tab of a user: the slash commands they can run, and the ones they recently ran,
+/// each with a button to run it
+///
+/// Commands are listed like `/majordomo help` lists them, without the handlers tagged `hidden`
+///
+/// # Arguments
+///
+/// * `env` - Environment variables, for the name of our own command
+/// * `handlers` - The handlers, by their uris
+/// * `recent` - The commands the user recently ran, most recent first
+fn view(env: &EnvInfo, handlers: &HashMap<String, Handler>, recent: &[RecentCommand]) -> Value {
+    let section =
+        |text: &str| json!({ "type": "section", "text": { "type": "mrkdwn", "text": text } });
+    let note =
+        |text: &str| json!({ "type": "context", "elements": [{ "type": "mrkdwn", "text": text }] });
+
+    // A BTreeMap, so that the commands are sorted
+    let commands = handlers
+        .iter()
+        .filter(|(uri, h)| uri.starts_with("slash-") && !h.tags.iter().any(|t| t == "hidden"))
+        .map(|(uri, h)| {
+            (
+                &uri["slash-".len()..],
+                h.description.as_deref().unwrap_or_default(),
+            )
+        })
+        .collect::<BTreeMap<&str, &str>>();
+
+    let mut blocks = vec![section("*Here's what I can do*")];
+    if commands.is_empty() {
+        blocks.push(note("There are no commands yet"));
+    }
+    for (name, description) in commands.iter().take(MAX_COMMANDS) {
+        let text = format!("*/{} {}*\n{}", env.slack_command, name, description);
+        blocks.push(command_section(text, "Run", name));
+    }
+    if commands.len() > MAX_COMMANDS {
+        let more = format!(
+            "And {} more, see `/{} help`",
+            commands.len() - MAX_COMMANDS,
+            env.slack_command
+        );
+        blocks.push(note(&more));
+    }
+
+    blocks.push(json!({ "type": "divider" }));
+    blocks.push(section("*Your recent commands*"));
+    if recent.is_empty() {
+        blocks.push(note("The commands you run show up here"));
+    }
+    for command in recent {
+        let typed = format!("{} {}", command.name, command.text)
+            .trim()
+            .to_string();
+        let outcome = if command.ok {
+            ":white_check_mark:"
+        } else {
+            ":x:"
+        };
+        // Slack shows the date in the user's own timezone
+        let text = format!(
+            "`/{} {}`\n{} <!date^{}^{{date_short_pretty}} at {{time}}|{}>",
+            env.slack_command,
+            shorten(&typed),
+            outcome,
+            command.at,
+            utc_datetime(command.at)
+        );
+        blocks.push(command_section(text, "Run again", &typed));
+    }
+
+    json!({ "type": "home", "blocks": blocks })
+}
+
+/// Publish a user's home tab, see `view`
+///
+/// # Arguments
+///
+/// * `env` - Environment variables
+/// * `services` - The handlers, and the commands the user recently ran
+/// * `user` - The Slack user id
+pub fn publish(env: &EnvInfo, services: &Services, user: &str) {
+    let recent = services.recent_commands.recent(user);
+    let view = view(env, &services.handlers.read().unwrap(), &recent);
+    let body = json!({ "user_id": user, "view": view });
+    if let Err(e) = slack_api(&services.http, &env.slack_token, "views.publish", &body) {
+        log_event!("slack.home_error", user = user, error = e);
+    }
+}
+
+/// Refresh the home tab of a user who opened it
+///
+/// # Arguments
+///
+/// * `id` - The correlation id of the request, attached to every log line
+/// * `env` - Environment variables
+/// * `services` - The handlers, and the commands the user recently ran
+/// * `opened` - The event
+pub fn home_opened(
+    id: &CorrelationId,
+    env: &EnvInfo,
+    services: &Services,
+    opened: SlackAppHomeOpened,
+) {
+    // The messages tab is Slack's own
+    if opened.tab != "home" {
+        return;
+    }
+    log_event!("slack.home_opened", id = id.0, user = opened.user);
+    publish(env, services, &opened.user);
+}
+
+/// Run the command of a button of the home tab, as if the user had typed it after our own slash
+/// command, see `slash::run_command`
+///
+/// The home tab has nowhere to show the response, so it is sent to the user as a direct message.
+/// Their home tab is then refreshed, to list the command as recently run.
+///
+/// # Arguments
+///
+/// * `id` - The correlation id of the request, attached to every log line
+/// * `env` - Environment variables
+/// * `services` - The stateful subsystems available to handlers
+/// * `interaction` - The click, whose value is the command
+/// * `payload` - The whole interaction, as sent by Slack
+pub fn run_clicked(
+    id: &CorrelationId,
+    env: &EnvInfo,
+    services: &Services,
+    interaction: &Interaction,
+    payload: &Value,
+) {
+    let channel = match open_dm(&services.http, &env.slack_token, interaction.user) {
+        Ok(channel) => channel,
+        Err(e) => {
+            log_event!("slack.home_run_error", id = id.0, error = e);
+            return;
+        }
+    };
+    let user_name = payload["user"]["username"]
+        .as_str()
+        .or_else(|| payload["user"]["name"].as_str())
+        .unwrap_or_default();
+    let command = SlashCommand {
+        command: format!("/{}", env.slack_command),
+        text: interaction.value.to_string(),
+        user_id: interaction.user.to_string(),
+        user_name: user_name.to_string(),
+        channel_id: channel.clone(),
+        channel_name: String::new(),
+        response_url: String::new(),
+    };
+
+    // Slack wants interactions acknowledged within 3 seconds, however long the handler takes
+    let env = env.clone();
+    let services = services.clone();
+    let id = id.clone();
+    thread::spawn(move || {
+        let (name, text) = dispatch(&env, &command);
+        if !services
+            .handlers
+            .read()
+            .unwrap()
+            .contains_key(&command_uri(name))
+        {
+            log_event!("slack.home_run_unknown", id = id.0, command = name);
+            return;
+        }
+        log_event!(
+            "slack.home_run",
+            id = id.0,
+            handler = command_uri(name),
+            user = command.user_id
+        );
+
+        let reply = run_command(&env, &services, &id, &command, name, text);
+        let mut message = json!({
+            "channel": channel,
+            "text": reply["text"].as_str().unwrap_or_default()
+        });
+        if reply["blocks"].is_array() {
+            message["blocks"] = reply["blocks"].clone();
+        }
+        if let Err(e) = slack_api(
+            &services.http,
+            &env.slack_token,
+            "chat.postMessage",
+            &message,
+        ) {
+            log_event!("slack.home_reply_error", id = id.0, error = e);
+        }
+        publish(&env, &services, &command.user_id);
+    });
+}
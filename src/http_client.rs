@@ -0,0 +1,148 @@
+use std::io::Read;
+use std::time::Duration;
+
+use reqwest::blocking::{Client, RequestBuilder};
+use reqwest::header::CONTENT_TYPE;
+use reqwest::Url;
+
+use rhai::{EvalAltResult, ImmutableString, Module};
+
+use crate::faults;
+use crate::logging::CorrelationId;
+use crate::services::Services;
+use crate::types::EnvInfo;
+
+/// How long a handler's request may take, in total
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The most of a response body we read, in bytes. Anything beyond is cut off
+const MAX_BODY_BYTES: u64 = 1024 * 1024;
+
+/// Whether a url may be requested by handlers, i.e. it is http(s) and its host is one of the
+/// allowed domains, or a subdomain of one
+///
+/// # Arguments
+///
+/// * `url` - The url to check
+/// * `allowlist` - The allowed domains
+pub fn is_allowed(url: &Url, allowlist: &[String]) -> bool {
+    let host = match url.host_str() {
+        Some(host) => host.to_lowercase(),
+        None => return false,
+    };
+
+    (url.scheme() == "https" || url.scheme() == "http")
+        && allowlist
+            .iter()
+            .any(|domain| host == *domain || host.ends_with(&format!(".{}", domain)))
+}
+
+/// Send a request built for a handler, and read the response body
+/// Fails if the url is not allowed, or the response is not a success
+///
+/// The request is held to our own `TIMEOUT`, which is shorter than that of the shared client.
+fn send(
+    allowlist: &[String],
+    url: &str,
+    build: impl FnOnce(Url) -> RequestBuilder,
+) -> Result<String, String> {
+    let url = Url::parse(url).map_err(|e| format!("Invalid url {}: {}", url, e))?;
+    if !is_allowed(&url, allowlist) {
+        return Err(format!(
+            "Requests to {} are not allowed",
+            url.host_str().unwrap_or("")
+        ));
+    }
+    faults::inject("http")?;
+
+    let response = build(url)
+        .timeout(TIMEOUT)
+        .send()
+        .map_err(|e| e.to_string())?;
+    let status = response.status();
+
+    let mut body = String::new();
+    response
+        .take(MAX_BODY_BYTES)
+        .read_to_string(&mut body)
+        .map_err(|e| e.to_string())?;
+
+    if status.is_success() {
+        Ok(body)
+    } else {
+        Err(format!("Request failed with status {}: {}", status, body))
+    }
+}
+
+/// Post a body to a url, as a handler's `http_post` would, e.g. for a relay
+///
+/// # Arguments
+///
+/// * `http` - The shared http client, see `Services::http`
+/// * `allowlist` - The allowed domains
+/// * `url` - The url to post to
+/// * `body` - The body of the request
+/// * `content_type` - Its content type
+pub fn post(
+    http: &Client,
+    allowlist: &[String],
+    url: &str,
+    body: String,
+    content_type: &str,
+) -> Result<String, String> {
+    send(allowlist, url, |url| {
+        http.post(url).header(CONTENT_TYPE, content_type).body(body)
+    })
+}
+
+/// Register the generic http functions available to clients
+///
+/// * `http_get(url)` returns the body of the response
+/// * `http_post(url, body, content_type)` returns the body of the response
+///
+/// Only domains in the `HTTP_ALLOWLIST` may be requested, so that we can't be used as an open
+/// proxy. Redirects are not followed, since they could lead anywhere.
+///
+/// # Arguments
+///
+/// * `module` - The module to register the functions in
+/// * `env` - Environment variables
+/// * `services` - For the shared http client
+/// * `id` - The correlation id of the request the handler is serving
+/// * `handler_addr` - The uri of the handler the functions are for
+pub fn register(
+    module: &mut Module,
+    env: &EnvInfo,
+    services: &Services,
+    id: &CorrelationId,
+    handler_addr: &str,
+) {
+    let http = services.http.clone();
+    let allowlist = env.http_allowlist.clone();
+    let addr = handler_addr.to_string();
+    let cid = id.clone();
+    let http_get = move |url: ImmutableString| -> Result<String, Box<EvalAltResult>> {
+        log_event!("http.get", id = cid.0, handler = addr, url = url);
+        send(&allowlist, &url, |url| http.get(url)).map_err(Into::into)
+    };
+
+    let http = services.http.clone();
+    let allowlist = env.http_allowlist.clone();
+    let addr = handler_addr.to_string();
+    let cid = id.clone();
+    let http_post = move |url: ImmutableString,
+                          body: ImmutableString,
+                          content_type: ImmutableString|
+          -> Result<String, Box<EvalAltResult>> {
+        log_event!("http.post", id = cid.0, handler = addr, url = url);
+        send(&allowlist, &url, |url| {
+            http.post(url)
+                .header(CONTENT_TYPE, content_type.as_str())
+                .body(body.to_string())
+        })
+        .map_err(Into::into)
+    };
+
+    module.set_fn_1("http_get", http_get);
+    module.set_fn_3("http_post", http_post);
+}
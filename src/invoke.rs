@@ -0,0 +1,117 @@
+use std::cell::RefCell;
+
+use rhai::{EvalAltResult, ImmutableString, Module};
+
+use crate::logging::CorrelationId;
+use crate::server::run_handler;
+use crate::services::Services;
+use crate::types::EnvInfo;
+
+/// How deep handlers may invoke each other, counting the handler which started it all
+const MAX_INVOKE_DEPTH: usize = 5;
+
+thread_local! {
+    /// The uris of the handlers on this thread waiting on an `invoke` to return, outermost first
+    ///
+    /// Invoked handlers run on the thread of their caller, so this is the whole chain.
+    static CALLERS: RefCell<Vec<String>> = RefCell::new(Vec::new());
+}
+
+/// Keeps a handler on the chain of callers while the handler it invoked runs, taking it off
+/// again however the run ends
+struct Caller;
+
+impl Caller {
+    fn enter(handler_addr: &str) -> Caller {
+        CALLERS.with(|c| c.borrow_mut().push(handler_addr.to_string()));
+        Caller
+    }
+}
+
+impl Drop for Caller {
+    fn drop(&mut self) {
+        CALLERS.with(|c| c.borrow_mut().pop());
+    }
+}
+
+/// Check whether a handler may invoke another, given the handlers already waiting on invokes
+///
+/// # Arguments
+///
+/// * `handler_addr` - The uri of the handler doing the invoking
+/// * `target` - The uri of the handler it wants to invoke
+fn check_invoke(handler_addr: &str, target: &str) -> Result<(), String> {
+    CALLERS.with(|c| {
+        let callers = c.borrow();
+        if target == handler_addr || callers.iter().any(|caller| caller == target) {
+            let mut chain = callers.clone();
+            chain.push(handler_addr.to_string());
+            chain.push(target.to_string());
+            return Err(format!(
+                "Handlers may not invoke each other in a loop: {}",
+                chain.join(" -> ")
+            ));
+        }
+        if callers.len() + 2 > MAX_INVOKE_DEPTH {
+            return Err(format!(
+                "Handlers may only invoke each other {} deep",
+                MAX_INVOKE_DEPTH
+            ));
+        }
+        Ok(())
+    })
+}
+
+/// Register the functions which let clients compose handlers
+///
+/// * `invoke(uri, data)` runs the `handle` function of another handler with `data`, on this
+///   thread, and returns what it returned. The invoked handler runs with its own limits, and is
+///   logged as its own run. Handlers may not invoke anything which is already waiting on them,
+///   directly or not, and chains are at most `MAX_INVOKE_DEPTH` handlers long.
+///
+/// # Arguments
+///
+/// * `module` - The module to register the functions in
+/// * `env` - Environment variables
+/// * `services` - The stateful subsystems, including the handlers to invoke
+/// * `id` - The correlation id of the run, shared by the handlers it invokes
+/// * `handler_addr` - The uri of the handler the functions are for
+pub fn register(
+    module: &mut Module,
+    env: &EnvInfo,
+    services: &Services,
+    id: &CorrelationId,
+    handler_addr: &str,
+) {
+    let env = env.clone();
+    let inner = services.clone();
+    let addr = handler_addr.to_string();
+    let cid = id.clone();
+    let invoke = move |target: ImmutableString,
+                       data: ImmutableString|
+          -> Result<String, Box<EvalAltResult>> {
+        check_invoke(&addr, &target)?;
+
+        // Whoever invoked us may be holding the handlers already. Waiting here could deadlock
+        // against an upsert waiting on them, so give up rather than wait
+        let guard = inner
+            .handlers
+            .try_read()
+            .map_err(|_| "Handlers are being updated, try again")?;
+        let handler = guard
+            .get(target.as_str())
+            .ok_or_else(|| format!("Unable to find handler {}", target))?;
+
+        log_event!(
+            "handler.invoke",
+            id = cid.0,
+            handler = addr,
+            target = target
+        );
+
+        let _caller = Caller::enter(&addr);
+        run_handler(&env, &inner, &cid, &target, handler, data.to_string(), None)
+    };
+
+    module.set_fn_2("invoke", invoke);
+}
@@ -0,0 +1,39 @@
+use rhai::de::from_dynamic;
+use rhai::ser::to_dynamic;
+use rhai::{Dynamic, EvalAltResult, ImmutableString, Module};
+
+use serde_json::Value;
+
+/// Register the json functions available to clients
+///
+/// Payloads arrive as strings, so these save handlers from picking fields out of json by hand.
+///
+/// * `json_parse(string)` parses json into Rhai values: objects become maps, arrays become
+///   arrays, and so on. Fails if the string is not valid json
+/// * `json_stringify(value)` turns a map, array, string, number, boolean or `()` into json
+/// * `json_stringify_pretty(value)` does the same, indented for people to read
+///
+/// # Arguments
+///
+/// * `module` - The module to register the functions in
+pub fn register(module: &mut Module) {
+    let json_parse = |string: ImmutableString| -> Result<Dynamic, Box<EvalAltResult>> {
+        let value: Value =
+            serde_json::from_str(&string).map_err(|e| format!("Invalid json: {}", e))?;
+        to_dynamic(value)
+    };
+
+    let json_stringify = |value: Dynamic| -> Result<String, Box<EvalAltResult>> {
+        let value: Value = from_dynamic(&value)?;
+        Ok(value.to_string())
+    };
+
+    let json_stringify_pretty = |value: Dynamic| -> Result<String, Box<EvalAltResult>> {
+        let value: Value = from_dynamic(&value)?;
+        serde_json::to_string_pretty(&value).map_err(|e| e.to_string().into())
+    };
+
+    module.set_fn_1("json_parse", json_parse);
+    module.set_fn_1("json_stringify", json_stringify);
+    module.set_fn_1("json_stringify_pretty", json_stringify_pretty);
+}
@@ -0,0 +1,311 @@
+use std::fs;
+use std::sync::Arc;
+use std::time::Duration;
+
+use reqwest::blocking::{Client, RequestBuilder};
+use reqwest::header::{AUTHORIZATION, CONTENT_TYPE};
+use reqwest::Certificate;
+
+use rhai::{Array, Dynamic, EvalAltResult, ImmutableString, Map, Module, INT};
+
+use serde_json::{json, Value};
+
+use crate::clock::{unix_now, utc_datetime};
+use crate::logging::CorrelationId;
+use crate::services::Services;
+use crate::types::K8sConfig;
+
+/// The verb allowing `k8s_scale`
+pub const SCALE_VERB: &str = "scale";
+
+/// The verb allowing `k8s_rollout_restart`
+pub const RESTART_VERB: &str = "rollout_restart";
+
+/// The verb allowing `k8s_get_pods`
+pub const GET_PODS_VERB: &str = "get_pods";
+
+/// How long a call to the Kubernetes API may take
+const API_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// The most replicas handlers may scale a deployment to
+const MAX_REPLICAS: INT = 50;
+
+/// A Kubernetes cluster handlers can run chat-ops against, within the verbs the operator allows
+pub struct Cluster {
+    /// Trusts the cluster's certificate authority, if one is configured
+    client: Client,
+    config: K8sConfig,
+}
+
+impl Cluster {
+    /// Get ready to talk to the cluster, if one is configured
+    ///
+    /// # Arguments
+    ///
+    /// * `config` - Where the cluster is, and what handlers may do to it
+    pub fn open(config: &K8sConfig) -> Option<Cluster> {
+        let mut builder = Client::builder().timeout(API_TIMEOUT);
+        if let Some(path) = &config.ca_cert_path {
+            let cert = fs::read(path)
+                .ok()
+                .map(|pem| Certificate::from_pem(&pem).ok())
+                .flatten();
+            match cert {
+                Some(cert) => builder = builder.add_root_certificate(cert),
+                None => println!("Warning! Unable to load Kubernetes CA from {}", path),
+            }
+        }
+
+        match builder.build() {
+            Ok(client) => Some(Cluster {
+                client,
+                config: config.clone(),
+            }),
+            Err(e) => {
+                println!("Warning! Unable to set up Kubernetes client: {}", e);
+                None
+            }
+        }
+    }
+
+    /// Fail unless the operator allows handlers to use a verb
+    fn check_verb(&self, verb: &str) -> Result<(), String> {
+        if self.config.verbs.iter().any(|v| v == verb) {
+            Ok(())
+        } else {
+            Err(format!("{} is not allowed on this cluster", verb))
+        }
+    }
+
+    /// The token to authenticate with
+    ///
+    /// Service account tokens are re-read every time, since the kubelet rotates them.
+    fn token(&self) -> Result<String, String> {
+        match &self.config.token {
+            Some(token) => Ok(token.clone()),
+            None => fs::read_to_string(&self.config.token_path)
+                .map(|t| t.trim().to_string())
+                .map_err(|e| format!("Unable to read Kubernetes token: {}", e)),
+        }
+    }
+
+    /// Send a request to the Kubernetes API, returning the parsed response, or the message the
+    /// API server gave for refusing it
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - The path of the API resource, for the logs
+    /// * `request` - The request, before authentication
+    fn send(&self, path: &str, request: RequestBuilder) -> Result<Value, String> {
+        let resp = request
+            .header(AUTHORIZATION, format!("Bearer {}", self.token()?))
+            .send()
+            .map_err(|e| e.to_string())?;
+        let status = resp.status();
+        let text = resp.text().map_err(|e| e.to_string())?;
+        let body: Value = serde_json::from_str(&text).unwrap_or(Value::Null);
+
+        if status.is_success() {
+            Ok(body)
+        } else {
+            let error = body["message"]
+                .as_str()
+                .map(String::from)
+                .unwrap_or_else(|| format!("Kubernetes API returned {}", status));
+            log_event!("k8s.api_error", path = path, error = error);
+            Err(error)
+        }
+    }
+
+    /// The path of a deployment in the configured namespace
+    fn deployment_path(&self, deployment: &str) -> Result<String, String> {
+        check_name(deployment)?;
+        Ok(format!(
+            "/apis/apps/v1/namespaces/{}/deployments/{}",
+            self.config.namespace, deployment
+        ))
+    }
+
+    /// Patch a resource
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - The path of the resource
+    /// * `content_type` - The kind of patch, e.g. `application/merge-patch+json`
+    /// * `patch` - The patch itself
+    fn patch(&self, path: &str, content_type: &str, patch: &Value) -> Result<Value, String> {
+        let request = self
+            .client
+            .patch(&format!("{}{}", self.config.api_url, path))
+            .header(CONTENT_TYPE, content_type)
+            .body(patch.to_string());
+        self.send(path, request)
+    }
+
+    /// Set how many replicas a deployment has
+    ///
+    /// # Arguments
+    ///
+    /// * `deployment` - The name of the deployment
+    /// * `replicas` - How many replicas it should have
+    pub fn scale(&self, deployment: &str, replicas: INT) -> Result<(), String> {
+        self.check_verb(SCALE_VERB)?;
+        if !(0..=MAX_REPLICAS).contains(&replicas) {
+            return Err(format!("Replicas must be between 0 and {}", MAX_REPLICAS));
+        }
+
+        let path = format!("{}/scale", self.deployment_path(deployment)?);
+        let patch = json!({ "spec": { "replicas": replicas } });
+        self.patch(&path, "application/merge-patch+json", &patch)
+            .map(|_| ())
+    }
+
+    /// Restart every pod of a deployment, one after another, like `kubectl rollout restart`
+    ///
+    /// # Arguments
+    ///
+    /// * `deployment` - The name of the deployment
+    pub fn rollout_restart(&self, deployment: &str) -> Result<(), String> {
+        self.check_verb(RESTART_VERB)?;
+
+        // Changing the pod template is what makes the deployment roll its pods
+        let path = self.deployment_path(deployment)?;
+        let patch = json!({
+            "spec": { "template": { "metadata": { "annotations": {
+                "kubectl.kubernetes.io/restartedAt": utc_datetime(unix_now())
+            } } } }
+        });
+        self.patch(&path, "application/strategic-merge-patch+json", &patch)
+            .map(|_| ())
+    }
+
+    /// List the pods matching a label selector
+    ///
+    /// # Arguments
+    ///
+    /// * `selector` - A label selector, e.g. `app=web,tier!=canary`
+    pub fn get_pods(&self, selector: &str) -> Result<Vec<Value>, String> {
+        self.check_verb(GET_PODS_VERB)?;
+
+        let path = format!("/api/v1/namespaces/{}/pods", self.config.namespace);
+        let request = self
+            .client
+            .get(&format!("{}{}", self.config.api_url, path))
+            .query(&[("labelSelector", selector)]);
+        let mut body = self.send(&path, request)?;
+        match body["items"].take() {
+            Value::Array(items) => Ok(items),
+            _ => Ok(Vec::new()),
+        }
+    }
+}
+
+/// Check the name of a resource, which Kubernetes restricts to lowercase letters, digits, '-'
+/// and '.', so it can't reach outside the path it is put in
+fn check_name(name: &str) -> Result<(), String> {
+    let valid = !name.is_empty()
+        && name.len() <= 253
+        && name
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-' || c == '.')
+        && !name.starts_with('.');
+    if valid {
+        Ok(())
+    } else {
+        Err(format!("{} is not a valid Kubernetes name", name))
+    }
+}
+
+/// Describe a pod for handlers
+///
+/// The map has the pod's `name`, its `phase`, e.g. `Running`, whether every container is
+/// `ready`, how many times its containers have `restarts`, and the `node` it runs on.
+fn pod_map(pod: &Value) -> Map {
+    let statuses = pod["status"]["containerStatuses"]
+        .as_array()
+        .cloned()
+        .unwrap_or_default();
+    let ready = !statuses.is_empty() && statuses.iter().all(|s| s["ready"] == true);
+    let restarts = statuses
+        .iter()
+        .map(|s| s["restartCount"].as_i64().unwrap_or(0))
+        .sum::<i64>();
+    let text = |value: &Value| Dynamic::from(value.as_str().unwrap_or_default().to_string());
+
+    let mut map = Map::new();
+    map.insert("name".into(), text(&pod["metadata"]["name"]));
+    map.insert("phase".into(), text(&pod["status"]["phase"]));
+    map.insert("ready".into(), Dynamic::from(ready));
+    map.insert("restarts".into(), Dynamic::from(restarts as INT));
+    map.insert("node".into(), text(&pod["spec"]["nodeName"]));
+    map
+}
+
+/// Fail unless a cluster is configured
+fn cluster(cluster: &Option<Arc<Cluster>>) -> Result<&Cluster, Box<EvalAltResult>> {
+    cluster
+        .as_deref()
+        .ok_or_else(|| "Kubernetes is not configured on this server".into())
+}
+
+/// Register the Kubernetes functions available to clients
+///
+/// Each only works if the operator allows its verb, see `K8sConfig::verbs`, and only in the
+/// configured namespace.
+///
+/// * `k8s_scale(deployment, replicas)` sets how many replicas a deployment has
+/// * `k8s_rollout_restart(deployment)` restarts the pods of a deployment, one after another
+/// * `k8s_get_pods(selector)` lists the pods matching a label selector, see `pod_map`
+///
+/// # Arguments
+///
+/// * `module` - The module to register the functions in
+/// * `services` - Where the cluster is
+/// * `id` - The correlation id of the run, attached to every log line
+/// * `handler_addr` - The uri of the handler the functions are for
+pub fn register(module: &mut Module, services: &Services, id: &CorrelationId, handler_addr: &str) {
+    let k8s = services.k8s.clone();
+    let addr = handler_addr.to_string();
+    let cid = id.clone();
+    let k8s_scale =
+        move |deployment: ImmutableString, replicas: INT| -> Result<(), Box<EvalAltResult>> {
+            log_event!(
+                "k8s.scale",
+                id = cid.0,
+                handler = addr,
+                deployment = deployment,
+                replicas = replicas
+            );
+            cluster(&k8s)?
+                .scale(&deployment, replicas)
+                .map_err(Into::into)
+        };
+
+    let k8s = services.k8s.clone();
+    let addr = handler_addr.to_string();
+    let cid = id.clone();
+    let k8s_rollout_restart = move |deployment: ImmutableString| -> Result<(), Box<EvalAltResult>> {
+        log_event!(
+            "k8s.rollout_restart",
+            id = cid.0,
+            handler = addr,
+            deployment = deployment
+        );
+        cluster(&k8s)?
+            .rollout_restart(&deployment)
+            .map_err(Into::into)
+    };
+
+    let k8s = services.k8s.clone();
+    let k8s_get_pods = move |selector: ImmutableString| -> Result<Array, Box<EvalAltResult>> {
+        let pods = cluster(&k8s)?.get_pods(&selector)?;
+        Ok(pods
+            .iter()
+            .map(|pod| Dynamic::from(pod_map(pod)))
+            .collect::<Array>())
+    };
+
+    module.set_fn_2("k8s_scale", k8s_scale);
+    module.set_fn_1("k8s_rollout_restart", k8s_rollout_restart);
+    module.set_fn_1("k8s_get_pods", k8s_get_pods);
+}
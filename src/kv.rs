@@ -0,0 +1,152 @@
+use std::collections::HashMap;
+
+use rhai::de::from_dynamic;
+use rhai::ser::to_dynamic;
+use rhai::{Array, Dynamic, EvalAltResult, ImmutableString, Map, Module, INT};
+
+use serde_json::Value;
+
+use crate::services::Services;
+
+/// How many keys a single handler may store
+const MAX_KEYS: usize = 1000;
+
+/// The values a handler has stored, indexed by key
+pub type Namespace = HashMap<String, Value>;
+
+/// The key a leaderboard is stored under, as a json object of entities to their scores
+fn board_key(board: &str) -> String {
+    format!("score:{}", board)
+}
+
+/// Register the key-value store functions available to clients
+///
+/// Each handler has its own namespace, so keys never clash between handlers. Values can be
+/// anything which can be represented as json: strings, numbers, booleans, arrays and maps.
+///
+/// * `kv_get(key)` returns the stored value, or `()` if there is none
+/// * `kv_set(key, value)` stores a value, replacing any previous one
+/// * `kv_delete(key)` removes a value, returning whether there was one
+///
+/// # Arguments
+///
+/// * `module` - The module to register the functions in
+/// * `services` - Where values are stored
+/// * `handler_addr` - The uri of the handler the functions are for, i.e. the namespace
+pub fn register(module: &mut Module, services: &Services, handler_addr: &str) {
+    let kv = services.kv.clone();
+    let addr = handler_addr.to_string();
+    let kv_get = move |key: ImmutableString| -> Result<Dynamic, Box<EvalAltResult>> {
+        match kv.read().get(&addr).and_then(|ns| ns.get(key.as_str())) {
+            Some(value) => to_dynamic(value),
+            None => Ok(Dynamic::from(())),
+        }
+    };
+
+    let kv = services.kv.clone();
+    let addr = handler_addr.to_string();
+    let kv_set = move |key: ImmutableString, value: Dynamic| -> Result<(), Box<EvalAltResult>> {
+        let value: Value = from_dynamic(&value)?;
+        kv.update(|map| {
+            let ns = map.entry(addr.clone()).or_default();
+            if ns.len() >= MAX_KEYS && !ns.contains_key(key.as_str()) {
+                return Err(format!("A handler may store at most {} keys", MAX_KEYS));
+            }
+            ns.insert(key.to_string(), value);
+            Ok(())
+        })?;
+        Ok(())
+    };
+
+    let kv = services.kv.clone();
+    let addr = handler_addr.to_string();
+    let kv_delete = move |key: ImmutableString| {
+        Ok(kv.update(|map| {
+            map.get_mut(&addr)
+                .map(|ns| ns.remove(key.as_str()).is_some())
+                .unwrap_or(false)
+        }))
+    };
+
+    module.set_fn_1("kv_get", kv_get);
+    module.set_fn_2("kv_set", kv_set);
+    module.set_fn_1("kv_delete", kv_delete);
+}
+
+/// Register the leaderboard functions available to clients, e.g. for karma bots
+///
+/// Boards are stored in the handler's key-value namespace, under `score:<board>`. Updates happen
+/// under the store's lock, so concurrent increments are never lost.
+///
+/// * `score_incr(board, entity, delta)` adds `delta` to the entity's score, starting from 0, and
+///   returns the new score
+/// * `score_top(board, n)` returns the `n` highest scoring entities, highest first, as an array of
+///   maps with `entity` and `score` fields
+///
+/// # Arguments
+///
+/// * `module` - The module to register the functions in
+/// * `services` - Where boards are stored
+/// * `handler_addr` - The uri of the handler the functions are for, i.e. the namespace
+pub fn register_scores(module: &mut Module, services: &Services, handler_addr: &str) {
+    let kv = services.kv.clone();
+    let addr = handler_addr.to_string();
+    let score_incr = move |board: ImmutableString,
+                           entity: ImmutableString,
+                           delta: INT|
+          -> Result<INT, Box<EvalAltResult>> {
+        let key = board_key(&board);
+        let score = kv.update(|map| {
+            let ns = map.entry(addr.clone()).or_default();
+            if ns.len() >= MAX_KEYS && !ns.contains_key(&key) {
+                return Err(format!("A handler may store at most {} keys", MAX_KEYS));
+            }
+
+            let scores = ns
+                .entry(key)
+                .or_insert_with(|| Value::Object(Default::default()));
+            if !scores.is_object() {
+                return Err(format!("{} is not a leaderboard", board));
+            }
+
+            let score = scores[entity.as_str()].as_i64().unwrap_or(0) + delta as i64;
+            scores[entity.as_str()] = score.into();
+            Ok(score)
+        })?;
+        Ok(score as INT)
+    };
+
+    let kv = services.kv.clone();
+    let addr = handler_addr.to_string();
+    let score_top = move |board: ImmutableString, n: INT| {
+        let guard = kv.read();
+        let mut scores = guard
+            .get(&addr)
+            .and_then(|ns| ns.get(&board_key(&board)))
+            .and_then(Value::as_object)
+            .map(|scores| {
+                scores
+                    .iter()
+                    .map(|(entity, score)| (entity.clone(), score.as_i64().unwrap_or(0)))
+                    .collect::<Vec<(String, i64)>>()
+            })
+            .unwrap_or_default();
+
+        // Highest first, ties broken alphabetically so the order is stable
+        scores.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+
+        Ok(scores
+            .into_iter()
+            .take(n.max(0) as usize)
+            .map(|(entity, score)| {
+                let mut row = Map::new();
+                row.insert("entity".into(), Dynamic::from(entity));
+                row.insert("score".into(), Dynamic::from(score as INT));
+                Dynamic::from(row)
+            })
+            .collect::<Array>())
+    };
+
+    module.set_fn_3("score_incr", score_incr);
+    module.set_fn_2("score_top", score_top);
+}
@@ -0,0 +1,173 @@
+use std::collections::HashMap;
+
+use rhai::module_resolvers::StaticModuleResolver;
+use rhai::{Engine, Module, Scope};
+
+use rocket::State;
+use rocket_contrib::json::Json;
+
+use serde::{Deserialize, Serialize};
+
+use crate::auth::{check_auth, check_scope, hash_key, AuthHeader};
+use crate::clock::unix_now;
+use crate::server::Collection;
+use crate::services::Services;
+use crate::types::{
+    ASTBox, ApiKeyInfo, EnvInfo, UpsertModuleRequest, UserResponse, DEFAULT_MAX_OPERATIONS,
+    WRITE_SCOPE,
+};
+
+/// How many modules a single API Key may store
+const MAX_MODULES: usize = 50;
+
+/// The longest the name of a module may be
+const MAX_NAME_CHARS: usize = 64;
+
+/// A module of shared Rhai functions, which the handlers of its owner can import
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Library {
+    #[serde(serialize_with = "crate::types::serialize_astbox")]
+    #[serde(deserialize_with = "crate::types::deserialize_astbox")]
+    pub code: ASTBox,
+    /// When the code was saved, as a unix timestamp
+    pub saved_at: u64,
+}
+
+/// The modules of an API Key, indexed by name
+pub type Libraries = HashMap<String, Library>;
+
+/// An engine to evaluate modules in
+///
+/// Modules only run to define their functions and constants, so they are held to the default
+/// operations limit, which keeps a stray loop from hanging every handler which imports them.
+fn module_engine() -> Engine {
+    let mut engine = Engine::new();
+    engine.set_max_operations(DEFAULT_MAX_OPERATIONS);
+    engine
+}
+
+/// Check the name of a module, so that names stay easy to import
+fn check_name(name: &str) -> Result<(), String> {
+    let valid = !name.is_empty()
+        && name.chars().count() <= MAX_NAME_CHARS
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+    if valid {
+        Ok(())
+    } else {
+        Err(format!(
+            "Module names must be 1 to {} letters, digits, '_' or '-'",
+            MAX_NAME_CHARS
+        ))
+    }
+}
+
+/// The modules a handler may import, i.e. those of its owner
+///
+/// Handlers import them by name, e.g. `import "utils" as utils;` and then call
+/// `utils::helper()`. Nothing else can be imported: this replaces Rhai's default resolver, which
+/// would otherwise load scripts from the server's disk.
+///
+/// # Arguments
+///
+/// * `services` - Where modules are stored
+/// * `owner` - The hash of the API Key which owns the handler
+pub fn resolver(services: &Services, owner: &str) -> StaticModuleResolver {
+    let mut resolver = StaticModuleResolver::new();
+    let guard = services.libraries.read();
+    let libraries = match guard.get(owner) {
+        Some(libraries) => libraries,
+        None => return resolver,
+    };
+
+    let engine = module_engine();
+    for (name, library) in libraries {
+        match Module::eval_ast_as_new(Scope::new(), &library.code.ast, &engine) {
+            Ok(module) => resolver.insert(name.clone(), module),
+            Err(e) => log_event!("module.eval_error", module = name, error = e),
+        }
+    }
+    resolver
+}
+
+/// Rocket Endpoint which creates or updates a module of shared functions
+///
+/// Every handler of the API Key can import the module, see `resolver`, so helpers don't need to
+/// be copied into each of them. Handlers pick up changes on their next run.
+///
+/// # Arguments
+///
+/// * `auth` - The API Key from the `Authorization` header, if any
+/// * `env` - Environment variables
+/// * `services` - Where modules are stored
+/// * `api_keys` - A reference to the collection of Client API keys, used to check for auth
+/// * `post_data` - The name and code of the module
+#[post("/upsert_module", data = "<post_data>")]
+pub fn upsert_module(
+    auth: AuthHeader,
+    env: State<EnvInfo>,
+    services: State<Services>,
+    api_keys: Collection<String, ApiKeyInfo>,
+    post_data: Json<UpsertModuleRequest>,
+) -> Json<UserResponse> {
+    let data = post_data.0;
+    let key = auth.key_or(&data.api_key);
+
+    if let Err(cause) = auth.verify(&key, check_auth(&key, &api_keys), "Invalid API Key") {
+        return Json(UserResponse::from(cause));
+    }
+    if let Err(cause) = check_scope(&key, &api_keys, WRITE_SCOPE) {
+        return Json(UserResponse::from(cause));
+    }
+
+    if env.read_only {
+        return Json(UserResponse::read_only());
+    }
+
+    if let Err(cause) = check_name(&data.name) {
+        return Json(UserResponse::failure(cause));
+    }
+
+    // Surface mistakes now, rather than in every handler which imports the module
+    let engine = module_engine();
+    let ast = match engine.compile(&data.code) {
+        Ok(ast) => ast,
+        Err(e) => return Json(UserResponse::failure(format!("Error parsing code: {}", e))),
+    };
+    if let Err(e) = Module::eval_ast_as_new(Scope::new(), &ast, &engine) {
+        return Json(UserResponse::failure(format!(
+            "Error running module: {}",
+            e
+        )));
+    }
+
+    let owner = hash_key(&key);
+    let name = data.name;
+    let library = Library {
+        code: ASTBox {
+            ast,
+            raw: data.code,
+        },
+        saved_at: unix_now(),
+    };
+    let saved = services.libraries.update(|map| {
+        let libraries = map.entry(owner.clone()).or_default();
+        if libraries.len() >= MAX_MODULES && !libraries.contains_key(&name) {
+            return Err(format!(
+                "An API Key may store at most {} modules",
+                MAX_MODULES
+            ));
+        }
+        libraries.insert(name.clone(), library);
+        Ok(())
+    });
+
+    match saved {
+        Ok(_) => {
+            log_event!("audit.module_upsert", key = &owner[..8], module = name);
+            Json(UserResponse::success())
+        }
+        Err(cause) => Json(UserResponse::failure(cause)),
+    }
+}
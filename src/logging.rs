@@ -0,0 +1,130 @@
+use std::time::Instant;
+
+use rand::Rng;
+
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::http::Header;
+use rocket::request::{self, FromRequest};
+use rocket::{Data, Outcome, Request, Response};
+
+/// Emit a structured log line
+///
+/// Every line starts with `event=<event>`, followed by each field as `key="value"`.
+/// Prefer this (via `log_event!`) over bare `println!` so that logs can be grepped and parsed.
+///
+/// # Arguments
+///
+/// * `event` - A short, dot separated name for what happened, e.g. `slack.post`
+/// * `fields` - Any additional key/value pairs to attach to the line
+pub fn emit(event: &str, fields: &[(&str, String)]) {
+    let mut line = format!("event={}", event);
+    for (key, value) in fields {
+        line.push_str(&format!(" {}={:?}", key, value));
+    }
+    println!("{}", line);
+}
+
+/// Log a structured event. See `logging::emit`
+///
+/// ```ignore
+/// log_event!("slack.post", handler = addr, channel = channel);
+/// ```
+macro_rules! log_event {
+    ($event:expr $(, $key:ident = $value:expr)* $(,)?) => {
+        $crate::logging::emit($event, &[$((stringify!($key), format!("{}", $value))),*])
+    };
+}
+
+/// The header used to read and propagate correlation ids
+const CORRELATION_HEADER: &str = "X-Request-Id";
+
+/// The moment a request arrived, stashed in Rocket's request-local cache
+struct RequestStart(Instant);
+
+/// A per-request id, used to tie together every log line produced while serving a request.
+/// Taken from the `X-Request-Id` header if the caller provided one, generated otherwise.
+#[derive(Debug, Clone)]
+pub struct CorrelationId(pub String);
+
+impl CorrelationId {
+    pub fn generate() -> CorrelationId {
+        CorrelationId(format!("{:016x}", rand::thread_rng().gen::<u64>()))
+    }
+
+    fn of(request: &Request) -> CorrelationId {
+        request
+            .local_cache(|| match request.headers().get_one(CORRELATION_HEADER) {
+                Some(id) if !id.is_empty() && id.len() <= 64 => CorrelationId(id.into()),
+                _ => CorrelationId::generate(),
+            })
+            .clone()
+    }
+}
+
+impl<'a, 'r> FromRequest<'a, 'r> for CorrelationId {
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<CorrelationId, ()> {
+        Outcome::Success(CorrelationId::of(request))
+    }
+}
+
+/// Rocket Fairing which logs every request once it has been responded to
+///
+/// Each line contains the method, the raw path, the route it matched, the status, the latency and
+/// the correlation id. Successful requests are sampled according to `sample_rate`; failures
+/// (anything with a 4xx or 5xx status) are always logged.
+pub struct RequestLogger {
+    /// The fraction of successful requests to log, between 0 and 1
+    sample_rate: f64,
+}
+
+impl RequestLogger {
+    pub fn new(sample_rate: f64) -> RequestLogger {
+        RequestLogger {
+            sample_rate: sample_rate.max(0.0).min(1.0),
+        }
+    }
+}
+
+impl Fairing for RequestLogger {
+    fn info(&self) -> Info {
+        Info {
+            name: "Request Logger",
+            kind: Kind::Request | Kind::Response,
+        }
+    }
+
+    fn on_request(&self, request: &mut Request, _: &Data) {
+        request.local_cache(|| RequestStart(Instant::now()));
+        CorrelationId::of(request);
+    }
+
+    fn on_response(&self, request: &Request, response: &mut Response) {
+        let start = request.local_cache(|| RequestStart(Instant::now()));
+        let latency = start.0.elapsed();
+        let id = CorrelationId::of(request);
+
+        response.set_header(Header::new(CORRELATION_HEADER, id.0.clone()));
+
+        let status = response.status().code;
+        if status < 400 && !rand::thread_rng().gen_bool(self.sample_rate) {
+            return;
+        }
+
+        let route = request
+            .route()
+            .map(|r| r.uri.to_string())
+            .unwrap_or("-".into());
+
+        log_event!(
+            "http.request",
+            id = id.0,
+            method = request.method(),
+            path = request.uri().path(),
+            route = route,
+            status = status,
+            latency_ms = format!("{:.3}", latency.as_secs_f64() * 1000.0),
+        );
+    }
+}
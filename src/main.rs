@@ -2,41 +2,295 @@
 
 #[macro_use]
 extern crate rocket;
+extern crate clap;
+extern crate flate2;
+extern crate httpdate;
 extern crate rand;
 extern crate reqwest;
 extern crate rhai;
 extern crate rocket_contrib;
+extern crate rusqlite;
 extern crate serde;
+extern crate sha2;
 
 use std::collections::HashMap;
 use std::env;
 use std::fs;
-use std::iter::FromIterator;
 use std::path::Path;
 
 use rocket_contrib::json::Json;
 
+#[macro_use]
+mod logging;
+
+mod admin;
+mod advisories;
+mod alerts;
+mod api;
+mod apply;
+mod approvals;
+mod archive;
+mod assets;
+
+mod auth;
+use auth::{hash_plain_keys, hash_plain_owners};
+
+mod broadcast;
+mod calendar;
+mod changes;
+mod channels;
+mod cleanup;
+mod cli;
+mod clock;
+mod codecheck;
+mod codeowners;
+mod completions;
+mod crosspost;
+mod dependencies;
+mod dryrun;
+mod envelope;
+mod faults;
+mod feed;
+mod flags;
+mod github;
+mod grpc;
+mod handler_logs;
+mod help;
+mod history;
+mod home;
+mod http_client;
+mod invoke;
+mod json;
+mod k8s;
+mod kv;
+mod libraries;
+mod metrics;
+mod namespaces;
+mod oncall;
+mod polls;
+mod probes;
+mod ratelimit;
+mod recent;
+mod relay;
+mod releases;
+mod reminders;
+mod remote;
+mod reprocess;
+mod responses;
+mod scheduler;
+mod search;
+mod secrets;
+
 mod server;
+mod services;
+mod signing;
+mod slack;
+mod slash;
 use server::http_server_start;
 
+mod sqlite;
+use sqlite::SqliteBackend;
+
+mod stale;
+mod stats;
+mod status;
+
+mod storage;
+use storage::{load_api_keys, load_handlers, restore_from_backup, Backend, JsonBackend, Storage};
+
+mod twilio;
+
 mod types;
+use types::ApiKeyInfo;
+use types::EnvInfo;
 use types::Handler;
+use types::K8sConfig;
+use types::MetricsFormat;
+use types::PathRoute;
+use types::RemoteCommand;
 use types::SlackVerification;
 
+mod uptime;
+mod users;
+mod windows;
+mod workflow;
+
+/// Where Kubernetes mounts the service account of a pod
+const SERVICE_ACCOUNT_DIR: &str = "/var/run/secrets/kubernetes.io/serviceaccount";
+
 #[post("/slack_redirector", data = "<post_data>")]
 fn slack_redirector(post_data: Json<SlackVerification>) -> Json<String> {
     Json(post_data.challenge.clone())
 }
 
+/// Copy the handlers and api keys from the json files into a fresh database, so that switching
+/// backends does not lose anything. Does nothing if the database already has data
+///
+/// # Arguments
+///
+/// * `json` - The json files
+/// * `sqlite` - The database
+fn migrate(json: &JsonBackend, sqlite: &SqliteBackend) {
+    if sqlite
+        .load_handlers()
+        .map(|h| h.is_empty())
+        .unwrap_or(false)
+    {
+        if let Some(handlers) = json.load_handlers() {
+            let uris = handlers.keys().cloned().collect::<Vec<String>>();
+            match sqlite.save_handlers(&handlers, &uris) {
+                Ok(_) => println!("Migrated {} Handlers to {}", uris.len(), sqlite.describe()),
+                Err(e) => println!("Warning! Unable to migrate handlers: {}", e),
+            }
+        }
+    }
+
+    if sqlite
+        .load_api_keys()
+        .map(|k| k.is_empty())
+        .unwrap_or(false)
+    {
+        if let Some(api_keys) = json.load_api_keys() {
+            let keys = api_keys.keys().cloned().collect::<Vec<String>>();
+            match sqlite.save_api_keys(&api_keys, &keys) {
+                Ok(_) => println!("Migrated {} API Keys to {}", keys.len(), sqlite.describe()),
+                Err(e) => println!("Warning! Unable to migrate api keys: {}", e),
+            }
+        }
+    }
+}
+
+/// Open where handlers and api keys are saved
+///
+/// Set STORAGE_BACKEND=sqlite to keep them in the SQLITE_PATH database, rather than in the json
+/// files. The json files are backed up before every save, keeping the last BACKUP_COUNT, 5 by
+/// default. Set RESTORE_FROM_BACKUP=1 to restore them from their latest readable backup if
+/// they can't be read
+///
+/// # Arguments
+///
+/// * `handlers_path` - The json file of handlers
+/// * `api_keys_path` - The json file of api keys
+fn open_storage(handlers_path: &str, api_keys_path: &str) -> Storage {
+    // Replicas leave restoring to the primary, which owns the files
+    let restore = env::var("RESTORE_FROM_BACKUP")
+        .map(|v| v == "1")
+        .unwrap_or(false);
+    let read_only = env::var("READ_ONLY").map(|v| v == "1").unwrap_or(false);
+    if restore && !read_only {
+        restore_from_backup(handlers_path, load_handlers);
+        restore_from_backup(api_keys_path, load_api_keys);
+    }
+
+    let json = JsonBackend {
+        handlers_path: handlers_path.into(),
+        api_keys_path: api_keys_path.into(),
+        backups: env::var("BACKUP_COUNT")
+            .ok()
+            .map(|s| s.parse::<usize>().ok())
+            .flatten()
+            .unwrap_or(5),
+    };
+    match env::var("STORAGE_BACKEND").unwrap_or_default().as_str() {
+        "sqlite" => {
+            let sqlite_path = env::var("SQLITE_PATH").unwrap_or("majordomo.db".into());
+            let sqlite = SqliteBackend::open(&sqlite_path)
+                .unwrap_or_else(|e| panic!("Unable to open {}: {}", sqlite_path, e));
+            migrate(&json, &sqlite);
+            Box::new(sqlite)
+        }
+        _ => Box::new(json),
+    }
+}
+
+/// Hash any keys still saved in plain text, from before keys were hashed at rest, and save them
+/// hashed. Replicas only hash them in memory, and leave saving to the primary
+///
+/// # Arguments
+///
+/// * `storage` - Where the handlers and api keys are saved
+/// * `handlers` - The handlers, as loaded
+/// * `api_keys` - The api keys, as loaded
+/// * `read_only` - Whether this instance is a read-only replica
+fn hash_at_rest(
+    storage: &Storage,
+    handlers: &mut HashMap<String, Handler>,
+    api_keys: &mut HashMap<String, ApiKeyInfo>,
+    read_only: bool,
+) {
+    let uris = hash_plain_owners(handlers);
+    let keys = hash_plain_keys(api_keys);
+    if read_only {
+        return;
+    }
+
+    if !uris.is_empty() {
+        match storage.save_handlers(handlers, &uris) {
+            Ok(_) => println!("Hashed the owner keys of {} Handlers", uris.len()),
+            Err(e) => println!("Warning! Unable to save hashed handler owners: {}", e),
+        }
+    }
+    if !keys.is_empty() {
+        match storage.save_api_keys(api_keys, &keys) {
+            Ok(_) => println!("Hashed {} API Keys", keys.len() / 2),
+            Err(e) => println!("Warning! Unable to save hashed api keys: {}", e),
+        }
+    }
+}
+
+/// Read a route of `GITHUB_PATH_ROUTES`, `<owner>/<repo>:<pattern>=<handler>`
+///
+/// # Arguments
+///
+/// * `route` - The route, as it was configured
+fn parse_path_route(route: &str) -> Option<PathRoute> {
+    let mut parts = route.splitn(2, ':');
+    let repo = parts.next()?;
+    let mut rest = parts.next()?.rsplitn(2, '=');
+    let handler = rest.next()?;
+    let pattern = rest.next()?;
+
+    if !repo.contains('/') || pattern.is_empty() || handler.is_empty() {
+        return None;
+    }
+
+    Some(PathRoute {
+        repo: repo.to_lowercase(),
+        pattern: pattern.into(),
+        handler: handler.into(),
+    })
+}
+
 /// The main function of the entire program
 ///
+/// Runs the subcommand given on the command line, see `cli::app`. Without one, the server is
+/// started, as it always was
+fn main() {
+    let matches = cli::app().get_matches();
+    let stored = || {
+        let handlers_path = env::var("HANDLER_PATH").unwrap_or("handlers.json".into());
+        let api_keys_path = env::var("API_KEYS_PATH").unwrap_or("api_keys.json".into());
+        open_storage(&handlers_path, &api_keys_path)
+    };
+
+    match matches.subcommand() {
+        ("validate", Some(args)) => cli::validate(args.value_of("path").unwrap_or_default()),
+        ("export", Some(args)) => cli::export(&stored(), args.value_of("out")),
+        ("import", Some(args)) => cli::import(&stored(), args.value_of("path").unwrap_or_default()),
+        ("gen-key", Some(args)) => cli::gen_key(&stored(), args),
+        _ => serve(),
+    }
+}
+
+/// Start the server
+///
 /// Handles
 /// * Loading in environment variables, and setting defaults
 /// * Reading in any saved handlers
 /// * TODO figure out if self is reachable globally
 /// * TODO post about status on slack
 /// * Any other future initialization work
-fn main() {
+fn serve() {
     // Allow us to respond to challenge slack thing
     // Set CH_MODE=1 to respond to slack challenges
     // Does not start any of the other server stuff, so you'll need to restart without CH_MODE=1 to
@@ -54,59 +308,364 @@ fn main() {
         .flatten()
         .unwrap_or(8000);
 
+    // The fraction of successful requests to log. Failed requests are always logged
+    let log_sample_rate = env::var("LOG_SAMPLE_RATE")
+        .ok()
+        .map(|s| s.parse::<f64>().ok())
+        .flatten()
+        .unwrap_or(1.0);
+
     let handlers_path = env::var("HANDLER_PATH").unwrap_or("handlers.json".into());
 
     let api_keys_path = env::var("API_KEYS_PATH").unwrap_or("api_keys.json".into());
 
+    let data_dir = env::var("DATA_DIR").unwrap_or(".".into());
+
     let slack_token = env::var("SLACK_TOKEN").unwrap_or("no-slack".into());
 
     if slack_token == "no-slack" {
         println!("No slack token specified! This will disable slack functionality.")
     }
 
+    let slack_signing_secret = env::var("SLACK_SIGNING_SECRET")
+        .ok()
+        .filter(|s| !s.is_empty());
+
+    if slack_token != "no-slack" && slack_signing_secret.is_none() {
+        println!("No slack signing secret specified! Slack events will not be verified.")
+    }
+
+    // The slash command whose first word picks the handler, e.g. `/majordomo deploy`
+    let slack_command = env::var("SLACK_COMMAND")
+        .map(|c| c.trim_start_matches('/').to_string())
+        .ok()
+        .filter(|c| !c.is_empty())
+        .unwrap_or_else(|| "majordomo".into());
+
     let github_token = env::var("GITHUB_TOKEN").unwrap_or("no-github".into());
 
     if github_token == "no-github" {
         println!("No github token specified! This will disable github functionality.")
     }
 
-    // Load in any saved handlers
-    let handlers_raw_data = fs::read_to_string(Path::new(&handlers_path)).ok();
+    let github_webhook_secret = env::var("GITHUB_WEBHOOK_SECRET")
+        .ok()
+        .filter(|s| !s.is_empty());
+
+    // The auth token of the Twilio account, which signs its webhooks. Unset disables /twilio
+    let twilio_auth_token = env::var("TWILIO_AUTH_TOKEN").ok().filter(|t| !t.is_empty());
+
+    // Where Twilio reaches us, e.g. https://majordomo.example.com, if a proxy changes the host
+    let twilio_webhook_url = env::var("TWILIO_WEBHOOK_URL")
+        .ok()
+        .filter(|u| !u.is_empty());
+
+    // A comma separated list of <owner>/<repo>:<pattern>=<handler>, e.g.
+    // GITHUB_PATH_ROUTES=octo/mono:services/foo/**=github-foo,octo/mono:docs/=github-docs
+    // Pushes and pull requests to the repository which change matching paths go to the handler
+    let github_path_routes = env::var("GITHUB_PATH_ROUTES")
+        .unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .filter(|r| !r.is_empty())
+        .filter_map(|r| {
+            let route = parse_path_route(r);
+            if route.is_none() {
+                println!("Warning! Ignoring invalid github path route {}", r);
+            }
+            route
+        })
+        .collect::<Vec<PathRoute>>();
+
+    // Requests per minute each Client API key may make, and calls per minute each handler may
+    // take. 0 or unset means there is no limit
+    let key_rate_limit = env::var("RATE_LIMIT_PER_KEY")
+        .ok()
+        .map(|s| s.parse::<u64>().ok())
+        .flatten()
+        .unwrap_or(0);
 
-    if handlers_raw_data.is_none() {
-        println!("Warning! Unable to load any handlers!")
+    let handler_rate_limit = env::var("RATE_LIMIT_PER_HANDLER")
+        .ok()
+        .map(|s| s.parse::<u64>().ok())
+        .flatten()
+        .unwrap_or(0);
+
+    // A comma separated list of <handler>=<calls per minute>, e.g.
+    // HANDLER_RATE_LIMITS=deploy=5,weather=120, overriding RATE_LIMIT_PER_HANDLER
+    let handler_rate_limits = env::var("HANDLER_RATE_LIMITS")
+        .unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .filter(|l| !l.is_empty())
+        .filter_map(|l| {
+            let mut parts = l.rsplitn(2, '=');
+            let limit = parts.next().map(|n| n.trim().parse::<u64>().ok()).flatten();
+            let handler = parts.next().map(str::trim).filter(|h| !h.is_empty());
+            match (handler, limit) {
+                (Some(handler), Some(limit)) => Some((handler.to_string(), limit)),
+                _ => {
+                    println!("Warning! Ignoring invalid handler rate limit {}", l);
+                    None
+                }
+            }
+        })
+        .collect::<HashMap<String, u64>>();
+
+    // What Client secrets are encrypted with. Changing it makes every stored secret unreadable
+    let secrets_key = env::var("SECRETS_KEY").ok().filter(|k| !k.is_empty());
+
+    if secrets_key.is_none() {
+        println!("No secrets key specified! This will disable secrets.")
     }
 
-    let handlers: HashMap<String, Handler> = handlers_raw_data
-        .map(|data| serde_json::from_str(&data).ok())
+    // Where handlers push their metrics, e.g. METRICS_ADDR=localhost:8125, over UDP
+    let metrics_addr = env::var("METRICS_ADDR").ok().filter(|a| !a.is_empty());
+
+    // METRICS_FORMAT=statsd, the default, or METRICS_FORMAT=influx for InfluxDB line protocol
+    let metrics_format = env::var("METRICS_FORMAT")
+        .ok()
+        .map(|f| {
+            let format = MetricsFormat::parse(&f);
+            if format.is_none() {
+                println!("Warning! Ignoring unknown metrics format {}", f);
+            }
+            format
+        })
         .flatten()
-        .unwrap_or(HashMap::new());
+        .unwrap_or(MetricsFormat::Statsd);
 
-    // Load in any saved api keys
-    let api_keys_raw_data = fs::read_to_string(Path::new(&api_keys_path)).ok();
+    // The Kubernetes cluster handlers may use. Inside a cluster, its own API server and service
+    // account are used, unless K8S_API_URL and K8S_TOKEN say otherwise
+    let k8s = env::var("K8S_API_URL")
+        .ok()
+        .filter(|u| !u.is_empty())
+        .or_else(|| {
+            let host = env::var("KUBERNETES_SERVICE_HOST").ok()?;
+            let port = env::var("KUBERNETES_SERVICE_PORT").unwrap_or("443".into());
+            Some(format!("https://{}:{}", host, port))
+        })
+        .map(|api_url| {
+            let sa_dir = Path::new(SERVICE_ACCOUNT_DIR);
+            let sa_ca = sa_dir.join("ca.crt");
+            K8sConfig {
+                api_url: api_url.trim_end_matches('/').to_string(),
+                token: env::var("K8S_TOKEN").ok().filter(|t| !t.is_empty()),
+                token_path: sa_dir.join("token").to_string_lossy().into_owned(),
+                ca_cert_path: env::var("K8S_CA_CERT")
+                    .ok()
+                    .filter(|p| !p.is_empty())
+                    .or_else(|| {
+                        Some(sa_ca.to_string_lossy().into_owned()).filter(|_| sa_ca.exists())
+                    }),
+                namespace: env::var("K8S_NAMESPACE")
+                    .ok()
+                    .filter(|n| !n.is_empty())
+                    .or_else(|| fs::read_to_string(sa_dir.join("namespace")).ok())
+                    .map(|n| n.trim().to_string())
+                    .unwrap_or("default".into()),
+                // A comma separated list of scale, rollout_restart and get_pods. Unset allows none
+                verbs: env::var("K8S_VERBS")
+                    .unwrap_or_default()
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|v| !v.is_empty())
+                    .map(String::from)
+                    .collect(),
+            }
+        });
+
+    // A json file of the commands handlers may run on other hosts over ssh, by name, e.g.
+    // {"restart-staging": {"host": "staging.example.com", "user": "deploy",
+    //                      "command": "sudo systemctl restart {}", "handlers": ["ops"]}}
+    // Unset allows none
+    let remote_commands = env::var("REMOTE_COMMANDS_PATH")
+        .ok()
+        .filter(|p| !p.is_empty())
+        .map(|path| {
+            fs::read_to_string(&path)
+                .map_err(|e| e.to_string())
+                .and_then(|s| {
+                    serde_json::from_str::<HashMap<String, RemoteCommand>>(&s)
+                        .map_err(|e| e.to_string())
+                })
+                .unwrap_or_else(|e| {
+                    println!(
+                        "Warning! Unable to load remote commands from {}: {}",
+                        path, e
+                    );
+                    HashMap::new()
+                })
+        })
+        .unwrap_or_default();
+
+    // A comma separated list of the handlers whose events are kept indefinitely in ARCHIVE_DIR,
+    // e.g. ARCHIVE_HANDLERS=billing,audit, or * for all of them. Unset archives nothing
+    let archive_handlers = env::var("ARCHIVE_HANDLERS")
+        .unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .filter(|h| !h.is_empty())
+        .map(String::from)
+        .collect::<Vec<String>>();
+
+    let archive_dir = env::var("ARCHIVE_DIR").unwrap_or_else(|_| {
+        Path::new(&data_dir)
+            .join("archive")
+            .to_string_lossy()
+            .into_owned()
+    });
+
+    // Owners are sent a Slack DM about handlers which haven't run, or been saved, in this many
+    // days. 0 disables the DMs, though `/dead_handlers` still lists them
+    let dead_handler_days = env::var("DEAD_HANDLER_DAYS")
+        .ok()
+        .map(|s| s.parse::<u64>().ok())
+        .flatten()
+        .unwrap_or(cleanup::DEFAULT_DAYS);
+
+    let admin_key = env::var("ADMIN_KEY").ok().filter(|k| !k.is_empty());
+
+    if admin_key.is_none() {
+        println!("No admin key specified! This will disable administrative functionality.")
+    }
+
+    // Set READ_ONLY=1 to start a replica: invocations are served, but management writes are
+    // rejected, and the handlers and api keys are periodically reloaded from disk
+    let read_only = env::var("READ_ONLY").map(|v| v == "1").unwrap_or(false);
+
+    let replica_refresh_secs = env::var("REPLICA_REFRESH_SECS")
+        .ok()
+        .map(|s| s.parse::<u64>().ok())
+        .flatten()
+        .unwrap_or(10);
 
-    if api_keys_raw_data.is_none() {
-        println!("Warning! Unable to load any api keys!")
+    if read_only {
+        println!(
+            "Starting as a read-only replica, refreshing every {}s",
+            replica_refresh_secs
+        )
     }
 
-    let api_keys_vec: Vec<String> = api_keys_raw_data
-        .map(|data| serde_json::from_str(&data).ok())
+    // Internal services which call handlers at high volume can do so over gRPC on this port, see
+    // proto/majordomo.proto. Unset disables gRPC
+    let grpc_port = env::var("GRPC_PORT")
+        .ok()
+        .map(|s| s.parse::<u16>().ok())
+        .flatten();
+
+    // The most operations, and the longest time in milliseconds, any handler may ask for when it
+    // is saved. Handlers which don't ask get 1000 operations and 5 seconds
+    let max_operations = env::var("MAX_HANDLER_OPERATIONS")
+        .ok()
+        .map(|s| s.parse::<u64>().ok())
         .flatten()
-        .unwrap_or(Vec::new());
+        .unwrap_or(100_000);
 
-    let api_keys = HashMap::from_iter(api_keys_vec.iter().map(|i| (i.clone(), ())));
+    let max_timeout_ms = env::var("MAX_HANDLER_TIMEOUT_MS")
+        .ok()
+        .map(|s| s.parse::<u64>().ok())
+        .flatten()
+        .unwrap_or(30_000);
 
-    println!("Loaded {} Handlers from {}", handlers.len(), handlers_path);
-    println!("Loaded {} API Keys from {}", api_keys.len(), api_keys_path);
+    let static_cache_control =
+        env::var("STATIC_CACHE_CONTROL").unwrap_or("public, max-age=3600".into());
 
-    let rocket = http_server_start(
+    let alert_channel = env::var("ALERT_CHANNEL").ok().filter(|c| !c.is_empty());
+
+    let change_channel = env::var("CHANGE_APPROVAL_CHANNEL")
+        .ok()
+        .filter(|c| !c.is_empty());
+
+    let default_handler = env::var("DEFAULT_HANDLER").ok().filter(|h| !h.is_empty());
+
+    // A comma separated list of domains, e.g. HTTP_ALLOWLIST=api.example.com,example.org
+    // Handlers can't make http requests anywhere else. Unset disables http requests entirely
+    let http_allowlist = env::var("HTTP_ALLOWLIST")
+        .unwrap_or_default()
+        .split(',')
+        .map(|d| d.trim().to_lowercase())
+        .filter(|d| !d.is_empty())
+        .collect::<Vec<String>>();
+
+    // Text files attached to Slack messages up to this size are passed to handlers. 0 disables
+    let slack_download_max_bytes = env::var("SLACK_DOWNLOAD_MAX_BYTES")
+        .ok()
+        .map(|s| s.parse::<u64>().ok())
+        .flatten()
+        .unwrap_or(0);
+
+    // Handlers may upload files to Slack up to this size, 1 MiB by default. 0 disables uploads
+    let slack_upload_max_bytes = env::var("SLACK_UPLOAD_MAX_BYTES")
+        .ok()
+        .map(|s| s.parse::<u64>().ok())
+        .flatten()
+        .unwrap_or(1 << 20);
+
+    let storage = open_storage(&handlers_path, &api_keys_path);
+
+    // Load in any saved handlers
+    let mut handlers = storage.load_handlers().unwrap_or_else(|| {
+        println!("Warning! Unable to load any handlers!");
+        HashMap::new()
+    });
+
+    // Load in any saved api keys
+    let mut api_keys = storage.load_api_keys().unwrap_or_else(|| {
+        println!("Warning! Unable to load any api keys!");
+        HashMap::new()
+    });
+
+    hash_at_rest(&storage, &mut handlers, &mut api_keys, read_only);
+
+    println!(
+        "Loaded {} Handlers and {} API Keys from {}",
+        handlers.len(),
+        api_keys.len(),
+        storage.describe()
+    );
+
+    let env = EnvInfo {
         slack_token,
         github_token,
         handlers_path,
-        handlers,
-        api_keys,
+        api_keys_path,
+        data_dir,
+        admin_key,
         port,
-    );
+        log_sample_rate,
+        read_only,
+        replica_refresh_secs,
+        static_cache_control,
+        alert_channel,
+        change_channel,
+        default_handler,
+        http_allowlist,
+        slack_download_max_bytes,
+        slack_upload_max_bytes,
+        slack_signing_secret,
+        slack_command,
+        github_webhook_secret,
+        grpc_port,
+        max_operations,
+        max_timeout_ms,
+        github_path_routes,
+        twilio_auth_token,
+        twilio_webhook_url,
+        key_rate_limit,
+        handler_rate_limit,
+        handler_rate_limits,
+        secrets_key,
+        metrics_addr,
+        metrics_format,
+        k8s,
+        remote_commands,
+        archive_handlers,
+        archive_dir,
+        dead_handler_days,
+    };
+
+    let rocket = http_server_start(env, storage, handlers, api_keys);
 
     rocket.launch();
 }
@@ -0,0 +1,163 @@
+use std::net::UdpSocket;
+
+use rhai::{EvalAltResult, ImmutableString, Map, Module, FLOAT, INT};
+
+use crate::logging::CorrelationId;
+use crate::services::Services;
+use crate::types::{EnvInfo, MetricsFormat};
+
+/// The longest a metric name may be
+const MAX_NAME_CHARS: usize = 200;
+
+/// How many tags a single metric may carry, not counting the `handler` tag added to every one
+const MAX_TAGS: usize = 20;
+
+/// Pushes the metrics of handlers to StatsD or InfluxDB, over UDP
+///
+/// UDP means a slow or missing collector never holds up a handler, at the cost of losing the
+/// odd metric, which dashboards tolerate well.
+pub struct Metrics {
+    /// Unbound if no `METRICS_ADDR` is configured, which disables metrics
+    socket: Option<UdpSocket>,
+    addr: String,
+    format: MetricsFormat,
+}
+
+impl Metrics {
+    /// Get ready to push metrics to wherever the environment says
+    pub fn open(env: &EnvInfo) -> Metrics {
+        let socket = env
+            .metrics_addr
+            .as_ref()
+            .map(|_| UdpSocket::bind("0.0.0.0:0").ok())
+            .flatten();
+
+        Metrics {
+            socket,
+            addr: env.metrics_addr.clone().unwrap_or_default(),
+            format: env.metrics_format,
+        }
+    }
+
+    /// Push a metric
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - The name of the metric
+    /// * `value` - Its value
+    /// * `tags` - Its tags, as names and values. Already sanitized, see `sanitize`
+    fn emit(&self, name: &str, value: f64, tags: &[(String, String)]) -> Result<(), String> {
+        let socket = self
+            .socket
+            .as_ref()
+            .ok_or("Metrics are disabled on this server")?;
+
+        let line = match self.format {
+            MetricsFormat::Statsd => {
+                let tags = tags
+                    .iter()
+                    .map(|(k, v)| format!("{}:{}", k, v))
+                    .collect::<Vec<String>>();
+                format!("{}:{}|g|#{}", name, value, tags.join(","))
+            }
+            MetricsFormat::Influx => {
+                let tags = tags
+                    .iter()
+                    .map(|(k, v)| format!(",{}={}", k, v))
+                    .collect::<String>();
+                format!("{}{} value={}", name, tags, value)
+            }
+        };
+
+        socket
+            .send_to(line.as_bytes(), &self.addr)
+            .map(|_| ())
+            .map_err(|e| format!("Unable to send metric: {}", e))
+    }
+}
+
+/// Make a tag name or value safe for both wire formats, which give `:`, `|`, `#`, `,`, `=` and
+/// whitespace special meanings
+fn sanitize(text: &str) -> String {
+    text.chars()
+        .map(|c| match c {
+            ':' | '|' | '#' | ',' | '=' | '\\' | '"' => '_',
+            c if c.is_whitespace() => '_',
+            c => c,
+        })
+        .collect()
+}
+
+/// Check the name of a metric, so that it reads the same in either wire format
+fn check_name(name: &str) -> Result<(), String> {
+    let valid = !name.is_empty()
+        && name.chars().count() <= MAX_NAME_CHARS
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-' || c == '.');
+    if valid {
+        Ok(())
+    } else {
+        Err(format!(
+            "Metric names must be 1 to {} letters, digits, '_', '-' or '.'",
+            MAX_NAME_CHARS
+        ))
+    }
+}
+
+/// Register the metric functions available to clients
+///
+/// * `statsd_emit(name, value, tags)` pushes a gauge to the configured StatsD or InfluxDB
+///   endpoint. `value` may be an integer or a decimal, and `tags` is a map of tag names to
+///   values. Every metric is also tagged with the `handler` which emitted it
+///
+/// # Arguments
+///
+/// * `module` - The module to register the functions in
+/// * `services` - Where metrics are pushed to
+/// * `id` - The correlation id of the run, attached to every log line
+/// * `handler_addr` - The uri of the handler the functions are for
+pub fn register(module: &mut Module, services: &Services, id: &CorrelationId, handler_addr: &str) {
+    let metrics = services.metrics.clone();
+    let addr = handler_addr.to_string();
+    let cid = id.clone();
+    let emit = move |name: &str, value: f64, tags: Map| -> Result<(), Box<EvalAltResult>> {
+        check_name(name)?;
+        if tags.len() > MAX_TAGS {
+            return Err(format!("Metrics may have at most {} tags", MAX_TAGS).into());
+        }
+        if !value.is_finite() {
+            return Err("Metric values must be finite numbers".into());
+        }
+
+        let mut tags = tags
+            .iter()
+            .filter(|(k, _)| k.as_str() != "handler")
+            .map(|(k, v)| (sanitize(k), sanitize(&v.to_string())))
+            .collect::<Vec<(String, String)>>();
+        tags.push(("handler".into(), sanitize(&addr)));
+        // Influx wants tags sorted by name, and it keeps StatsD lines stable too
+        tags.sort();
+
+        metrics.emit(name, value, &tags).map_err(|e| {
+            log_event!(
+                "metrics.error",
+                id = cid.0,
+                handler = addr,
+                metric = name,
+                error = e
+            );
+            e.into()
+        })
+    };
+
+    let emit_float = emit.clone();
+    module.set_fn_3(
+        "statsd_emit",
+        move |name: ImmutableString, value: FLOAT, tags: Map| emit_float(&name, value, tags),
+    );
+    module.set_fn_3(
+        "statsd_emit",
+        move |name: ImmutableString, value: INT, tags: Map| emit(&name, value as f64, tags),
+    );
+}
@@ -0,0 +1,129 @@
+use std::collections::HashMap;
+
+use crate::types::{ApiKeyInfo, Handler};
+
+/// The uris events are routed to by what they are about, e.g. `slack-<channel>` by the channel a
+/// message was posted in, or `slash-<name>` by the name of a slash command. They name something
+/// there is only one of, so they stay global
+pub const ROUTED_PREFIXES: [&str; 4] = ["github-", "slack-", "slash-", "twilio-"];
+
+/// The shortest and longest a registered namespace may be
+const MIN_NAMESPACE_CHARS: usize = 2;
+const MAX_NAMESPACE_CHARS: usize = 32;
+
+/// How many characters of the hash of a key make up its namespace, if it has none registered
+const HASH_PREFIX_CHARS: usize = 8;
+
+/// The namespace the handlers of an API Key live in, as `<namespace>/<name>`: the team prefix
+/// registered for it, or else the start of its hash
+///
+/// # Arguments
+///
+/// * `hash` - The hash of the API Key
+/// * `api_keys` - The Client API keys
+pub fn namespace_of(hash: &str, api_keys: &HashMap<String, ApiKeyInfo>) -> String {
+    api_keys
+        .get(hash)
+        .map(|info| info.namespace.clone())
+        .flatten()
+        .unwrap_or_else(|| hash[..HASH_PREFIX_CHARS.min(hash.len())].to_string())
+}
+
+/// Check a namespace can be registered for an API Key: it is short, made of lowercase letters,
+/// digits and `-`, and no other key has it
+///
+/// # Arguments
+///
+/// * `namespace` - The namespace
+/// * `hash` - The hash of the API Key it is for
+/// * `api_keys` - The Client API keys
+pub fn check_namespace(
+    namespace: &str,
+    hash: &str,
+    api_keys: &HashMap<String, ApiKeyInfo>,
+) -> Result<(), String> {
+    let chars = namespace.chars().count();
+    let valid = (MIN_NAMESPACE_CHARS..=MAX_NAMESPACE_CHARS).contains(&chars)
+        && namespace
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-');
+    if !valid {
+        return Err(format!(
+            "Namespaces must be {} to {} lowercase letters, digits or '-'",
+            MIN_NAMESPACE_CHARS, MAX_NAMESPACE_CHARS
+        ));
+    }
+
+    let taken = api_keys
+        .keys()
+        .any(|other| other != hash && namespace_of(other, api_keys) == namespace);
+    if taken {
+        return Err(format!("The namespace {} is taken", namespace));
+    }
+    Ok(())
+}
+
+/// Whether a uri is one events are routed to, see `ROUTED_PREFIXES`
+pub fn is_routed(uri: &str) -> bool {
+    ROUTED_PREFIXES.iter().any(|p| uri.starts_with(p))
+}
+
+/// The uri a Client means by the name of one of its handlers
+///
+/// Names outside of any namespace are looked for in the Client's namespace, e.g. `deploy` is
+/// `payments/deploy`. Handlers saved before uris were namespaced keep their global uri, for as
+/// long as their owner doesn't have one of the same name in its namespace. Routed uris, and those
+/// which already name a namespace, are left as they are.
+///
+/// # Arguments
+///
+/// * `uri` - The name, or uri, the Client gave
+/// * `owner` - The hash of the Client's API Key
+/// * `namespace` - The Client's namespace, see `namespace_of`
+/// * `handlers` - The handlers, by their uris
+pub fn resolve_uri(
+    uri: &str,
+    owner: &str,
+    namespace: &str,
+    handlers: &HashMap<String, Handler>,
+) -> String {
+    if uri.contains('/') || is_routed(uri) {
+        return uri.to_string();
+    }
+
+    let namespaced = format!("{}/{}", namespace, uri);
+    let legacy = handlers
+        .get(uri)
+        .map(|h| h.api_key == owner)
+        .unwrap_or(false);
+    if legacy && !handlers.contains_key(&namespaced) {
+        uri.to_string()
+    } else {
+        namespaced
+    }
+}
+
+/// Check a Client may save a handler at a uri: a `<namespace>/<name>` uri must be in its own
+/// namespace, and have a name
+///
+/// # Arguments
+///
+/// * `uri` - The uri, see `resolve_uri`
+/// * `namespace` - The Client's namespace, see `namespace_of`
+pub fn check_uri(uri: &str, namespace: &str) -> Result<(), String> {
+    let mut parts = uri.splitn(2, '/');
+    let (prefix, name) = match (parts.next(), parts.next()) {
+        (Some(prefix), Some(name)) => (prefix, name),
+        _ => return Ok(()),
+    };
+    if prefix != namespace {
+        return Err(format!(
+            "Handlers of this API Key must be under {}/",
+            namespace
+        ));
+    }
+    if name.is_empty() || name.contains('/') {
+        return Err(format!("Expected {}/<name>, not {}", namespace, uri));
+    }
+    Ok(())
+}
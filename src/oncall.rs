@@ -0,0 +1,143 @@
+use rhai::{Array, EvalAltResult, ImmutableString, Module, INT};
+
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::clock::unix_now;
+use crate::services::Services;
+use crate::slack::slack_api;
+use crate::types::EnvInfo;
+
+/// An on-call rotation: members take turns being on call, each for `cadence_secs`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Rotation {
+    pub name: String,
+    /// The uri of the handler which set up the rotation. Only it may change the rotation, but
+    /// any handler may look up who is on call
+    pub handler: String,
+    /// The Slack user ids of the members, in the order they go on call
+    pub members: Vec<String>,
+    /// How long each shift lasts, in seconds
+    pub cadence_secs: u64,
+    /// When the first member's first shift started, as a unix timestamp
+    pub started_at: u64,
+    /// Where handoffs are announced, if anywhere
+    pub channel: Option<String>,
+    /// The number of the last shift which was announced
+    #[serde(default)]
+    pub announced_shift: Option<u64>,
+}
+
+impl Rotation {
+    /// The number of the shift in progress at `now`, counting from 0
+    fn shift(&self, now: u64) -> u64 {
+        now.saturating_sub(self.started_at) / self.cadence_secs
+    }
+
+    /// The member on call at `now`
+    pub fn on_call(&self, now: u64) -> &str {
+        let index = self.shift(now) % self.members.len() as u64;
+        &self.members[index as usize]
+    }
+}
+
+/// Register the on-call functions available to clients
+///
+/// * `oncall_rotation(name, members, cadence_secs, channel)` sets up a rotation, or changes the
+///   members, cadence and channel of an existing one without restarting it. Handoffs are
+///   announced in the channel, unless it is empty
+/// * `oncall_current(name)` returns the Slack user id of whoever is on call
+///
+/// # Arguments
+///
+/// * `module` - The module to register the functions in
+/// * `services` - Where rotations are stored
+/// * `handler_addr` - The uri of the handler the functions are for
+pub fn register(module: &mut Module, services: &Services, handler_addr: &str) {
+    let rotations = services.rotations.clone();
+    let addr = handler_addr.to_string();
+    let oncall_rotation = move |name: ImmutableString,
+                                members: Array,
+                                cadence: INT,
+                                channel: ImmutableString|
+          -> Result<(), Box<EvalAltResult>> {
+        let members = members
+            .into_iter()
+            .map(|m| m.to_string())
+            .collect::<Vec<String>>();
+
+        if members.is_empty() {
+            return Err("A rotation needs at least one member".into());
+        }
+        if cadence <= 0 {
+            return Err("A rotation needs a positive cadence".into());
+        }
+        let channel = Some(channel.to_string()).filter(|c| !c.is_empty());
+
+        rotations.update(|map| match map.get_mut(name.as_str()) {
+            Some(rotation) if rotation.handler != addr => {
+                Err(format!("The rotation {} belongs to another handler", name))
+            }
+            Some(rotation) => {
+                rotation.members = members;
+                rotation.cadence_secs = cadence as u64;
+                rotation.channel = channel;
+                Ok(())
+            }
+            None => {
+                let rotation = Rotation {
+                    name: name.to_string(),
+                    handler: addr.clone(),
+                    members,
+                    cadence_secs: cadence as u64,
+                    started_at: unix_now(),
+                    channel,
+                    announced_shift: None,
+                };
+                log_event!("oncall.create", handler = addr, rotation = name);
+                map.insert(name.to_string(), rotation);
+                Ok(())
+            }
+        })?;
+        Ok(())
+    };
+
+    let rotations = services.rotations.clone();
+    let oncall_current = move |name: ImmutableString| match rotations.read().get(name.as_str()) {
+        Some(rotation) => Ok(rotation.on_call(unix_now()).to_string()),
+        None => Err(format!("Unknown rotation {}", name).into()),
+    };
+
+    module.set_fn_4("oncall_rotation", oncall_rotation);
+    module.set_fn_1("oncall_current", oncall_current);
+}
+
+/// Announce every handoff which has happened since it was last checked. Run by the scheduler
+///
+/// # Arguments
+///
+/// * `env` - Environment variables
+/// * `services` - Where rotations are stored
+/// * `now` - The current unix timestamp
+pub fn tick(env: &EnvInfo, services: &Services, now: u64) {
+    let handoffs = services.rotations.update(|map| {
+        map.values_mut()
+            .filter(|r| r.channel.is_some() && r.announced_shift != Some(r.shift(now)))
+            .map(|r| {
+                r.announced_shift = Some(r.shift(now));
+                r.clone()
+            })
+            .collect::<Vec<Rotation>>()
+    });
+
+    let client = services.http.clone();
+    for rotation in handoffs {
+        let member = rotation.on_call(now);
+        log_event!("oncall.handoff", rotation = rotation.name, member = member);
+        let message = json!({
+            "channel": rotation.channel,
+            "text": format!(":pager: <@{}> is now on call for *{}*", member, rotation.name)
+        });
+        let _ = slack_api(&client, &env.slack_token, "chat.postMessage", &message);
+    }
+}
@@ -0,0 +1,231 @@
+use std::collections::HashMap;
+
+use rhai::{Array, Dynamic, EvalAltResult, ImmutableString, Map, Module, INT};
+
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::clock::unix_now;
+use crate::services::Services;
+use crate::slack::slack_api;
+use crate::storage::new_id;
+use crate::types::EnvInfo;
+
+/// The action id prefix of the option buttons on poll messages
+pub const VOTE_ACTION: &str = "majordomo_vote";
+
+/// The most options a poll may have. Slack allows at most 25 elements in an actions block
+const MAX_OPTIONS: usize = 25;
+
+/// A poll posted to Slack by a handler
+///
+/// Votes are cast with the buttons on the poll message. Reactions are not counted, since we do
+/// not subscribe to Slack's reaction events.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Poll {
+    pub id: String,
+    /// The uri of the handler which created the poll
+    pub handler: String,
+    pub channel: String,
+    pub question: String,
+    pub options: Vec<String>,
+    /// The option each user voted for, indexed by Slack user id. Users may change their vote
+    pub votes: HashMap<String, usize>,
+    /// When voting ends, and results are posted, as a unix timestamp
+    pub deadline: u64,
+    pub closed: bool,
+}
+
+impl Poll {
+    /// How many votes each option got, in the order of the options
+    pub fn tally(&self) -> Vec<(String, usize)> {
+        let mut counts = vec![0; self.options.len()];
+        for choice in self.votes.values() {
+            if let Some(count) = counts.get_mut(*choice) {
+                *count += 1;
+            }
+        }
+        self.options.iter().cloned().zip(counts).collect()
+    }
+
+    /// The results, formatted for Slack
+    fn results_text(&self) -> String {
+        let mut text = format!("*Poll closed:* {}\n", self.question);
+        let mut tally = self.tally();
+        tally.sort_by(|a, b| b.1.cmp(&a.1));
+        for (option, count) in tally {
+            text.push_str(&format!(
+                "• {}: {} vote{}\n",
+                option,
+                count,
+                if count == 1 { "" } else { "s" }
+            ));
+        }
+        text
+    }
+}
+
+/// Register the poll functions available to clients
+///
+/// * `poll_create(channel, question, options, duration_secs)` posts a poll, with a button per
+///   option, and returns its id. Results are posted to the channel once the duration is up
+/// * `poll_results(poll_id)` returns a map of each option to its number of votes so far
+///
+/// # Arguments
+///
+/// * `module` - The module to register the functions in
+/// * `env` - Environment variables
+/// * `services` - Where polls are stored
+/// * `handler_addr` - The uri of the handler the functions are for
+pub fn register(module: &mut Module, env: &EnvInfo, services: &Services, handler_addr: &str) {
+    let client = services.http.clone();
+    let slack_token = env.slack_token.clone();
+    let polls = services.polls.clone();
+    let addr = handler_addr.to_string();
+    let poll_create = move |channel: ImmutableString,
+                            question: ImmutableString,
+                            options: Array,
+                            duration: INT|
+          -> Result<String, Box<EvalAltResult>> {
+        let options = options
+            .into_iter()
+            .map(|o| o.to_string())
+            .collect::<Vec<String>>();
+
+        if options.len() < 2 || options.len() > MAX_OPTIONS {
+            return Err(format!("A poll needs between 2 and {} options", MAX_OPTIONS).into());
+        }
+        if duration <= 0 {
+            return Err("A poll needs a positive duration".into());
+        }
+
+        let poll = Poll {
+            id: new_id(),
+            handler: addr.clone(),
+            channel: channel.to_string(),
+            question: question.to_string(),
+            options,
+            votes: HashMap::new(),
+            deadline: unix_now() + duration as u64,
+            closed: false,
+        };
+
+        slack_api(
+            &client,
+            &slack_token,
+            "chat.postMessage",
+            &poll_message(&poll),
+        )?;
+
+        log_event!(
+            "poll.create",
+            handler = addr,
+            poll = poll.id,
+            channel = channel
+        );
+
+        let id = poll.id.clone();
+        polls.update(|map| map.insert(id.clone(), poll));
+        Ok(id)
+    };
+
+    let polls = services.polls.clone();
+    let poll_results = move |id: ImmutableString| match polls.read().get(id.as_str()) {
+        Some(poll) => {
+            let mut results = Map::new();
+            for (option, count) in poll.tally() {
+                results.insert(option.into(), Dynamic::from(count as INT));
+            }
+            Ok(results)
+        }
+        None => Err(format!("Unknown poll {}", id).into()),
+    };
+
+    module.set_fn_4("poll_create", poll_create);
+    module.set_fn_1("poll_results", poll_results);
+}
+
+/// The Slack message presenting a poll
+fn poll_message(poll: &Poll) -> serde_json::Value {
+    let buttons = poll
+        .options
+        .iter()
+        .enumerate()
+        .map(|(i, option)| {
+            json!({
+                "type": "button",
+                "text": { "type": "plain_text", "text": option },
+                "action_id": format!("{}_{}", VOTE_ACTION, i),
+                "value": format!("{}:{}", poll.id, i)
+            })
+        })
+        .collect::<Vec<serde_json::Value>>();
+
+    json!({
+        "channel": poll.channel,
+        "text": format!("Poll: {}", poll.question),
+        "blocks": [
+            {
+                "type": "section",
+                "text": { "type": "mrkdwn", "text": format!("*{}*", poll.question) }
+            },
+            { "type": "actions", "elements": buttons }
+        ]
+    })
+}
+
+/// Record a user's vote
+///
+/// Returns the option voted for, or why the vote was refused
+///
+/// # Arguments
+///
+/// * `services` - Where polls are stored
+/// * `value` - The value of the clicked button, `<poll id>:<option index>`
+/// * `user` - The Slack user id of the voter
+pub fn vote(services: &Services, value: &str, user: &str) -> Result<String, String> {
+    let mut parts = value.splitn(2, ':');
+    let poll_id = parts.next().unwrap_or_default();
+    let choice = parts
+        .next()
+        .and_then(|c| c.parse::<usize>().ok())
+        .ok_or("Invalid vote")?;
+
+    services.polls.update(|map| -> Result<String, String> {
+        let poll = map.get_mut(poll_id).ok_or("This poll no longer exists")?;
+
+        if poll.closed || poll.deadline <= unix_now() {
+            return Err("This poll is closed".to_string());
+        }
+
+        let option = poll.options.get(choice).cloned().ok_or("Invalid vote")?;
+        poll.votes.insert(user.to_string(), choice);
+        Ok(option)
+    })
+}
+
+/// Close every poll whose deadline has passed, and post its results. Run by the scheduler
+///
+/// # Arguments
+///
+/// * `env` - Environment variables
+/// * `services` - Where polls are stored
+/// * `now` - The current unix timestamp
+pub fn tick(env: &EnvInfo, services: &Services, now: u64) {
+    let due = services.polls.update(|map| {
+        map.values_mut()
+            .filter(|p| !p.closed && p.deadline <= now)
+            .map(|p| {
+                p.closed = true;
+                p.clone()
+            })
+            .collect::<Vec<Poll>>()
+    });
+
+    let client = services.http.clone();
+    for poll in due {
+        log_event!("poll.close", poll = poll.id, votes = poll.votes.len());
+        let message = json!({ "channel": poll.channel, "text": poll.results_text() });
+        let _ = slack_api(&client, &env.slack_token, "chat.postMessage", &message);
+    }
+}
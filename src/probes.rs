@@ -0,0 +1,174 @@
+use std::net::{TcpStream, ToSocketAddrs};
+use std::str::FromStr;
+use std::time::Duration;
+
+use openssl::asn1::Asn1Time;
+use openssl::ssl::{SslConnector, SslMethod, SslVerifyMode};
+
+use reqwest::Url;
+
+use rhai::{Array, Dynamic, EvalAltResult, ImmutableString, Module, INT};
+
+use trust_dns_resolver::error::ResolveErrorKind;
+use trust_dns_resolver::proto::rr::{RData, RecordType};
+use trust_dns_resolver::Resolver;
+
+use crate::http_client::is_allowed;
+use crate::logging::CorrelationId;
+use crate::types::EnvInfo;
+
+/// How long connecting to a host and finishing the TLS handshake may take
+const TLS_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The record types handlers may look up
+const RECORD_TYPES: [&str; 6] = ["A", "AAAA", "CNAME", "MX", "NS", "TXT"];
+
+/// Look up the records of a name, as text
+///
+/// MX records read `<preference> <exchange>`, and the strings of a TXT record are joined.
+///
+/// # Arguments
+///
+/// * `name` - The name to look up, e.g. `example.com`
+/// * `record_type` - Which records, one of `RECORD_TYPES`
+fn lookup(name: &str, record_type: &str) -> Result<Vec<String>, String> {
+    let record_type = record_type.to_uppercase();
+    if !RECORD_TYPES.contains(&record_type.as_str()) {
+        return Err(format!(
+            "Record type must be one of {}",
+            RECORD_TYPES.join(", ")
+        ));
+    }
+    let record_type = RecordType::from_str(&record_type).map_err(|e| e.to_string())?;
+
+    let resolver = Resolver::from_system_conf()
+        .map_err(|e| format!("Unable to set up a DNS resolver: {}", e))?;
+    let records = match resolver.lookup(name, record_type) {
+        Ok(records) => records,
+        // A name without records of the type is an answer, not a failure
+        Err(e) => match e.kind() {
+            ResolveErrorKind::NoRecordsFound { .. } => return Ok(Vec::new()),
+            _ => return Err(format!("Unable to look up {}: {}", name, e)),
+        },
+    };
+
+    Ok(records
+        .iter()
+        .filter_map(|rdata| match rdata {
+            RData::A(ip) => Some(ip.to_string()),
+            RData::AAAA(ip) => Some(ip.to_string()),
+            RData::CNAME(name) | RData::NS(name) => Some(name.to_string()),
+            RData::MX(mx) => Some(format!("{} {}", mx.preference(), mx.exchange())),
+            RData::TXT(txt) => Some(
+                txt.txt_data()
+                    .iter()
+                    .map(|data| String::from_utf8_lossy(data).into_owned())
+                    .collect::<String>(),
+            ),
+            _ => None,
+        })
+        .collect())
+}
+
+/// When the certificate a host presents expires, as a unix timestamp
+///
+/// The certificate is not verified, so expired and self-signed certificates can be checked too.
+///
+/// # Arguments
+///
+/// * `host` - The host, e.g. `example.com`
+/// * `port` - The port it serves TLS on
+fn cert_expiry(host: &str, port: u16) -> Result<INT, String> {
+    let addr = (host, port)
+        .to_socket_addrs()
+        .map_err(|e| format!("Unable to resolve {}: {}", host, e))?
+        .next()
+        .ok_or_else(|| format!("Unable to resolve {}", host))?;
+    let stream = TcpStream::connect_timeout(&addr, TLS_TIMEOUT).map_err(|e| e.to_string())?;
+    stream
+        .set_read_timeout(Some(TLS_TIMEOUT))
+        .and_then(|_| stream.set_write_timeout(Some(TLS_TIMEOUT)))
+        .map_err(|e| e.to_string())?;
+
+    let mut builder = SslConnector::builder(SslMethod::tls()).map_err(|e| e.to_string())?;
+    builder.set_verify(SslVerifyMode::NONE);
+    let tls = builder
+        .build()
+        .configure()
+        .map_err(|e| e.to_string())?
+        .verify_hostname(false)
+        .connect(host, stream)
+        .map_err(|e| format!("TLS handshake with {} failed: {}", host, e))?;
+
+    let cert = tls
+        .ssl()
+        .peer_certificate()
+        .ok_or_else(|| format!("{} presented no certificate", host))?;
+    let diff = Asn1Time::from_unix(0)
+        .and_then(|epoch| epoch.diff(cert.not_after()))
+        .map_err(|e| e.to_string())?;
+    Ok(diff.days as INT * 86_400 + diff.secs as INT)
+}
+
+/// Register the network inspection functions available to clients, for monitoring handlers
+///
+/// * `dns_lookup(name, type)` returns the records of `name` as an array of strings, e.g.
+///   `dns_lookup("example.com", "MX")`. Types are A, AAAA, CNAME, MX, NS and TXT. The array is
+///   empty if there are none
+/// * `tls_cert_expiry(host)` returns when the certificate `host` presents expires, as a unix
+///   timestamp. `host` may include a port, e.g. `example.com:8443`, and must be allowed by the
+///   http allowlist, so handlers can't probe arbitrary hosts
+///
+/// # Arguments
+///
+/// * `module` - The module to register the functions in
+/// * `env` - Environment variables, for the http allowlist
+/// * `id` - The correlation id of the run, attached to every log line
+/// * `handler_addr` - The uri of the handler the functions are for
+pub fn register(module: &mut Module, env: &EnvInfo, id: &CorrelationId, handler_addr: &str) {
+    let addr = handler_addr.to_string();
+    let cid = id.clone();
+    let dns_lookup = move |name: ImmutableString,
+                           record_type: ImmutableString|
+          -> Result<Array, Box<EvalAltResult>> {
+        log_event!(
+            "dns.lookup",
+            id = cid.0,
+            handler = addr,
+            name = name,
+            record_type = record_type
+        );
+        Ok(lookup(&name, &record_type)?
+            .into_iter()
+            .map(Dynamic::from)
+            .collect())
+    };
+
+    let allowlist = env.http_allowlist.clone();
+    let addr = handler_addr.to_string();
+    let cid = id.clone();
+    let tls_cert_expiry = move |host: ImmutableString| -> Result<INT, Box<EvalAltResult>> {
+        let url = Url::parse(&format!("https://{}", host))
+            .map_err(|e| format!("Invalid host {}: {}", host, e))?;
+        if !is_allowed(&url, &allowlist) {
+            return Err(format!("Connections to {} are not allowed", host).into());
+        }
+        let hostname = url.host_str().unwrap_or_default();
+        let port = url.port().unwrap_or(443);
+
+        log_event!("tls.cert_expiry", id = cid.0, handler = addr, host = host);
+        cert_expiry(hostname, port).map_err(|e| {
+            log_event!(
+                "tls.error",
+                id = cid.0,
+                handler = addr,
+                host = host,
+                error = e
+            );
+            e.into()
+        })
+    };
+
+    module.set_fn_2("dns_lookup", dns_lookup);
+    module.set_fn_1("tls_cert_expiry", tls_cert_expiry);
+}
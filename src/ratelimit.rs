@@ -0,0 +1,175 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Instant;
+
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::http::{Header, Status};
+use rocket::request::{self, FromRequest};
+use rocket::{Outcome, Request, Response, State};
+
+/// How many buckets are kept before the full ones, i.e. those of anything which hasn't been
+/// used in a while, are dropped
+const MAX_BUCKETS: usize = 10_000;
+
+/// The requests left to something which is rate limited
+struct Bucket {
+    /// How many requests may be made right away
+    tokens: f64,
+    /// The limit, in requests per minute, the bucket last refilled at
+    per_minute: u64,
+    /// When the bucket was last refilled
+    updated: Instant,
+}
+
+impl Bucket {
+    /// The tokens in the bucket at `now`, after refilling
+    fn tokens_at(&self, now: Instant) -> f64 {
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        let capacity = self.per_minute as f64;
+        (self.tokens + elapsed * capacity / 60.0).min(capacity)
+    }
+}
+
+/// Token buckets, limiting how often each API Key and handler may be used
+///
+/// Each bucket holds a minute's worth of requests, and refills steadily, so short bursts are
+/// fine, but sustained use is held to the limit.
+#[derive(Default)]
+pub struct RateLimits(Mutex<HashMap<String, Bucket>>);
+
+impl RateLimits {
+    /// Take a token from a bucket
+    /// Returns how many seconds until a token is available, if the bucket is empty
+    ///
+    /// # Arguments
+    ///
+    /// * `bucket` - What is rate limited, e.g. `handler:<uri>`
+    /// * `per_minute` - The limit, in requests per minute. Must not be 0
+    /// * `now` - The current time
+    fn take(&self, bucket: &str, per_minute: u64, now: Instant) -> Result<(), u64> {
+        let mut buckets = self.0.lock().unwrap();
+
+        // Don't let lots of one-off callers grow this forever
+        if buckets.len() > MAX_BUCKETS {
+            buckets.retain(|_, b| b.tokens_at(now) < b.per_minute as f64);
+        }
+
+        let entry = buckets.entry(bucket.to_string()).or_insert(Bucket {
+            tokens: per_minute as f64,
+            per_minute,
+            updated: now,
+        });
+        entry.tokens = entry.tokens_at(now).min(per_minute as f64);
+        entry.per_minute = per_minute;
+        entry.updated = now;
+
+        if entry.tokens >= 1.0 {
+            entry.tokens -= 1.0;
+            Ok(())
+        } else {
+            let wait = (1.0 - entry.tokens) * 60.0 / per_minute as f64;
+            Err(wait.ceil().max(1.0) as u64)
+        }
+    }
+}
+
+/// When a request refused by a rate limit may be retried, in seconds. 0 if it wasn't refused
+#[derive(Default)]
+pub struct RetryAfter(AtomicU64);
+
+impl RetryAfter {
+    pub fn seconds(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Rocket Request Guard which checks rate limits
+///
+/// When a request is refused, this remembers when it may be retried, so that `RetryAfterHeader`
+/// can tell the client.
+pub struct Throttle<'a> {
+    limits: &'a RateLimits,
+    retry_after: &'a RetryAfter,
+}
+
+impl<'a> Throttle<'a> {
+    /// A throttle for requests which don't come through Rocket, e.g. over gRPC
+    ///
+    /// # Arguments
+    ///
+    /// * `limits` - The buckets to count requests against
+    /// * `retry_after` - Where to remember when a refused request may be retried
+    pub fn new(limits: &'a RateLimits, retry_after: &'a RetryAfter) -> Throttle<'a> {
+        Throttle {
+            limits,
+            retry_after,
+        }
+    }
+
+    /// Count a request against a bucket, failing with the cause if it is over the limit
+    ///
+    /// # Arguments
+    ///
+    /// * `bucket` - What is rate limited, e.g. `handler:<uri>`
+    /// * `per_minute` - The limit, in requests per minute. 0 means there is none
+    pub fn check(&self, bucket: &str, per_minute: u64) -> Result<(), String> {
+        if per_minute == 0 {
+            return Ok(());
+        }
+
+        match self.limits.take(bucket, per_minute, Instant::now()) {
+            Ok(_) => Ok(()),
+            Err(wait) => {
+                self.retry_after.0.store(wait, Ordering::Relaxed);
+                log_event!("ratelimit.exceeded", bucket = bucket, retry_after = wait);
+                Err(format!(
+                    "Rate limit exceeded, try again in {} seconds",
+                    wait
+                ))
+            }
+        }
+    }
+}
+
+impl<'a, 'r> FromRequest<'a, 'r> for Throttle<'a> {
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Throttle<'a>, ()> {
+        let limits = match request.guard::<State<RateLimits>>() {
+            Outcome::Success(limits) => limits.inner(),
+            _ => return Outcome::Failure((Status::InternalServerError, ())),
+        };
+
+        Outcome::Success(Throttle {
+            limits,
+            retry_after: request.local_cache(RetryAfter::default),
+        })
+    }
+}
+
+/// Rocket Fairing which turns responses to requests refused by a rate limit into
+/// `429 Too Many Requests`, with a `Retry-After` header
+///
+/// The body is left alone, so clients still get the usual failed `UserResponse`.
+pub struct RetryAfterHeader;
+
+impl Fairing for RetryAfterHeader {
+    fn info(&self) -> Info {
+        Info {
+            name: "Retry-After Header",
+            kind: Kind::Response,
+        }
+    }
+
+    fn on_response(&self, request: &Request, response: &mut Response) {
+        let wait = request
+            .local_cache(RetryAfter::default)
+            .0
+            .load(Ordering::Relaxed);
+        if wait > 0 {
+            response.set_status(Status::TooManyRequests);
+            response.set_header(Header::new("Retry-After", wait.to_string()));
+        }
+    }
+}
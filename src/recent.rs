@@ -0,0 +1,39 @@
+use std::collections::{HashSet, VecDeque};
+use std::sync::Mutex;
+
+/// The ids of the most recently seen events, e.g. to tell a retry apart from a new event
+///
+/// At most `capacity` ids are remembered, the oldest being forgotten first.
+pub struct RecentIds {
+    capacity: usize,
+    seen: Mutex<(VecDeque<String>, HashSet<String>)>,
+}
+
+impl RecentIds {
+    pub fn new(capacity: usize) -> RecentIds {
+        RecentIds {
+            capacity,
+            seen: Mutex::new((VecDeque::new(), HashSet::new())),
+        }
+    }
+
+    /// Remember an id, returning false if it was seen already
+    ///
+    /// # Arguments
+    ///
+    /// * `id` - The id of the event
+    pub fn insert(&self, id: &str) -> bool {
+        let mut guard = self.seen.lock().unwrap();
+        let (order, ids) = &mut *guard;
+        if !ids.insert(id.to_string()) {
+            return false;
+        }
+        order.push_back(id.to_string());
+        if order.len() > self.capacity {
+            if let Some(oldest) = order.pop_front() {
+                ids.remove(&oldest);
+            }
+        }
+        true
+    }
+}
@@ -0,0 +1,236 @@
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::http_client;
+use crate::logging::CorrelationId;
+use crate::services::Services;
+use crate::slack::slack_api;
+use crate::types::EnvInfo;
+
+/// A handler which only reshapes what it is sent and forwards it, declared rather than written,
+/// e.g. a GitHub webhook posted to Slack as a one line summary
+///
+/// Relays don't run any code, so they answer without starting a scripting engine. Anything
+/// which needs a decision, or more than one destination, is still a job for a handler's code.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Relay {
+    /// The shape of what is forwarded, with placeholders filled in from the payload, see
+    /// `render`, e.g. `{"text": "{{pull_request.title}} was merged"}`. The payload as it is, if
+    /// None
+    #[serde(default)]
+    pub template: Option<Value>,
+    /// The url to post the result to, as json. Must be allowed by `HTTP_ALLOWLIST`
+    #[serde(default)]
+    pub url: Option<String>,
+    /// The Slack channel to post the result to. A string result is the text of the message,
+    /// a map the arguments of `chat.postMessage`, e.g. with `blocks`
+    #[serde(default)]
+    pub slack_channel: Option<String>,
+}
+
+impl Relay {
+    /// Check the relay makes sense, before it is saved: it has exactly one destination
+    pub fn check(&self) -> Result<(), String> {
+        match (&self.url, &self.slack_channel) {
+            (Some(_), Some(_)) => {
+                Err("A relay has either a url or a slack_channel, not both".into())
+            }
+            (None, None) => Err("A relay needs a url or a slack_channel to forward to".into()),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Find the value at a path in the payload, e.g. `pull_request.user.login`, or `commits.0.id`
+/// for the first item of a list. `.` is the whole payload
+fn lookup<'a>(payload: &'a Value, path: &str) -> Option<&'a Value> {
+    if path == "." {
+        return Some(payload);
+    }
+    path.split('.').try_fold(payload, |value, key| match value {
+        Value::Array(items) => key.parse::<usize>().ok().map(|i| items.get(i)).flatten(),
+        _ => value.get(key),
+    })
+}
+
+/// The text of a value, as it is substituted into a longer string. Missing values are empty
+fn text_of(value: Option<&Value>) -> String {
+    match value {
+        None | Some(Value::Null) => String::new(),
+        Some(Value::String(text)) => text.clone(),
+        Some(other) => other.to_string(),
+    }
+}
+
+/// Fill in the `{{path}}` placeholders of a string, see `lookup`
+///
+/// A string which is nothing but one placeholder becomes the value itself, so that numbers,
+/// lists and maps keep their type, e.g. `"{{commits}}"`.
+fn render_string(text: &str, payload: &Value) -> Value {
+    let trimmed = text.trim();
+    if trimmed.starts_with("{{") && trimmed.ends_with("}}") && trimmed.matches("{{").count() == 1 {
+        let path = trimmed[2..trimmed.len() - 2].trim();
+        return lookup(payload, path).cloned().unwrap_or(Value::Null);
+    }
+
+    let mut rendered = String::new();
+    let mut rest = text;
+    while let Some(start) = rest.find("{{") {
+        rendered.push_str(&rest[..start]);
+        let after = &rest[start + 2..];
+        match after.find("}}") {
+            Some(end) => {
+                rendered.push_str(&text_of(lookup(payload, after[..end].trim())));
+                rest = &after[end + 2..];
+            }
+            None => {
+                rendered.push_str(&rest[start..]);
+                rest = "";
+            }
+        }
+    }
+    rendered.push_str(rest);
+    Value::String(rendered)
+}
+
+/// Fill in every placeholder of a template, in any of its strings, see `render_string`
+///
+/// # Arguments
+///
+/// * `template` - The template
+/// * `payload` - What the relay was sent, parsed as json if it is
+pub fn render(template: &Value, payload: &Value) -> Value {
+    match template {
+        Value::String(text) => render_string(text, payload),
+        Value::Array(items) => Value::Array(items.iter().map(|t| render(t, payload)).collect()),
+        Value::Object(fields) => Value::Object(
+            fields
+                .iter()
+                .map(|(key, t)| (key.clone(), render(t, payload)))
+                .collect(),
+        ),
+        other => other.clone(),
+    }
+}
+
+/// Reshape a payload with a relay's template, and forward it to its destination
+///
+/// Returns what the destination answered: the body of the response of a url, or the ts of the
+/// message posted to Slack
+///
+/// # Arguments
+///
+/// * `env` - Environment variables, for the allowed domains and the Slack token
+/// * `services` - The shared http client
+/// * `id` - The correlation id to attach to every log line
+/// * `handler_addr` - The uri of the relay
+/// * `relay` - The relay
+/// * `payload` - What the relay was sent
+pub fn forward(
+    env: &EnvInfo,
+    services: &Services,
+    id: &CorrelationId,
+    handler_addr: &str,
+    relay: &Relay,
+    payload: &str,
+) -> Result<String, String> {
+    let parsed = serde_json::from_str(payload).unwrap_or_else(|_| Value::String(payload.into()));
+    let body = match &relay.template {
+        Some(template) => render(template, &parsed),
+        None => parsed,
+    };
+
+    if let Some(url) = &relay.url {
+        log_event!(
+            "relay.forward",
+            id = id.0,
+            handler = handler_addr,
+            url = url
+        );
+        return http_client::post(
+            &services.http,
+            &env.http_allowlist,
+            url,
+            body.to_string(),
+            "application/json",
+        );
+    }
+
+    let channel = relay.slack_channel.clone().unwrap_or_default();
+    log_event!(
+        "relay.forward",
+        id = id.0,
+        handler = handler_addr,
+        channel = channel
+    );
+    let message = match body {
+        Value::Object(mut fields) => {
+            fields.insert("channel".into(), Value::String(channel));
+            Value::Object(fields)
+        }
+        other => json!({ "channel": channel, "text": text_of(Some(&other)) }),
+    };
+    let response = slack_api(
+        &services.http,
+        &env.slack_token,
+        "chat.postMessage",
+        &message,
+    )?;
+    Ok(text_of(response.get("ts")))
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn push() -> Value {
+        json!({
+            "ref": "refs/heads/main",
+            "pusher": { "name": "octocat" },
+            "commits": [{ "id": "abc123", "added": 2 }, { "id": "def456" }]
+        })
+    }
+
+    #[test]
+    fn placeholders_are_filled_in_from_the_payload() {
+        let template = json!({
+            "text": "{{pusher.name}} pushed {{ commits.0.id }} to {{ref}}",
+            "missing": "[{{nothing.here}}]",
+            "unclosed": "{{ref",
+            "kept": 42
+        });
+        assert_eq!(
+            render(&template, &push()),
+            json!({
+                "text": "octocat pushed abc123 to refs/heads/main",
+                "missing": "[]",
+                "unclosed": "{{ref",
+                "kept": 42
+            })
+        );
+    }
+
+    #[test]
+    fn a_lone_placeholder_keeps_the_type_of_its_value() {
+        let template = json!([
+            "{{commits.0.added}}",
+            "{{commits}}",
+            " {{.}} ",
+            "{{nothing}}"
+        ]);
+        let rendered = render(&template, &push());
+        assert_eq!(rendered[0], json!(2));
+        assert_eq!(rendered[1], push()["commits"]);
+        assert_eq!(rendered[2], push());
+        assert_eq!(rendered[3], Value::Null);
+    }
+
+    #[test]
+    fn payloads_which_are_not_json_can_still_be_relayed() {
+        let payload = Value::String("hello".into());
+        assert_eq!(render(&json!("said {{.}}"), &payload), json!("said hello"));
+        assert_eq!(render(&json!("{{text}}"), &payload), Value::Null);
+    }
+}
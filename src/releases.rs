@@ -0,0 +1,270 @@
+use std::collections::BTreeSet;
+
+use reqwest::blocking::Client;
+
+use rhai::{Array, Dynamic, EvalAltResult, ImmutableString, Map, Module, INT};
+
+use serde_json::{json, Value};
+
+use crate::github::{check_ref, check_repo, rest_get, rest_post};
+use crate::logging::CorrelationId;
+use crate::services::Services;
+use crate::types::EnvInfo;
+
+/// How many pull requests a draft describes, at most
+const MAX_RELEASE_PRS: usize = 100;
+
+/// The sections of a draft, in order, with the labels which put a pull request in them.
+/// Pull requests with none of these labels go under "Other Changes"
+const SECTIONS: [(&str, &[&str]); 4] = [
+    ("Breaking Changes", &["breaking", "breaking-change"]),
+    ("Features", &["feature", "enhancement"]),
+    ("Bug Fixes", &["bug", "fix", "bugfix"]),
+    ("Documentation", &["docs", "documentation"]),
+];
+
+/// A merged pull request, as described in a draft
+struct PullRequest {
+    number: u64,
+    title: String,
+    author: String,
+    labels: Vec<String>,
+    url: String,
+}
+
+impl PullRequest {
+    /// The section of the draft the pull request goes in
+    fn section(&self) -> &'static str {
+        SECTIONS
+            .iter()
+            .find(|(_, labels)| {
+                self.labels
+                    .iter()
+                    .any(|l| labels.contains(&l.to_lowercase().as_str()))
+            })
+            .map(|(name, _)| *name)
+            .unwrap_or("Other Changes")
+    }
+
+    /// The pull request as a map, for handlers
+    fn to_map(&self) -> Map {
+        let mut map = Map::new();
+        map.insert("number".into(), Dynamic::from(self.number as INT));
+        map.insert("title".into(), Dynamic::from(self.title.clone()));
+        map.insert("author".into(), Dynamic::from(self.author.clone()));
+        map.insert(
+            "labels".into(),
+            Dynamic::from(
+                self.labels
+                    .iter()
+                    .cloned()
+                    .map(Dynamic::from)
+                    .collect::<Array>(),
+            ),
+        );
+        map.insert("section".into(), Dynamic::from(self.section().to_string()));
+        map.insert("url".into(), Dynamic::from(self.url.clone()));
+        map
+    }
+}
+
+/// The number of the pull request a commit merged, if its message says
+///
+/// Understands merge commits, `Merge pull request #12 from ...`, and squashed ones, whose
+/// first line ends with `(#12)`.
+///
+/// # Arguments
+///
+/// * `message` - The commit message
+fn pr_number(message: &str) -> Option<u64> {
+    let first = message.lines().next().unwrap_or_default().trim();
+    if first.starts_with("Merge pull request #") {
+        let rest = first.trim_start_matches("Merge pull request #");
+        return rest.split_whitespace().next()?.parse().ok();
+    }
+    if first.ends_with(')') {
+        let start = first.rfind("(#")?;
+        return first[start + 2..first.len() - 1].parse().ok();
+    }
+    None
+}
+
+/// The pull requests merged between two tags, oldest first, up to `MAX_RELEASE_PRS` of them
+///
+/// # Arguments
+///
+/// * `client` - A reqwest HTTP "client" to make the requests
+/// * `token` - The github token to authenticate with
+/// * `repo` - The full name of the repository, `<owner>/<repo>`
+/// * `from` - The tag of the previous release
+/// * `to` - The tag, branch or commit being released
+fn merged_prs(
+    client: &Client,
+    token: &str,
+    repo: &str,
+    from: &str,
+    to: &str,
+) -> Result<Vec<PullRequest>, String> {
+    check_repo(repo)?;
+    check_ref(from)?;
+    check_ref(to)?;
+
+    let path = format!("/repos/{}/compare/{}...{}", repo, from, to);
+    let resp = rest_get(client, token, &path, &[])?;
+
+    let mut seen = BTreeSet::new();
+    let numbers = resp["commits"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(|c| pr_number(c["commit"]["message"].as_str().unwrap_or_default()))
+        .filter(|n| seen.insert(*n))
+        .take(MAX_RELEASE_PRS)
+        .collect::<Vec<u64>>();
+
+    numbers
+        .into_iter()
+        .map(|number| {
+            let pr = rest_get(
+                client,
+                token,
+                &format!("/repos/{}/pulls/{}", repo, number),
+                &[],
+            )?;
+            let text = |v: &Value| v.as_str().unwrap_or_default().to_string();
+            Ok(PullRequest {
+                number,
+                title: text(&pr["title"]),
+                author: text(&pr["user"]["login"]),
+                labels: pr["labels"]
+                    .as_array()
+                    .into_iter()
+                    .flatten()
+                    .map(|l| text(&l["name"]))
+                    .collect(),
+                url: text(&pr["html_url"]),
+            })
+        })
+        .collect()
+}
+
+/// Group pull requests into a markdown draft of release notes
+///
+/// # Arguments
+///
+/// * `from`, `to` - The range the pull requests were merged in, for the heading
+/// * `prs` - The pull requests
+fn render(from: &str, to: &str, prs: &[PullRequest]) -> String {
+    let mut markdown = format!("## Changes from {} to {}\n", from, to);
+    if prs.is_empty() {
+        markdown.push_str("\nNo pull requests were merged.\n");
+        return markdown;
+    }
+
+    let names = SECTIONS
+        .iter()
+        .map(|(name, _)| *name)
+        .chain(std::iter::once("Other Changes"));
+    for name in names {
+        let section = prs.iter().filter(|pr| pr.section() == name);
+        let mut lines = section
+            .map(|pr| format!("- {} (#{}) @{}\n", pr.title, pr.number, pr.author))
+            .peekable();
+        if lines.peek().is_none() {
+            continue;
+        }
+        markdown.push_str(&format!("\n### {}\n\n", name));
+        lines.for_each(|line| markdown.push_str(&line));
+    }
+    markdown
+}
+
+/// Register the release functions available to clients
+///
+/// * `github_release_notes(repo, from_tag, to_tag)` drafts release notes from the pull
+///   requests merged between two tags (or a tag and a branch). Returns a map with the
+///   `markdown` of the draft, grouped into sections by label, and the `prs` it describes, each
+///   a map of `number`, `title`, `author`, `labels`, `section` and `url`. Pull requests are
+///   found from the merge and squash commits in the range, at most 100 of them
+/// * `github_release_create(repo, tag, body, draft)` creates a release of a tag, named after
+///   it, which is only a draft if `draft` is true. Returns the url of the release
+///
+/// # Arguments
+///
+/// * `module` - The module to register the functions in
+/// * `env` - Environment variables
+/// * `services` - The shared http client
+/// * `id` - The correlation id to attach to every log line
+/// * `handler_addr` - The uri of the handler the functions are for
+pub fn register(
+    module: &mut Module,
+    env: &EnvInfo,
+    services: &Services,
+    id: &CorrelationId,
+    handler_addr: &str,
+) {
+    let client = services.http.clone();
+    let github_token = env.github_token.clone();
+    let addr = handler_addr.to_string();
+    let cid = id.clone();
+    let github_release_notes = move |repo: ImmutableString,
+                                     from: ImmutableString,
+                                     to: ImmutableString|
+          -> Result<Map, Box<EvalAltResult>> {
+        log_event!(
+            "github.release_notes",
+            id = cid.0,
+            handler = addr,
+            repo = repo,
+            from = from,
+            to = to,
+        );
+
+        let prs = merged_prs(&client, &github_token, &repo, &from, &to)?;
+        let mut map = Map::new();
+        map.insert("markdown".into(), Dynamic::from(render(&from, &to, &prs)));
+        map.insert(
+            "prs".into(),
+            Dynamic::from(
+                prs.iter()
+                    .map(|pr| Dynamic::from(pr.to_map()))
+                    .collect::<Array>(),
+            ),
+        );
+        Ok(map)
+    };
+
+    let client = services.http.clone();
+    let github_token = env.github_token.clone();
+    let addr = handler_addr.to_string();
+    let cid = id.clone();
+    let github_release_create = move |repo: ImmutableString,
+                                      tag: ImmutableString,
+                                      body: ImmutableString,
+                                      draft: bool|
+          -> Result<String, Box<EvalAltResult>> {
+        log_event!(
+            "github.release_create",
+            id = cid.0,
+            handler = addr,
+            repo = repo,
+            tag = tag,
+            draft = draft,
+        );
+        check_repo(&repo)?;
+        check_ref(&tag)?;
+
+        let release = json!({
+            "tag_name": tag.as_str(),
+            "name": tag.as_str(),
+            "body": body.as_str(),
+            "draft": draft,
+        });
+        let path = format!("/repos/{}/releases", repo);
+        let resp = rest_post(&client, &github_token, &path, &release)?;
+        Ok(resp["html_url"].as_str().unwrap_or_default().to_string())
+    };
+
+    module.set_fn_3("github_release_notes", github_release_notes);
+    module.set_fn_4("github_release_create", github_release_create);
+}
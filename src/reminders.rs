@@ -0,0 +1,397 @@
+use std::collections::HashMap;
+
+use rhai::{EvalAltResult, ImmutableString, Module};
+
+use rocket::State;
+
+use rocket_contrib::json::Json;
+
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::auth::{check_auth, check_scope, hash_key, AuthHeader};
+use crate::clock::{unix_from_utc, unix_now};
+use crate::server::Collection;
+use crate::services::Services;
+use crate::slack::slack_api;
+use crate::storage::new_id;
+use crate::types::{
+    APIKeyRequest, ApiKeyInfo, CancelReminderRequest, EnvInfo, FailureKind, Handler, UserResponse,
+    READ_SCOPE, WRITE_SCOPE,
+};
+
+const DAY_SECS: u64 = 86_400;
+
+/// A message a handler asked us to deliver later
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Reminder {
+    pub id: String,
+    /// The uri of the handler which set the reminder
+    pub handler: String,
+    /// Where to deliver the reminder: a channel, or a user id for a direct message
+    pub target: String,
+    pub message: String,
+    /// When to deliver the reminder, as a unix timestamp
+    pub deliver_at: u64,
+    pub created_at: u64,
+}
+
+/// Register the reminder functions available to clients
+///
+/// * `remind(channel_or_user, when, message)` delivers the message at the time described by
+///   `when`, see `parse_when`, and returns the id of the reminder
+///
+/// # Arguments
+///
+/// * `module` - The module to register the functions in
+/// * `services` - Where reminders are stored
+/// * `handler_addr` - The uri of the handler the functions are for
+pub fn register(module: &mut Module, services: &Services, handler_addr: &str) {
+    let reminders = services.reminders.clone();
+    let addr = handler_addr.to_string();
+    let remind = move |target: ImmutableString,
+                       when: ImmutableString,
+                       message: ImmutableString|
+          -> Result<String, Box<EvalAltResult>> {
+        let now = unix_now();
+        let reminder = Reminder {
+            id: new_id(),
+            handler: addr.clone(),
+            target: target.to_string(),
+            message: message.to_string(),
+            deliver_at: parse_when(&when, now)?,
+            created_at: now,
+        };
+
+        log_event!(
+            "reminder.create",
+            handler = addr,
+            reminder = reminder.id,
+            deliver_at = reminder.deliver_at,
+        );
+
+        let id = reminder.id.clone();
+        reminders.update(|map| map.insert(id.clone(), reminder));
+        Ok(id)
+    };
+
+    module.set_fn_3("remind", remind);
+}
+
+/// Work out when a reminder should be delivered. All times are in UTC
+///
+/// Understands
+/// * relative times: `in 2h`, `in 1h 30m`, `in 3 days`, `in 10 minutes and 5 seconds`
+/// * dates and times: `2021-03-14`, `2021-03-14 09:30`, `2021-03-14T09:30:00Z`
+/// * times of day, today or tomorrow if that has passed: `09:30`, `at 17:00`, `tomorrow 9:00`
+/// * unix timestamps: `1615714200`
+///
+/// # Arguments
+///
+/// * `when` - The description of the time
+/// * `now` - The current unix timestamp
+pub fn parse_when(when: &str, now: u64) -> Result<u64, String> {
+    let when = when.trim().to_lowercase();
+    let invalid = || format!("Could not understand the time \"{}\"", when);
+
+    let at = if let Some(duration) = when.strip_prefix("in ") {
+        now + parse_duration(duration).ok_or_else(invalid)?
+    } else if !when.is_empty() && when.chars().all(|c| c.is_ascii_digit()) {
+        when.parse::<u64>().map_err(|_| invalid())?
+    } else if let Some(time) = when.strip_prefix("tomorrow") {
+        let time = time.trim().trim_start_matches("at").trim();
+        let (hour, minute) = parse_time_of_day(time).ok_or_else(invalid)?;
+        now - now % DAY_SECS + DAY_SECS + hour * 3_600 + minute * 60
+    } else if let Some((hour, minute)) = parse_time_of_day(when.trim_start_matches("at ")) {
+        let today = now - now % DAY_SECS + hour * 3_600 + minute * 60;
+        if today > now {
+            today
+        } else {
+            today + DAY_SECS
+        }
+    } else {
+        parse_date_time(&when).ok_or_else(invalid)?
+    };
+
+    if at <= now {
+        return Err(format!("The time \"{}\" has already passed", when));
+    }
+    Ok(at)
+}
+
+/// Parse a duration like `1h 30m` or `3 days`, in seconds
+fn parse_duration(duration: &str) -> Option<u64> {
+    let mut total = 0;
+    let mut amount = None;
+    let mut rest = duration.trim();
+
+    while !rest.is_empty() {
+        let digits = rest
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(rest.len());
+        if digits > 0 {
+            amount = Some(rest[..digits].parse::<u64>().ok()?);
+            rest = rest[digits..].trim_start();
+            continue;
+        }
+
+        let word = rest
+            .find(|c: char| !c.is_ascii_alphabetic())
+            .unwrap_or(rest.len());
+        let unit = match &rest[..word] {
+            "" if rest.starts_with(',') => 0,
+            "and" => 0,
+            "s" | "sec" | "secs" | "second" | "seconds" => 1,
+            "m" | "min" | "mins" | "minute" | "minutes" => 60,
+            "h" | "hr" | "hrs" | "hour" | "hours" => 3_600,
+            "d" | "day" | "days" => DAY_SECS,
+            "w" | "week" | "weeks" => 7 * DAY_SECS,
+            _ => return None,
+        };
+        if unit > 0 {
+            total += amount.take()? * unit;
+        }
+        rest = rest[word.max(1)..].trim_start();
+    }
+
+    // A number without a unit is not a duration
+    match (amount, total) {
+        (None, total) if total > 0 => Some(total),
+        _ => None,
+    }
+}
+
+/// Parse a time of day like `9:30` into hours and minutes
+fn parse_time_of_day(time: &str) -> Option<(u64, u64)> {
+    let mut parts = time.trim().splitn(2, ':');
+    let hour = parts.next()?.parse::<u64>().ok()?;
+    let minute = parts.next()?.parse::<u64>().ok()?;
+    if hour > 23 || minute > 59 {
+        return None;
+    }
+    Some((hour, minute))
+}
+
+/// Parse a date like `2021-03-14`, optionally followed by a time like `09:30` or `09:30:15`,
+/// separated by a space or a `t`, and optionally followed by a `z`
+fn parse_date_time(when: &str) -> Option<u64> {
+    let when = when.trim_end_matches('z');
+    let (date, time) = match when.find(|c| c == ' ' || c == 't') {
+        Some(i) => (&when[..i], when[i + 1..].trim()),
+        None => (when, ""),
+    };
+
+    let date = date
+        .split('-')
+        .map(|p| p.parse::<u64>().ok())
+        .collect::<Option<Vec<u64>>>()?;
+    let time = if time.is_empty() {
+        vec![0, 0, 0]
+    } else {
+        time.split(':')
+            .map(|p| p.parse::<u64>().ok())
+            .collect::<Option<Vec<u64>>>()?
+    };
+
+    match (date.as_slice(), time.as_slice()) {
+        (&[y, mo, d], &[h, mi]) => unix_from_utc(y, mo, d, h, mi, 0),
+        (&[y, mo, d], &[h, mi, s]) => unix_from_utc(y, mo, d, h, mi, s),
+        _ => None,
+    }
+}
+
+/// Deliver every reminder which has come due. Run by the scheduler
+///
+/// Reminders are removed once they have been attempted, so a failed delivery is not retried.
+///
+/// # Arguments
+///
+/// * `env` - Environment variables
+/// * `services` - Where reminders are stored
+/// * `now` - The current unix timestamp
+pub fn tick(env: &EnvInfo, services: &Services, now: u64) {
+    let due = services.reminders.update(|map| {
+        let ids = map
+            .values()
+            .filter(|r| r.deliver_at <= now)
+            .map(|r| r.id.clone())
+            .collect::<Vec<String>>();
+        ids.iter()
+            .filter_map(|id| map.remove(id))
+            .collect::<Vec<Reminder>>()
+    });
+
+    let client = services.http.clone();
+    for reminder in due {
+        let message = json!({
+            "channel": reminder.target,
+            "text": format!(":alarm_clock: Reminder: {}", reminder.message)
+        });
+        let delivered = slack_api(&client, &env.slack_token, "chat.postMessage", &message);
+        log_event!(
+            "reminder.deliver",
+            reminder = reminder.id,
+            handler = reminder.handler,
+            delivered = delivered.is_ok(),
+        );
+    }
+}
+
+/// Whether the given api key owns the handler which set a reminder
+fn owns(key: &str, handlers: &HashMap<String, Handler>, reminder: &Reminder) -> bool {
+    handlers
+        .get(&reminder.handler)
+        .map(|h| h.owned_by(&hash_key(key)))
+        .unwrap_or(false)
+}
+
+/// Rocket Endpoint which lists the pending reminders set by the handlers of an API Key
+///
+/// # Arguments
+///
+/// * `auth` - The API Key from the `Authorization` header, if any
+/// * `services` - Where reminders are stored
+/// * `api_keys` - A reference to the collection of Client API keys, used to check for auth
+/// * `handlers` - A reference to the collection of User created handlers
+/// * `post_data` - The API Key, if not in the header
+#[post("/list_reminders", data = "<post_data>")]
+pub fn list_reminders(
+    auth: AuthHeader,
+    services: State<Services>,
+    api_keys: Collection<String, ApiKeyInfo>,
+    handlers: Collection<String, Handler>,
+    post_data: Option<Json<APIKeyRequest>>,
+) -> Json<UserResponse> {
+    let key = auth.key_or(&post_data.map(|d| d.0.api_key).unwrap_or_default());
+
+    if let Err(cause) = auth.verify(&key, check_auth(&key, &api_keys), "Invalid API Key") {
+        return Json(UserResponse::from(cause));
+    }
+    if let Err(cause) = check_scope(&key, &api_keys, READ_SCOPE) {
+        return Json(UserResponse::from(cause));
+    }
+
+    let handlers = handlers.read().unwrap();
+    let mut reminders = services
+        .reminders
+        .read()
+        .values()
+        .filter(|r| owns(&key, &handlers, r))
+        .cloned()
+        .collect::<Vec<Reminder>>();
+    reminders.sort_by_key(|r| r.deliver_at);
+
+    Json(
+        UserResponse::success_with_raw(reminders)
+            .unwrap_or_else(|| UserResponse::failure("Unable to list reminders".into())),
+    )
+}
+
+/// Rocket Endpoint which cancels a pending reminder set by one of the handlers of an API Key
+///
+/// # Arguments
+///
+/// * `auth` - The API Key from the `Authorization` header, if any
+/// * `env` - Environment variables
+/// * `services` - Where reminders are stored
+/// * `api_keys` - A reference to the collection of Client API keys, used to check for auth
+/// * `handlers` - A reference to the collection of User created handlers
+/// * `post_data` - The id of the reminder to cancel
+#[post("/cancel_reminder", data = "<post_data>")]
+pub fn cancel_reminder(
+    auth: AuthHeader,
+    env: State<EnvInfo>,
+    services: State<Services>,
+    api_keys: Collection<String, ApiKeyInfo>,
+    handlers: Collection<String, Handler>,
+    post_data: Json<CancelReminderRequest>,
+) -> Json<UserResponse> {
+    let data = post_data.0;
+    let key = auth.key_or(&data.api_key);
+
+    if let Err(cause) = auth.verify(&key, check_auth(&key, &api_keys), "Invalid API Key") {
+        return Json(UserResponse::from(cause));
+    }
+    if let Err(cause) = check_scope(&key, &api_keys, WRITE_SCOPE) {
+        return Json(UserResponse::from(cause));
+    }
+
+    if env.read_only {
+        return Json(UserResponse::read_only());
+    }
+
+    let handlers = handlers.read().unwrap();
+    let cancelled = services.reminders.update(|map| match map.get(&data.id) {
+        Some(reminder) if owns(&key, &handlers, reminder) => map.remove(&data.id),
+        _ => None,
+    });
+
+    match cancelled {
+        Some(reminder) => {
+            log_event!(
+                "reminder.cancel",
+                reminder = reminder.id,
+                handler = reminder.handler
+            );
+            Json(UserResponse::success())
+        }
+        None => Json(UserResponse::failure_of(
+            FailureKind::NotFound,
+            format!("No pending reminder with id {}", data.id),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 2021-03-14 12:00:00 UTC
+    const NOW: u64 = 1_615_723_200;
+
+    #[test]
+    fn parse_when_understands_relative_times() {
+        assert_eq!(parse_when("in 2h", NOW), Ok(NOW + 7_200));
+        assert_eq!(parse_when("in 1h 30m", NOW), Ok(NOW + 5_400));
+        assert_eq!(parse_when("In 3 Days", NOW), Ok(NOW + 3 * DAY_SECS));
+        assert_eq!(
+            parse_when("in 10 minutes and 5 seconds", NOW),
+            Ok(NOW + 605)
+        );
+        assert!(parse_when("in 10", NOW).is_err());
+        assert!(parse_when("in h", NOW).is_err());
+        assert!(parse_when("in 2 fortnights", NOW).is_err());
+    }
+
+    #[test]
+    fn parse_when_understands_dates_times_and_timestamps() {
+        assert_eq!(parse_when("2021-03-15", NOW), Ok(1_615_766_400));
+        assert_eq!(parse_when("2021-03-14 13:30", NOW), Ok(1_615_728_600));
+        assert_eq!(parse_when("2021-03-14T13:30:15Z", NOW), Ok(1_615_728_615));
+        assert_eq!(parse_when("1615728600", NOW), Ok(1_615_728_600));
+        assert!(parse_when("2021-03-14 25:00", NOW).is_err());
+        assert!(parse_when("next tuesday", NOW).is_err());
+    }
+
+    #[test]
+    fn parse_when_picks_the_next_time_of_day() {
+        assert_eq!(parse_when("13:30", NOW), Ok(1_615_728_600));
+        assert_eq!(parse_when("at 9:00", NOW), Ok(1_615_712_400 + DAY_SECS));
+        assert_eq!(
+            parse_when("tomorrow 9:00", NOW),
+            Ok(1_615_712_400 + DAY_SECS)
+        );
+        assert_eq!(
+            parse_when("tomorrow at 13:30", NOW),
+            Ok(1_615_728_600 + DAY_SECS)
+        );
+        assert!(parse_when("24:00", NOW).is_err());
+    }
+
+    #[test]
+    fn parse_when_refuses_the_past() {
+        assert!(parse_when("2021-03-14 11:59", NOW).is_err());
+        assert!(parse_when(&NOW.to_string(), NOW).is_err());
+        assert!(parse_when("", NOW).is_err());
+    }
+}
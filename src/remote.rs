@@ -0,0 +1,269 @@
+use std::io::{self, Read};
+use std::process::{Command, Stdio};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use rhai::{Array, Dynamic, EvalAltResult, ImmutableString, Map, Module, INT};
+
+use crate::logging::CorrelationId;
+use crate::types::{EnvInfo, RemoteCommand};
+
+/// How long a remote command may take, if the operator doesn't say
+const DEFAULT_TIMEOUT_SECS: u64 = 30;
+
+/// The longest the operator may let a remote command take, since the handler waits on it
+pub const MAX_TIMEOUT_SECS: u64 = 300;
+
+/// How long connecting to the host may take, part of the timeout of the command
+const CONNECT_TIMEOUT_SECS: u64 = 10;
+
+/// The most of each of stdout and stderr we keep, in bytes. Anything beyond is cut off
+const MAX_OUTPUT_BYTES: u64 = 64 * 1024;
+
+/// How often we check whether the command has finished
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// How a remote command went
+struct Outcome {
+    /// The exit code of the command, or None if it timed out or was killed by a signal
+    exit_code: Option<i32>,
+    stdout: String,
+    stderr: String,
+    timed_out: bool,
+}
+
+/// Check an argument from a handler, which may only contain characters the remote shell treats
+/// literally, so it can't add commands of its own
+fn check_arg(arg: &str) -> Result<(), String> {
+    let valid = !arg.is_empty()
+        && arg.len() <= 256
+        && arg
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-_.:/@=+,".contains(c));
+    if valid {
+        Ok(())
+    } else {
+        Err(format!(
+            "{} is not a valid argument. Arguments may only contain letters, digits and -_.:/@=+,",
+            arg
+        ))
+    }
+}
+
+/// Fill in the `{}`s of a command template with the arguments of a handler, in order
+///
+/// Arguments are single quoted on top of being checked, so the remote shell never splits them.
+///
+/// # Arguments
+///
+/// * `template` - The command, as the operator configured it
+/// * `args` - The arguments, as the handler passed them
+fn render(template: &str, args: &[String]) -> Result<String, String> {
+    let slots = template.matches("{}").count();
+    if args.len() != slots {
+        return Err(format!(
+            "This command takes {} arguments, not {}",
+            slots,
+            args.len()
+        ));
+    }
+
+    let mut parts = template.split("{}");
+    let mut command = parts.next().unwrap_or_default().to_string();
+    for (arg, part) in args.iter().zip(parts) {
+        check_arg(arg)?;
+        command.push_str(&format!("'{}'", arg));
+        command.push_str(part);
+    }
+    Ok(command)
+}
+
+/// Read a pipe of the command on another thread, keeping the start of it
+///
+/// The rest is read and thrown away, so a chatty command never blocks on a full pipe.
+fn capture<R: Read + Send + 'static>(mut pipe: R) -> JoinHandle<String> {
+    thread::spawn(move || {
+        let mut kept = Vec::new();
+        let _ = pipe.by_ref().take(MAX_OUTPUT_BYTES).read_to_end(&mut kept);
+        let _ = io::copy(&mut pipe, &mut io::sink());
+        String::from_utf8_lossy(&kept).into_owned()
+    })
+}
+
+/// Run a command on its host over ssh, killing it if it takes too long
+///
+/// Killing ssh hangs up the session, which stops most commands on the host too, but one which
+/// ignores the hangup may keep running there.
+///
+/// # Arguments
+///
+/// * `remote` - Where and how to run the command
+/// * `command` - The command, with its arguments filled in
+fn run(remote: &RemoteCommand, command: &str) -> Result<Outcome, String> {
+    let mut ssh = Command::new("ssh");
+    ssh.arg("-o")
+        .arg("BatchMode=yes")
+        .arg("-o")
+        .arg(format!("ConnectTimeout={}", CONNECT_TIMEOUT_SECS))
+        .arg("-p")
+        .arg(remote.port.unwrap_or(22).to_string())
+        .arg("-l")
+        .arg(&remote.user);
+    if let Some(identity_file) = &remote.identity_file {
+        ssh.arg("-i").arg(identity_file);
+    }
+    let mut child = ssh
+        .arg("--")
+        .arg(&remote.host)
+        .arg(command)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| format!("Unable to start ssh: {}", e))?;
+
+    let stdout = child.stdout.take().map(capture);
+    let stderr = child.stderr.take().map(capture);
+
+    let timeout = remote
+        .timeout_secs
+        .unwrap_or(DEFAULT_TIMEOUT_SECS)
+        .min(MAX_TIMEOUT_SECS);
+    let deadline = Instant::now() + Duration::from_secs(timeout);
+    let status = loop {
+        match child.try_wait() {
+            Ok(Some(status)) => break Some(status),
+            Ok(None) if Instant::now() < deadline => thread::sleep(POLL_INTERVAL),
+            Ok(None) => {
+                let _ = child.kill();
+                let _ = child.wait();
+                break None;
+            }
+            Err(e) => return Err(format!("Unable to wait on ssh: {}", e)),
+        }
+    };
+
+    let output = |pipe: Option<JoinHandle<String>>| {
+        pipe.map(|p| p.join().unwrap_or_default())
+            .unwrap_or_default()
+    };
+    Ok(Outcome {
+        exit_code: status.map(|s| s.code()).flatten(),
+        stdout: output(stdout),
+        stderr: output(stderr),
+        timed_out: status.is_none(),
+    })
+}
+
+/// Register the remote command functions available to clients
+///
+/// * `remote_run(name, args)` runs a command the operator configured, see `RemoteCommand`, on
+///   its host over ssh, filling in its `{}`s with `args`, an array of strings. Returns a map of
+///   the command's `exit_code`, -1 if there is none, its `stdout` and `stderr`, and whether it
+///   `timed_out`. `remote_run(name)` runs a command which takes no arguments
+///
+/// Handlers can only run what the operator configured, never arbitrary commands.
+///
+/// # Arguments
+///
+/// * `module` - The module to register the functions in
+/// * `env` - Environment variables, for the configured commands
+/// * `id` - The correlation id of the run, attached to every log line
+/// * `handler_addr` - The uri of the handler the functions are for
+pub fn register(module: &mut Module, env: &EnvInfo, id: &CorrelationId, handler_addr: &str) {
+    let commands = env.remote_commands.clone();
+    let addr = handler_addr.to_string();
+    let cid = id.clone();
+    let remote_run = move |name: &str, args: Array| -> Result<Map, Box<EvalAltResult>> {
+        let remote = commands
+            .get(name)
+            .filter(|c| c.handlers.is_empty() || c.handlers.contains(&addr))
+            .ok_or_else(|| format!("{} is not a remote command this handler may run", name))?;
+        let args = args
+            .into_iter()
+            .map(|a| a.try_cast::<ImmutableString>().map(|a| a.to_string()))
+            .collect::<Option<Vec<String>>>()
+            .ok_or("The arguments of a remote command must be strings")?;
+        let command = render(&remote.command, &args)?;
+
+        log_event!(
+            "audit.remote_run",
+            id = cid.0,
+            handler = addr,
+            command = name,
+            host = remote.host
+        );
+        let outcome = run(remote, &command).map_err(|e| {
+            log_event!(
+                "remote.error",
+                id = cid.0,
+                handler = addr,
+                command = name,
+                error = e
+            );
+            e
+        })?;
+        if outcome.timed_out {
+            log_event!("remote.timeout", id = cid.0, handler = addr, command = name);
+        }
+
+        let mut map = Map::new();
+        map.insert(
+            "exit_code".into(),
+            Dynamic::from(outcome.exit_code.map(INT::from).unwrap_or(-1)),
+        );
+        map.insert("stdout".into(), Dynamic::from(outcome.stdout));
+        map.insert("stderr".into(), Dynamic::from(outcome.stderr));
+        map.insert("timed_out".into(), Dynamic::from(outcome.timed_out));
+        Ok(map)
+    };
+
+    let run_with_args = remote_run.clone();
+    module.set_fn_2("remote_run", move |name: ImmutableString, args: Array| {
+        run_with_args(&name, args)
+    });
+    module.set_fn_1("remote_run", move |name: ImmutableString| {
+        remote_run(&name, Array::new())
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|a| a.to_string()).collect()
+    }
+
+    #[test]
+    fn render_quotes_each_argument() {
+        assert_eq!(
+            render("systemctl restart {} --host={}", &args(&["api", "web-1"])),
+            Ok("systemctl restart 'api' --host='web-1'".to_string())
+        );
+        assert_eq!(render("uptime", &[]), Ok("uptime".to_string()));
+    }
+
+    #[test]
+    fn render_wants_one_argument_per_slot() {
+        assert!(render("restart {}", &[]).is_err());
+        assert!(render("uptime", &args(&["now"])).is_err());
+    }
+
+    #[test]
+    fn render_refuses_arguments_the_shell_would_interpret() {
+        for arg in &[
+            "",
+            "api; rm -rf /",
+            "$(whoami)",
+            "a'b",
+            "a b",
+            "`id`",
+            "a\nb",
+        ] {
+            assert!(render("restart {}", &args(&[arg])).is_err(), "{:?}", arg);
+        }
+        assert!(render("restart {}", &args(&[&"a".repeat(257)])).is_err());
+        assert!(render("deploy {}", &args(&["v1.2.3+build=7,user@host:/tmp"])).is_ok());
+    }
+}
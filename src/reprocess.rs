@@ -0,0 +1,279 @@
+use std::thread;
+use std::time::Duration;
+
+use rhai::ser::to_dynamic;
+use rhai::{Dynamic, Engine, EvalAltResult, Map, Scope};
+
+use rocket::State;
+use rocket_contrib::json::Json;
+
+use serde::Serialize;
+
+use crate::archive::ArchivedEvent;
+use crate::auth::{check_auth, check_scope, describe_key, hash_key, AuthHeader};
+use crate::dryrun::{describe, MockCall, Transcript};
+use crate::logging::CorrelationId;
+use crate::server::{run_function, Collection};
+use crate::services::Services;
+use crate::types::{
+    ApiKeyInfo, EnvInfo, FailureKind, Handler, ReprocessRequest, UserResponse, WRITE_SCOPE,
+};
+
+/// The most events a single request may replay
+const MAX_EVENTS: usize = 1_000;
+
+/// How many events are replayed per minute when live, if the request doesn't say
+const DEFAULT_PER_MINUTE: u64 = 60;
+
+/// The most events which may be replayed per minute when live
+const MAX_PER_MINUTE: u64 = 600;
+
+/// How an archived event went when replayed with integrations mocked
+#[derive(Debug, Serialize)]
+pub struct Replayed {
+    /// The correlation id of the original run
+    pub id: String,
+    /// When the original run was, as a unix timestamp
+    pub at: u64,
+    /// What the handler returned, if it succeeded. Anything but a string is shown as json
+    pub result: Option<String>,
+    /// Why the handler failed, if it did
+    pub error: Option<String>,
+    /// The calls the handler made to mocked integrations, in order
+    pub calls: Vec<MockCall>,
+}
+
+/// Run a handler against an archived event, the way it was originally run
+///
+/// # Arguments
+///
+/// * `engine` - The engine to run the handler in
+/// * `handler` - The handler
+/// * `event` - The event
+fn replay(
+    engine: &Engine,
+    handler: &Handler,
+    event: &ArchivedEvent,
+) -> Result<Dynamic, Box<EvalAltResult>> {
+    let context = event
+        .context
+        .clone()
+        .map(|c| to_dynamic(c).ok())
+        .flatten()
+        .map(|c| c.try_cast::<Map>())
+        .flatten();
+    let payload = event.payload.clone();
+    let mut scope = Scope::new();
+    match context {
+        Some(context) if handler.defines("handle", 2) => {
+            engine.call_fn(&mut scope, &handler.code.ast, "handle", (payload, context))
+        }
+        _ => engine.call_fn(&mut scope, &handler.code.ast, "handle", (payload,)),
+    }
+}
+
+/// Replay events for real, at a steady pace, in the background. Each run is recorded in the
+/// handler's logs and stats, like any other. Stops early if the handler is deleted
+///
+/// # Arguments
+///
+/// * `env` - Environment variables
+/// * `services` - The stateful subsystems available to handlers
+/// * `handler` - The handler to replay the events through
+/// * `events` - The events, oldest first
+/// * `per_minute` - How many events to replay per minute
+fn replay_live(
+    env: EnvInfo,
+    services: Services,
+    handler: Handler,
+    events: Vec<ArchivedEvent>,
+    per_minute: u64,
+) {
+    let pause = Duration::from_millis(60_000 / per_minute);
+    thread::spawn(move || {
+        for (i, event) in events.iter().enumerate() {
+            if i > 0 {
+                thread::sleep(pause);
+            }
+            if !services.handlers.read().unwrap().contains_key(&handler.uri) {
+                log_event!("reprocess.abandoned", handler = handler.uri, replayed = i);
+                return;
+            }
+
+            let id = CorrelationId::generate();
+            log_event!(
+                "reprocess.run",
+                id = id.0,
+                handler = handler.uri,
+                original = event.id
+            );
+            let result = run_function(
+                &env,
+                &services,
+                &id,
+                &handler.uri,
+                &handler,
+                None,
+                "handle",
+                |engine| replay(engine, &handler, event),
+            );
+            if let Err(e) = result {
+                log_event!("handler.error", id = id.0, handler = handler.uri, error = e);
+            }
+        }
+    });
+}
+
+/// Rocket Endpoint which replays archived events through a handler, e.g. to backfill after a
+/// bug fix, see `archive::Archive`
+///
+/// By default, `slack_post`, `github_issue_create` and the other integrations which reach
+/// people are mocked, like `/test_handler` does, and how each event went is returned. Live
+/// replays happen in the background, at `per_minute`, and go wherever the handler sends them;
+/// their runs show up in `/handler_logs`. Either way, replays are not archived again, and
+/// everything besides the mocked integrations, e.g. the key-value store, is real.
+///
+/// # Arguments
+///
+/// * `auth` - The API Key from the `Authorization` header, if any
+/// * `env` - Environment variables
+/// * `services` - The stateful subsystems available to handlers
+/// * `api_keys` - A reference to the collection of Client API keys, used to check for auth
+/// * `handlers` - A reference to the collection of User created handlers, indexed by their uris
+/// * `post_data` - The handler, which must be owned by the API Key, the events to replay, and how
+#[post("/reprocess", data = "<post_data>")]
+pub fn reprocess(
+    auth: AuthHeader,
+    env: State<EnvInfo>,
+    services: State<Services>,
+    api_keys: Collection<String, ApiKeyInfo>,
+    handlers: Collection<String, Handler>,
+    post_data: Json<ReprocessRequest>,
+) -> Json<UserResponse> {
+    if env.read_only {
+        return Json(UserResponse::read_only());
+    }
+
+    let data = post_data.0;
+    let key = auth.key_or(&data.api_key);
+
+    if let Err(cause) = auth.verify(&key, check_auth(&key, &api_keys), "Invalid API Key") {
+        return Json(UserResponse::from(cause));
+    }
+    if let Err(cause) = check_scope(&key, &api_keys, WRITE_SCOPE) {
+        return Json(UserResponse::from(cause));
+    }
+
+    let per_minute = data.per_minute.unwrap_or(DEFAULT_PER_MINUTE);
+    if data.live && !(1..=MAX_PER_MINUTE).contains(&per_minute) {
+        return Json(UserResponse::failure(format!(
+            "per_minute must be between 1 and {}",
+            MAX_PER_MINUTE
+        )));
+    }
+
+    // The handler is compiled afresh, so it can outlive the lock, and be a previous revision
+    let handler = {
+        let guard = handlers.read().unwrap();
+        let saved = match guard.get(&data.uri) {
+            Some(h) if h.owned_by(&hash_key(&key)) => h,
+            Some(_) => {
+                return Json(UserResponse::failure_of(
+                    FailureKind::Unauthorized,
+                    "Invalid API Key".into(),
+                ))
+            }
+            None => {
+                return Json(UserResponse::failure_of(
+                    FailureKind::NotFound,
+                    "Unknown handler uri".into(),
+                ))
+            }
+        };
+        let code = match data.revision {
+            Some(index) => match saved.history.get(index) {
+                Some(revision) => revision.code.raw.clone(),
+                None => {
+                    return Json(UserResponse::failure_of(
+                        FailureKind::NotFound,
+                        "Unknown revision".into(),
+                    ))
+                }
+            },
+            None => saved.code.raw.clone(),
+        };
+        match Handler::new(saved.uri.clone(), saved.api_key.clone(), code) {
+            Ok(mut handler) => {
+                handler.max_operations = saved.max_operations;
+                handler.timeout_ms = saved.timeout_ms;
+                handler
+            }
+            Err(e) => return Json(UserResponse::failure(format!("Error parsing code: {}", e))),
+        }
+    };
+
+    let events = services.archive.search(
+        &data.uri,
+        data.since.unwrap_or(0),
+        data.until.unwrap_or(u64::MAX),
+        data.contains.as_deref(),
+        MAX_EVENTS,
+    );
+
+    log_event!(
+        "audit.reprocess",
+        handler = data.uri,
+        events = events.len(),
+        live = data.live,
+        key = describe_key(&hash_key(&key), &api_keys),
+    );
+
+    if data.live {
+        let count = events.len();
+        replay_live(
+            env.inner().clone(),
+            services.inner().clone(),
+            handler,
+            events,
+            per_minute,
+        );
+        return Json(UserResponse::success_with_data(format!(
+            "Replaying {} events at {} per minute",
+            count, per_minute
+        )));
+    }
+
+    let replayed = events
+        .iter()
+        .map(|event| {
+            let id = CorrelationId::generate();
+            let transcript = Transcript::default();
+            let result = run_function(
+                &env,
+                &services,
+                &id,
+                &handler.uri,
+                &handler,
+                Some(&transcript),
+                "handle",
+                |engine| replay(engine, &handler, event),
+            );
+            let calls = transcript.lock().unwrap().clone();
+            let (result, error) = match result {
+                Ok(value) => (Some(describe(value)), None),
+                Err(e) => (None, Some(e.to_string())),
+            };
+            Replayed {
+                id: event.id.clone(),
+                at: event.at,
+                result,
+                error,
+                calls,
+            }
+        })
+        .collect::<Vec<Replayed>>();
+    Json(
+        UserResponse::success_with_raw(replayed)
+            .unwrap_or_else(|| UserResponse::failure("Unable to describe the replay".into())),
+    )
+}
@@ -0,0 +1,164 @@
+use std::io::Cursor;
+
+use rhai::de::from_dynamic;
+use rhai::{Dynamic, ImmutableString, Map, INT};
+
+use rocket::http::{ContentType, Header, Status};
+use rocket::response::{self, Responder, Response};
+use rocket::Request;
+use rocket_contrib::json::Json;
+
+use serde_json::Value;
+
+use crate::types::UserResponse;
+
+/// Headers handlers may not set, since the server manages them, or they would let a handler
+/// act for the whole domain, e.g. by setting cookies
+const RESERVED_HEADERS: [&str; 7] = [
+    "connection",
+    "content-length",
+    "content-type",
+    "set-cookie",
+    "transfer-encoding",
+    "x-request-id",
+    "retry-after",
+];
+
+/// A response a handler fully controls
+#[derive(Debug)]
+pub struct CustomResponse {
+    pub status: Status,
+    pub content_type: ContentType,
+    pub headers: Vec<(String, String)>,
+    pub body: String,
+}
+
+/// What a request to a handler gets back
+///
+/// Handlers which return a string are wrapped in a `UserResponse`, like they always have been.
+/// Handlers which return a map control the response themselves, see `reply_from`.
+pub enum Reply {
+    Wrapped(Json<UserResponse>),
+    Custom(CustomResponse),
+}
+
+impl<'r> Responder<'r> for Reply {
+    fn respond_to(self, request: &Request) -> response::Result<'r> {
+        match self {
+            Reply::Wrapped(json) => json.respond_to(request),
+            Reply::Custom(custom) => {
+                let mut response = Response::build();
+                response
+                    .status(custom.status)
+                    .header(custom.content_type)
+                    .sized_body(Cursor::new(custom.body));
+                for (name, value) in custom.headers {
+                    response.header_adjoin(Header::new(name, value));
+                }
+                response.ok()
+            }
+        }
+    }
+}
+
+/// Whether a header name is a valid http token, so it can't smuggle in other headers
+fn valid_header_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "!#$%&'*+-.^_`|~".contains(c))
+}
+
+/// Read a response a handler returned as a map
+///
+/// The map may have
+/// * `status` - The http status, 200 if omitted
+/// * `content_type` - e.g. `text/plain`. Plain text if omitted, or json if the body isn't a string
+/// * `headers` - A map of other headers to send. Some are reserved, see `RESERVED_HEADERS`
+/// * `body` - The body, as a string, or as a map or array to send as json
+///
+/// # Arguments
+///
+/// * `map` - The map the handler returned
+fn custom_response(mut map: Map) -> Result<CustomResponse, String> {
+    let status = match map.remove("status") {
+        Some(status) => {
+            let code = status
+                .try_cast::<INT>()
+                .ok_or("The status of a response must be a number")?;
+            Some(code)
+                .filter(|code| (100..=599).contains(code))
+                .map(|code| Status::from_code(code as u16))
+                .flatten()
+                .ok_or_else(|| format!("{} is not a known http status", code))?
+        }
+        None => Status::Ok,
+    };
+
+    let (body, json) = match map.remove("body") {
+        None => (String::new(), false),
+        Some(body) if body.is::<ImmutableString>() => (body.to_string(), false),
+        Some(body) => {
+            let value: Value = from_dynamic(&body).map_err(|e| e.to_string())?;
+            (value.to_string(), true)
+        }
+    };
+
+    let content_type = match map.remove("content_type") {
+        Some(content_type) => {
+            let content_type = content_type.to_string();
+            ContentType::parse_flexible(&content_type)
+                .ok_or_else(|| format!("{} is not a valid content type", content_type))?
+        }
+        None if json => ContentType::JSON,
+        None => ContentType::Plain,
+    };
+
+    let mut headers = Vec::new();
+    if let Some(map) = map.remove("headers") {
+        let map = map
+            .try_cast::<Map>()
+            .ok_or("The headers of a response must be a map")?;
+        for (name, value) in map {
+            let value = value.to_string();
+            if !valid_header_name(&name) || value.contains(|c: char| c == '\r' || c == '\n') {
+                return Err(format!("{} is not a valid header", name));
+            }
+            if RESERVED_HEADERS.contains(&name.to_lowercase().as_str()) {
+                return Err(format!("Handlers may not set the {} header", name));
+            }
+            headers.push((name.to_string(), value));
+        }
+    }
+
+    Ok(CustomResponse {
+        status,
+        content_type,
+        headers,
+        body,
+    })
+}
+
+/// Turn what a handler returned into a reply
+///
+/// Strings are wrapped in a successful `UserResponse`, and maps are sent as the handler
+/// describes, see `custom_response`. Anything else is a mistake.
+///
+/// # Arguments
+///
+/// * `value` - What the handler returned
+pub fn reply_from(value: Dynamic) -> Result<Reply, String> {
+    if value.is::<Map>() {
+        let map = value.cast::<Map>();
+        return custom_response(map).map(Reply::Custom);
+    }
+
+    let type_name = value.type_name();
+    match value.try_cast::<String>() {
+        Some(data) => Ok(Reply::Wrapped(Json(UserResponse::success_with_data(data)))),
+        None => Err(format!(
+            "handle returned {}, rather than a string or a response map",
+            type_name
+        )),
+    }
+}
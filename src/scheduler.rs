@@ -0,0 +1,60 @@
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+use std::thread;
+use std::time::Duration;
+
+use crate::cleanup;
+use crate::clock::unix_now;
+use crate::flags;
+use crate::oncall;
+use crate::polls;
+use crate::reminders;
+use crate::services::Services;
+use crate::stale;
+use crate::stats;
+use crate::types::{ApiKeyInfo, EnvInfo, Handler};
+use crate::uptime;
+use crate::windows;
+
+/// How often the scheduler checks for work that has come due
+const TICK_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Start the background thread which runs time based work, e.g. closing polls, delivering
+/// reminders, announcing on-call handoffs, sweeping stale issues, probing uptime checks,
+/// announcing flag changes, running calls queued for active windows, telling owners about their
+/// dead handlers and saving handler stats
+///
+/// Only the primary runs the scheduler, replicas would otherwise do everything twice.
+///
+/// # Arguments
+///
+/// * `env` - Environment variables
+/// * `services` - The subsystems with work to run
+/// * `handlers` - The handlers, for work which calls them
+/// * `api_keys` - The Client API keys, for work which tells the owners of handlers something
+pub fn start(
+    env: EnvInfo,
+    services: Services,
+    handlers: Arc<RwLock<HashMap<String, Handler>>>,
+    api_keys: Arc<RwLock<HashMap<String, ApiKeyInfo>>>,
+) {
+    let spawned = thread::Builder::new()
+        .name("scheduler".into())
+        .spawn(move || loop {
+            let now = unix_now();
+            polls::tick(&env, &services, now);
+            reminders::tick(&env, &services, now);
+            oncall::tick(&env, &services, now);
+            stale::tick(&env, &services, &handlers, now);
+            uptime::tick(&env, &services, &handlers, now);
+            flags::tick(&env, &services, &handlers);
+            windows::tick(&env, &services, &handlers, now);
+            cleanup::tick(&env, &services, &handlers, &api_keys, now);
+            stats::tick(&services, now);
+            thread::sleep(TICK_INTERVAL);
+        });
+
+    if let Err(e) = spawned {
+        log_event!("scheduler.start_error", error = e);
+    }
+}
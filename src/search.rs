@@ -0,0 +1,152 @@
+use rocket::State;
+use rocket_contrib::json::Json;
+
+use serde::Serialize;
+
+use crate::auth::{check_admin, check_auth, check_scope, hash_key, AuthHeader};
+use crate::server::Collection;
+use crate::types::{ApiKeyInfo, EnvInfo, Handler, SearchHandlersRequest, UserResponse, READ_SCOPE};
+
+/// The most matching lines of code shown for a single handler
+const MAX_LINES: usize = 20;
+
+/// How much of a matching line is shown, in characters
+const MAX_LINE_CHARS: usize = 200;
+
+/// Where a handler mentions what was searched for
+#[derive(Debug, Serialize)]
+pub struct Hit {
+    /// Which part of the handler matched: `uri`, `description`, `tags` or `code`
+    pub field: &'static str,
+    /// The line of code, counting from 1, for matches in the code
+    pub line: Option<usize>,
+    /// The text which matched, e.g. the line of code
+    pub text: String,
+}
+
+/// A handler which mentions what was searched for
+#[derive(Debug, Serialize)]
+pub struct SearchResult {
+    pub uri: String,
+    pub description: Option<String>,
+    pub tags: Vec<String>,
+    /// Every place it matched, at most `MAX_LINES` of them in the code
+    pub hits: Vec<Hit>,
+}
+
+/// Find where a handler mentions some text, ignoring case
+///
+/// # Arguments
+///
+/// * `uri` - The uri of the handler
+/// * `handler` - The handler
+/// * `query` - What to find, in lower case
+fn hits(uri: &str, handler: &Handler, query: &str) -> Vec<Hit> {
+    let matches = |text: &str| text.to_lowercase().contains(query);
+    let mut hits = Vec::new();
+
+    if matches(uri) {
+        hits.push(Hit {
+            field: "uri",
+            line: None,
+            text: uri.into(),
+        });
+    }
+    if let Some(description) = handler.description.as_ref().filter(|d| matches(d)) {
+        hits.push(Hit {
+            field: "description",
+            line: None,
+            text: description.clone(),
+        });
+    }
+    for tag in handler.tags.iter().filter(|t| matches(t)) {
+        hits.push(Hit {
+            field: "tags",
+            line: None,
+            text: tag.clone(),
+        });
+    }
+
+    let lines = handler
+        .code
+        .raw
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| matches(line))
+        .take(MAX_LINES)
+        .map(|(i, line)| Hit {
+            field: "code",
+            line: Some(i + 1),
+            text: line.trim().chars().take(MAX_LINE_CHARS).collect(),
+        });
+    hits.extend(lines);
+    hits
+}
+
+/// Rocket Endpoint which finds the handlers whose uri, description, tags or code mention some
+/// text, ignoring case, e.g. a channel or API about to go away
+///
+/// API Keys search the handlers they own. The admin key searches every handler.
+///
+/// # Arguments
+///
+/// * `auth` - The API Key, or admin key, from the `Authorization` header, if any
+/// * `env` - Environment variables, for the admin key
+/// * `api_keys` - A reference to the collection of Client API keys, used to check for auth
+/// * `handlers` - A reference to the collection of User created handlers, indexed by their uris
+/// * `post_data` - What to search for
+#[post("/search_handlers", data = "<post_data>")]
+pub fn search_handlers(
+    auth: AuthHeader,
+    env: State<EnvInfo>,
+    api_keys: Collection<String, ApiKeyInfo>,
+    handlers: Collection<String, Handler>,
+    post_data: Json<SearchHandlersRequest>,
+) -> Json<UserResponse> {
+    let data = post_data.0;
+    let key = auth.key_or(&data.api_key);
+
+    let admin = check_admin(&key, &env);
+    if let Err(cause) = auth.verify(
+        &key,
+        admin || check_auth(&key, &api_keys),
+        "Invalid API Key",
+    ) {
+        return Json(UserResponse::from(cause));
+    }
+    if !admin {
+        if let Err(cause) = check_scope(&key, &api_keys, READ_SCOPE) {
+            return Json(UserResponse::from(cause));
+        }
+    }
+
+    let query = data.query.trim().to_lowercase();
+    if query.is_empty() {
+        return Json(UserResponse::failure("The query must not be empty".into()));
+    }
+
+    let owner = hash_key(&key);
+    let guard = handlers.read().unwrap();
+    let mut results = guard
+        .iter()
+        .filter(|(_, h)| admin || h.owned_by(&owner))
+        .filter_map(|(uri, h)| {
+            let hits = hits(uri, h, &query);
+            if hits.is_empty() {
+                return None;
+            }
+            Some(SearchResult {
+                uri: uri.clone(),
+                description: h.description.clone(),
+                tags: h.tags.clone(),
+                hits,
+            })
+        })
+        .collect::<Vec<SearchResult>>();
+    results.sort_by(|a, b| a.uri.cmp(&b.uri));
+
+    Json(
+        UserResponse::success_with_raw(results)
+            .unwrap_or_else(|| UserResponse::failure("Unable to list results".into())),
+    )
+}
@@ -0,0 +1,336 @@
+use std::collections::HashMap;
+
+use aes_gcm::aead::generic_array::GenericArray;
+use aes_gcm::aead::{Aead, NewAead, Payload};
+use aes_gcm::Aes256Gcm;
+use rand::Rng;
+use sha2::{Digest, Sha256};
+
+use rhai::{Dynamic, EvalAltResult, ImmutableString, Module};
+
+use rocket::State;
+use rocket_contrib::json::Json;
+
+use crate::auth::{check_auth, check_scope, hash_key, AuthHeader};
+use crate::logging::CorrelationId;
+use crate::server::Collection;
+use crate::services::Services;
+use crate::storage::JsonStore;
+use crate::types::{
+    ApiKeyInfo, DeleteSecretRequest, EnvInfo, FailureKind, SetSecretRequest, UserResponse,
+    WRITE_SCOPE,
+};
+
+/// How many secrets a single API Key may store
+const MAX_SECRETS: usize = 100;
+
+/// The longest a secret may be, in bytes
+const MAX_SECRET_BYTES: usize = 8192;
+
+/// The longest the name of a secret may be
+const MAX_NAME_CHARS: usize = 64;
+
+/// The length of the nonce stored in front of each encrypted secret, in bytes
+const NONCE_BYTES: usize = 12;
+
+/// The failure returned when no `SECRETS_KEY` is configured
+const DISABLED_FAILURE: &str = "Secrets are disabled on this server";
+
+/// The secrets of an API Key, indexed by name. Each is encrypted, see `seal`
+pub type Vault = HashMap<String, String>;
+
+/// Encrypt a secret, as base64 of a random nonce followed by the ciphertext
+///
+/// The owner and name are authenticated along with the secret, so that a ciphertext copied
+/// to another owner or name fails to decrypt, rather than giving the secret away.
+fn seal(cipher: &Aes256Gcm, owner: &str, name: &str, value: &str) -> Option<String> {
+    let nonce: [u8; NONCE_BYTES] = rand::thread_rng().gen();
+    let aad = format!("{}/{}", owner, name);
+    let payload = Payload {
+        msg: value.as_bytes(),
+        aad: aad.as_bytes(),
+    };
+    let mut sealed = nonce.to_vec();
+    sealed.extend(
+        cipher
+            .encrypt(GenericArray::from_slice(&nonce), payload)
+            .ok()?,
+    );
+    Some(base64::encode(sealed))
+}
+
+/// Decrypt a secret sealed by `seal`
+fn unseal(cipher: &Aes256Gcm, owner: &str, name: &str, sealed: &str) -> Option<String> {
+    let sealed = base64::decode(sealed).ok()?;
+    if sealed.len() < NONCE_BYTES {
+        return None;
+    }
+    let (nonce, msg) = sealed.split_at(NONCE_BYTES);
+    let aad = format!("{}/{}", owner, name);
+    let payload = Payload {
+        msg,
+        aad: aad.as_bytes(),
+    };
+    let value = cipher
+        .decrypt(GenericArray::from_slice(nonce), payload)
+        .ok()?;
+    String::from_utf8(value).ok()
+}
+
+/// The secrets of every API Key, encrypted at rest
+///
+/// Secrets are indexed by the hash of the key which owns them, the same hash handlers record as
+/// their owner, so that handlers can only get at the secrets of whoever uploaded them.
+pub struct Secrets {
+    store: JsonStore<Vault>,
+    /// None if no `SECRETS_KEY` is configured, which disables secrets
+    cipher: Option<Aes256Gcm>,
+}
+
+impl Secrets {
+    /// Open the secrets saved at `path`
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - Where the secrets are saved
+    /// * `passphrase` - What the secrets are encrypted with. None disables secrets
+    pub fn open(path: String, passphrase: Option<&str>) -> Secrets {
+        let cipher = passphrase.map(|p| {
+            let key = Sha256::digest(p.as_bytes());
+            Aes256Gcm::new(GenericArray::from_slice(&key))
+        });
+
+        Secrets {
+            store: JsonStore::open(path),
+            cipher,
+        }
+    }
+
+    /// Store a secret, replacing any previous one of the same name
+    ///
+    /// # Arguments
+    ///
+    /// * `owner` - The hash of the API Key which owns the secret
+    /// * `name` - The name of the secret
+    /// * `value` - The secret itself
+    pub fn set(&self, owner: &str, name: &str, value: &str) -> Result<(), String> {
+        let cipher = self.cipher.as_ref().ok_or(DISABLED_FAILURE)?;
+        let sealed = seal(cipher, owner, name, value).ok_or("Unable to encrypt the secret")?;
+
+        self.store.update(|map| {
+            let vault = map.entry(owner.to_string()).or_default();
+            if vault.len() >= MAX_SECRETS && !vault.contains_key(name) {
+                return Err(format!(
+                    "An API Key may store at most {} secrets",
+                    MAX_SECRETS
+                ));
+            }
+            vault.insert(name.to_string(), sealed);
+            Ok(())
+        })
+    }
+
+    /// Remove a secret, returning whether there was one
+    ///
+    /// # Arguments
+    ///
+    /// * `owner` - The hash of the API Key which owns the secret
+    /// * `name` - The name of the secret
+    pub fn delete(&self, owner: &str, name: &str) -> bool {
+        self.store.update(|map| {
+            let removed = map
+                .get_mut(owner)
+                .map(|vault| vault.remove(name).is_some())
+                .unwrap_or(false);
+            if map.get(owner).map(Vault::is_empty).unwrap_or(false) {
+                map.remove(owner);
+            }
+            removed
+        })
+    }
+
+    /// Get a secret, if there is one, and it can be decrypted
+    ///
+    /// # Arguments
+    ///
+    /// * `owner` - The hash of the API Key which owns the secret
+    /// * `name` - The name of the secret
+    pub fn get(&self, owner: &str, name: &str) -> Option<String> {
+        let cipher = self.cipher.as_ref()?;
+        let guard = self.store.read();
+        let sealed = guard.get(owner)?.get(name)?;
+        unseal(cipher, owner, name, sealed)
+    }
+
+    /// The names of the secrets of an API Key, sorted, but never their values
+    ///
+    /// # Arguments
+    ///
+    /// * `owner` - The hash of the API Key which owns the secrets
+    pub fn names(&self, owner: &str) -> Vec<String> {
+        let mut names = self
+            .store
+            .read()
+            .get(owner)
+            .map(|vault| vault.keys().cloned().collect::<Vec<String>>())
+            .unwrap_or_default();
+        names.sort();
+        names
+    }
+}
+
+/// Check the name of a secret, so that names stay easy to refer to from handlers
+fn check_name(name: &str) -> Result<(), String> {
+    let valid = !name.is_empty()
+        && name.chars().count() <= MAX_NAME_CHARS
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-' || c == '.');
+    if valid {
+        Ok(())
+    } else {
+        Err(format!(
+            "Secret names must be 1 to {} letters, digits, '_', '-' or '.'",
+            MAX_NAME_CHARS
+        ))
+    }
+}
+
+/// Register the secret functions available to clients
+///
+/// * `secret_get(name)` returns a secret set via `/set_secret` with the API Key which owns the
+///   handler, or `()` if there is none
+///
+/// Values are never logged, only which secret was asked for.
+///
+/// # Arguments
+///
+/// * `module` - The module to register the functions in
+/// * `services` - Where secrets are stored
+/// * `id` - The correlation id of the run, attached to every log line
+/// * `handler_addr` - The uri of the handler the functions are for
+/// * `owner` - The hash of the API Key which owns the handler
+pub fn register(
+    module: &mut Module,
+    services: &Services,
+    id: &CorrelationId,
+    handler_addr: &str,
+    owner: &str,
+) {
+    let secrets = services.secrets.clone();
+    let addr = handler_addr.to_string();
+    let owner = owner.to_string();
+    let cid = id.clone();
+    let secret_get = move |name: ImmutableString| -> Result<Dynamic, Box<EvalAltResult>> {
+        let value = secrets.get(&owner, &name);
+        log_event!(
+            "secret.get",
+            id = cid.0,
+            handler = addr,
+            name = name,
+            found = value.is_some()
+        );
+        Ok(value
+            .map(Dynamic::from)
+            .unwrap_or_else(|| Dynamic::from(())))
+    };
+
+    module.set_fn_1("secret_get", secret_get);
+}
+
+/// Rocket Endpoint which stores a secret for the handlers of an API Key
+///
+/// The secret is encrypted before it is saved, and can only be read back by those handlers, via
+/// `secret_get`, never through the API.
+///
+/// # Arguments
+///
+/// * `auth` - The API Key from the `Authorization` header, if any
+/// * `env` - Environment variables
+/// * `services` - Where secrets are stored
+/// * `api_keys` - A reference to the collection of Client API keys, used to check for auth
+/// * `post_data` - The name and value of the secret
+#[post("/set_secret", data = "<post_data>")]
+pub fn set_secret(
+    auth: AuthHeader,
+    env: State<EnvInfo>,
+    services: State<Services>,
+    api_keys: Collection<String, ApiKeyInfo>,
+    post_data: Json<SetSecretRequest>,
+) -> Json<UserResponse> {
+    let data = post_data.0;
+    let key = auth.key_or(&data.api_key);
+
+    if let Err(cause) = auth.verify(&key, check_auth(&key, &api_keys), "Invalid API Key") {
+        return Json(UserResponse::from(cause));
+    }
+    if let Err(cause) = check_scope(&key, &api_keys, WRITE_SCOPE) {
+        return Json(UserResponse::from(cause));
+    }
+
+    if env.read_only {
+        return Json(UserResponse::read_only());
+    }
+
+    if let Err(cause) = check_name(&data.name) {
+        return Json(UserResponse::failure(cause));
+    }
+    if data.value.len() > MAX_SECRET_BYTES {
+        return Json(UserResponse::failure(format!(
+            "Secrets may be at most {} bytes",
+            MAX_SECRET_BYTES
+        )));
+    }
+
+    let owner = hash_key(&key);
+    match services.secrets.set(&owner, &data.name, &data.value) {
+        Ok(_) => {
+            log_event!("audit.secret_set", key = &owner[..8], name = data.name);
+            Json(UserResponse::success())
+        }
+        Err(cause) => Json(UserResponse::failure(cause)),
+    }
+}
+
+/// Rocket Endpoint which removes a secret of an API Key
+///
+/// # Arguments
+///
+/// * `auth` - The API Key from the `Authorization` header, if any
+/// * `env` - Environment variables
+/// * `services` - Where secrets are stored
+/// * `api_keys` - A reference to the collection of Client API keys, used to check for auth
+/// * `post_data` - The name of the secret
+#[post("/delete_secret", data = "<post_data>")]
+pub fn delete_secret(
+    auth: AuthHeader,
+    env: State<EnvInfo>,
+    services: State<Services>,
+    api_keys: Collection<String, ApiKeyInfo>,
+    post_data: Json<DeleteSecretRequest>,
+) -> Json<UserResponse> {
+    let data = post_data.0;
+    let key = auth.key_or(&data.api_key);
+
+    if let Err(cause) = auth.verify(&key, check_auth(&key, &api_keys), "Invalid API Key") {
+        return Json(UserResponse::from(cause));
+    }
+    if let Err(cause) = check_scope(&key, &api_keys, WRITE_SCOPE) {
+        return Json(UserResponse::from(cause));
+    }
+
+    if env.read_only {
+        return Json(UserResponse::read_only());
+    }
+
+    let owner = hash_key(&key);
+    if services.secrets.delete(&owner, &data.name) {
+        log_event!("audit.secret_delete", key = &owner[..8], name = data.name);
+        Json(UserResponse::success())
+    } else {
+        Json(UserResponse::failure_of(
+            FailureKind::NotFound,
+            format!("No secret named {}", data.name),
+        ))
+    }
+}
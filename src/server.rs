@@ -1,56 +1,128 @@
 use std::collections::HashMap;
-use std::fs::File;
-use std::io::Write;
 use std::ops::{Deref, DerefMut};
-use std::sync::RwLock;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
 
 use rocket::config::Environment;
 use rocket::logger::LoggingLevel;
-use rocket::response::content::{Html, JavaScript};
+use rocket::request::{FromQuery, Query};
+use rocket::response::content::Html;
 use rocket::{Config, Request, Rocket, State};
 
 use rocket_contrib::json::Json;
 
-use rhai::{Engine, ImmutableString, Module, Scope};
+use rhai::{Array, Dynamic, Engine, EvalAltResult, ImmutableString, Map, Module, Scope};
 
 use reqwest::blocking::{Client, Response};
 use reqwest::header::{HeaderMap, HeaderValue, AUTHORIZATION, CONTENT_TYPE, USER_AGENT};
 
 use serde::de::DeserializeOwned;
+use serde_json::json;
 
 use rand::*;
 
+use crate::admin;
+use crate::api;
+use crate::apply;
+use crate::approvals;
+use crate::archive;
+use crate::assets::{Assets, Served};
+use crate::auth::{
+    check_admin, check_auth, check_scope, describe_key, hash_key, hash_plain_owners, AuthHeader,
+    Lockouts, SlackBody,
+};
+use crate::broadcast;
+use crate::calendar;
+use crate::changes;
+use crate::cleanup;
+use crate::clock::unix_now;
+use crate::codecheck;
+use crate::completions;
+use crate::crosspost;
+use crate::dependencies;
+use crate::dryrun::{self, Transcript};
+use crate::envelope::{self, Envelope};
+use crate::faults;
+use crate::feed;
+use crate::flags;
+use crate::github;
+use crate::grpc;
+use crate::handler_logs;
+use crate::help;
+use crate::help::render_help;
+use crate::history;
+use crate::home;
+use crate::http_client;
+use crate::invoke;
+use crate::json;
+use crate::k8s;
+use crate::kv;
+use crate::libraries;
+use crate::logging::{CorrelationId, RequestLogger};
+use crate::metrics;
+use crate::namespaces::{check_uri, namespace_of, resolve_uri};
+use crate::oncall;
+use crate::polls;
+use crate::probes;
+use crate::ratelimit::{RateLimits, RetryAfterHeader, Throttle};
+use crate::relay;
+use crate::releases;
+use crate::reminders;
+use crate::remote;
+use crate::reprocess;
+use crate::responses::{reply_from, Reply};
+use crate::scheduler;
+use crate::search;
+use crate::secrets;
+use crate::services::Services;
+use crate::signing::check_signature;
+use crate::slack;
+use crate::slash;
+use crate::stale;
+use crate::stats;
+use crate::status;
+use crate::storage::{ReplicaRefresher, Storage};
+use crate::twilio;
 use crate::types::{
-    APIKeyRequest, EnvInfo, FindHandlerRequest, FindHandlerResponse, GenericOkResponse,
-    GithubIssueCreateResponse, Handler, SlackConversationInfoResponse, SlackEvent,
-    UpsertHandlerRequest, UserResponse,
+    APIKeyRequest, ActivateKeyRequest, AdminRequest, ApiKeyInfo, EnvInfo, FailureKind,
+    FindHandlerRequest, FindHandlerResponse, GenericOkResponse, GithubIssueCommentResponse,
+    GithubIssueCreateResponse, Handler, HandlerMetadata, HandlerSpec, HandlerSummary,
+    SlackConversationInfoResponse, SlackEvent, SlackEventInner, SlackMessage, SyncDiff,
+    SyncFromRequest, UpsertHandlerRequest, UserResponse, READ_SCOPE, WRITE_SCOPE,
 };
+use crate::uptime;
+use crate::windows::{self, Admission};
+use crate::workflow;
 
 /// A Type Alias to Emulate a Database of type V, indexed by a key type K
 /// This is:
 /// * Faster than a real db in this use-case
 /// * Sufficient for our purposes
-type Collection<'a, K, V> = State<'a, RwLock<HashMap<K, V>>>;
+///
+/// Shared so that work outside of requests, e.g. the scheduler, can see it too
+pub type Collection<'a, K, V> = State<'a, Arc<RwLock<HashMap<K, V>>>>;
 
 fn try_parse_response<T: DeserializeOwned>(req: Option<Response>) -> Option<T> {
     match req {
         Some(r) => match r.text() {
-            Ok(text) => {
-                println!("{}", text);
-                match text.parse() {
-                    Ok(v) => serde_json::from_value(v).ok(),
-                    Err(t) => {
-                        println!("\t=> Unexpected error triggered! {}", t.to_string());
-                        None
-                    }
+            Ok(text) => match text.parse() {
+                Ok(v) => serde_json::from_value(v).ok(),
+                Err(t) => {
+                    log_event!("outbound.parse_error", error = t, body = text);
+                    None
                 }
-            }
+            },
             Err(_) => None,
         },
         None => None,
     }
 }
 
+/// The body of a `chat.postMessage`, with whatever the Client sent escaped as json
+fn slack_post_body(channel: &str, message: &str) -> String {
+    json!({ "channel": channel, "text": message, "unfurl_links": true }).to_string()
+}
+
 /// Post a message to Slack
 ///
 /// # Arguments
@@ -59,8 +131,13 @@ fn try_parse_response<T: DeserializeOwned>(req: Option<Response>) -> Option<T> {
 /// * `token` - The slack token to authenticate with. Never seen by Clients
 /// * `channel` - The channel to post to. Specified by the Clients
 /// * `message` - The message to send. Specified by the Clients
-fn slack_post_internal(client: &Client, token: &String, channel: String, message: String) -> bool {
-    if token == "no-slack" {
+pub fn slack_post_internal(
+    client: &Client,
+    token: &String,
+    channel: String,
+    message: String,
+) -> bool {
+    if token == "no-slack" || faults::inject("slack").is_err() {
         return false;
     }
 
@@ -71,27 +148,53 @@ fn slack_post_internal(client: &Client, token: &String, channel: String, message
     let req: Result<Response, _> = client
         .post("https://slack.com/api/chat.postMessage")
         .headers(headers)
-        .body(format!(
-            "{{ \"channel\": \"{}\", \"text\": \"{}\", \"unfurl_links\": \"true\"}}",
-            channel, message
-        ))
+        .body(slack_post_body(&channel, &message))
         .send();
 
     let msg: Option<GenericOkResponse> = try_parse_response(req.ok());
-    println!("\t=> Slack: {:?}", msg);
+    log_event!(
+        "slack.post.result",
+        ok = msg.as_ref().map(|m| m.ok).unwrap_or(false)
+    );
     match msg {
         Some(i) => i.ok,
         None => false,
     }
 }
 
+/// The body of a request to open a GitHub issue, with whatever the Client sent escaped as json
+fn github_issue_body(title: &str, body: &str, labels: &[String], assignees: &[String]) -> String {
+    json!({
+        "title": title,
+        "body": body,
+        "labels": labels,
+        "assignees": assignees
+    })
+    .to_string()
+}
+
+/// Open an issue on GitHub
+///
+/// # Arguments
+///
+/// * `client` - A reqwest HTTP "client" to make the request. Never seen by Clients
+/// * `token` - The github token to authenticate with. Never seen by Clients
+/// * `repo` - The repository, `<owner>/<repo>`. Specified by the Clients
+/// * `title` - The title of the issue. Specified by the Clients
+/// * `body` - The body of the issue. Specified by the Clients
+/// * `labels` - The labels to give it, if any. Specified by the Clients
+/// * `assignees` - The logins of whoever to assign it to, if anyone. Specified by the Clients
 fn github_issue_create_internal(
     client: &Client,
     token: &String,
     repo: String,
     title: String,
     body: String,
+    labels: Vec<String>,
+    assignees: Vec<String>,
 ) -> Option<GithubIssueCreateResponse> {
+    faults::inject("github").ok()?;
+
     let mut headers = HeaderMap::new();
     headers.insert(AUTHORIZATION, format!("token {}", token).parse().unwrap());
     headers.insert(USER_AGENT, HeaderValue::from_static("dti-majordomo"));
@@ -99,123 +202,762 @@ fn github_issue_create_internal(
     let req: Result<Response, _> = client
         .post(&format!("https://api.github.com/repos/{}/issues", repo))
         .headers(headers)
-        .body(format!(
-            "{{ \"title\": \"{}\", \"body\": \"{}\"}}",
-            title, body
-        ))
+        .body(github_issue_body(&title, &body, &labels, &assignees))
         .send();
 
     let resp: Option<GithubIssueCreateResponse> = try_parse_response(req.ok());
-    println!("\t=> Github Issue Create: {:?}", resp);
+    log_event!("github.issue_create.result", ok = resp.is_some());
     resp
 }
 
-/// Rocket Endpoint which passes User Requests onto the Client provided handlers
+/// Build the Rhai engine a handler runs in
+///
+/// This is where the functions available to Client code are registered. Besides those each
+/// subsystem registers, there are:
+///
+/// * `slack_post(channel, message)` posts a message to a channel. Returns whether it was posted
+/// * `slack_dm(user, message)` sends a message to a user directly, by their id, e.g. to answer
+///   privately. Returns whether it was sent
+/// * `github_issue_create(repo, title, body)` opens an issue in `<owner>/<repo>`, and returns
+///   it, with its `url`, `id` and `title`. `github_issue_create(repo, title, body, options)`
+///   also gives it the `labels` and `assignees` listed in `options`, e.g.
+///   `#{labels: ["bug"], assignees: ["octocat"]}`
+/// * `debug_println(message)` logs a line, which shows up in `/handler_logs`
+/// * `runbook(uri)` returns the runbook of one of the API Key's handlers, e.g. for an alert to
+///   say what to do next. Empty if it has none
 ///
 /// # Arguments
 ///
 /// * `env` - Environment variables
-/// * `handlers` - A reference to the collection of User created handlers, indexed by their uris
-/// * `handler_addr` - The address of the handler that the User has invoked
-/// * `post_data` - Any post data that the client has passed alone with the request
-#[post("/h/<handler_addr>", data = "<post_data>")]
-fn call_handler(
-    env: State<EnvInfo>,
-    handlers: Collection<String, Handler>,
-    handler_addr: String,
-    post_data: String,
-) -> Json<UserResponse> {
-    let guard = handlers.read().unwrap();
-    let map = guard.deref();
+/// * `services` - The stateful subsystems available to handlers
+/// * `id` - The correlation id to attach to every log line
+/// * `handler_addr` - The uri of the handler being run
+/// * `owner` - The hash of the API Key which owns the handler, whose secrets and modules it may
+///   use
+pub fn build_engine(
+    env: &EnvInfo,
+    services: &Services,
+    id: &CorrelationId,
+    handler_addr: &str,
+    owner: &str,
+) -> Engine {
+    // Provide a way for Client code to make slack requests
+    // Note that the API exposed to clients does not allow them to specify a token
+    // That is hidden away, and never exposed to Rhai, so it cannot be leaked
+    let client = services.http.clone();
+    let slack_token = env.slack_token.clone();
+    let addr = handler_addr.to_string();
+    let cid = id.clone();
+    let slack_post = move |channel: ImmutableString, message: ImmutableString| {
+        log_event!(
+            "slack.post",
+            id = cid.0,
+            handler = addr,
+            channel = channel,
+            message = message,
+        );
 
-    match map.get(&handler_addr) {
-        Some(handler) => {
-            // Provide a way for Client code to make slack requests
-            // Note that the API exposed to clients does not allow them to specify a token
-            // That is hidden away, and never exposed to Rhai, so it cannot be leaked
-            let client = Client::new();
-            let slack_token = env.slack_token.clone();
-            let addr = handler_addr.clone();
-            let slack_post = move |channel: ImmutableString, message: ImmutableString| {
-                println!(
-                    "\t=> /h/{} made a slack message in channel #{}: {}",
-                    addr, channel, message
-                );
+        Ok(slack_post_internal(
+            &client,
+            &slack_token,
+            channel.into(),
+            message.into(),
+        ))
+    };
 
-                Ok(slack_post_internal(
-                    &client,
-                    &slack_token,
-                    channel.into(),
-                    message.into(),
-                ))
+    let client = services.http.clone();
+    let slack_token = env.slack_token.clone();
+    let addr = handler_addr.to_string();
+    let cid = id.clone();
+    let slack_dm = move |user: ImmutableString,
+                         message: ImmutableString|
+          -> Result<bool, Box<EvalAltResult>> {
+        log_event!("slack.dm", id = cid.0, handler = addr, user = user);
+
+        match slack::open_dm(&client, &slack_token, &user) {
+            Ok(channel) => Ok(slack_post_internal(
+                &client,
+                &slack_token,
+                channel,
+                message.into(),
+            )),
+            Err(e) => {
+                log_event!("slack.dm_error", id = cid.0, handler = addr, error = e);
+                Ok(false)
+            }
+        }
+    };
+
+    // Provide a way for Client code to make slack requests
+    // Note that the API exposed to clients does not allow them to specify a token
+    // That is hidden away, and never exposed to Rhai, so it cannot be leaked
+    let client = services.http.clone();
+    let github_token = env.github_token.clone();
+    let addr = handler_addr.to_string();
+    let cid = id.clone();
+    let github_issue_create =
+        move |repo: ImmutableString, title: ImmutableString, body: ImmutableString| {
+            log_event!(
+                "github.issue_create",
+                id = cid.0,
+                handler = addr,
+                repo = repo,
+                title = title,
+            );
+
+            github_issue_create_internal(
+                &client,
+                &github_token,
+                repo.into(),
+                title.into(),
+                body.into(),
+                Vec::new(),
+                Vec::new(),
+            )
+            .ok_or("Test".into())
+        };
+
+    let client = services.http.clone();
+    let github_token = env.github_token.clone();
+    let addr = handler_addr.to_string();
+    let cid = id.clone();
+    let github_issue_create_with =
+        move |repo: ImmutableString,
+              title: ImmutableString,
+              body: ImmutableString,
+              options: Map|
+              -> Result<GithubIssueCreateResponse, Box<EvalAltResult>> {
+            let list = |key: &str| {
+                options
+                    .get(key)
+                    .cloned()
+                    .map(|value| value.try_cast::<Array>())
+                    .flatten()
+                    .unwrap_or_default()
+                    .into_iter()
+                    .map(|item| item.to_string())
+                    .collect::<Vec<String>>()
             };
+            let (labels, assignees) = (list("labels"), list("assignees"));
+            log_event!(
+                "github.issue_create",
+                id = cid.0,
+                handler = addr,
+                repo = repo,
+                title = title,
+                labels = labels.join(","),
+                assignees = assignees.join(","),
+            );
 
-            // Provide a way for Client code to make slack requests
-            // Note that the API exposed to clients does not allow them to specify a token
-            // That is hidden away, and never exposed to Rhai, so it cannot be leaked
-            let client = Client::new();
-            let github_token = env.github_token.clone();
-            let addr = handler_addr.clone();
-            let github_issue_create =
-                move |repo: ImmutableString, title: ImmutableString, body: ImmutableString| {
-                    println!(
-                        "\t=> /h/{} created a new issue in {}, with title: {} and body: {}",
-                        addr, repo, title, body
-                    );
+            github_issue_create_internal(
+                &client,
+                &github_token,
+                repo.to_string(),
+                title.into(),
+                body.into(),
+                labels,
+                assignees,
+            )
+            .ok_or_else(|| format!("Unable to create an issue in {}", repo).into())
+        };
 
-                    github_issue_create_internal(
-                        &client,
-                        &github_token,
-                        repo.into(),
-                        title.into(),
-                        body.into(),
-                    )
-                    .ok_or("Test".into())
-                };
+    let logs = services.logs.clone();
+    let addr = handler_addr.to_string();
+    let cid = id.clone();
+    let debug_println = move |string: ImmutableString| {
+        logs.record(&addr, &cid.0, "debug", string.to_string());
+        Ok(log_event!(
+            "handler.debug",
+            id = cid.0,
+            handler = addr,
+            message = string
+        ))
+    };
 
-            let debug_println = |string: ImmutableString| Ok(println!("{}", string));
+    let handlers = services.handlers.clone();
+    let key = owner.to_string();
+    let runbook = move |uri: ImmutableString| -> Result<String, Box<EvalAltResult>> {
+        // Whoever runs us may be holding the handlers already, e.g. to warm us up after an
+        // upsert, so give up rather than wait, like `invoke` does
+        let guard = handlers
+            .try_read()
+            .map_err(|_| "Handlers are being updated, try again")?;
+        match guard.get(uri.as_str()) {
+            Some(h) if h.owned_by(&key) => Ok(h.runbook.clone().unwrap_or_default()),
+            _ => Err(format!("Unknown handler uri {}", uri).into()),
+        }
+    };
 
-            // Register the various functions available to clients
-            let mut module = Module::new();
-            module.set_fn_2("slack_post", slack_post);
-            module.set_fn_3("github_issue_create", github_issue_create);
-            module.set_fn_1("debug_println", debug_println);
+    // Register the various functions available to clients
+    let mut module = Module::new();
+    module.set_fn_2("slack_post", slack_post);
+    module.set_fn_2("slack_dm", slack_dm);
+    module.set_fn_3("github_issue_create", github_issue_create);
+    module.set_fn_4("github_issue_create", github_issue_create_with);
+    module.set_fn_1("debug_println", debug_println);
+    module.set_fn_1("runbook", runbook);
+    json::register(&mut module);
+    slack::register(&mut module, env, services, id, handler_addr);
+    github::register(&mut module, env, services, id, handler_addr);
+    releases::register(&mut module, env, services, id, handler_addr);
+    crosspost::register(&mut module, env, services, id, handler_addr);
+    http_client::register(&mut module, env, services, id, handler_addr);
+    remote::register(&mut module, env, id, handler_addr);
+    probes::register(&mut module, env, id, handler_addr);
+    broadcast::register(&mut module, services, id, handler_addr);
+    approvals::register(&mut module, env, services, handler_addr);
+    polls::register(&mut module, env, services, handler_addr);
+    reminders::register(&mut module, services, handler_addr);
+    oncall::register(&mut module, services, handler_addr);
+    kv::register(&mut module, services, handler_addr);
+    kv::register_scores(&mut module, services, handler_addr);
+    stale::register(&mut module, services, handler_addr);
+    uptime::register(&mut module, env, services, handler_addr);
+    calendar::register(&mut module, services, handler_addr);
+    feed::register(&mut module, services, handler_addr);
+    flags::register(&mut module, services, handler_addr, owner);
+    metrics::register(&mut module, services, id, handler_addr);
+    k8s::register(&mut module, services, id, handler_addr);
+    invoke::register(&mut module, env, services, id, handler_addr);
+    secrets::register(&mut module, services, id, handler_addr, owner);
 
-            let mut engine = Engine::new();
-            engine.load_package(module);
-            engine.set_max_operations(1000);
-            engine
-                .register_type::<GithubIssueCreateResponse>()
-                .register_get("url", GithubIssueCreateResponse::get_url)
-                .register_get("id", GithubIssueCreateResponse::get_id)
-                .register_get("title", GithubIssueCreateResponse::get_title);
-            let engine = engine;
+    let mut engine = Engine::new();
+    engine.load_package(module);
+    engine.set_module_resolver(Some(libraries::resolver(services, owner)));
+    engine
+        .register_type::<GithubIssueCreateResponse>()
+        .register_get("url", GithubIssueCreateResponse::get_url)
+        .register_get("id", GithubIssueCreateResponse::get_id)
+        .register_get("title", GithubIssueCreateResponse::get_title);
+    engine
+        .register_type::<GithubIssueCommentResponse>()
+        .register_get("url", GithubIssueCommentResponse::get_url)
+        .register_get("id", GithubIssueCommentResponse::get_id)
+        .register_get("body", GithubIssueCommentResponse::get_body);
+    engine
+}
 
-            // Run the client's code in response to user request
-            let mut scope = Scope::new();
-            let result = engine.call_fn(&mut scope, &handler.code.ast, "handle", (post_data,));
+/// Hold an engine to the execution limits of the handler about to run in it
+///
+/// The time limit starts counting now, so this should be called just before running the handler.
+///
+/// # Arguments
+///
+/// * `engine` - The engine the handler will run in
+/// * `env` - Environment variables, for the server's ceilings
+/// * `handler` - The handler which will run
+pub fn limit_engine(engine: &mut Engine, env: &EnvInfo, handler: &Handler) {
+    let deadline = Instant::now() + handler.time_limit(env);
+    engine.set_max_operations(handler.operations_limit(env));
+    // Checking the clock on every operation would slow everything down
+    engine.on_progress(move |&ops| ops % 100 != 0 || Instant::now() < deadline);
+}
+
+/// Add how a run of a handler went to its log and its stats
+///
+/// # Arguments
+///
+/// * `services` - Where the logs and stats are kept
+/// * `id` - The correlation id of the run
+/// * `handler_addr` - The uri of the handler which ran
+/// * `function` - The function of the handler which ran, e.g. `handle`
+/// * `started` - When the run started
+/// * `result` - How the run went
+pub fn record_run<T>(
+    services: &Services,
+    id: &CorrelationId,
+    handler_addr: &str,
+    function: &str,
+    started: Instant,
+    result: &Result<T, Box<EvalAltResult>>,
+) {
+    let elapsed = started.elapsed().as_millis();
+    services
+        .stats
+        .record(handler_addr, elapsed as u64, result.is_ok());
+    match result {
+        Ok(_) => services.logs.record(
+            handler_addr,
+            &id.0,
+            "invocation",
+            format!("{} ran in {}ms", function, elapsed),
+        ),
+        Err(e) => services.logs.record(
+            handler_addr,
+            &id.0,
+            "error",
+            format!("{} failed after {}ms: {}", function, elapsed, e),
+        ),
+    }
+}
+
+/// Run some of a handler's code, e.g. one of its hooks like `on_event`, or a replay
+///
+/// Every run of Client code goes through here, so that each is held to the handler's limits, see
+/// `limit_engine`, has its faults armed, see `faults::arm`, and is recorded in the handler's log
+/// and stats, see `record_run`. Dry runs are the exception: their integrations are mocked, and
+/// they aren't faulted or recorded, since nothing really happened.
+///
+/// # Arguments
+///
+/// * `env` - Environment variables
+/// * `services` - The stateful subsystems available to handlers
+/// * `id` - The correlation id to attach to every log line
+/// * `handler_addr` - The uri of the handler being run
+/// * `handler` - The handler to run
+/// * `dry_run` - Where to record the calls to mocked integrations, if this is a dry run, see
+///               `dryrun::mock_integrations`
+/// * `function` - The function being run, which the run is recorded as, e.g. `on_event`
+/// * `call` - Calls the function in the engine it is given
+#[allow(clippy::too_many_arguments)]
+pub fn run_function<F>(
+    env: &EnvInfo,
+    services: &Services,
+    id: &CorrelationId,
+    handler_addr: &str,
+    handler: &Handler,
+    dry_run: Option<&Transcript>,
+    function: &str,
+    call: F,
+) -> Result<Dynamic, Box<EvalAltResult>>
+where
+    F: FnOnce(&Engine) -> Result<Dynamic, Box<EvalAltResult>>,
+{
+    let started = Instant::now();
+    let mut engine = build_engine(env, services, id, handler_addr, &handler.api_key);
+    if let Some(transcript) = dry_run {
+        dryrun::mock_integrations(&mut engine, transcript);
+        limit_engine(&mut engine, env, handler);
+        return call(&engine);
+    }
+
+    let _faults = faults::arm(services, handler_addr);
+    limit_engine(&mut engine, env, handler);
+    let result = call(&engine);
+    record_run(services, id, handler_addr, function, started, &result);
+    result
+}
+
+/// Run a handler's `handle` function against some payload
+///
+/// Used both by User requests and by warm-up invocations. The run is recorded in the handler's
+/// log.
+///
+/// # Arguments
+///
+/// * `env` - Environment variables
+/// * `services` - The stateful subsystems available to handlers
+/// * `id` - The correlation id to attach to every log line
+/// * `handler_addr` - The uri of the handler being run
+/// * `handler` - The handler to run
+/// * `payload` - The data to pass to the handler
+/// * `context` - More information about where the payload came from, e.g. the Slack event.
+///               Passed as a second argument if the handler defines `handle(payload, context)`
+pub fn run_handler(
+    env: &EnvInfo,
+    services: &Services,
+    id: &CorrelationId,
+    handler_addr: &str,
+    handler: &Handler,
+    payload: String,
+    context: Option<Map>,
+) -> Result<String, Box<EvalAltResult>> {
+    let value = run_handler_dynamic(env, services, id, handler_addr, handler, payload, context)?;
+    let type_name = value.type_name();
+    value
+        .try_cast::<String>()
+        .ok_or_else(|| format!("handle returned {}, rather than a string", type_name).into())
+}
+
+/// Run a handler's `handle` function against some payload, like `run_handler`, but accept
+/// whatever it returns, e.g. a response map, see `responses::reply_from`
+///
+/// Every run, warm-ups included, is archived if the handler is, see `archive::Archive`. Relays
+/// forward the payload rather than run code, see `relay::forward`.
+///
+/// # Arguments
+///
+/// * `env` - Environment variables
+/// * `services` - The stateful subsystems available to handlers
+/// * `id` - The correlation id to attach to every log line
+/// * `handler_addr` - The uri of the handler being run
+/// * `handler` - The handler to run
+/// * `payload` - The data to pass to the handler
+/// * `context` - More information about where the payload came from, e.g. the Slack event.
+///               Passed as a second argument if the handler defines `handle(payload, context)`
+pub fn run_handler_dynamic(
+    env: &EnvInfo,
+    services: &Services,
+    id: &CorrelationId,
+    handler_addr: &str,
+    handler: &Handler,
+    payload: String,
+    context: Option<Map>,
+) -> Result<Dynamic, Box<EvalAltResult>> {
+    services
+        .archive
+        .record(&id.0, handler_addr, &payload, &context, unix_now());
+
+    if let Some(relay) = &handler.relay {
+        let started = Instant::now();
+        let _faults = faults::arm(services, handler_addr);
+        let result = relay::forward(env, services, id, handler_addr, relay, &payload)
+            .map(Dynamic::from)
+            .map_err(|e| e.into());
+        record_run(services, id, handler_addr, "relay", started, &result);
+        return result;
+    }
+
+    let ast = &handler.code.ast;
+    run_function(
+        env,
+        services,
+        id,
+        handler_addr,
+        handler,
+        None,
+        "handle",
+        |engine| match context {
+            Some(context) if handler.defines("handle", 2) => {
+                engine.call_fn(&mut Scope::new(), ast, "handle", (payload, context))
+            }
+            _ => engine.call_fn(&mut Scope::new(), ast, "handle", (payload,)),
+        },
+    )
+}
 
-            match result {
-                Ok(res) => Json(UserResponse::success_with_data(res)),
+/// Invoke a handler once with the synthetic payload `"warmup"`
+///
+/// This primes anything the handler caches, and surfaces errors at deploy time rather than on
+/// the first real request. Handlers can check for the payload if they want to skip side effects.
+///
+/// # Arguments
+///
+/// * `env` - Environment variables
+/// * `services` - The stateful subsystems available to handlers
+/// * `handler` - The handler to warm up
+pub fn warm_up_handler(
+    env: &EnvInfo,
+    services: &Services,
+    handler: &Handler,
+) -> Result<String, String> {
+    let id = CorrelationId::generate();
+    let result = run_handler(
+        env,
+        services,
+        &id,
+        &handler.uri,
+        handler,
+        "warmup".into(),
+        None,
+    );
+    log_event!(
+        "handler.warmup",
+        id = id.0,
+        handler = handler.uri,
+        ok = result.is_ok()
+    );
+    result.map_err(|e| e.to_string())
+}
+
+/// Pass a User Request onto the Client provided handler it is addressed to
+///
+/// Handlers may return a string, which is wrapped in a `UserResponse`, or a map describing the
+/// response to send, see `responses::reply_from`. Requests to handlers which don't exist go to
+/// the catch-all handler, if there is one, as `handle(addr, payload)`.
+///
+/// # Arguments
+///
+/// * `id` - The correlation id of the request, attached to every log line
+/// * `env` - Environment variables
+/// * `services` - The stateful subsystems available to handlers
+/// * `handlers` - The User created handlers, indexed by their uris
+/// * `throttle` - Holds each handler to its rate limit
+/// * `handler_addr` - The address of the handler that the User has invoked
+/// * `payload` - The data to pass to the handler
+/// * `context` - More about the request, passed as a second argument if the handler defines
+///               `handle(payload, context)`
+#[allow(clippy::too_many_arguments)]
+pub fn dispatch(
+    id: &CorrelationId,
+    env: &EnvInfo,
+    services: &Services,
+    handlers: &RwLock<HashMap<String, Handler>>,
+    throttle: &Throttle,
+    handler_addr: String,
+    payload: String,
+    context: Option<Map>,
+) -> Reply {
+    let limit = env
+        .handler_rate_limits
+        .get(&handler_addr)
+        .copied()
+        .unwrap_or(env.handler_rate_limit);
+    if let Err(cause) = throttle.check(&format!("handler:{}", handler_addr), limit) {
+        return Reply::Wrapped(Json(UserResponse::failure(cause)));
+    }
+
+    let guard = handlers.read().unwrap();
+    let map = guard.deref();
+
+    match map.get(&handler_addr) {
+        Some(handler) => {
+            // Outside its active window, the call may be refused, queued or routed elsewhere
+            let now = unix_now();
+            let (addr, handler) = match windows::admit(
+                services,
+                map,
+                &handler_addr,
+                handler,
+                &payload,
+                &context,
+                now,
+            ) {
+                Admission::Run(addr, handler) => (addr, handler),
+                Admission::Queued => {
+                    let data = format!("Queued until {} is active", handler_addr);
+                    return Reply::Wrapped(Json(UserResponse::success_with_data(data)));
+                }
+                Admission::Rejected(cause) => {
+                    return Reply::Wrapped(Json(UserResponse::failure(cause)))
+                }
+            };
+
+            // Run the client's code in response to user request
+            let result = run_handler_dynamic(env, services, id, addr, handler, payload, context);
+            match result.map_err(|e| e.to_string()).and_then(reply_from) {
+                Ok(reply) => reply,
                 Err(e) => {
-                    println!("\t=> Error running client code: {}", e);
-                    Json(UserResponse::failure("Error running client code!".into()))
+                    log_event!("handler.error", id = id.0, handler = addr, error = e);
+                    Reply::Wrapped(Json(UserResponse::failure(
+                        "Error running client code!".into(),
+                    )))
                 }
             }
         }
         None => {
-            let cause = format!("Unable to find endpoint {}", handler_addr);
-            Json(UserResponse::failure(cause))
+            // Give the catch-all handler, if there is one, a chance to deal with this
+            let default = env
+                .default_handler
+                .as_ref()
+                .map(|uri| map.get(uri).map(|h| (uri, h)))
+                .flatten();
+
+            match default {
+                Some((uri, handler)) => {
+                    let args = (handler_addr.clone(), payload);
+                    let ast = &handler.code.ast;
+                    let result =
+                        run_function(env, services, id, uri, handler, None, "handle", |engine| {
+                            engine.call_fn(&mut Scope::new(), ast, "handle", args)
+                        });
+                    match result.map_err(|e| e.to_string()).and_then(reply_from) {
+                        Ok(reply) => reply,
+                        Err(e) => {
+                            log_event!("handler.error", id = id.0, handler = uri, error = e);
+                            Reply::Wrapped(Json(UserResponse::failure(
+                                "Error running client code!".into(),
+                            )))
+                        }
+                    }
+                }
+                None => {
+                    let cause = format!("Unable to find endpoint {}", handler_addr);
+                    Reply::Wrapped(Json(UserResponse::failure(cause)))
+                }
+            }
         }
     }
 }
 
-/// Compute if a client is authorized or not
-/// TODO documentation
-fn check_auth(key: &String, api_keys: Collection<String, ()>) -> bool {
-    let guard = api_keys.read().unwrap();
-    let map = guard.deref();
-    map.contains_key(key)
+/// The event a request to a handler's url is passed on to subscribers as, see `envelope::fan_in`
+///
+/// Requests to handlers which don't exist are nobody's, so aren't passed on.
+///
+/// # Arguments
+///
+/// * `handlers` - The User created handlers, indexed by their uris
+/// * `handler_addr` - The address of the handler the request was for
+/// * `payload` - The body of the request, or its query string
+pub fn webhook_envelope(
+    handlers: &RwLock<HashMap<String, Handler>>,
+    handler_addr: &str,
+    payload: &str,
+) -> Option<Envelope> {
+    handlers
+        .read()
+        .unwrap()
+        .get(handler_addr)
+        .map(|h| Envelope::webhook(handler_addr, &h.api_key, payload))
+}
+
+/// Rocket Endpoint which passes User Requests onto the Client provided handlers
+///
+/// # Arguments
+///
+/// * `id` - The correlation id of the request, attached to every log line
+/// * `env` - Environment variables
+/// * `services` - The stateful subsystems available to handlers
+/// * `handlers` - A reference to the collection of User created handlers, indexed by their uris
+/// * `throttle` - Holds each handler to its rate limit
+/// * `handler_addr` - The address of the handler that the User has invoked
+/// * `post_data` - Any post data that the client has passed alone with the request
+#[post("/h/<handler_addr>", data = "<post_data>")]
+fn call_handler(
+    id: CorrelationId,
+    env: State<EnvInfo>,
+    services: State<Services>,
+    handlers: Collection<String, Handler>,
+    throttle: Throttle,
+    handler_addr: String,
+    post_data: String,
+) -> Reply {
+    let envelope = webhook_envelope(&handlers, &handler_addr, &post_data);
+    let reply = dispatch(
+        &id,
+        &env,
+        &services,
+        &handlers,
+        &throttle,
+        handler_addr,
+        post_data,
+        None,
+    );
+    if let Some(envelope) = envelope {
+        envelope::fan_in(&env, &services, &id, envelope);
+    }
+    reply
+}
+
+/// Rocket Endpoint which passes User Requests onto handlers in a namespace, see `call_handler`
+///
+/// # Arguments
+///
+/// * `namespace` - The namespace of the handler, see `namespaces::namespace_of`
+/// * `name` - The name of the handler within it
+///
+/// The rest are as for `call_handler`
+#[allow(clippy::too_many_arguments)]
+#[post("/h/<namespace>/<name>", data = "<post_data>")]
+fn call_namespaced_handler(
+    id: CorrelationId,
+    env: State<EnvInfo>,
+    services: State<Services>,
+    handlers: Collection<String, Handler>,
+    throttle: Throttle,
+    namespace: String,
+    name: String,
+    post_data: String,
+) -> Reply {
+    let handler_addr = format!("{}/{}", namespace, name);
+    call_handler(
+        id,
+        env,
+        services,
+        handlers,
+        throttle,
+        handler_addr,
+        post_data,
+    )
+}
+
+/// The query parameters of a request
+pub struct QueryParams {
+    /// The query string, as it was sent
+    pub raw: String,
+    /// The decoded parameters, in the order they were given
+    pub params: Vec<(String, String)>,
+}
+
+impl<'q> FromQuery<'q> for QueryParams {
+    type Error = ();
+
+    fn from_query(query: Query<'q>) -> Result<QueryParams, ()> {
+        let mut raw = Vec::new();
+        let mut params = Vec::new();
+        for item in query {
+            raw.push(item.raw.as_str());
+            params.push(item.key_value_decoded());
+        }
+        Ok(QueryParams {
+            raw: raw.join("&"),
+            params,
+        })
+    }
+}
+
+/// Rocket Endpoint which passes GET requests onto the Client provided handlers, for webhooks and
+/// browsers which can't POST
+///
+/// Handlers are called with the query string as their payload, e.g. `a=1&b=2`, and, if they
+/// define `handle(payload, context)`, with the decoded parameters as a map. Parameters given
+/// more than once take their last value.
+///
+/// # Arguments
+///
+/// * `id` - The correlation id of the request, attached to every log line
+/// * `env` - Environment variables
+/// * `services` - The stateful subsystems available to handlers
+/// * `handlers` - A reference to the collection of User created handlers, indexed by their uris
+/// * `throttle` - Holds each handler to its rate limit
+/// * `handler_addr` - The address of the handler that the User has invoked
+/// * `params` - The query parameters
+#[get("/h/<handler_addr>?<params..>")]
+fn call_handler_get(
+    id: CorrelationId,
+    env: State<EnvInfo>,
+    services: State<Services>,
+    handlers: Collection<String, Handler>,
+    throttle: Throttle,
+    handler_addr: String,
+    params: QueryParams,
+) -> Reply {
+    let context = params
+        .params
+        .into_iter()
+        .map(|(k, v)| (k.into(), Dynamic::from(v)))
+        .collect::<Map>();
+
+    let envelope = webhook_envelope(&handlers, &handler_addr, &params.raw);
+    let reply = dispatch(
+        &id,
+        &env,
+        &services,
+        &handlers,
+        &throttle,
+        handler_addr,
+        params.raw,
+        Some(context),
+    );
+    if let Some(envelope) = envelope {
+        envelope::fan_in(&env, &services, &id, envelope);
+    }
+    reply
+}
+
+/// Rocket Endpoint which passes GET requests onto handlers in a namespace, see
+/// `call_handler_get`
+///
+/// # Arguments
+///
+/// * `namespace` - The namespace of the handler, see `namespaces::namespace_of`
+/// * `name` - The name of the handler within it
+///
+/// The rest are as for `call_handler_get`
+#[allow(clippy::too_many_arguments)]
+#[get("/h/<namespace>/<name>?<params..>")]
+fn call_namespaced_handler_get(
+    id: CorrelationId,
+    env: State<EnvInfo>,
+    services: State<Services>,
+    handlers: Collection<String, Handler>,
+    throttle: Throttle,
+    namespace: String,
+    name: String,
+    params: QueryParams,
+) -> Reply {
+    let handler_addr = format!("{}/{}", namespace, name);
+    call_handler_get(id, env, services, handlers, throttle, handler_addr, params)
 }
 
 /// Public wrapper around check auth
@@ -226,118 +968,688 @@ fn check_auth(key: &String, api_keys: Collection<String, ()>) -> bool {
 /// On the other hand, you can figure this out by calling other methods.
 #[post("/verify_key", data = "<post_data>")]
 fn verify_key(
-    api_keys: Collection<String, ()>,
-    post_data: Json<APIKeyRequest>,
+    auth: AuthHeader,
+    api_keys: Collection<String, ApiKeyInfo>,
+    post_data: Option<Json<APIKeyRequest>>,
 ) -> Json<UserResponse> {
-    match check_auth(&post_data.0.api_key, api_keys) {
-        true => Json(UserResponse::success()),
-        false => Json(UserResponse::failure("Invalid API Key".into())),
+    let key = auth.key_or(&post_data.map(|d| d.0.api_key).unwrap_or_default());
+
+    match auth.verify(&key, check_auth(&key, &api_keys), "Invalid API Key") {
+        Ok(_) => Json(UserResponse::success()),
+        Err(cause) => Json(UserResponse::from(cause)),
     }
 }
 
-/// List handlers
-/// TODO: Documentation
+/// Rocket Endpoint which activates a key on its first use, binding it to a Slack user
+///
+/// Keys issued with `requires_activation` do not work until this has been called. Once bound,
+/// a key cannot be activated again, so whoever activates it first owns it.
+///
+/// # Arguments
+///
+/// * `auth` - The API Key from the `Authorization` header, if any. Takes precedence over the body
+/// * `env` - Environment variables
+/// * `storage` - Where the keys are saved
+/// * `api_keys` - A reference to the collection of Client API keys
+/// * `post_data` - The key, and the Slack user to bind it to
+#[post("/activate_key", data = "<post_data>")]
+fn activate_key(
+    auth: AuthHeader,
+    env: State<EnvInfo>,
+    storage: State<Storage>,
+    api_keys: Collection<String, ApiKeyInfo>,
+    post_data: Json<ActivateKeyRequest>,
+) -> Json<UserResponse> {
+    if env.read_only {
+        return Json(UserResponse::read_only());
+    }
+
+    let data = post_data.0;
+    let api_key = auth.key_or(&data.api_key);
+
+    // Slack user ids look like U012AB3CD, or W012AB3CD for enterprise grid users
+    let valid_user = data.slack_user.len() > 1
+        && (data.slack_user.starts_with('U') || data.slack_user.starts_with('W'))
+        && data.slack_user.chars().all(|c| c.is_ascii_alphanumeric());
+    if !valid_user {
+        return Json(UserResponse::failure("Invalid Slack user id".into()));
+    }
+
+    // Unknown keys count towards lockouts, so this can't be used to guess keys
+    let hash = hash_key(&api_key);
+    let known = api_keys.read().unwrap().contains_key(&hash);
+    if let Err(cause) = auth.verify(&api_key, known, "Invalid API Key") {
+        return Json(UserResponse::from(cause));
+    }
+
+    let mut guard = api_keys.write().unwrap();
+    let map = guard.deref_mut();
+
+    match map.get_mut(&hash) {
+        Some(info) if info.requires_activation && info.activated_by.is_none() => {
+            info.activated_by = Some(data.slack_user.clone());
+        }
+        Some(info) if info.requires_activation => {
+            return Json(UserResponse::failure("Key is already activated".into()));
+        }
+        Some(_) => return Json(UserResponse::failure("Key does not need activation".into())),
+        None => {
+            return Json(UserResponse::failure_of(
+                FailureKind::Unauthorized,
+                "Invalid API Key".into(),
+            ))
+        }
+    }
+
+    log_event!(
+        "audit.key_activate",
+        key = &hash[..8],
+        slack_user = data.slack_user,
+    );
+
+    match storage.save_api_keys(map, &[hash]) {
+        Ok(_) => Json(UserResponse::success()),
+        Err(e) => {
+            log_event!("keys.save_error", storage = storage.describe(), error = e);
+            Json(UserResponse::failure_of(
+                FailureKind::Internal,
+                "Server error while saving keys".into(),
+            ))
+        }
+    }
+}
+
+/// List handlers, sorted by uri, with the description, tags and timestamps of those the API Key
+/// owns
 /// TODO: rethink security policy here
 /// Is it a good idea that anyone with an API Key can see all endpoints?
 /// For now, it is...
 #[post("/list_handlers", data = "<post_data>")]
-fn list_handlers(
-    api_keys: Collection<String, ()>,
+pub fn list_handlers(
+    auth: AuthHeader,
+    api_keys: Collection<String, ApiKeyInfo>,
     handlers: Collection<String, Handler>,
-    post_data: Json<APIKeyRequest>
+    post_data: Option<Json<APIKeyRequest>>,
 ) -> Json<UserResponse> {
-    if !check_auth(&post_data.0.api_key, api_keys) {
-        return Json(UserResponse::failure("Invalid API Key".into()));
+    let key = auth.key_or(&post_data.map(|d| d.0.api_key).unwrap_or_default());
+
+    if let Err(cause) = auth.verify(&key, check_auth(&key, &api_keys), "Invalid API Key") {
+        return Json(UserResponse::from(cause));
+    }
+    if let Err(cause) = check_scope(&key, &api_keys, READ_SCOPE) {
+        return Json(UserResponse::from(cause));
     }
 
     let guard = handlers.read().unwrap();
     let map = guard.deref();
 
-    let handler_addrs = map.keys().map(String::clone).collect::<Vec<String>>();
+    let owner = hash_key(&key);
+    let mut summaries = map
+        .iter()
+        .map(|(uri, h)| HandlerSummary {
+            uri: uri.clone(),
+            metadata: Some(HandlerMetadata::of(h)).filter(|_| h.owned_by(&owner)),
+        })
+        .collect::<Vec<HandlerSummary>>();
+    summaries.sort_by(|a, b| a.uri.cmp(&b.uri));
 
     Json(
-        UserResponse::success_with_raw(handler_addrs).unwrap_or(UserResponse::failure(
+        UserResponse::success_with_raw(summaries).unwrap_or(UserResponse::failure(
             "Internal Server Error Code 2: Ping Luis Hoderlein about it".into(),
         )),
     )
 }
 
+/// Build a handler from what its owner describes, checking its limits, window and subscription
+///
+/// # Arguments
+///
+/// * `env` - Environment variables, for the ceilings on limits
+/// * `uri` - The uri of the handler, see `namespaces::resolve_uri`
+/// * `owner` - The hash of its owner's API Key
+/// * `spec` - What the handler is
+pub fn build_handler(
+    env: &EnvInfo,
+    uri: String,
+    owner: String,
+    spec: HandlerSpec,
+) -> Result<Handler, String> {
+    let mut handler =
+        Handler::new(uri, owner, spec.code).map_err(|e| format!("Error parsing code: {}", e))?;
+
+    if let Some(ops) = spec.max_operations {
+        if ops == 0 || ops > env.max_operations {
+            return Err(format!(
+                "max_operations must be between 1 and {}",
+                env.max_operations
+            ));
+        }
+    }
+    if let Some(ms) = spec.timeout_ms {
+        if ms == 0 || ms > env.max_timeout_ms {
+            return Err(format!(
+                "timeout_ms must be between 1 and {}",
+                env.max_timeout_ms
+            ));
+        }
+    }
+    if let Some(window) = &spec.active_window {
+        window.check(&handler.uri)?;
+    }
+    if let Some(subscription) = &spec.subscription {
+        subscription.check()?;
+    }
+    match &spec.relay {
+        Some(relay) if spec.warmup => {
+            relay.check()?;
+            return Err("Relays can't be warmed up, it would forward the warm-up".into());
+        }
+        Some(relay) => relay.check()?,
+        None if handler.code.raw.trim().is_empty() => {
+            return Err("Handlers need code, unless they are relays".into())
+        }
+        None => {}
+    }
+
+    handler.warmup = spec.warmup;
+    handler.description = spec.description;
+    handler.tags = spec.tags;
+    handler.max_operations = spec.max_operations;
+    handler.timeout_ms = spec.timeout_ms;
+    handler.active_window = spec.active_window;
+    handler.subscription = spec.subscription;
+    handler.relay = spec.relay;
+    handler.runbook = spec.runbook;
+    handler.protected = spec.protected;
+    handler.public_status = spec.public_status;
+    Ok(handler)
+}
+
+/// Settle who owns a handler about to be saved over another, or for the first time
+///
+/// A handler keeps its owner whoever saves it, so that it goes on running with the owner's
+/// secrets and modules. Only the owner may change who its co-owners are, and a protected handler
+/// must have some, since its changes are approved by another of its owners.
+///
+/// # Arguments
+///
+/// * `handler` - The handler about to be saved, owned by whoever is saving it
+/// * `current` - The handler it replaces, if any
+/// * `co_owners` - The hashes of the API Keys to make its co-owners. Left as they are, if None
+/// * `api_keys` - The Client API keys, by hash
+pub fn settle_owners(
+    handler: &mut Handler,
+    current: Option<&Handler>,
+    co_owners: Option<Vec<String>>,
+    api_keys: &HashMap<String, ApiKeyInfo>,
+) -> Result<(), String> {
+    let owner = current
+        .map(|h| h.api_key.clone())
+        .unwrap_or_else(|| handler.api_key.clone());
+    let co_owners = co_owners.map(|mut keys| {
+        keys.retain(|k| *k != owner);
+        keys.sort();
+        keys.dedup();
+        keys
+    });
+
+    if let Some(keys) = &co_owners {
+        if let Some(unknown) = keys.iter().find(|k| !api_keys.contains_key(*k)) {
+            return Err(format!(
+                "Unknown co-owner {}, expected the hash of an API Key",
+                unknown
+            ));
+        }
+    }
+    let unchanged = current.map(|h| h.co_owners.clone()).unwrap_or_default();
+    if handler.api_key != owner && co_owners.as_ref().map_or(false, |k| *k != unchanged) {
+        return Err(format!(
+            "Only the owner of {} may change its co-owners",
+            handler.uri
+        ));
+    }
+
+    handler.api_key = owner;
+    handler.co_owners = co_owners.unwrap_or(unchanged);
+    if handler.protected && handler.co_owners.is_empty() {
+        return Err("Protected handlers need co-owners, to approve their changes".into());
+    }
+    Ok(())
+}
+
 /// Rocket Endpoint which allows Clients to create and update handlers.
 ///
+/// Handlers are saved in the namespace of the Client's API Key, e.g. `deploy` is saved as
+/// `payments/deploy`, and called at `/h/payments/deploy`, see `namespaces::resolve_uri`. The
+/// uri the handler was saved at is returned.
+///
+/// Changes to a protected handler aren't saved, but proposed, and only go live once another of
+/// its owners approves them, see `changes`. The uri and the id of the pending change are returned
+/// instead.
+///
 /// # Arguments
 ///
-/// * `handlers_path` - The file path that the db should be saved to after update
+/// * `auth` - The API Key from the `Authorization` header, if any. Takes precedence over the body
+/// * `env` - Environment variables
+/// * `storage` - Where the handlers are saved after the update
+/// * `services` - The stateful subsystems available to handlers, for the warm-up
 /// * `api_keys` - A reference to the collection of Client API keys, used to check for auth
 /// * `handlers` - A reference to the collection of User created handlers, indexed by their uris
 /// * `post_data` - Any post data that the client has passed alone with the request
 ///
-/// Note that `handlers_path`, `handlers`, and `api_keys` are state managed by Rocket, and are
+/// Note that `env`, `storage`, `services`, `handlers`, and `api_keys` are state managed by Rocket, and are
 /// **NOT** part of the User's post requests in any way
 #[post("/upsert_handler", data = "<post_data>")]
-fn upsert_handler(
+pub fn upsert_handler(
+    auth: AuthHeader,
     env: State<EnvInfo>,
-    api_keys: Collection<String, ()>,
+    storage: State<Storage>,
+    services: State<Services>,
+    api_keys: Collection<String, ApiKeyInfo>,
     handlers: Collection<String, Handler>,
     post_data: Json<UpsertHandlerRequest>,
 ) -> Json<UserResponse> {
-    let data = post_data.0;
+    let (response, _) = upsert(
+        auth,
+        env,
+        storage,
+        services,
+        api_keys,
+        handlers,
+        post_data.0,
+    );
+    Json(response)
+}
+
+/// What `upsert` did with a handler, besides its response
+#[derive(Debug, Default)]
+pub struct Upserted {
+    /// The uri the handler was saved at, if it was saved
+    pub uri: Option<String>,
+    /// Whether it was created, rather than updated
+    pub created: bool,
+}
+
+/// Create or update a handler, see `upsert_handler`
+///
+/// # Arguments
+///
+/// * `data` - The request, see `upsert_handler` for the rest
+pub fn upsert(
+    auth: AuthHeader,
+    env: State<EnvInfo>,
+    storage: State<Storage>,
+    services: State<Services>,
+    api_keys: Collection<String, ApiKeyInfo>,
+    handlers: Collection<String, Handler>,
+    mut data: UpsertHandlerRequest,
+) -> (UserResponse, Upserted) {
+    if env.read_only {
+        return (UserResponse::read_only(), Upserted::default());
+    }
+
+    let api_key = auth.key_or(&data.api_key);
 
     // fail is user is not auth'd
-    if !check_auth(&data.api_key, api_keys) {
-        return Json(UserResponse::failure("Invalid API Key".into()));
+    let valid = check_auth(&api_key, &api_keys);
+    if let Err(cause) = auth.verify(&api_key, valid, "Invalid API Key") {
+        return (UserResponse::from(cause), Upserted::default());
+    }
+    if let Err(cause) = check_scope(&api_key, &api_keys, WRITE_SCOPE) {
+        return (UserResponse::from(cause), Upserted::default());
     }
 
     let mut guard = handlers.write().unwrap();
     let map = guard.deref_mut();
 
-    let new_handler = match Handler::new(data.uri.clone(), data.api_key.clone(), data.code) {
+    // Handlers only record the hash of their owner's key, see `hash_key`
+    let owner = hash_key(&api_key);
+    let namespace = namespace_of(&owner, &api_keys.read().unwrap());
+    let uri = resolve_uri(&data.uri, &owner, &namespace, map);
+    let created = !map.contains_key(&uri);
+    if created {
+        if let Err(cause) = check_uri(&uri, &namespace) {
+            return (UserResponse::failure(cause), Upserted::default());
+        }
+    }
+
+    let proposed = data.spec.clone();
+    let co_owners = data.spec.co_owners.take();
+    let signature = data.spec.signature.take();
+    let mut new_handler = match build_handler(&env, uri.clone(), owner.clone(), data.spec) {
         Ok(h) => h,
-        Err(e) => return Json(UserResponse::failure(format!("Error parsing code: {}", e))),
+        Err(cause) => return (UserResponse::failure(cause), Upserted::default()),
     };
 
-    match map.get(&data.uri) {
+    let signed_by = match map.get(&uri) {
         Some(handler) => {
             // prevent one Client changing another's endpoint
-            if handler.api_key == data.api_key {
-                map.insert(data.uri, new_handler);
+            if handler.owned_by(&owner) {
+                let keys = api_keys.read().unwrap();
+                let protected = handler.protected || new_handler.protected;
+                let signed_by = settle_owners(&mut new_handler, Some(handler), co_owners, &keys)
+                    .and_then(|_| {
+                        let code = &new_handler.code.raw;
+                        check_signature(keys.get(&owner), protected, code, signature.as_deref())
+                    });
+                let signed_by = match signed_by {
+                    Ok(signed_by) => signed_by,
+                    Err(cause) => return (UserResponse::failure(cause), Upserted::default()),
+                };
+                drop(keys);
+                if handler.protected {
+                    let change = changes::propose(
+                        &env, &services, &api_keys, handler, &owner, proposed, signed_by,
+                    );
+                    let pending = json!({ "uri": uri, "pending_change": change.id });
+                    let response = UserResponse::success_with_raw(pending).unwrap_or_else(|| {
+                        UserResponse::failure("Unable to describe the change".into())
+                    });
+                    return (response, Upserted::default());
+                }
+                if let Some(previous) = map.remove(&uri) {
+                    new_handler.supersede(previous);
+                }
+                map.insert(uri.clone(), new_handler);
+                signed_by
             } else {
-                let cause = format!("A handler with uri {} already exists", handler.api_key);
-                return Json(UserResponse::failure(cause));
+                log_event!(
+                    "audit.upsert_denied",
+                    handler = uri,
+                    key = describe_key(&owner, &api_keys),
+                    owner = describe_key(&handler.api_key, &api_keys),
+                );
+                let cause = format!("A handler with uri {} already exists", uri);
+                return (
+                    UserResponse::failure_of(FailureKind::Conflict, cause),
+                    Upserted::default(),
+                );
             }
         }
         None => {
-            map.insert(data.uri, new_handler);
+            let keys = api_keys.read().unwrap();
+            let protected = new_handler.protected;
+            let signed_by =
+                settle_owners(&mut new_handler, None, co_owners, &keys).and_then(|_| {
+                    let code = &new_handler.code.raw;
+                    check_signature(keys.get(&owner), protected, code, signature.as_deref())
+                });
+            let signed_by = match signed_by {
+                Ok(signed_by) => signed_by,
+                Err(cause) => return (UserResponse::failure(cause), Upserted::default()),
+            };
+            map.insert(uri.clone(), new_handler);
+            signed_by
         }
+    };
+
+    if let Err(e) = storage.save_handlers(map, &[uri.clone()]) {
+        log_event!("db.save_error", storage = storage.describe(), error = e);
+        let cause = "Server error while saving db".into();
+        return (
+            UserResponse::failure_of(FailureKind::Internal, cause),
+            Upserted::default(),
+        );
     }
 
-    match save_map(&map, &env.handlers_path) {
-        Ok(_) => Json(UserResponse::success()),
-        Err(_) => {
-            println!("\t=> Unable to save db to file!");
-            Json(UserResponse::failure("Server error while saving db".into()))
+    log_event!(
+        "audit.upsert",
+        handler = uri,
+        key = describe_key(&owner, &api_keys),
+        signed_by = signed_by.as_deref().unwrap_or("unsigned"),
+    );
+
+    // The warm-up runs the handler, maybe for a while, which mustn't hold up every other
+    // request, nor deadlock against the handler reading the handlers itself
+    drop(guard);
+    let guard = handlers.read().unwrap();
+
+    // The handler is saved either way, but a failed warm-up should be surfaced now
+    let response = match guard.get(&uri).filter(|h| h.warmup) {
+        Some(handler) => match warm_up_handler(&env, &services, handler) {
+            Ok(_) => UserResponse::success_with_data(uri.clone()),
+            Err(e) => UserResponse::failure_of(
+                FailureKind::WarmUpFailed,
+                format!("Handler saved, but warm-up failed: {}", e),
+            ),
+        },
+        None => UserResponse::success_with_data(uri.clone()),
+    };
+    let upserted = Upserted {
+        uri: Some(uri),
+        created,
+    };
+    (response, upserted)
+}
+
+/// Rocket Endpoint which dumps every handler, in the same format they are saved to disk in.
+/// Used by `/sync_from` on other instances.
+///
+/// # Arguments
+///
+/// * `env` - Environment variables
+/// * `handlers` - A reference to the collection of User created handlers, indexed by their uris
+/// * `auth` - The admin key from the `Authorization` header, if any
+/// * `post_data` - Must contain the admin key, unless it was passed in the header
+#[post("/export_handlers", data = "<post_data>")]
+fn export_handlers(
+    auth: AuthHeader,
+    env: State<EnvInfo>,
+    handlers: Collection<String, Handler>,
+    post_data: Option<Json<AdminRequest>>,
+) -> Json<UserResponse> {
+    let admin_key = auth.key_or(&post_data.map(|d| d.0.admin_key).unwrap_or_default());
+
+    if let Err(cause) = auth.verify(
+        &admin_key,
+        check_admin(&admin_key, &env),
+        "Invalid admin key",
+    ) {
+        return Json(UserResponse::from(cause));
+    }
+
+    let guard = handlers.read().unwrap();
+    let map = guard.deref();
+
+    match serde_json::to_string(map) {
+        Ok(data) => Json(UserResponse::success_with_data(data)),
+        Err(_) => Json(UserResponse::failure(
+            "Internal Server Error Code 3: Ping Luis Hoderlein about it".into(),
+        )),
+    }
+}
+
+/// Fetch every handler from another majordomo instance, via its `/export_handlers`
+///
+/// # Arguments
+///
+/// * `client` - A reqwest HTTP "client" to make the request
+/// * `source` - The base url of the other instance
+/// * `admin_key` - The admin key of the other instance
+fn fetch_remote_handlers(
+    client: &Client,
+    source: &str,
+    admin_key: &str,
+) -> Result<HashMap<String, Handler>, String> {
+    let mut headers = HeaderMap::new();
+    headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+    headers.insert(
+        AUTHORIZATION,
+        format!("Bearer {}", admin_key)
+            .parse()
+            .map_err(|_| "Invalid admin key".to_string())?,
+    );
+
+    // Also sent in the body, for instances which predate header auth
+    let body = serde_json::to_string(&AdminRequest {
+        admin_key: admin_key.into(),
+    })
+    .map_err(|e| e.to_string())?;
+
+    let req: Result<Response, _> = client
+        .post(&format!("{}/export_handlers", source.trim_end_matches('/')))
+        .headers(headers)
+        .body(body)
+        .send();
+
+    let resp: UserResponse =
+        try_parse_response(req.ok()).ok_or(format!("Unable to reach {}", source))?;
+
+    match (resp.status, resp.data) {
+        (true, Some(data)) => serde_json::from_str(&data)
+            .map_err(|e| format!("Unable to read handlers from {}: {}", source, e)),
+        (_, cause) => Err(format!(
+            "{} refused to export handlers: {}",
+            source,
+            cause.unwrap_or_default()
+        )),
+    }
+}
+
+/// A handler as `diff_handlers` compares it: everything which is saved about it, except when it
+/// was saved and its history, which differ between instances however it was promoted
+fn comparable(handler: &Handler) -> serde_json::Value {
+    let mut value = json!(handler);
+    if let Some(fields) = value.as_object_mut() {
+        for field in &["saved_at", "created_at", "history"] {
+            fields.remove(*field);
+        }
+    }
+    value
+}
+
+/// Compute what would change if `local` were brought in line with `remote`
+///
+/// # Arguments
+///
+/// * `local` - The handlers on this instance
+/// * `remote` - The handlers to sync from, already narrowed down to the requested uris
+/// * `scope` - If present, only these uris are considered for removal
+fn diff_handlers(
+    local: &HashMap<String, Handler>,
+    remote: &HashMap<String, Handler>,
+    scope: &Option<Vec<String>>,
+) -> SyncDiff {
+    let mut diff = SyncDiff::default();
+
+    for (uri, handler) in remote {
+        match local.get(uri) {
+            None => diff.added.push(uri.clone()),
+            Some(current) => {
+                if comparable(current) != comparable(handler) {
+                    diff.changed.push(uri.clone())
+                }
+            }
+        }
+    }
+
+    for uri in local.keys() {
+        let in_scope = scope.as_ref().map(|s| s.contains(uri)).unwrap_or(true);
+        if in_scope && !remote.contains_key(uri) {
+            diff.removed.push(uri.clone());
         }
     }
+
+    diff.added.sort();
+    diff.changed.sort();
+    diff.removed.sort();
+    diff
 }
 
-/// Save the new state of the database to the disk
+/// Rocket Endpoint which pulls handlers from another majordomo instance
 ///
-/// It is reasonable, if unfortunate, that we have to keep the mutex locked while doing this.
-/// This operation should not take too long, and in any case should occur only when a Client
-/// is updating code, which is not often compared to User requests. A several msec delay is
-/// acceptable occasionally.
+/// This is the promotion workflow: vet handlers on a staging instance, call this on production
+/// with `dry_run` to review the diff, then call it again without `dry_run` to apply it. The
+/// update happens under a single write lock, so Users never see a half-applied sync.
 ///
 /// # Arguments
 ///
-/// * `map` - the database of handlers to save
-/// * `path` - the file path to save to.
-///            For testing purposes, if equal to "do-not-write", no write occurs.
-fn save_map(map: &HashMap<String, Handler>, path: &String) -> Result<(), std::io::Error> {
-    if path == "do-not-write" {
-        return Ok(());
+/// * `env` - Environment variables
+/// * `storage` - Where the handlers are saved
+/// * `services` - The shared http client, to reach the other instance
+/// * `handlers` - A reference to the collection of User created handlers, indexed by their uris
+/// * `post_data` - The sync request. See `SyncFromRequest`
+#[post("/sync_from", data = "<post_data>")]
+fn sync_from(
+    auth: AuthHeader,
+    env: State<EnvInfo>,
+    storage: State<Storage>,
+    services: State<Services>,
+    handlers: Collection<String, Handler>,
+    post_data: Json<SyncFromRequest>,
+) -> Json<UserResponse> {
+    if env.read_only {
+        return Json(UserResponse::read_only());
     }
-    let mut file = File::create(path)?;
-    file.write_all(serde_json::to_string(map)?.as_ref())?;
-    Ok(())
+
+    let data = post_data.0;
+
+    let admin_key = auth.key_or(&data.admin_key);
+    if let Err(cause) = auth.verify(
+        &admin_key,
+        check_admin(&admin_key, &env),
+        "Invalid admin key",
+    ) {
+        return Json(UserResponse::from(cause));
+    }
+
+    let mut remote =
+        match fetch_remote_handlers(&services.http, &data.source, &data.source_admin_key) {
+            Ok(r) => r,
+            Err(e) => return Json(UserResponse::failure(e)),
+        };
+
+    if let Some(uris) = &data.uris {
+        remote.retain(|uri, _| uris.contains(uri));
+    }
+    // Instances from before keys were hashed at rest export their handlers' keys as they are
+    hash_plain_owners(&mut remote);
+
+    let mut guard = handlers.write().unwrap();
+    let map = guard.deref_mut();
+
+    let mut diff = diff_handlers(map, &remote, &data.uris);
+
+    if !data.dry_run {
+        if data.prune {
+            for uri in &diff.removed {
+                map.remove(uri);
+            }
+        }
+
+        for (uri, handler) in remote {
+            if diff.added.contains(&uri) || diff.changed.contains(&uri) {
+                map.insert(uri, handler);
+            }
+        }
+
+        let mut changed = [diff.added.clone(), diff.changed.clone()].concat();
+        if data.prune {
+            changed.extend(diff.removed.iter().cloned());
+        }
+        if let Err(e) = storage.save_handlers(map, &changed) {
+            log_event!("db.save_error", storage = storage.describe(), error = e);
+            return Json(UserResponse::failure_of(
+                FailureKind::Internal,
+                "Server error while saving db".into(),
+            ));
+        }
+
+        diff.applied = true;
+        log_event!(
+            "handlers.sync",
+            source = data.source,
+            added = diff.added.len(),
+            changed = diff.changed.len(),
+            removed = if data.prune { diff.removed.len() } else { 0 },
+        );
+    }
+
+    Json(
+        UserResponse::success_with_raw(diff).unwrap_or(UserResponse::failure(
+            "Internal Server Error Code 4: Ping Luis Hoderlein about it".into(),
+        )),
+    )
 }
 
 /// Check if a user is
@@ -345,50 +1657,77 @@ fn save_map(map: &HashMap<String, Handler>, path: &String) -> Result<(), std::io
 /// Fetch a particular handler
 /// TODO documentation
 #[post("/find_handler", data = "<post_data>")]
-fn find_handler(
-    api_keys: Collection<String, ()>,
+pub fn find_handler(
+    auth: AuthHeader,
+    api_keys: Collection<String, ApiKeyInfo>,
     handlers: Collection<String, Handler>,
     post_data: Json<FindHandlerRequest>,
 ) -> Json<UserResponse> {
-    let handler = post_data.0.uri;
-    let key = post_data.0.api_key;
+    let key = auth.key_or(&post_data.0.api_key);
 
     // fail is user is not auth'd
-    if !check_auth(&key, api_keys) {
-        return Json(UserResponse::failure("Invalid API Key".into()));
+    if let Err(cause) = auth.verify(&key, check_auth(&key, &api_keys), "Invalid API Key") {
+        return Json(UserResponse::from(cause));
+    }
+    if let Err(cause) = check_scope(&key, &api_keys, READ_SCOPE) {
+        return Json(UserResponse::from(cause));
     }
 
     let guard = handlers.read().unwrap();
     let map = guard.deref();
 
+    let owner = hash_key(&key);
+    let namespace = namespace_of(&owner, &api_keys.read().unwrap());
+    let handler = resolve_uri(&post_data.0.uri, &owner, &namespace, map);
+
     match map.get(&handler) {
         Some(h) => {
-            if h.api_key == key {
+            if h.owned_by(&owner) {
                 Json(
                     UserResponse::success_with_raw(FindHandlerResponse {
                         code: h.code.raw.clone(),
+                        relay: h.relay.clone(),
+                        runbook: h.runbook.clone(),
+                        metadata: HandlerMetadata::of(h),
                     })
                     .unwrap_or(UserResponse::failure(
                         "Internal Server Error Code 1: Ping Luis Hoderlein about it".into(),
                     )),
                 )
             } else {
-                Json(UserResponse::failure("Invalid API Key".into()))
+                Json(UserResponse::failure_of(
+                    FailureKind::Unauthorized,
+                    "Invalid API Key".into(),
+                ))
             }
         }
-        None => Json(UserResponse::failure("Unknown handler uri".into())),
+        None => Json(UserResponse::failure_of(
+            FailureKind::NotFound,
+            "Unknown handler uri".into(),
+        )),
     }
 }
 
-/// Accept inbound slack connections
-/// Also doubles as an automatic Slack challenge guard responder
-/// Just passes on the request to the appropriate handler
-#[post("/slack_redirector", data = "<post_data>")]
-fn slack_redirector(
-    env: State<EnvInfo>,
-    handlers: Collection<String, Handler>,
-    post_data: Json<SlackEvent>,
-) {
+/// Look up the name of a Slack channel, e.g. `general` for `C012AB3CD`
+///
+/// Names are cached, see `channels::ChannelNames`, so Slack is only asked occasionally
+///
+/// # Arguments
+///
+/// * `id` - The correlation id of the request, attached to every log line
+/// * `env` - Environment variables, for the Slack token
+/// * `services` - The shared http client, and the cache of names
+/// * `channel` - The id of the channel
+fn channel_name(
+    id: &CorrelationId,
+    env: &EnvInfo,
+    services: &Services,
+    channel: &str,
+) -> Option<String> {
+    if let Some(name) = services.channel_names.get(channel) {
+        return Some(name);
+    }
+
     let mut headers = HeaderMap::new();
     headers.insert(
         AUTHORIZATION,
@@ -399,65 +1738,331 @@ fn slack_redirector(
         HeaderValue::from_static("application/x-www-form-urlencoded"),
     );
 
-    // TODO Terrible hack to the get the name of the channel that this message was posted in
-    // One day, we may get an improved implementation
-    // For now, this just works, and that's ok!
-    // Alternative 1. Fetch this data once when the app starts
-    // Alternative 2. Allow only slack endpoints with the slack id as the uri
-    // That would be hard on the user though, and we can't have that!
-    let req: Result<Response, _> = Client::new()
+    // Handlers are addressed by the name of their channel, rather than its id, which is
+    // easier on the user
+    let req: Result<Response, _> = services
+        .http
         .post(&format!(
             "https://slack.com/api/conversations.info?channel={}",
-            &post_data.event.channel
+            channel
         ))
         .headers(headers)
         .send();
 
     let resp: Option<SlackConversationInfoResponse> = try_parse_response(req.ok());
-    let name = match resp {
-        Some(data) => data.channel.name,
+    match resp {
+        Some(data) => {
+            services
+                .channel_names
+                .insert(channel, data.channel.name.clone());
+            Some(data.channel.name)
+        }
         None => {
-            println!("\t=> Failure getting channel information!");
+            log_event!("slack.channel_info_error", id = id.0, channel = channel);
+            None
+        }
+    }
+}
+
+/// Accept inbound slack connections
+/// Also doubles as an automatic Slack challenge guard responder
+/// Just passes on the request to the appropriate handler
+///
+/// Messages, and mentions of us, go to the handler of their channel, see `message_posted`.
+/// Reactions and users joining channels go to `slack-event-reaction_added` and
+/// `slack-event-member_joined_channel`, see `event_received`. Renamed channels update the
+/// cache of channel names, see `channel_name`. Opening our App Home refreshes the user's home
+/// tab, see `home::home_opened`. Other events are ignored.
+///
+/// Events which are not signed by Slack are refused, see `auth::SlackBody`, and Slack's retries
+/// of events which were already received are ignored
+#[post("/slack_redirector", data = "<body>")]
+fn slack_redirector(
+    id: CorrelationId,
+    env: State<EnvInfo>,
+    services: State<Services>,
+    handlers: Collection<String, Handler>,
+    throttle: Throttle,
+    body: SlackBody,
+) {
+    let post_data: SlackEvent = match serde_json::from_str(&body.0) {
+        Ok(event) => event,
+        Err(e) => {
+            log_event!("slack.event_error", id = id.0, error = e);
             return;
         }
     };
 
+    // Slack retries events it thinks we missed, e.g. because a handler was slow to answer, and
+    // running the handler again would repeat whatever it did
+    if let Some(event_id) = &post_data.event_id {
+        if !services.slack_events.insert(event_id) {
+            log_event!("slack.event_retry", id = id.0, event_id = event_id);
+            return;
+        }
+    }
+
+    match post_data.event {
+        SlackEventInner::Message(message) => {
+            message_posted(&id, &env, &services, &handlers, &throttle, message, false)
+        }
+        SlackEventInner::AppMention(message) => {
+            message_posted(&id, &env, &services, &handlers, &throttle, message, true)
+        }
+        SlackEventInner::ReactionAdded(reaction) => {
+            let channel = reaction.item.channel.clone();
+            let mut context = Map::new();
+            context.insert("user".into(), Dynamic::from(reaction.user));
+            context.insert("reaction".into(), Dynamic::from(reaction.reaction.clone()));
+            context.insert("item_user".into(), Dynamic::from(reaction.item_user));
+            context.insert("item_type".into(), Dynamic::from(reaction.item.item_type));
+            context.insert("ts".into(), Dynamic::from(reaction.item.ts));
+            event_received(
+                &id,
+                &env,
+                &services,
+                &handlers,
+                "reaction_added",
+                reaction.reaction,
+                &channel,
+                context,
+            )
+        }
+        SlackEventInner::MemberJoinedChannel(joined) => {
+            let mut context = Map::new();
+            context.insert("user".into(), Dynamic::from(joined.user.clone()));
+            context.insert(
+                "inviter".into(),
+                Dynamic::from(joined.inviter.unwrap_or_default()),
+            );
+            event_received(
+                &id,
+                &env,
+                &services,
+                &handlers,
+                "member_joined_channel",
+                joined.user,
+                &joined.channel,
+                context,
+            )
+        }
+        SlackEventInner::ChannelRename(rename) => {
+            log_event!(
+                "slack.channel_renamed",
+                id = id.0,
+                channel = rename.channel.id,
+                name = rename.channel.name,
+            );
+            services
+                .channel_names
+                .insert(&rename.channel.id, rename.channel.name)
+        }
+        SlackEventInner::AppHomeOpened(opened) => home::home_opened(&id, &env, &services, opened),
+        SlackEventInner::Other => {}
+    }
+}
+
+/// Pass a message on to the handler of the channel it was posted in, `slack-<channel name>`
+///
+/// The handler is passed the text after the first word, i.e. after the mention. Handlers
+/// defining `handle(text, event)` also receive a map describing the message, with `user`,
+/// `channel`, `channel_name`, `ts`, `thread_ts` (empty if not in a thread), the raw `text`,
+/// including the mention, and the attached `files` and `shares`. See `slack::files_context` and
+/// `slack::shares_context`
+///
+/// # Arguments
+///
+/// * `id` - The correlation id of the request, attached to every log line
+/// * `env` - Environment variables
+/// * `services` - The stateful subsystems available to handlers
+/// * `handlers` - The User created handlers, indexed by their uris
+/// * `throttle` - Holds the catch-all handler to its rate limit
+/// * `event` - The message
+/// * `mentioned` - Whether the message mentions us, i.e. it came as an `app_mention` event
+fn message_posted(
+    id: &CorrelationId,
+    env: &EnvInfo,
+    services: &Services,
+    handlers: &RwLock<HashMap<String, Handler>>,
+    throttle: &Throttle,
+    event: SlackMessage,
+    mentioned: bool,
+) {
+    let name = match channel_name(id, env, services, &event.channel) {
+        Some(name) => name,
+        None => return,
+    };
+
     let addr = format!("slack-{}", name);
-    let first_space = post_data.event.text.find(' ').unwrap_or(0);
-    let data = post_data.event.text.clone()[first_space..].to_string();
-    let res = call_handler(env, handlers, addr, data);
-    if !res.status {
-        println!(
-            "\t=> Something has errored internally on a slack message: {:?}",
-            res.data
-        )
+    let first_space = event.text.find(' ').unwrap_or(0);
+    let data = event.text.clone()[first_space..].to_string();
+
+    // `@majordomo help` is answered by us, rather than by the channel's handler. Without the
+    // mention, e.g. `someone help`, it's just a message for the handler
+    if mentioned && data.trim().eq_ignore_ascii_case("help") {
+        let text = render_help(&handlers.read().unwrap());
+        slack_post_internal(&services.http, &env.slack_token, event.channel, text);
+        return;
+    }
+
+    // Handlers which want to know more than the text, e.g. to reply in a thread, get the event
+    let raw = json!({
+        "user": event.user,
+        "channel": event.channel,
+        "channel_name": name,
+        "ts": event.ts,
+        "thread_ts": event.thread_ts,
+        "text": event.text,
+    });
+    let envelope = Envelope::slack(&event.user, &event.text, raw);
+    let files = slack::files_context(
+        &services.http,
+        &env.slack_token,
+        &event.files,
+        env.slack_download_max_bytes,
+    );
+    let shares = slack::shares_context(&event.attachments);
+
+    let guard = handlers.read().unwrap();
+    // What the channel's handler didn't take, if there is none
+    let unhandled = match guard.get(&addr) {
+        Some(handler) => {
+            let mut context = Map::new();
+            context.insert("user".into(), Dynamic::from(event.user.clone()));
+            context.insert("channel".into(), Dynamic::from(event.channel.clone()));
+            context.insert("channel_name".into(), Dynamic::from(name.clone()));
+            context.insert("ts".into(), Dynamic::from(event.ts.clone()));
+            context.insert(
+                "thread_ts".into(),
+                Dynamic::from(event.thread_ts.clone().unwrap_or_default()),
+            );
+            context.insert("text".into(), Dynamic::from(event.text.clone()));
+            context.insert("files".into(), Dynamic::from(files));
+            context.insert("shares".into(), Dynamic::from(shares));
+
+            let result = run_handler(env, services, id, &addr, handler, data, Some(context));
+            if let Err(e) = result {
+                log_event!("slack.handler_error", id = id.0, handler = addr, error = e);
+            }
+            None
+        }
+        None => Some(data),
+    };
+    drop(guard);
+
+    // Give the catch-all handler, if there is one, a chance to deal with this
+    if let Some(data) = unhandled {
+        let reply = dispatch(
+            id,
+            env,
+            services,
+            handlers,
+            throttle,
+            addr.clone(),
+            data,
+            None,
+        );
+        if let Reply::Wrapped(Json(res)) = reply {
+            if !res.status {
+                log_event!(
+                    "slack.handler_error",
+                    id = id.0,
+                    handler = addr,
+                    error = res.data.unwrap_or_default(),
+                );
+            }
+        }
+    }
+
+    // Subscribers hear about the message once the channel's handler has run
+    envelope::fan_in(env, services, id, envelope);
+}
+
+/// Pass an event other than a message on to the handler of its type, `slack-event-<type>`, as
+/// `handle(payload, event)`. Nothing happens if there is no such handler
+///
+/// Besides the fields given, the event map has its `type`, and the `channel` and `channel_name`
+/// it happened in, if any.
+///
+/// # Arguments
+///
+/// * `id` - The correlation id of the request, attached to every log line
+/// * `env` - Environment variables
+/// * `services` - The stateful subsystems available to handlers
+/// * `handlers` - The User created handlers, indexed by their uris
+/// * `event_type` - The type of the event, e.g. `reaction_added`
+/// * `payload` - The gist of the event, e.g. the name of the emoji
+/// * `channel` - The id of the channel it happened in. Empty if none
+/// * `context` - The fields of the event
+#[allow(clippy::too_many_arguments)]
+fn event_received(
+    id: &CorrelationId,
+    env: &EnvInfo,
+    services: &Services,
+    handlers: &RwLock<HashMap<String, Handler>>,
+    event_type: &str,
+    payload: String,
+    channel: &str,
+    mut context: Map,
+) {
+    let addr = format!("{}{}", slack::EVENT_PREFIX, event_type);
+    if !handlers.read().unwrap().contains_key(&addr) {
+        return;
+    }
+
+    let name = match channel {
+        "" => String::new(),
+        channel => match channel_name(id, env, services, channel) {
+            Some(name) => name,
+            None => return,
+        },
+    };
+    context.insert("type".into(), Dynamic::from(event_type.to_string()));
+    context.insert("channel".into(), Dynamic::from(channel.to_string()));
+    context.insert("channel_name".into(), Dynamic::from(name));
+
+    let guard = handlers.read().unwrap();
+    if let Some(handler) = guard.get(&addr) {
+        if let Err(e) = run_handler(env, services, id, &addr, handler, payload, Some(context)) {
+            log_event!("slack.handler_error", id = id.0, handler = addr, error = e);
+        }
     }
 }
 
 /// Rocket Endpoint which serves the frontend to any user
 #[get("/")]
-fn site_root() -> Html<String> {
+fn site_root<'r>(
+    env: State<EnvInfo>,
+    services: State<Services>,
+    assets: State<'r, Assets>,
+) -> Served<'r> {
     if rand::thread_rng().gen_bool(0.3) {
         let mut headers = HeaderMap::new();
         headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
 
         // TODO: maybe handle the result
-        let _req: Result<Response, _> = Client::new()
+        let _req: Result<Response, _> = services
+            .http
             .post("https://major.ngrok.io/h/awesome-endpoint-2")
             .headers(headers)
             .body("Hey, remember how you have that backend function that might have a critical error condition? Well, it was happened. Now you know!")
             .send();
 
-        Html(include_str!("error_page.html").into())
+        // Never let anyone cache an error
+        assets.inner().serve("error_page.html", "no-store".into())
     } else {
-        Html(include_str!("site.html").into())
+        assets
+            .inner()
+            .serve("site.html", env.static_cache_control.clone())
     }
 }
 
 /// Rocket Endpoint which gets the suggestion box
 #[get("/suggestion-box.js")]
-fn suggestion_box_js() -> JavaScript<String> {
-    JavaScript(include_str!("suggestion-box.js").into())
+fn suggestion_box_js<'r>(env: State<EnvInfo>, assets: State<'r, Assets>) -> Served<'r> {
+    assets
+        .inner()
+        .serve("suggestion-box.js", env.static_cache_control.clone())
 }
 
 /// Rocket Endpoint which catches any 404's due to User or Client requests.
@@ -501,31 +2106,46 @@ fn unprocessable_entity(req: &Request) -> Json<UserResponse> {
 ///
 /// # Arguments
 ///
-/// * `slack_token` - A slack token to work with, or "no-slack"
-/// * `github_token` - A github token to work with, or "no-github"
-/// * `handlers_path` - The file path to save the handlers to
+/// * `env` - The configuration of the server, including tokens, file paths and the port
+/// * `storage` - Where handlers and api keys are saved
 /// * `handlers` - A map of uris to the handlers that have that uri
-/// * `api_keys` - A hash set of api keys. HashMap<T, ()> is basically the same as HashSet<T>
-/// * `port` - the port to start the server on
+/// * `api_keys` - A map of api keys to what we know about them
 pub fn http_server_start(
-    slack_token: String,
-    github_token: String,
-    handlers_path: String,
+    env: EnvInfo,
+    storage: Storage,
     handlers: HashMap<String, Handler>,
-    api_keys: HashMap<String, ()>,
-    port: u16,
+    api_keys: HashMap<String, ApiKeyInfo>,
 ) -> Rocket {
     let config = Config::build(Environment::Staging)
         .log_level(LoggingLevel::Normal)
-        .port(port)
+        .port(env.port)
         .finalize()
         .unwrap();
 
-    let env = EnvInfo {
-        slack_token,
-        github_token,
-        handlers_path,
-    };
+    let handlers = Arc::new(RwLock::new(handlers));
+    let services = Services::open(&env, handlers.clone());
+
+    // Surface errors in flagged handlers at start up, rather than on their first request
+    for handler in handlers.read().unwrap().values().filter(|h| h.warmup) {
+        if let Err(e) = warm_up_handler(&env, &services, handler) {
+            log_event!("handler.warmup_error", handler = handler.uri, error = e);
+        }
+    }
+
+    let api_keys = Arc::new(RwLock::new(api_keys));
+
+    if !env.read_only {
+        scheduler::start(
+            env.clone(),
+            services.clone(),
+            handlers.clone(),
+            api_keys.clone(),
+        );
+    }
+
+    if let Some(port) = env.grpc_port {
+        grpc::start(port, env.clone(), services.clone(), handlers.clone());
+    }
 
     let rocket = rocket::custom(config)
         .mount(
@@ -533,18 +2153,191 @@ pub fn http_server_start(
             routes![
                 site_root,
                 call_handler,
+                call_handler_get,
+                call_namespaced_handler,
+                call_namespaced_handler_get,
+                help::help_get,
+                help::help_post,
+                slack::slack_interactive,
+                slash::slack_command,
                 upsert_handler,
                 slack_redirector,
                 list_handlers,
                 find_handler,
                 verify_key,
-                suggestion_box_js
+                activate_key,
+                suggestion_box_js,
+                export_handlers,
+                sync_from,
+                apply::apply,
+                changes::list_changes,
+                changes::decide_change,
+                admin::import_keys,
+                admin::export_keys,
+                admin::update_key,
+                admin::create_key,
+                admin::revoke_key,
+                admin::list_keys,
+                admin::reload,
+                faults::inject_fault,
+                faults::list_faults,
+                dependencies::dependencies,
+                reminders::list_reminders,
+                reminders::cancel_reminder,
+                history::handler_history,
+                history::rollback_handler,
+                github::github_webhook,
+                twilio::twilio_webhook,
+                workflow::workflow_webhook,
+                handler_logs::handler_logs,
+                dryrun::test_handler,
+                codecheck::check_code,
+                completions::completions,
+                flags::set_flag,
+                flags::flag_eval,
+                archive::archive_search,
+                archive::archive_export,
+                reprocess::reprocess,
+                search::search_handlers,
+                cleanup::dead_handlers,
+                cleanup::archive_handler,
+                cleanup::restore_handler,
+                stats::handler_stats,
+                status::status_page,
+                secrets::set_secret,
+                secrets::delete_secret,
+                calendar::calendar_feed,
+                feed::feed_xml,
+                libraries::upsert_module
+            ],
+        )
+        .mount(
+            "/api/v1",
+            routes![
+                api::list_handlers,
+                api::find_handler,
+                api::upsert_handler,
+                api::archive_handler,
+                api::restore_handler,
+                api::handler_history,
+                api::rollback_handler,
+                api::handler_logs,
+                api::test_handler,
+                api::check_code,
+                api::search_handlers,
+                api::dead_handlers,
+                api::handler_stats,
+                api::list_completions,
+                api::set_secret,
+                api::delete_secret,
+                api::set_flag,
+                api::upsert_module,
+                api::list_reminders,
+                api::cancel_reminder,
+                api::list_keys,
+                api::create_key,
+                api::update_key,
+                api::revoke_key
             ],
         )
         .register(catchers![not_found, bad_request, unprocessable_entity])
-        .manage(env)
-        .manage(RwLock::new(handlers))
-        .manage(RwLock::new(api_keys));
+        .attach(RequestLogger::new(env.log_sample_rate))
+        .attach(RetryAfterHeader);
+
+    // Replicas never write, they just pick up whatever the primary has written
+    let rocket = if env.read_only {
+        rocket.attach(ReplicaRefresher::new(
+            &storage,
+            Duration::from_secs(env.replica_refresh_secs),
+        ))
+    } else {
+        rocket
+    };
 
     rocket
+        .manage(env)
+        .manage(storage)
+        .manage(Assets::load())
+        .manage(services)
+        .manage(Lockouts::default())
+        .manage(RateLimits::default())
+        .manage(handlers)
+        .manage(api_keys)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::Value;
+
+    use super::*;
+
+    fn handlers(list: Vec<Handler>) -> HashMap<String, Handler> {
+        list.into_iter().map(|h| (h.uri.clone(), h)).collect()
+    }
+
+    fn handler(uri: &str) -> Handler {
+        Handler::new(uri.into(), "owner".into(), "fn handle(v) { v }".into()).unwrap()
+    }
+
+    /// Text which breaks json that is put together by hand
+    const AWKWARD: &str = "say \"hi\"\\n, then C:\\temp\\ and\na new line\t}";
+
+    #[test]
+    fn slack_post_body_round_trips_awkward_text() {
+        let body: Value = serde_json::from_str(&slack_post_body("#general \"", AWKWARD)).unwrap();
+        assert_eq!(body["channel"], "#general \"");
+        assert_eq!(body["text"], AWKWARD);
+        assert_eq!(body["unfurl_links"], true);
+    }
+
+    #[test]
+    fn github_issue_body_round_trips_awkward_text() {
+        let labels = vec!["needs \"triage\"".to_string()];
+        let assignees = vec!["octo\\cat".to_string()];
+        let body = github_issue_body(AWKWARD, AWKWARD, &labels, &assignees);
+        let body: Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(body["title"], AWKWARD);
+        assert_eq!(body["body"], AWKWARD);
+        assert_eq!(body["labels"], json!(labels));
+        assert_eq!(body["assignees"], json!(assignees));
+    }
+
+    #[test]
+    fn diff_handlers_sees_changes_to_any_saved_field() {
+        let local = handlers(vec![handler("a"), handler("b"), handler("c")]);
+        let mut documented = handler("a");
+        documented.runbook = Some("Page whoever is on call".into());
+        let mut protected = handler("b");
+        protected.protected = true;
+        let remote = handlers(vec![documented, protected, handler("c"), handler("d")]);
+
+        let diff = diff_handlers(&local, &remote, &None);
+        assert_eq!(diff.added, vec!["d".to_string()]);
+        assert_eq!(diff.changed, vec!["a".to_string(), "b".to_string()]);
+        assert!(diff.removed.is_empty());
+    }
+
+    #[test]
+    fn diff_handlers_ignores_when_handlers_were_saved() {
+        let local = handlers(vec![handler("a")]);
+        let mut resaved = handler("a");
+        resaved.saved_at = local["a"].saved_at + 60;
+        resaved.created_at = local["a"].created_at + 60;
+        let remote = handlers(vec![resaved]);
+
+        assert!(diff_handlers(&local, &remote, &None).changed.is_empty());
+    }
+
+    #[test]
+    fn diff_handlers_only_removes_in_scope() {
+        let local = handlers(vec![handler("a"), handler("b")]);
+        let remote = HashMap::new();
+
+        let scope = Some(vec!["a".to_string()]);
+        assert_eq!(
+            diff_handlers(&local, &remote, &scope).removed,
+            vec!["a".to_string()]
+        );
+        assert_eq!(diff_handlers(&local, &remote, &None).removed.len(), 2);
+    }
 }
@@ -0,0 +1,187 @@
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use reqwest::blocking::Client;
+use reqwest::redirect::Policy;
+
+use crate::approvals::Approval;
+use crate::archive::Archive;
+use crate::broadcast::Broadcaster;
+use crate::calendar::Calendar;
+use crate::changes::PendingChange;
+use crate::channels::ChannelNames;
+use crate::cleanup::Cleanup;
+use crate::faults::Fault;
+use crate::feed::Feed;
+use crate::flags::Flag;
+use crate::handler_logs::HandlerLogs;
+use crate::home::RecentCommands;
+use crate::k8s::Cluster;
+use crate::kv::Namespace;
+use crate::libraries::Libraries;
+use crate::metrics::Metrics;
+use crate::oncall::Rotation;
+use crate::polls::Poll;
+use crate::recent::RecentIds;
+use crate::reminders::Reminder;
+use crate::secrets::Secrets;
+use crate::stale::Sweep;
+use crate::stats::Stats;
+use crate::storage::JsonStore;
+use crate::types::{EnvInfo, Handler};
+use crate::uptime::Check;
+use crate::users::SlackUsers;
+use crate::windows::Queued;
+
+/// How long an outbound call made on behalf of a handler may take
+const OUTBOUND_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// How long connecting to another service may take, part of the `OUTBOUND_TIMEOUT`
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long an unused pooled connection is kept open
+const POOL_IDLE_TIMEOUT: Duration = Duration::from_secs(90);
+
+/// How many Slack events are remembered, to recognize Slack's retries of them. Slack retries
+/// within minutes, so this only needs to cover a few minutes of a busy workspace
+const RECENT_SLACK_EVENTS: usize = 10_000;
+
+/// The stateful subsystems handlers can use, e.g. approvals
+///
+/// Everything in here is reference counted, so that it can be moved into the functions
+/// registered with Rhai, which must not borrow anything.
+#[derive(Clone)]
+pub struct Services {
+    /// Pending and decided approval requests, indexed by their id
+    pub approvals: Arc<JsonStore<Approval>>,
+    /// Changes to protected handlers waiting to be approved, indexed by their id
+    pub changes: Arc<JsonStore<PendingChange>>,
+    /// Open and closed polls, indexed by their id
+    pub polls: Arc<JsonStore<Poll>>,
+    /// Reminders which have yet to be delivered, indexed by their id
+    pub reminders: Arc<JsonStore<Reminder>>,
+    /// On-call rotations, indexed by their name
+    pub rotations: Arc<JsonStore<Rotation>>,
+    /// The values handlers have stored, indexed by the uri of the handler
+    pub kv: Arc<JsonStore<Namespace>>,
+    /// The queue of messages going out to many channels
+    pub broadcaster: Arc<Broadcaster>,
+    /// The http client for outbound calls, e.g. to Slack and GitHub. Clones share one connection
+    /// pool, so calls don't each pay for a new connection, and a timeout, so a slow API can only
+    /// hold up a worker for so long. Redirects are never followed, so that the calls handlers
+    /// make can't be led off their allowlist, see `http_client`
+    pub http: Client,
+    /// What handlers printed and how their recent runs went
+    pub logs: Arc<HandlerLogs>,
+    /// Stale issue sweeps, indexed by the uri of their handler and their repository
+    pub sweeps: Arc<JsonStore<Sweep>>,
+    /// How often each handler has run, failed, and how long it took
+    pub stats: Arc<Stats>,
+    /// The events handlers have published to their calendars, indexed by the uri of the handler
+    pub calendars: Arc<JsonStore<Calendar>>,
+    /// The entries handlers have appended to their feeds, indexed by the uri of the handler
+    pub feeds: Arc<JsonStore<Feed>>,
+    /// Feature flags, indexed by their name
+    pub flags: Arc<JsonStore<Flag>>,
+    /// The Kubernetes cluster handlers may run chat-ops against, if one is configured
+    pub k8s: Option<Arc<Cluster>>,
+    /// The modules of shared functions of each API Key, indexed by the hash of the key
+    pub libraries: Arc<JsonStore<Libraries>>,
+    /// Where handlers push their own metrics to, e.g. StatsD
+    pub metrics: Arc<Metrics>,
+    /// The encrypted secrets of each API Key, indexed by the hash of the key
+    pub secrets: Arc<Secrets>,
+    /// The urls handlers monitor, indexed by the uri of their handler and their name
+    pub uptime: Arc<JsonStore<Check>>,
+    /// The calls waiting for the active window of their handler to open, indexed by its uri
+    pub queued: Arc<JsonStore<Vec<Queued>>>,
+    /// Every event the selected handlers have run against
+    pub archive: Arc<Archive>,
+    /// Which owners were told about their dead handlers, and the handlers they archived
+    pub cleanup: Arc<Cleanup>,
+    /// The names of Slack channels recently looked up, by their id
+    pub channel_names: Arc<ChannelNames>,
+    /// The faults injected into the calls handlers make, indexed by integration
+    pub faults: Arc<JsonStore<Fault>>,
+    /// The ids of the Slack events recently received
+    pub slack_events: Arc<RecentIds>,
+    /// The Slack users recently looked up, by their id
+    pub slack_users: Arc<SlackUsers>,
+    /// The slash commands each Slack user recently ran, for their home tab
+    pub recent_commands: Arc<RecentCommands>,
+    /// The handlers themselves, indexed by their uris, so that handlers can invoke each other
+    pub handlers: Arc<RwLock<HashMap<String, Handler>>>,
+}
+
+impl Services {
+    /// Open every subsystem, loading whatever state they saved in the data directory
+    /// The key-value store is the exception: it lives next to the handlers it belongs to
+    ///
+    /// # Arguments
+    ///
+    /// * `env` - Environment variables
+    /// * `handlers` - The User created handlers, indexed by their uris
+    pub fn open(env: &EnvInfo, handlers: Arc<RwLock<HashMap<String, Handler>>>) -> Services {
+        let path = |file: &str| {
+            Path::new(&env.data_dir)
+                .join(file)
+                .to_string_lossy()
+                .into_owned()
+        };
+
+        let kv_path = match env.handlers_path.as_str() {
+            "do-not-write" => "do-not-write".to_string(),
+            handlers_path => Path::new(handlers_path)
+                .with_file_name("kv.json")
+                .to_string_lossy()
+                .into_owned(),
+        };
+
+        let http = Client::builder()
+            .timeout(OUTBOUND_TIMEOUT)
+            .connect_timeout(CONNECT_TIMEOUT)
+            .pool_idle_timeout(POOL_IDLE_TIMEOUT)
+            .redirect(Policy::none())
+            .build()
+            .unwrap_or_else(|_| Client::new());
+
+        Services {
+            broadcaster: Arc::new(Broadcaster::start(env, http.clone())),
+            http,
+            approvals: Arc::new(JsonStore::open(path("approvals.json"))),
+            changes: Arc::new(JsonStore::open(path("changes.json"))),
+            polls: Arc::new(JsonStore::open(path("polls.json"))),
+            reminders: Arc::new(JsonStore::open(path("reminders.json"))),
+            rotations: Arc::new(JsonStore::open(path("rotations.json"))),
+            kv: Arc::new(JsonStore::open(kv_path)),
+            logs: Arc::new(HandlerLogs::default()),
+            stats: Arc::new(Stats::open(path("stats.json"))),
+            sweeps: Arc::new(JsonStore::open(path("sweeps.json"))),
+            calendars: Arc::new(JsonStore::open(path("calendars.json"))),
+            feeds: Arc::new(JsonStore::open(path("feeds.json"))),
+            flags: Arc::new(JsonStore::open(path("flags.json"))),
+            k8s: env.k8s.as_ref().map(Cluster::open).flatten().map(Arc::new),
+            libraries: Arc::new(JsonStore::open(path("libraries.json"))),
+            metrics: Arc::new(Metrics::open(env)),
+            secrets: Arc::new(Secrets::open(
+                path("secrets.json"),
+                env.secrets_key.as_deref(),
+            )),
+            uptime: Arc::new(JsonStore::open(path("uptime.json"))),
+            queued: Arc::new(JsonStore::open(path("queued.json"))),
+            archive: Arc::new(Archive::open(env)),
+            cleanup: Arc::new(Cleanup::open(
+                path("dead_notices.json"),
+                path("archived_handlers.json"),
+            )),
+            channel_names: Arc::new(ChannelNames::default()),
+            faults: Arc::new(JsonStore::open(path("faults.json"))),
+            slack_events: Arc::new(RecentIds::new(RECENT_SLACK_EVENTS)),
+            slack_users: Arc::new(SlackUsers::default()),
+            recent_commands: Arc::new(RecentCommands::default()),
+            handlers,
+        }
+    }
+}
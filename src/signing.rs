@@ -0,0 +1,92 @@
+use openssl::pkey::{Id, PKey, Public};
+use openssl::sign::Verifier;
+
+use sha2::{Digest, Sha256};
+
+use crate::types::ApiKeyInfo;
+
+/// Read an Ed25519 public key, as PEM, e.g. the output of
+/// `openssl pkey -in private.pem -pubout`
+fn parse_public_key(pem: &str) -> Result<PKey<Public>, String> {
+    PKey::public_key_from_pem(pem.as_bytes())
+        .ok()
+        .filter(|key| key.id() == Id::ED25519)
+        .ok_or_else(|| "Signing keys must be Ed25519 public keys, as PEM".to_string())
+}
+
+/// Check a public key can be registered as a signing key, see `ApiKeyInfo::signing_keys`
+///
+/// # Arguments
+///
+/// * `pem` - The public key, as PEM
+pub fn check_public_key(pem: &str) -> Result<(), String> {
+    parse_public_key(pem).map(|_| ())
+}
+
+/// Identify a signing key in the audit log, without spelling out all of it: the start of the
+/// sha256 of its PEM
+///
+/// # Arguments
+///
+/// * `pem` - The public key, as PEM
+pub fn fingerprint(pem: &str) -> String {
+    Sha256::digest(pem.trim().as_bytes())
+        .iter()
+        .take(8)
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+/// Check the signature of a handler's code against the signing keys of the API Key saving it
+///
+/// Signatures are Ed25519 signatures of the code exactly as it is sent, base64 encoded, e.g.
+/// from `openssl pkeyutl -sign -inkey private.pem -rawin -in handler.rhai | base64 -w0`.
+/// Unsigned code is fine, unless the handler is protected and the key has signing keys, so that
+/// whoever steals such a key can't change its protected handlers without also stealing one of
+/// its private keys.
+///
+/// Returns the fingerprint of the key which signed the code, if it was signed
+///
+/// # Arguments
+///
+/// * `info` - The API Key saving the handler
+/// * `protected` - Whether the handler is, or is about to be, protected
+/// * `code` - The code of the handler
+/// * `signature` - The signature of the code, if any
+pub fn check_signature(
+    info: Option<&ApiKeyInfo>,
+    protected: bool,
+    code: &str,
+    signature: Option<&str>,
+) -> Result<Option<String>, String> {
+    let signing_keys = info.map(|i| i.signing_keys.as_slice()).unwrap_or_default();
+    let signature = match signature {
+        Some(signature) => signature,
+        None if protected && !signing_keys.is_empty() => {
+            return Err("Changes to protected handlers must be signed by this API Key".into())
+        }
+        None => return Ok(None),
+    };
+    if signing_keys.is_empty() {
+        return Err("This API Key has no signing keys to check the signature against".into());
+    }
+
+    let signature =
+        base64::decode(signature.trim()).map_err(|_| "The signature isn't valid base64")?;
+    signing_keys
+        .iter()
+        .find(|pem| {
+            parse_public_key(pem)
+                .ok()
+                .map(|key| {
+                    Verifier::new_without_digest(&key)
+                        .and_then(|mut v| v.verify_oneshot(&signature, code.as_bytes()))
+                        .unwrap_or(false)
+                })
+                .unwrap_or(false)
+        })
+        .map(|pem| Some(fingerprint(pem)))
+        .ok_or_else(|| {
+            "The signature doesn't match the code, or any of the API Key's signing keys".into()
+        })
+}
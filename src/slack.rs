@@ -0,0 +1,1006 @@
+use std::collections::HashMap;
+use std::sync::RwLock;
+use std::thread;
+
+use reqwest::blocking::{Client, RequestBuilder, Response};
+use reqwest::header::{HeaderMap, HeaderValue, AUTHORIZATION, CONTENT_TYPE};
+
+use rocket::request::{FormItems, FromForm};
+use rocket::State;
+
+use rhai::de::from_dynamic;
+use rhai::ser::to_dynamic;
+use rhai::{Array, Dynamic, EvalAltResult, ImmutableString, Map, Module, Scope, INT};
+
+use serde_json::{json, Value};
+
+use crate::approvals::{self, APPROVE_ACTION, DENY_ACTION};
+use crate::auth::SlackBody;
+use crate::changes::{self, APPROVE_CHANGE_ACTION, REJECT_CHANGE_ACTION};
+use crate::faults;
+use crate::home::{self, HOME_RUN_ACTION};
+use crate::logging::CorrelationId;
+use crate::polls::{self, VOTE_ACTION};
+use crate::server::{run_function, run_handler_dynamic, Collection};
+use crate::services::Services;
+use crate::storage::Storage;
+use crate::types::{ApiKeyInfo, EnvInfo, Handler, SlackAttachment, SlackFile};
+
+/// Call a method of the Slack Web API, e.g. `chat.postMessage`
+///
+/// # Arguments
+///
+/// * `client` - A reqwest HTTP "client" to make the request. Never seen by Clients
+/// * `token` - The slack token to authenticate with. Never seen by Clients
+/// * `method` - The name of the API method
+/// * `body` - The arguments of the method, sent as json
+///
+/// Returns the response if Slack reports success, otherwise Slack's error code
+pub fn slack_api(
+    client: &Client,
+    token: &str,
+    method: &str,
+    body: &Value,
+) -> Result<Value, String> {
+    let request = client
+        .post(&format!("https://slack.com/api/{}", method))
+        .header(
+            CONTENT_TYPE,
+            HeaderValue::from_static("application/json; charset=utf-8"),
+        )
+        .body(body.to_string());
+    slack_send(token, method, request)
+}
+
+/// Call a method of the Slack Web API which does not accept json arguments, e.g. the ones which
+/// read history like `conversations.replies`
+///
+/// # Arguments
+///
+/// * `client` - A reqwest HTTP "client" to make the request. Never seen by Clients
+/// * `token` - The slack token to authenticate with. Never seen by Clients
+/// * `method` - The name of the API method
+/// * `query` - The arguments of the method, sent in the query string
+///
+/// Returns the response if Slack reports success, otherwise Slack's error code
+pub fn slack_api_get(
+    client: &Client,
+    token: &str,
+    method: &str,
+    query: &[(&str, &str)],
+) -> Result<Value, String> {
+    let request = client
+        .get(&format!("https://slack.com/api/{}", method))
+        .query(query);
+    slack_send(token, method, request)
+}
+
+/// Call a method of the Slack Web API which takes its arguments as a form, e.g. `files.upload`
+///
+/// # Arguments
+///
+/// * `client` - A reqwest HTTP "client" to make the request. Never seen by Clients
+/// * `token` - The slack token to authenticate with. Never seen by Clients
+/// * `method` - The name of the API method
+/// * `form` - The arguments of the method, sent url encoded in the body
+///
+/// Returns the response if Slack reports success, otherwise Slack's error code
+pub fn slack_api_form(
+    client: &Client,
+    token: &str,
+    method: &str,
+    form: &[(&str, &str)],
+) -> Result<Value, String> {
+    let request = client
+        .post(&format!("https://slack.com/api/{}", method))
+        .form(form);
+    slack_send(token, method, request)
+}
+
+/// Open the direct message conversation between us and a user, returning its channel id
+///
+/// Opening a conversation which is already open just returns it
+///
+/// # Arguments
+///
+/// * `client` - A reqwest HTTP "client" to make the request
+/// * `token` - The slack token to authenticate with
+/// * `user` - The Slack user id, e.g. `U012AB3CD`
+pub fn open_dm(client: &Client, token: &str, user: &str) -> Result<String, String> {
+    let resp = slack_api(
+        client,
+        token,
+        "conversations.open",
+        &json!({ "users": user }),
+    )?;
+    Ok(resp["channel"]["id"]
+        .as_str()
+        .unwrap_or_default()
+        .to_string())
+}
+
+/// Authenticate and send a request to the Slack Web API, and check Slack's verdict
+fn slack_send(token: &str, method: &str, request: RequestBuilder) -> Result<Value, String> {
+    if token == "no-slack" {
+        return Err("slack is not configured".into());
+    }
+    faults::inject("slack")?;
+
+    let mut headers = HeaderMap::new();
+    headers.insert(
+        AUTHORIZATION,
+        format!("Bearer {}", token)
+            .parse()
+            .map_err(|_| "invalid slack token".to_string())?,
+    );
+
+    let text = request
+        .headers(headers)
+        .send()
+        .and_then(Response::text)
+        .map_err(|e| e.to_string())?;
+    let resp: Value = serde_json::from_str(&text).map_err(|e| e.to_string())?;
+
+    if resp["ok"].as_bool().unwrap_or(false) {
+        Ok(resp)
+    } else {
+        let error = resp["error"]
+            .as_str()
+            .unwrap_or("unknown_error")
+            .to_string();
+        log_event!("slack.api_error", method = method, error = error);
+        Err(error)
+    }
+}
+
+/// The most messages `slack_thread_replies` returns
+const MAX_THREAD_REPLIES: usize = 1000;
+
+/// Fetch the messages of a thread as Slack describes them, the parent message first, up to
+/// `MAX_THREAD_REPLIES` of them
+///
+/// # Arguments
+///
+/// * `client` - A reqwest HTTP "client" to make the requests
+/// * `token` - The slack token to authenticate with. Never seen by Clients
+/// * `channel` - The id of the channel the thread is in
+/// * `thread_ts` - The ts of the parent message of the thread
+pub fn thread_messages(
+    client: &Client,
+    token: &str,
+    channel: &str,
+    thread_ts: &str,
+) -> Result<Vec<Value>, String> {
+    let mut messages = Vec::new();
+    let mut cursor = String::new();
+
+    loop {
+        let mut query = vec![("channel", channel), ("ts", thread_ts), ("limit", "200")];
+        if !cursor.is_empty() {
+            query.push(("cursor", cursor.as_str()));
+        }
+        let resp = slack_api_get(client, token, "conversations.replies", &query)?;
+
+        messages.extend(resp["messages"].as_array().into_iter().flatten().cloned());
+
+        cursor = resp["response_metadata"]["next_cursor"]
+            .as_str()
+            .unwrap_or_default()
+            .to_string();
+        if cursor.is_empty() || messages.len() >= MAX_THREAD_REPLIES {
+            break;
+        }
+    }
+
+    messages.truncate(MAX_THREAD_REPLIES);
+    Ok(messages)
+}
+
+/// Fetch the messages of a thread, the parent message first
+///
+/// Each message becomes a map with `user`, `text` and `ts`. Messages posted by bots have an empty
+/// `user`, and their `bot_id` instead.
+///
+/// # Arguments
+///
+/// * `client` - A reqwest HTTP "client" to make the requests
+/// * `token` - The slack token to authenticate with. Never seen by Clients
+/// * `channel` - The id of the channel the thread is in
+/// * `thread_ts` - The ts of the parent message of the thread
+fn thread_replies(
+    client: &Client,
+    token: &str,
+    channel: &str,
+    thread_ts: &str,
+) -> Result<Array, String> {
+    let messages = thread_messages(client, token, channel, thread_ts)?;
+    Ok(messages
+        .iter()
+        .map(|message| {
+            let field =
+                |name: &str| Dynamic::from(message[name].as_str().unwrap_or_default().to_string());
+            let mut map = Map::new();
+            map.insert("user".into(), field("user"));
+            map.insert("bot_id".into(), field("bot_id"));
+            map.insert("text".into(), field("text"));
+            map.insert("ts".into(), field("ts"));
+            Dynamic::from(map)
+        })
+        .collect())
+}
+
+/// Find the id of a user group from its handle, e.g. `oncall` for `@oncall`
+///
+/// # Arguments
+///
+/// * `client` - A reqwest HTTP "client" to make the request
+/// * `token` - The slack token to authenticate with. Never seen by Clients
+/// * `handle` - The handle of the group, with or without the leading `@`
+fn usergroup_id(client: &Client, token: &str, handle: &str) -> Result<Option<String>, String> {
+    let handle = handle.trim_start_matches('@');
+    let resp = slack_api_get(client, token, "usergroups.list", &[])?;
+    Ok(resp["usergroups"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .find(|g| g["handle"].as_str() == Some(handle))
+        .and_then(|g| g["id"].as_str())
+        .map(String::from))
+}
+
+/// Format a mention of a user, a user group or the channel, so that Slack notifies them
+///
+/// Understands user ids (`U012AB3CD`), user group ids (`S012AB3CD`), user group handles
+/// (`@oncall`), and `here`, `channel` and `everyone`. Anything else is returned as is.
+///
+/// # Arguments
+///
+/// * `client` - A reqwest HTTP "client" to look up user group handles
+/// * `token` - The slack token to authenticate with. Never seen by Clients
+/// * `target` - Who to mention
+fn mention(client: &Client, token: &str, target: &str) -> String {
+    let target = target.trim();
+    let is_id = |prefix: &[char]| {
+        target.len() > 1
+            && target.starts_with(prefix)
+            && target
+                .chars()
+                .all(|c| c.is_ascii_uppercase() || c.is_ascii_digit())
+    };
+
+    match target.trim_start_matches('@') {
+        "here" | "channel" | "everyone" => format!("<!{}>", target.trim_start_matches('@')),
+        _ if is_id(&['U', 'W']) => format!("<@{}>", target),
+        _ if is_id(&['S']) => format!("<!subteam^{}>", target),
+        handle => match usergroup_id(client, token, handle) {
+            Ok(Some(id)) => format!("<!subteam^{}>", id),
+            _ => target.to_string(),
+        },
+    }
+}
+
+/// Describe the files attached to a message, for handlers
+///
+/// Each file becomes a map with `id`, `name`, `mimetype`, `size`, `permalink` and `url_private`.
+/// Text files no larger than `max_bytes` are downloaded, and their contents added as `content`.
+///
+/// # Arguments
+///
+/// * `client` - A reqwest HTTP "client" to download the files with
+/// * `token` - The slack token to authenticate with. Never seen by Clients
+/// * `files` - The files of the message
+/// * `max_bytes` - The largest file to download. 0 disables downloads
+pub fn files_context(client: &Client, token: &str, files: &[SlackFile], max_bytes: u64) -> Array {
+    files
+        .iter()
+        .map(|file| {
+            let mut map = Map::new();
+            map.insert("id".into(), Dynamic::from(file.id.clone()));
+            map.insert("name".into(), Dynamic::from(file.name.clone()));
+            map.insert("mimetype".into(), Dynamic::from(file.mimetype.clone()));
+            map.insert("size".into(), Dynamic::from(file.size as INT));
+            map.insert("permalink".into(), Dynamic::from(file.permalink.clone()));
+            map.insert(
+                "url_private".into(),
+                Dynamic::from(file.url_private.clone()),
+            );
+
+            if file.size > 0 && file.size <= max_bytes {
+                if let Some(content) = download_text(client, token, &file.url_private) {
+                    map.insert("content".into(), Dynamic::from(content));
+                }
+            }
+            Dynamic::from(map)
+        })
+        .collect()
+}
+
+/// Download a file from Slack, if it is text
+fn download_text(client: &Client, token: &str, url: &str) -> Option<String> {
+    // Never send our token anywhere but Slack
+    if token == "no-slack" || !url.starts_with("https://files.slack.com/") {
+        return None;
+    }
+
+    let bytes = client
+        .get(url)
+        .header(AUTHORIZATION, format!("Bearer {}", token))
+        .send()
+        .and_then(Response::bytes)
+        .ok()?;
+    String::from_utf8(bytes.to_vec()).ok()
+}
+
+/// Describe the messages shared into a message, for handlers
+///
+/// Each share becomes a map with `url` (the permalink of the shared message), `text`, `author`,
+/// `channel` and `ts`. Link previews and other attachments are left out.
+///
+/// # Arguments
+///
+/// * `attachments` - The attachments of the message
+pub fn shares_context(attachments: &[SlackAttachment]) -> Array {
+    attachments
+        .iter()
+        .filter(|a| a.is_share)
+        .map(|share| {
+            let field = |value: &Option<String>| Dynamic::from(value.clone().unwrap_or_default());
+            let mut map = Map::new();
+            map.insert("url".into(), field(&share.from_url));
+            map.insert("text".into(), field(&share.text));
+            map.insert("author".into(), field(&share.author_id));
+            map.insert("channel".into(), field(&share.channel_id));
+            map.insert("ts".into(), field(&share.ts));
+            Dynamic::from(map)
+        })
+        .collect()
+}
+
+/// Register the Slack functions available to clients, beyond `slack_post`
+///
+/// * `slack_post_thread(channel, thread_ts, message)` replies in the thread of a message. Pass
+///   the `ts` of a message which is not in a thread yet to start one
+/// * `slack_thread_replies(channel, thread_ts)` returns the messages of a thread, see
+///   `thread_replies`
+/// * `slack_usergroup_members(handle)` returns the user ids of the members of a user group
+/// * `slack_user_info(user)` returns a map of a user's `id`, `name`, `display_name`, `real_name`
+///   and `is_bot`, e.g. to greet them by name. Users are cached for an hour, see `users`
+/// * `mention(user_or_group)` formats a mention, see `mention`
+/// * `slack_channel_create(name)` creates a public channel, and returns its id
+/// * `slack_invite(channel, users)` invites a list of user ids to a channel. Users who are
+///   already in the channel are skipped
+/// * `slack_set_topic(channel, topic)` sets the topic of a channel
+/// * `slack_post_blocks(channel, text, blocks)` posts a message laid out with Block Kit, e.g.
+///   with sections, fields, buttons or context, and returns its `ts`. The `text` is shown in
+///   notifications. Interactions with its elements go to the handler at
+///   `slack-action-<action_id>`, see `action_clicked`. `slack_post_blocks(channel, blocks)` takes
+///   either the list of blocks, or a whole message map, e.g. `#{blocks: [...], thread_ts: ts}`,
+///   and notifies with the first text of the blocks unless the map has a `text`
+/// * `slack_file_upload(channel, filename, contents)` uploads text as a file to a channel, e.g. a
+///   report too long for a message, and returns the id of the file. Files are limited to
+///   `SLACK_UPLOAD_MAX_BYTES`
+///
+/// Channels are referred to by id. We can only manage channels we are a member of, which
+/// includes every channel we created.
+///
+/// # Arguments
+///
+/// * `module` - The module to register the functions in
+/// * `env` - Environment variables
+/// * `services` - The shared http client
+/// * `id` - The correlation id of the request the handler is serving
+/// * `handler_addr` - The uri of the handler the functions are for
+pub fn register(
+    module: &mut Module,
+    env: &EnvInfo,
+    services: &Services,
+    id: &CorrelationId,
+    handler_addr: &str,
+) {
+    let client = services.http.clone();
+    let slack_token = env.slack_token.clone();
+    let addr = handler_addr.to_string();
+    let cid = id.clone();
+    let slack_channel_create = move |name: ImmutableString| -> Result<String, Box<EvalAltResult>> {
+        log_event!(
+            "slack.channel_create",
+            id = cid.0,
+            handler = addr,
+            name = name
+        );
+        let resp = slack_api(
+            &client,
+            &slack_token,
+            "conversations.create",
+            &json!({ "name": name.as_str() }),
+        )?;
+        Ok(resp["channel"]["id"]
+            .as_str()
+            .unwrap_or_default()
+            .to_string())
+    };
+
+    let client = services.http.clone();
+    let slack_token = env.slack_token.clone();
+    let addr = handler_addr.to_string();
+    let cid = id.clone();
+    let slack_invite =
+        move |channel: ImmutableString, users: Array| -> Result<(), Box<EvalAltResult>> {
+            let users = users
+                .into_iter()
+                .map(|u| u.to_string())
+                .collect::<Vec<String>>()
+                .join(",");
+            log_event!(
+                "slack.invite",
+                id = cid.0,
+                handler = addr,
+                channel = channel,
+                users = users
+            );
+            let body = json!({ "channel": channel.as_str(), "users": users });
+            match slack_api(&client, &slack_token, "conversations.invite", &body) {
+                Ok(_) => Ok(()),
+                Err(e) if e == "already_in_channel" => Ok(()),
+                Err(e) => Err(e.into()),
+            }
+        };
+
+    let client = services.http.clone();
+    let slack_token = env.slack_token.clone();
+    let addr = handler_addr.to_string();
+    let cid = id.clone();
+    let slack_set_topic =
+        move |channel: ImmutableString, topic: ImmutableString| -> Result<(), Box<EvalAltResult>> {
+            log_event!(
+                "slack.set_topic",
+                id = cid.0,
+                handler = addr,
+                channel = channel
+            );
+            let body = json!({ "channel": channel.as_str(), "topic": topic.as_str() });
+            slack_api(&client, &slack_token, "conversations.setTopic", &body)?;
+            Ok(())
+        };
+
+    let client = services.http.clone();
+    let slack_token = env.slack_token.clone();
+    let addr = handler_addr.to_string();
+    let cid = id.clone();
+    let slack_post_thread = move |channel: ImmutableString,
+                                  thread_ts: ImmutableString,
+                                  message: ImmutableString|
+          -> Result<(), Box<EvalAltResult>> {
+        log_event!(
+            "slack.post_thread",
+            id = cid.0,
+            handler = addr,
+            channel = channel,
+            thread_ts = thread_ts,
+        );
+        let body = json!({
+            "channel": channel.as_str(),
+            "thread_ts": thread_ts.as_str(),
+            "text": message.as_str()
+        });
+        slack_api(&client, &slack_token, "chat.postMessage", &body)?;
+        Ok(())
+    };
+
+    let client = services.http.clone();
+    let slack_token = env.slack_token.clone();
+    let addr = handler_addr.to_string();
+    let cid = id.clone();
+    let slack_thread_replies = move |channel: ImmutableString,
+                                     thread_ts: ImmutableString|
+          -> Result<Array, Box<EvalAltResult>> {
+        log_event!(
+            "slack.thread_replies",
+            id = cid.0,
+            handler = addr,
+            channel = channel,
+            thread_ts = thread_ts,
+        );
+        thread_replies(&client, &slack_token, &channel, &thread_ts).map_err(Into::into)
+    };
+
+    let client = services.http.clone();
+    let slack_token = env.slack_token.clone();
+    let addr = handler_addr.to_string();
+    let cid = id.clone();
+    let slack_post_blocks = move |channel: ImmutableString,
+                                  text: ImmutableString,
+                                  blocks: Array|
+          -> Result<String, Box<EvalAltResult>> {
+        log_event!(
+            "slack.post_blocks",
+            id = cid.0,
+            handler = addr,
+            channel = channel,
+            blocks = blocks.len(),
+        );
+        let blocks: Value = from_dynamic(&Dynamic::from(blocks))?;
+        let body = json!({
+            "channel": channel.as_str(),
+            "text": text.as_str(),
+            "blocks": blocks
+        });
+        let resp = slack_api(&client, &slack_token, "chat.postMessage", &body)?;
+        Ok(resp["ts"].as_str().unwrap_or_default().to_string())
+    };
+
+    let client = services.http.clone();
+    let slack_token = env.slack_token.clone();
+    let addr = handler_addr.to_string();
+    let cid = id.clone();
+    let slack_post_message =
+        move |channel: ImmutableString, message: Dynamic| -> Result<String, Box<EvalAltResult>> {
+            let mut message = match from_dynamic(&message)? {
+                blocks @ Value::Array(_) => json!({ "blocks": blocks }),
+                message @ Value::Object(_) => message,
+                _ => return Err("Expected a list of blocks, or a message map".into()),
+            };
+            log_event!(
+                "slack.post_blocks",
+                id = cid.0,
+                handler = addr,
+                channel = channel,
+                blocks = message["blocks"].as_array().map(Vec::len).unwrap_or(0),
+            );
+            message["channel"] = json!(channel.as_str());
+            if message["text"].is_null() {
+                message["text"] = json!(fallback_text(&message["blocks"]));
+            }
+            let resp = slack_api(&client, &slack_token, "chat.postMessage", &message)?;
+            Ok(resp["ts"].as_str().unwrap_or_default().to_string())
+        };
+
+    let client = services.http.clone();
+    let slack_token = env.slack_token.clone();
+    let max_bytes = env.slack_upload_max_bytes;
+    let addr = handler_addr.to_string();
+    let cid = id.clone();
+    let slack_file_upload = move |channel: ImmutableString,
+                                  filename: ImmutableString,
+                                  contents: ImmutableString|
+          -> Result<String, Box<EvalAltResult>> {
+        if contents.len() as u64 > max_bytes {
+            return Err(format!(
+                "{} is {} bytes, files of at most {} bytes may be uploaded",
+                filename,
+                contents.len(),
+                max_bytes
+            )
+            .into());
+        }
+        log_event!(
+            "slack.file_upload",
+            id = cid.0,
+            handler = addr,
+            channel = channel,
+            filename = filename,
+            bytes = contents.len(),
+        );
+        let form = [
+            ("channels", channel.as_str()),
+            ("filename", filename.as_str()),
+            ("content", contents.as_str()),
+        ];
+        let resp = slack_api_form(&client, &slack_token, "files.upload", &form)?;
+        Ok(resp["file"]["id"].as_str().unwrap_or_default().to_string())
+    };
+
+    let client = services.http.clone();
+    let slack_token = env.slack_token.clone();
+    let slack_usergroup_members =
+        move |handle: ImmutableString| -> Result<Array, Box<EvalAltResult>> {
+            let id = usergroup_id(&client, &slack_token, &handle)?
+                .ok_or_else(|| format!("Unknown user group {}", handle))?;
+            let resp = slack_api_get(
+                &client,
+                &slack_token,
+                "usergroups.users.list",
+                &[("usergroup", id.as_str())],
+            )?;
+            Ok(resp["users"]
+                .as_array()
+                .into_iter()
+                .flatten()
+                .filter_map(|u| u.as_str())
+                .map(|u| Dynamic::from(u.to_string()))
+                .collect())
+        };
+
+    let client = services.http.clone();
+    let slack_token = env.slack_token.clone();
+    let users = services.slack_users.clone();
+    let slack_user_info = move |user: ImmutableString| -> Result<Map, Box<EvalAltResult>> {
+        Ok(users.lookup(&client, &slack_token, &user)?.to_map())
+    };
+
+    let client = services.http.clone();
+    let slack_token = env.slack_token.clone();
+    let format_mention = move |target: ImmutableString| Ok(mention(&client, &slack_token, &target));
+
+    module.set_fn_1("slack_usergroup_members", slack_usergroup_members);
+    module.set_fn_1("slack_user_info", slack_user_info);
+    module.set_fn_1("mention", format_mention);
+    module.set_fn_3("slack_post_thread", slack_post_thread);
+    module.set_fn_3("slack_post_blocks", slack_post_blocks);
+    module.set_fn_2("slack_post_blocks", slack_post_message);
+    module.set_fn_3("slack_file_upload", slack_file_upload);
+    module.set_fn_2("slack_thread_replies", slack_thread_replies);
+    module.set_fn_1("slack_channel_create", slack_channel_create);
+    module.set_fn_2("slack_invite", slack_invite);
+    module.set_fn_2("slack_set_topic", slack_set_topic);
+}
+
+/// The first text of a message's blocks, for its notification, e.g. the text of its first
+/// section. Empty if none of its blocks have text
+fn fallback_text(blocks: &Value) -> String {
+    blocks
+        .as_array()
+        .into_iter()
+        .flatten()
+        .map(|block| &block["text"]["text"])
+        .find_map(Value::as_str)
+        .unwrap_or_default()
+        .to_string()
+}
+
+/// Post a json message to a Slack `response_url`, e.g. to replace a message a user interacted
+/// with. These urls are pre-authorized, so no token is needed.
+///
+/// # Arguments
+///
+/// * `client` - A reqwest HTTP "client" to make the request
+/// * `url` - The `response_url` Slack gave us
+/// * `body` - The message to send
+pub fn slack_respond(client: &Client, url: &str, body: &Value) -> bool {
+    // Only ever send these to Slack, whatever the payload claims
+    if !url.starts_with("https://hooks.slack.com/") {
+        return false;
+    }
+
+    client
+        .post(url)
+        .header(CONTENT_TYPE, "application/json")
+        .body(body.to_string())
+        .send()
+        .map(|r| r.status().is_success())
+        .unwrap_or(false)
+}
+
+/// The start of the uris of the handlers which answer interactions with the elements of messages,
+/// followed by the `action_id` of the element, e.g. `slack-action-deploy_confirm`
+pub const ACTION_PREFIX: &str = "slack-action-";
+
+/// The start of the uris of the handlers which receive Slack events other than messages,
+/// followed by the type of the event, e.g. `slack-event-reaction_added`
+pub const EVENT_PREFIX: &str = "slack-event-";
+
+/// What Slack sends when a user interacts with a message, e.g. clicks a button
+#[derive(FromForm)]
+pub struct SlackInteraction {
+    /// The details of the interaction, as json
+    pub payload: String,
+}
+
+/// A click on one of the interactive elements of a message we posted
+pub struct Interaction<'a> {
+    /// The Slack user id of whoever clicked
+    pub user: &'a str,
+    /// Where to send replies, see `slack_respond`
+    pub response_url: &'a str,
+    /// Which kind of element was clicked, e.g. `APPROVE_ACTION`
+    pub action_id: &'a str,
+    /// The value we attached to the element, or the value of the option picked from a menu
+    pub value: &'a str,
+}
+
+/// Reply to an interaction with a message only the user who interacted can see
+pub fn slack_respond_ephemeral(client: &Client, interaction: &Interaction, text: &str) -> bool {
+    let reply = json!({
+        "response_type": "ephemeral",
+        "replace_original": false,
+        "text": text
+    });
+    slack_respond(client, interaction.response_url, &reply)
+}
+
+/// Rocket Endpoint which receives interactions with messages we posted, e.g. button clicks
+///
+/// This handles
+/// * the approve/deny buttons of approval requests: the decision is recorded, the message is
+///   updated, and the requesting handler's `on_approval` is invoked.
+/// * the option buttons of polls: the vote is recorded
+/// * the buttons of the home tab: the command is run, see `home::run_clicked`
+/// * any other element, e.g. a button or menu a handler posted with `slack_post_blocks`: the
+///   handler at `slack-action-<action_id>` is invoked, see `action_clicked`
+///
+/// # Arguments
+///
+/// * `id` - The correlation id of the request, attached to every log line
+/// * `env` - Environment variables
+/// * `services` - The stateful subsystems, e.g. approvals
+/// * `handlers` - A reference to the collection of User created handlers, indexed by their uris
+/// * `body` - The interaction, as sent by Slack, form encoded. Its signature has been checked
+#[post("/slack_interactive", data = "<body>")]
+pub fn slack_interactive(
+    id: CorrelationId,
+    env: State<EnvInfo>,
+    storage: State<Storage>,
+    services: State<Services>,
+    api_keys: Collection<String, ApiKeyInfo>,
+    handlers: Collection<String, Handler>,
+    body: SlackBody,
+) {
+    let form = match SlackInteraction::from_form(&mut FormItems::from(body.0.as_str()), false) {
+        Ok(form) => form,
+        Err(e) => {
+            log_event!(
+                "slack.interaction_error",
+                id = id.0,
+                error = format!("{:?}", e)
+            );
+            return;
+        }
+    };
+
+    let payload: Value = match serde_json::from_str(&form.payload) {
+        Ok(p) => p,
+        Err(e) => {
+            log_event!("slack.interaction_error", id = id.0, error = e);
+            return;
+        }
+    };
+
+    let action = &payload["actions"][0];
+    let interaction = Interaction {
+        user: payload["user"]["id"].as_str().unwrap_or_default(),
+        response_url: payload["response_url"].as_str().unwrap_or_default(),
+        action_id: action["action_id"].as_str().unwrap_or_default(),
+        value: action["value"]
+            .as_str()
+            .or_else(|| action["selected_option"]["value"].as_str())
+            .unwrap_or_default(),
+    };
+
+    let client = services.http.clone();
+    match interaction.action_id {
+        APPROVE_ACTION | DENY_ACTION => {
+            approval_clicked(&env, &services, &handlers, &id, &client, &interaction)
+        }
+        APPROVE_CHANGE_ACTION | REJECT_CHANGE_ACTION => change_clicked(
+            &env,
+            &storage,
+            &services,
+            &api_keys,
+            &handlers,
+            &client,
+            &interaction,
+        ),
+        HOME_RUN_ACTION => home::run_clicked(&id, &env, &services, &interaction, &payload),
+        // Slack wants the buttons of a block to have distinct ids, so each is suffixed
+        action if action.starts_with(VOTE_ACTION) => {
+            let text = match polls::vote(&services, interaction.value, interaction.user) {
+                Ok(option) => format!("Your vote for *{}* was recorded", option),
+                Err(cause) => cause,
+            };
+            slack_respond_ephemeral(&client, &interaction, &text);
+        }
+        _ => action_clicked(&env, &services, &handlers, &id, &interaction, &payload),
+    }
+}
+
+/// Pass an interaction with an element of a message on to the handler at
+/// `slack-action-<action_id>`, as `handle(value, interaction)`
+///
+/// The interaction is a map of the `action_id`, `block_id`, `value`, `user`, `channel`, and the
+/// originating `message`, as Slack describes it. The handler runs in the background, since Slack
+/// wants interactions acknowledged within 3 seconds. What it returns is sent back: a string as
+/// a reply only the user sees, and a map as it is, e.g. `#{replace_original: true, text: "Done"}`
+/// to change the message. An empty string sends nothing.
+///
+/// # Arguments
+///
+/// * `env` - Environment variables
+/// * `services` - The stateful subsystems available to handlers
+/// * `handlers` - A reference to the collection of User created handlers, indexed by their uris
+/// * `id` - The correlation id of the request, attached to every log line
+/// * `interaction` - The interaction
+/// * `payload` - The whole interaction, as sent by Slack
+fn action_clicked(
+    env: &EnvInfo,
+    services: &Services,
+    handlers: &RwLock<HashMap<String, Handler>>,
+    id: &CorrelationId,
+    interaction: &Interaction,
+    payload: &Value,
+) {
+    let addr = format!("{}{}", ACTION_PREFIX, interaction.action_id);
+    if interaction.action_id.is_empty() || !handlers.read().unwrap().contains_key(&addr) {
+        log_event!(
+            "slack.interaction_unknown",
+            id = id.0,
+            action = interaction.action_id
+        );
+        return;
+    }
+
+    let action = &payload["actions"][0];
+    let mut context = Map::new();
+    let mut insert = |key: &str, value: &str| {
+        context.insert(key.into(), Dynamic::from(value.to_string()));
+    };
+    insert("action_id", interaction.action_id);
+    insert("block_id", action["block_id"].as_str().unwrap_or_default());
+    insert("value", interaction.value);
+    insert("user", interaction.user);
+    insert(
+        "channel",
+        payload["channel"]["id"].as_str().unwrap_or_default(),
+    );
+    let message = to_dynamic(&payload["message"]).unwrap_or_else(|_| Dynamic::from(Map::new()));
+    context.insert("message".into(), message);
+
+    let env = env.clone();
+    let services = services.clone();
+    let id = id.clone();
+    let value = interaction.value.to_string();
+    let response_url = interaction.response_url.to_string();
+    thread::spawn(move || {
+        let guard = services.handlers.read().unwrap();
+        let handler = match guard.get(&addr) {
+            Some(handler) => handler,
+            None => return,
+        };
+        let result =
+            run_handler_dynamic(&env, &services, &id, &addr, handler, value, Some(context));
+        drop(guard);
+
+        let reply = match result {
+            Ok(reply) if reply.is::<Map>() => from_dynamic::<Value>(&reply).ok(),
+            Ok(reply) => reply
+                .try_cast::<String>()
+                .filter(|text| !text.is_empty())
+                .map(|text| {
+                    json!({
+                        "response_type": "ephemeral",
+                        "replace_original": false,
+                        "text": text
+                    })
+                }),
+            Err(e) => {
+                log_event!("slack.handler_error", id = id.0, handler = addr, error = e);
+                None
+            }
+        };
+        if let Some(reply) = reply {
+            slack_respond(&services.http, &response_url, &reply);
+        }
+    });
+}
+
+/// Decide a change to a protected handler, on behalf of the API Key of whoever clicked
+///
+/// Slack users decide for the keys they are the contact of, or activated, which must be another
+/// owner of the handler than the one which proposed the change, see `changes::decide`.
+fn change_clicked(
+    env: &EnvInfo,
+    storage: &Storage,
+    services: &Services,
+    api_keys: &RwLock<HashMap<String, ApiKeyInfo>>,
+    handlers: &RwLock<HashMap<String, Handler>>,
+    client: &Client,
+    interaction: &Interaction,
+) {
+    let change = match services.changes.read().get(interaction.value).cloned() {
+        Some(change) => change,
+        None => {
+            slack_respond_ephemeral(client, interaction, "This change was already decided");
+            return;
+        }
+    };
+    // Keys whose contact is the user, but which can't decide the change, are skipped
+    let guard = handlers.read().unwrap();
+    let handler = guard.get(&change.uri);
+    let decider = api_keys
+        .read()
+        .unwrap()
+        .iter()
+        .filter(|(_, info)| {
+            info.contact.as_deref() == Some(interaction.user)
+                || info.activated_by.as_deref() == Some(interaction.user)
+        })
+        .map(|(hash, _)| hash.clone())
+        .find(|hash| handler.map_or(false, |h| h.owned_by(hash)) && *hash != change.proposed_by);
+    drop(guard);
+    let decider = match decider {
+        Some(decider) => decider,
+        None => {
+            let cause =
+                "Only the contact of another API Key which owns the handler may decide this";
+            slack_respond_ephemeral(client, interaction, cause);
+            return;
+        }
+    };
+
+    let approve = interaction.action_id == APPROVE_CHANGE_ACTION;
+    let change = match changes::decide(
+        env, storage, services, api_keys, handlers, &change.id, &decider, approve,
+    ) {
+        Ok(change) => change,
+        Err(cause) => {
+            slack_respond_ephemeral(client, interaction, &cause);
+            return;
+        }
+    };
+
+    let verdict = if approve {
+        ":white_check_mark: Approved, and live"
+    } else {
+        ":x: Rejected"
+    };
+    let reply = json!({
+        "replace_original": true,
+        "text": format!("*Change proposed to `{}`*\n{} by <@{}>", change.uri, verdict, interaction.user)
+    });
+    slack_respond(client, interaction.response_url, &reply);
+}
+
+/// Record a click on an approve/deny button, and pass the decision on to the handler
+fn approval_clicked(
+    env: &EnvInfo,
+    services: &Services,
+    handlers: &RwLock<HashMap<String, Handler>>,
+    id: &CorrelationId,
+    client: &Client,
+    interaction: &Interaction,
+) {
+    let approve = interaction.action_id == APPROVE_ACTION;
+    let approval = match approvals::decide(services, interaction.value, interaction.user, approve) {
+        Ok(approval) => approval,
+        Err(cause) => {
+            slack_respond_ephemeral(client, interaction, &cause);
+            return;
+        }
+    };
+
+    let verdict = if approve {
+        ":white_check_mark: Approved"
+    } else {
+        ":x: Denied"
+    };
+    let reply = json!({
+        "replace_original": true,
+        "text": format!("*Approval requested*\n{}\n{} by <@{}>", approval.summary, verdict, interaction.user)
+    });
+    slack_respond(client, interaction.response_url, &reply);
+
+    // Let the handler which asked know about the decision
+    let guard = handlers.read().unwrap();
+    if let Some(handler) = guard.get(&approval.handler) {
+        let result = run_function(
+            env,
+            services,
+            id,
+            &approval.handler,
+            handler,
+            None,
+            "on_approval",
+            |engine| {
+                let args = (approval.to_map(),);
+                engine.call_fn(&mut Scope::new(), &handler.code.ast, "on_approval", args)
+            },
+        );
+        if let Err(e) = result {
+            log_event!(
+                "handler.error",
+                id = id.0,
+                handler = approval.handler,
+                error = e
+            );
+        }
+    }
+}
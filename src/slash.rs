@@ -0,0 +1,299 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use rhai::de::from_dynamic;
+use rhai::{Dynamic, Map};
+
+use rocket::request::{FormItems, FromForm};
+use rocket::State;
+use rocket_contrib::json::Json;
+
+use serde_json::{json, Value};
+
+use crate::auth::SlackBody;
+use crate::help::render_help;
+use crate::logging::CorrelationId;
+use crate::server::{run_handler_dynamic, Collection};
+use crate::services::Services;
+use crate::slack::slack_respond;
+use crate::types::{EnvInfo, Handler};
+
+/// Slack gives up on a slash command which isn't answered within 3 seconds. Handlers which take
+/// longer than this answer later, through the command's `response_url`
+const REPLY_WITHIN: Duration = Duration::from_millis(2500);
+
+/// The response types Slack knows, the first being the default: only the user who ran the
+/// command sees `ephemeral` responses, while `in_channel` ones are posted for everyone
+const RESPONSE_TYPES: [&str; 2] = ["ephemeral", "in_channel"];
+
+/// What Slack sends when a user runs a slash command, form encoded
+#[derive(FromForm)]
+pub struct SlashCommand {
+    /// The command, e.g. `/majordomo`
+    pub command: String,
+    /// What the user typed after it
+    pub text: String,
+    pub user_id: String,
+    pub user_name: String,
+    pub channel_id: String,
+    pub channel_name: String,
+    /// Where responses to the command can be sent, for 30 minutes
+    pub response_url: String,
+}
+
+/// The uri of the handler which answers a slash command of a given name
+pub fn command_uri(name: &str) -> String {
+    format!("slash-{}", name.to_lowercase())
+}
+
+/// Split a slash command into the name of the handler which answers it, and what it is passed
+///
+/// The first word after `EnvInfo::slack_command` is the name, e.g. `deploy` for
+/// `/majordomo deploy api`. Other commands the Slack app has are answered by the handler of the
+/// same name, e.g. `/deploy api`.
+///
+/// # Arguments
+///
+/// * `env` - Environment variables, for the name of our own command
+/// * `command` - The command, as sent by Slack
+pub fn dispatch<'a>(env: &EnvInfo, command: &'a SlashCommand) -> (&'a str, &'a str) {
+    let name = command.command.trim_start_matches('/');
+    if name != env.slack_command {
+        return (name, command.text.trim());
+    }
+    let text = command.text.trim();
+    let end = text.find(char::is_whitespace).unwrap_or_else(|| text.len());
+    (&text[..end], text[end..].trim())
+}
+
+/// A response only the user who ran the command sees
+fn ephemeral(text: &str) -> Value {
+    json!({ "response_type": RESPONSE_TYPES[0], "text": text })
+}
+
+/// Turn what a handler returned into the response Slack shows
+///
+/// Strings are shown to the user who ran the command. Maps are sent as they are, so handlers can
+/// post `in_channel`, or send blocks, e.g. `#{response_type: "in_channel", text: "Deploying"}`.
+///
+/// # Arguments
+///
+/// * `value` - What the handler returned
+fn response_from(value: Dynamic) -> Result<Value, String> {
+    if value.is::<Map>() {
+        let mut reply: Value = from_dynamic(&value).map_err(|e| e.to_string())?;
+        let response_type = reply["response_type"]
+            .as_str()
+            .unwrap_or(RESPONSE_TYPES[0])
+            .to_string();
+        if !RESPONSE_TYPES.contains(&response_type.as_str()) {
+            return Err(format!(
+                "{} is not a response type, expected one of {}",
+                response_type,
+                RESPONSE_TYPES.join(", ")
+            ));
+        }
+        reply["response_type"] = Value::String(response_type);
+        return Ok(reply);
+    }
+
+    let type_name = value.type_name();
+    match value.try_cast::<String>() {
+        Some(text) => Ok(ephemeral(&text)),
+        None => Err(format!(
+            "handle returned {}, rather than a string or a response map",
+            type_name
+        )),
+    }
+}
+
+/// What the slash commands are, for `/majordomo help`
+fn usage(env: &EnvInfo, handlers: &HashMap<String, Handler>) -> String {
+    let commands = handlers
+        .iter()
+        .filter(|(uri, h)| uri.starts_with("slash-") && !h.tags.iter().any(|t| t == "hidden"))
+        .map(|(uri, h)| {
+            let description = h.description.clone().unwrap_or_default();
+            (&uri["slash-".len()..], description)
+        })
+        .collect::<BTreeMap<&str, String>>();
+
+    if commands.is_empty() {
+        return render_help(handlers);
+    }
+    let mut text = String::from("*Commands:*\n");
+    for (name, description) in commands {
+        text.push_str(&format!(
+            "• `/{} {}`: {}\n",
+            env.slack_command, name, description
+        ));
+    }
+    text
+}
+
+/// Run the handler which answers a slash command, and turn what it returned into the response
+/// Slack shows, see `response_from`. Failures are answered with what went wrong
+///
+/// The command is remembered for the home tab of whoever ran it, see `home::RecentCommands`
+///
+/// # Arguments
+///
+/// * `env` - Environment variables
+/// * `services` - The stateful subsystems available to handlers
+/// * `id` - The correlation id of the request, attached to every log line
+/// * `command` - The command, as sent by Slack
+/// * `name` - The name of the command, see `dispatch`
+/// * `text` - What the handler is passed, see `dispatch`
+pub fn run_command(
+    env: &EnvInfo,
+    services: &Services,
+    id: &CorrelationId,
+    command: &SlashCommand,
+    name: &str,
+    text: &str,
+) -> Value {
+    let mut context = Map::new();
+    context.insert("command".into(), Dynamic::from(command.command.clone()));
+    context.insert("name".into(), Dynamic::from(name.to_string()));
+    context.insert("text".into(), Dynamic::from(command.text.clone()));
+    context.insert("user".into(), Dynamic::from(command.user_id.clone()));
+    context.insert("user_name".into(), Dynamic::from(command.user_name.clone()));
+    context.insert("channel".into(), Dynamic::from(command.channel_id.clone()));
+    context.insert(
+        "channel_name".into(),
+        Dynamic::from(command.channel_name.clone()),
+    );
+
+    let addr = command_uri(name);
+    let guard = services.handlers.read().unwrap();
+    let result = match guard.get(&addr) {
+        Some(handler) => run_handler_dynamic(
+            env,
+            services,
+            id,
+            &addr,
+            handler,
+            text.to_string(),
+            Some(context),
+        )
+        .map_err(|e| e.to_string())
+        .and_then(response_from),
+        None => Err("The command was removed".to_string()),
+    };
+    drop(guard);
+
+    services
+        .recent_commands
+        .record(&command.user_id, name, text, result.is_ok());
+    result.unwrap_or_else(|e| {
+        log_event!("slack.handler_error", id = id.0, handler = addr, error = e);
+        ephemeral(&format!("Sorry, that failed: {}", e))
+    })
+}
+
+/// The answer to a slash command, once its handler has run
+#[derive(Default)]
+struct Pending {
+    reply: Option<Value>,
+    /// Whether Slack has already been told the answer will follow
+    late: bool,
+}
+
+/// Rocket Endpoint which receives slash commands, e.g. `/majordomo deploy api`, see `dispatch`
+///
+/// The handler at `slash-<name>` is called as `handle(text, command)`, with what the user typed
+/// after the name, and a map of the `command`, `name`, `text`, `user`, `user_name`, `channel`
+/// and `channel_name`. What it returns is the response, see `response_from`. Handlers which don't
+/// return within `REPLY_WITHIN` are answered with a note, and their response follows once
+/// they do.
+///
+/// Commands which are not signed by Slack are refused, see `auth::SlackBody`
+///
+/// # Arguments
+///
+/// * `id` - The correlation id of the request, attached to every log line
+/// * `env` - Environment variables
+/// * `services` - The stateful subsystems available to handlers
+/// * `handlers` - A reference to the collection of User created handlers, indexed by their uris
+/// * `body` - The command, as sent by Slack, form encoded. Its signature has been checked
+#[post("/slack_command", data = "<body>")]
+pub fn slack_command(
+    id: CorrelationId,
+    env: State<EnvInfo>,
+    services: State<Services>,
+    handlers: Collection<String, Handler>,
+    body: SlackBody,
+) -> Json<Value> {
+    let command = match SlashCommand::from_form(&mut FormItems::from(body.0.as_str()), false) {
+        Ok(command) => command,
+        Err(e) => {
+            log_event!("slack.command_error", id = id.0, error = format!("{:?}", e));
+            return Json(ephemeral("Sorry, I couldn't read that command"));
+        }
+    };
+
+    let (name, text) = dispatch(&env, &command);
+    if name.is_empty() || name.eq_ignore_ascii_case("help") {
+        return Json(ephemeral(&usage(&env, &handlers.read().unwrap())));
+    }
+
+    let addr = command_uri(name);
+    if !handlers.read().unwrap().contains_key(&addr) {
+        return Json(ephemeral(&format!(
+            "I don't know the command {}, try `/{} help`",
+            name, env.slack_command
+        )));
+    }
+    log_event!(
+        "slack.command",
+        id = id.0,
+        handler = addr,
+        user = command.user_id
+    );
+
+    // The handler runs aside, so Slack can be answered in time however long it takes
+    let pending = Arc::new((Mutex::new(Pending::default()), Condvar::new()));
+    {
+        let pending = pending.clone();
+        let env = env.inner().clone();
+        let services = services.inner().clone();
+        let id = id.clone();
+        let (name, text) = (name.to_string(), text.to_string());
+        thread::spawn(move || {
+            let reply = run_command(&env, &services, &id, &command, &name, &text);
+            let response_url = &command.response_url;
+
+            let (lock, ready) = &*pending;
+            let mut state = lock.lock().unwrap();
+            if state.late {
+                drop(state);
+                if !slack_respond(&services.http, response_url, &reply) {
+                    log_event!("slack.command_reply_error", id = id.0, handler = addr);
+                }
+            } else {
+                state.reply = Some(reply);
+                ready.notify_one();
+            }
+        });
+    }
+
+    let (lock, ready) = &*pending;
+    let deadline = Instant::now() + REPLY_WITHIN;
+    let mut state = lock.lock().unwrap();
+    while state.reply.is_none() {
+        let now = Instant::now();
+        if now >= deadline {
+            break;
+        }
+        state = ready.wait_timeout(state, deadline - now).unwrap().0;
+    }
+    match state.reply.take() {
+        Some(reply) => Json(reply),
+        None => {
+            state.late = true;
+            Json(ephemeral("Working on it, I'll answer here when it's done"))
+        }
+    }
+}
@@ -0,0 +1,140 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use rusqlite::{params, Connection, NO_PARAMS};
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::storage::Backend;
+use crate::types::{ApiKeyInfo, Handler};
+
+/// Storage backend keeping handlers and api keys in an SQLite database
+///
+/// Each entry is a row holding its json, so saves only touch the entries which changed, and
+/// happen in a transaction: a failed save leaves the previous state intact.
+pub struct SqliteBackend {
+    path: String,
+    conn: Mutex<Connection>,
+}
+
+impl SqliteBackend {
+    /// Open the database at `path`, creating it and its tables if need be
+    pub fn open(path: &str) -> Result<SqliteBackend, String> {
+        let conn = Connection::open(path).map_err(|e| e.to_string())?;
+        conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS handlers (key TEXT PRIMARY KEY, data TEXT NOT NULL);
+             CREATE TABLE IF NOT EXISTS api_keys (key TEXT PRIMARY KEY, data TEXT NOT NULL);",
+        )
+        .map_err(|e| e.to_string())?;
+
+        Ok(SqliteBackend {
+            path: path.to_string(),
+            conn: Mutex::new(conn),
+        })
+    }
+
+    /// Read every row of a table. Rows which no longer deserialize are skipped, with a warning
+    fn load<V: DeserializeOwned>(&self, table: &str) -> Option<HashMap<String, V>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn
+            .prepare(&format!("SELECT key, data FROM {}", table))
+            .ok()?;
+        let rows = stmt
+            .query_map(NO_PARAMS, |row| {
+                Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
+            })
+            .ok()?;
+
+        let mut map = HashMap::new();
+        for row in rows {
+            let (key, data) = row.ok()?;
+            match serde_json::from_str(&data) {
+                Ok(value) => {
+                    map.insert(key, value);
+                }
+                Err(e) => log_event!("sqlite.load_error", table = table, error = e),
+            }
+        }
+        Some(map)
+    }
+
+    /// Write the changed entries of a collection to a table, in a single transaction
+    fn save<V: Serialize>(
+        &self,
+        table: &str,
+        map: &HashMap<String, V>,
+        changed: &[String],
+    ) -> Result<(), String> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction().map_err(|e| e.to_string())?;
+
+        for key in changed {
+            match map.get(key) {
+                Some(value) => {
+                    let data = serde_json::to_string(value).map_err(|e| e.to_string())?;
+                    tx.execute(
+                        &format!(
+                            "INSERT OR REPLACE INTO {} (key, data) VALUES (?1, ?2)",
+                            table
+                        ),
+                        params![key, data],
+                    )
+                }
+                None => tx.execute(
+                    &format!("DELETE FROM {} WHERE key = ?1", table),
+                    params![key],
+                ),
+            }
+            .map_err(|e| e.to_string())?;
+        }
+
+        tx.commit().map_err(|e| e.to_string())
+    }
+
+    /// Changes whenever another connection commits to the database
+    fn data_version(&self) -> Option<u64> {
+        let conn = self.conn.lock().unwrap();
+        conn.query_row("PRAGMA data_version", NO_PARAMS, |row| row.get::<_, i64>(0))
+            .ok()
+            .map(|v| v as u64)
+    }
+}
+
+impl Backend for SqliteBackend {
+    fn describe(&self) -> String {
+        self.path.clone()
+    }
+
+    fn load_handlers(&self) -> Option<HashMap<String, Handler>> {
+        self.load("handlers")
+    }
+
+    fn load_api_keys(&self) -> Option<HashMap<String, ApiKeyInfo>> {
+        self.load("api_keys")
+    }
+
+    fn save_handlers(
+        &self,
+        handlers: &HashMap<String, Handler>,
+        changed: &[String],
+    ) -> Result<(), String> {
+        self.save("handlers", handlers, changed)
+    }
+
+    fn save_api_keys(
+        &self,
+        api_keys: &HashMap<String, ApiKeyInfo>,
+        changed: &[String],
+    ) -> Result<(), String> {
+        self.save("api_keys", api_keys, changed)
+    }
+
+    fn handlers_version(&self) -> Option<u64> {
+        self.data_version()
+    }
+
+    fn api_keys_version(&self) -> Option<u64> {
+        self.data_version()
+    }
+}
@@ -0,0 +1,265 @@
+use std::collections::HashMap;
+use std::sync::RwLock;
+
+use rhai::{EvalAltResult, ImmutableString, Module, Scope, INT};
+
+use serde::{Deserialize, Serialize};
+
+use crate::clock::utc_date;
+use crate::github::{add_labels, close_issue, issue_map, search_issues};
+use crate::logging::CorrelationId;
+use crate::server::run_function;
+use crate::services::Services;
+use crate::types::{EnvInfo, Handler};
+
+/// How often each sweep runs, in seconds
+const SWEEP_INTERVAL_SECS: u64 = 24 * 60 * 60;
+
+/// The label the `label` action adds
+pub const STALE_LABEL: &str = "stale";
+
+/// What a sweep does to each stale issue, besides telling its handler
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SweepAction {
+    /// Nothing, the handler decides what to do
+    Notify,
+    /// Add the `STALE_LABEL`
+    Label,
+    /// Close the issue
+    Close,
+}
+
+/// A daily search of a repository for issues nobody has touched in a while
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Sweep {
+    /// The uri of the handler which set up the sweep, and whose `on_stale(issue)` is called with
+    /// each stale issue
+    pub handler: String,
+    /// The full name of the repository, `<owner>/<repo>`
+    pub repo: String,
+    /// How many days an issue must go without updates to be stale
+    pub days: u64,
+    /// Only issues with this label are swept, if not empty
+    pub label: String,
+    pub action: SweepAction,
+    /// When the sweep last ran, as a unix timestamp. 0 if never
+    pub last_run_at: u64,
+}
+
+impl Sweep {
+    /// The key of the sweep a handler set up for a repository
+    fn key(handler: &str, repo: &str) -> String {
+        format!("{} {}", handler, repo.to_lowercase())
+    }
+
+    /// The search query for the open issues of the repository which are stale at `now`
+    fn query(&self, now: u64) -> String {
+        let cutoff = utc_date(now.saturating_sub(self.days * 24 * 60 * 60));
+        let mut query = format!("repo:{} is:issue is:open updated:<{}", self.repo, cutoff);
+        if !self.label.is_empty() {
+            query.push_str(&format!(" label:\"{}\"", self.label));
+        }
+        if self.action == SweepAction::Label {
+            // Don't label the same issues over and over
+            query.push_str(&format!(" -label:\"{}\"", STALE_LABEL));
+        }
+        query
+    }
+}
+
+/// Register the stale sweep functions available to clients
+///
+/// * `github_stale_sweep(repo, days, label, action)` searches the open issues of a repository
+///   once a day, for those which haven't been updated in `days`, and only those labelled `label`
+///   unless it is empty. Each is labelled `stale` if `action` is `"label"`, closed if it is
+///   `"close"`, and left alone if it is `"notify"`, then passed to the handler's
+///   `on_stale(issue)`, as a map described by `github::issue_map`. Calling it again for the
+///   same repository changes the sweep. At most 100 issues are swept a day
+/// * `github_stale_sweep_cancel(repo)` stops the sweep of a repository. Returns whether there
+///   was one
+///
+/// # Arguments
+///
+/// * `module` - The module to register the functions in
+/// * `services` - Where sweeps are stored
+/// * `handler_addr` - The uri of the handler the functions are for
+pub fn register(module: &mut Module, services: &Services, handler_addr: &str) {
+    let sweeps = services.sweeps.clone();
+    let addr = handler_addr.to_string();
+    let github_stale_sweep = move |repo: ImmutableString,
+                                   days: INT,
+                                   label: ImmutableString,
+                                   action: ImmutableString|
+          -> Result<(), Box<EvalAltResult>> {
+        if repo.split('/').count() != 2 {
+            return Err(format!("Expected <owner>/<repo>, not {}", repo).into());
+        }
+        if days <= 0 {
+            return Err("A sweep needs a positive number of days".into());
+        }
+        let action = match action.as_str() {
+            "notify" => SweepAction::Notify,
+            "label" => SweepAction::Label,
+            "close" => SweepAction::Close,
+            other => {
+                let cause = format!("Unknown action {}, expected notify, label or close", other);
+                return Err(cause.into());
+            }
+        };
+
+        let sweep = Sweep {
+            handler: addr.clone(),
+            repo: repo.to_string(),
+            days: days as u64,
+            label: label.to_string(),
+            action,
+            last_run_at: 0,
+        };
+        log_event!("stale.sweep", handler = addr, repo = repo, days = days);
+
+        sweeps.update(|map| {
+            let key = Sweep::key(&addr, &repo);
+            let last_run_at = map.get(&key).map(|s| s.last_run_at).unwrap_or(0);
+            map.insert(
+                key,
+                Sweep {
+                    last_run_at,
+                    ..sweep
+                },
+            );
+        });
+        Ok(())
+    };
+
+    let sweeps = services.sweeps.clone();
+    let addr = handler_addr.to_string();
+    let github_stale_sweep_cancel = move |repo: ImmutableString| {
+        let removed = sweeps.update(|map| map.remove(&Sweep::key(&addr, &repo)).is_some());
+        Ok(removed)
+    };
+
+    module.set_fn_4("github_stale_sweep", github_stale_sweep);
+    module.set_fn_1("github_stale_sweep_cancel", github_stale_sweep_cancel);
+}
+
+/// Run every sweep which is due. Run by the scheduler
+///
+/// Sweeps of handlers which no longer exist are dropped.
+///
+/// # Arguments
+///
+/// * `env` - Environment variables
+/// * `services` - Where sweeps are stored
+/// * `handlers` - The handlers to pass stale issues to
+/// * `now` - The current unix timestamp
+pub fn tick(
+    env: &EnvInfo,
+    services: &Services,
+    handlers: &RwLock<HashMap<String, Handler>>,
+    now: u64,
+) {
+    let is_due = |s: &Sweep| now.saturating_sub(s.last_run_at) >= SWEEP_INTERVAL_SECS;
+
+    // Most ticks have nothing to do, and shouldn't save the sweeps for nothing
+    let changed = {
+        let guard = handlers.read().unwrap();
+        services
+            .sweeps
+            .read()
+            .values()
+            .any(|s| is_due(s) || !guard.contains_key(&s.handler))
+    };
+    if !changed {
+        return;
+    }
+
+    let due = services.sweeps.update(|map| {
+        let guard = handlers.read().unwrap();
+        map.retain(|_, s| guard.contains_key(&s.handler));
+        map.values_mut()
+            .filter(|s| is_due(s))
+            .map(|s| {
+                s.last_run_at = now;
+                s.clone()
+            })
+            .collect::<Vec<Sweep>>()
+    });
+
+    for sweep in due {
+        let id = CorrelationId::generate();
+        let issues = match search_issues(&services.http, &env.github_token, &sweep.query(now)) {
+            Ok(issues) => issues,
+            Err(e) => {
+                log_event!(
+                    "stale.search_error",
+                    id = id.0,
+                    handler = sweep.handler,
+                    repo = sweep.repo,
+                    error = e
+                );
+                continue;
+            }
+        };
+        log_event!(
+            "stale.run",
+            id = id.0,
+            handler = sweep.handler,
+            repo = sweep.repo,
+            issues = issues.len()
+        );
+
+        for issue in &issues {
+            let number = issue["number"].as_i64().unwrap_or_default() as INT;
+            let acted = match sweep.action {
+                SweepAction::Notify => Ok(()),
+                SweepAction::Label => add_labels(
+                    &services.http,
+                    &env.github_token,
+                    &sweep.repo,
+                    number,
+                    &[STALE_LABEL.to_string()],
+                ),
+                SweepAction::Close => {
+                    close_issue(&services.http, &env.github_token, &sweep.repo, number)
+                }
+            };
+            if let Err(e) = acted {
+                log_event!(
+                    "stale.action_error",
+                    id = id.0,
+                    repo = sweep.repo,
+                    issue = number,
+                    error = e
+                );
+            }
+
+            let guard = handlers.read().unwrap();
+            let handler = match guard.get(&sweep.handler) {
+                Some(handler) if handler.defines("on_stale", 1) => handler,
+                _ => continue,
+            };
+
+            let args = (issue_map(issue),);
+            let ast = &handler.code.ast;
+            let result = run_function(
+                env,
+                services,
+                &id,
+                &sweep.handler,
+                handler,
+                None,
+                "on_stale",
+                |engine| engine.call_fn(&mut Scope::new(), ast, "on_stale", args),
+            );
+            if let Err(e) = result {
+                log_event!(
+                    "handler.error",
+                    id = id.0,
+                    handler = sweep.handler,
+                    error = e
+                );
+            }
+        }
+    }
+}
@@ -0,0 +1,160 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+use rocket::State;
+
+use rocket_contrib::json::Json;
+
+use serde::{Deserialize, Serialize};
+
+use crate::auth::{check_auth, check_scope, hash_key, AuthHeader};
+use crate::clock::unix_now;
+use crate::server::Collection;
+use crate::services::Services;
+use crate::storage::JsonStore;
+use crate::types::{APIKeyRequest, ApiKeyInfo, Handler, UserResponse, READ_SCOPE};
+
+/// How often the stats are saved, at most, in seconds
+const SAVE_INTERVAL_SECS: u64 = 60;
+
+/// How a handler has been doing
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct HandlerStats {
+    /// How many times the handler has run
+    pub invocations: u64,
+    /// How many of those runs failed
+    pub errors: u64,
+    /// When the handler last ran, as a unix timestamp
+    pub last_invoked_at: u64,
+    /// How long all of the runs took together, in milliseconds
+    pub total_duration_ms: u64,
+}
+
+/// The stats of every handler
+///
+/// Handlers run far too often to save after every run, so changes are only kept in memory, and
+/// saved by the scheduler every `SAVE_INTERVAL_SECS`. Replicas don't run the scheduler, so
+/// their stats are never saved, and only cover what they served since they started.
+pub struct Stats {
+    store: JsonStore<HandlerStats>,
+    /// Whether anything was recorded since the stats were last saved
+    dirty: AtomicBool,
+    /// When the stats were last saved, as a unix timestamp
+    saved_at: AtomicU64,
+}
+
+impl Stats {
+    /// Open the stats saved at `path`, or empty ones if there is nothing there yet
+    pub fn open(path: String) -> Stats {
+        Stats {
+            store: JsonStore::open(path),
+            dirty: AtomicBool::new(false),
+            saved_at: AtomicU64::new(unix_now()),
+        }
+    }
+
+    /// Count a run of a handler
+    ///
+    /// # Arguments
+    ///
+    /// * `handler` - The uri of the handler
+    /// * `duration_ms` - How long the run took, in milliseconds
+    /// * `ok` - Whether the run succeeded
+    pub fn record(&self, handler: &str, duration_ms: u64, ok: bool) {
+        self.store.update_unsaved(|map| {
+            let stats = map.entry(handler.to_string()).or_default();
+            stats.invocations += 1;
+            if !ok {
+                stats.errors += 1;
+            }
+            stats.last_invoked_at = unix_now();
+            stats.total_duration_ms += duration_ms;
+        });
+        self.dirty.store(true, Ordering::Relaxed);
+    }
+
+    /// The stats of a handler, all zero if it has never run
+    pub fn get(&self, handler: &str) -> HandlerStats {
+        self.store.read().get(handler).cloned().unwrap_or_default()
+    }
+}
+
+/// Save the stats, if they changed and haven't been saved in a while
+///
+/// # Arguments
+///
+/// * `services` - Where the stats are kept
+/// * `now` - The current unix timestamp
+pub fn tick(services: &Services, now: u64) {
+    let stats = &services.stats;
+    if now.saturating_sub(stats.saved_at.load(Ordering::Relaxed)) < SAVE_INTERVAL_SECS {
+        return;
+    }
+
+    stats.saved_at.store(now, Ordering::Relaxed);
+    if stats.dirty.swap(false, Ordering::Relaxed) {
+        stats.store.save();
+    }
+}
+
+/// The stats of a handler, as returned by `/handler_stats`
+#[derive(Debug, Serialize)]
+struct StatsResponse {
+    invocations: u64,
+    errors: u64,
+    last_invoked_at: u64,
+    avg_duration_ms: u64,
+}
+
+/// Rocket Endpoint which returns the stats of every handler owned by the API Key, by uri
+///
+/// Each has the number of `invocations`, how many of them were `errors`, when it was
+/// `last_invoked_at` as a unix timestamp (0 if never) and the `avg_duration_ms` of a run.
+///
+/// # Arguments
+///
+/// * `auth` - The API Key from the `Authorization` header, if any
+/// * `services` - Where the stats are kept
+/// * `api_keys` - A reference to the collection of Client API keys, used to check for auth
+/// * `handlers` - A reference to the collection of User created handlers, indexed by their uris
+/// * `post_data` - The API Key, unless it is in the header
+#[post("/handler_stats", data = "<post_data>")]
+pub fn handler_stats(
+    auth: AuthHeader,
+    services: State<Services>,
+    api_keys: Collection<String, ApiKeyInfo>,
+    handlers: Collection<String, Handler>,
+    post_data: Option<Json<APIKeyRequest>>,
+) -> Json<UserResponse> {
+    let key = auth.key_or(&post_data.map(|d| d.0.api_key).unwrap_or_default());
+
+    if let Err(cause) = auth.verify(&key, check_auth(&key, &api_keys), "Invalid API Key") {
+        return Json(UserResponse::from(cause));
+    }
+    if let Err(cause) = check_scope(&key, &api_keys, READ_SCOPE) {
+        return Json(UserResponse::from(cause));
+    }
+
+    let recorded = services.stats.store.read();
+    let stats = handlers
+        .read()
+        .unwrap()
+        .values()
+        .filter(|h| h.owned_by(&hash_key(&key)))
+        .map(|h| {
+            let s = recorded.get(&h.uri).cloned().unwrap_or_default();
+            let response = StatsResponse {
+                invocations: s.invocations,
+                errors: s.errors,
+                last_invoked_at: s.last_invoked_at,
+                avg_duration_ms: s.total_duration_ms.checked_div(s.invocations).unwrap_or(0),
+            };
+            (h.uri.clone(), response)
+        })
+        .collect::<HashMap<String, StatsResponse>>();
+
+    Json(
+        UserResponse::success_with_raw(stats)
+            .unwrap_or_else(|| UserResponse::failure("Unable to list stats".into())),
+    )
+}
@@ -0,0 +1,124 @@
+use rocket::http::ContentType;
+use rocket::response::content::Content;
+use rocket::State;
+
+use crate::clock::utc_datetime;
+use crate::handler_logs::MAX_ENTRIES;
+use crate::server::Collection;
+use crate::services::Services;
+use crate::twilio::escape_xml;
+use crate::types::Handler;
+
+/// How one handler has been doing, as the status page shows it
+struct HandlerStatus {
+    uri: String,
+    description: Option<String>,
+    /// How many of the runs in the handler's log there are, and how many succeeded
+    recent_runs: usize,
+    recent_successes: usize,
+    /// Whether the most recent run in the log succeeded, if there is one
+    last_ok: Option<bool>,
+    /// When the handler last ran, as a unix timestamp. 0 if never
+    last_invoked_at: u64,
+}
+
+impl HandlerStatus {
+    /// Work out how a handler has been doing, from its log and its stats
+    fn of(services: &Services, handler: &Handler) -> HandlerStatus {
+        let runs = services
+            .logs
+            .recent(&handler.uri, MAX_ENTRIES)
+            .into_iter()
+            .filter(|entry| entry.kind == "invocation" || entry.kind == "error")
+            .map(|entry| entry.kind == "invocation")
+            .collect::<Vec<bool>>();
+        HandlerStatus {
+            uri: handler.uri.clone(),
+            description: handler.description.clone(),
+            recent_runs: runs.len(),
+            recent_successes: runs.iter().filter(|ok| **ok).count(),
+            last_ok: runs.last().copied(),
+            last_invoked_at: services.stats.get(&handler.uri).last_invoked_at,
+        }
+    }
+
+    /// The row of the status page's table
+    fn row(&self) -> String {
+        let health = match self.last_ok {
+            Some(true) => "ok",
+            Some(false) => "failing",
+            None => "unknown",
+        };
+        let success_rate = match self.recent_runs {
+            0 => "-".to_string(),
+            runs => format!(
+                "{}% of the last {} runs",
+                self.recent_successes * 100 / runs,
+                runs
+            ),
+        };
+        let last_run = match self.last_invoked_at {
+            0 => "never".to_string(),
+            at => utc_datetime(at),
+        };
+        format!(
+            "<tr class=\"{}\"><td><b>{}</b><br>{}</td><td>{}</td><td>{}</td><td>{}</td></tr>\n",
+            health,
+            escape_xml(&self.uri),
+            escape_xml(self.description.as_deref().unwrap_or_default()),
+            health,
+            success_rate,
+            last_run
+        )
+    }
+}
+
+/// Render the status page of some handlers
+fn render(statuses: &[HandlerStatus]) -> String {
+    let mut html = String::from(
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>Status</title>\n\
+         <style>body { font-family: sans-serif; } td, th { padding: 4px 12px; text-align: left; } \
+         .ok td:nth-child(2) { color: green; } .failing td:nth-child(2) { color: red; }</style>\n\
+         </head>\n<body>\n<h1>Status</h1>\n",
+    );
+    if statuses.is_empty() {
+        html.push_str("<p>No handlers are on this page yet.</p>\n");
+    } else {
+        html.push_str(
+            "<table>\n<tr><th>Handler</th><th>Status</th><th>Success rate</th><th>Last run</th></tr>\n",
+        );
+        for status in statuses {
+            html.push_str(&status.row());
+        }
+        html.push_str("</table>\n");
+    }
+    html.push_str("</body>\n</html>\n");
+    html
+}
+
+/// Rocket Endpoint which serves a public page of how the handlers which opted in have been
+/// doing, e.g. so people can see the nightly report ran without asking its owner
+///
+/// Handlers opt in with `public_status`. Each is shown with its description, whether its last
+/// run succeeded, its success rate over the runs in its log, and when it last ran, including
+/// the runs of its scheduled work. Nothing a run returned or failed with is shown. Logs are kept
+/// in memory, so success rates start over when the server restarts.
+///
+/// # Arguments
+///
+/// * `services` - Where the logs and stats of handlers are kept
+/// * `handlers` - A reference to the collection of User created handlers, indexed by their uris
+#[get("/status")]
+pub fn status_page(
+    services: State<Services>,
+    handlers: Collection<String, Handler>,
+) -> Content<String> {
+    let guard = handlers.read().unwrap();
+    let mut statuses = guard
+        .values()
+        .filter(|h| h.public_status)
+        .map(|h| HandlerStatus::of(&services, h))
+        .collect::<Vec<HandlerStatus>>();
+    statuses.sort_by(|a, b| a.uri.cmp(&b.uri));
+    Content(ContentType::HTML, render(&statuses))
+}
@@ -0,0 +1,491 @@
+use std::collections::HashMap;
+use std::fs;
+use std::fs::File;
+use std::io::Write;
+use std::iter::FromIterator;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock, RwLockReadGuard};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use rand::Rng;
+
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::{Data, Request, State};
+
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+use crate::auth::{hash_plain_keys, hash_plain_owners};
+use crate::types::{ApiKeyInfo, Handler, KeyFormat};
+
+/// Load the saved handlers from disk
+///
+/// # Arguments
+///
+/// * `path` - The file the handlers were saved to
+pub fn load_handlers(path: &str) -> Option<HashMap<String, Handler>> {
+    fs::read_to_string(Path::new(path))
+        .ok()
+        .map(|data| serde_json::from_str(&data).ok())
+        .flatten()
+}
+
+/// The formats api keys can be saved in
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum SavedApiKeys {
+    /// The original format: a plain list of keys
+    List(Vec<String>),
+    /// Keys along with what we know about them
+    Map(HashMap<String, ApiKeyInfo>),
+}
+
+/// Load the saved api keys from disk
+///
+/// # Arguments
+///
+/// * `path` - The file the api keys were saved to, either as a json list of keys, or as a json
+///            object mapping keys to their `ApiKeyInfo`
+pub fn load_api_keys(path: &str) -> Option<HashMap<String, ApiKeyInfo>> {
+    let saved: SavedApiKeys = fs::read_to_string(Path::new(path))
+        .ok()
+        .map(|data| serde_json::from_str(&data).ok())
+        .flatten()?;
+
+    match saved {
+        // Such lists predate hashing keys at rest
+        SavedApiKeys::List(keys) => Some(HashMap::from_iter(keys.into_iter().map(|k| {
+            let info = ApiKeyInfo {
+                key_format: KeyFormat::Plain,
+                ..ApiKeyInfo::default()
+            };
+            (k, info)
+        }))),
+        SavedApiKeys::Map(keys) => Some(keys),
+    }
+}
+
+/// Save a collection to the disk, as json
+///
+/// It is reasonable, if unfortunate, that we have to keep the mutex locked while doing this.
+/// This operation should not take too long, and in any case should occur only when a Client
+/// is updating code, which is not often compared to User requests. A several msec delay is
+/// acceptable occasionally.
+///
+/// The collection is written to a temporary file first, which then replaces the old file, so a
+/// failed or interrupted write never leaves a truncated file behind.
+///
+/// # Arguments
+///
+/// * `map` - the collection to save, e.g. the handlers or the api keys
+/// * `path` - the file path to save to.
+///            For testing purposes, if equal to "do-not-write", no write occurs.
+pub fn save_map<V: Serialize>(map: &HashMap<String, V>, path: &str) -> Result<(), std::io::Error> {
+    if path == "do-not-write" {
+        return Ok(());
+    }
+    let tmp_path = format!("{}.tmp", path);
+    let mut file = File::create(&tmp_path)?;
+    file.write_all(serde_json::to_string(map)?.as_ref())?;
+    file.sync_all()?;
+    fs::rename(&tmp_path, path)
+}
+
+/// The backups of a file, oldest first, with when they were taken, in milliseconds since the
+/// epoch. Backups are kept next to the file, as `<file>.<milliseconds>.bak`
+///
+/// # Arguments
+///
+/// * `path` - The file which was backed up
+fn backups(path: &str) -> Vec<(u128, PathBuf)> {
+    let path = Path::new(path);
+    let dir = path
+        .parent()
+        .filter(|d| !d.as_os_str().is_empty())
+        .unwrap_or_else(|| Path::new("."));
+    let prefix = match path.file_name() {
+        Some(name) => format!("{}.", name.to_string_lossy()),
+        None => return Vec::new(),
+    };
+
+    let mut backups = fs::read_dir(dir)
+        .map(|entries| {
+            entries
+                .filter_map(|e| e.ok())
+                .filter_map(|e| {
+                    let name = e.file_name().into_string().ok()?;
+                    let taken = name
+                        .strip_prefix(&prefix)?
+                        .strip_suffix(".bak")?
+                        .parse::<u128>()
+                        .ok()?;
+                    Some((taken, e.path()))
+                })
+                .collect::<Vec<(u128, PathBuf)>>()
+        })
+        .unwrap_or_default();
+    backups.sort();
+    backups
+}
+
+/// Copy a file to a new timestamped backup before it is replaced, and delete the oldest
+/// backups, so that only the most recent `keep` remain
+///
+/// # Arguments
+///
+/// * `path` - The file about to be replaced. Nothing is backed up if it doesn't exist yet
+/// * `keep` - How many backups to keep. 0 disables backups
+fn rotate_backups(path: &str, keep: usize) -> Result<(), std::io::Error> {
+    if keep == 0 || !Path::new(path).exists() {
+        return Ok(());
+    }
+
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis())
+        .unwrap_or(0);
+    fs::copy(path, format!("{}.{}.bak", path, now))?;
+
+    let backups = backups(path);
+    for (_, old) in backups.iter().take(backups.len().saturating_sub(keep)) {
+        fs::remove_file(old)?;
+    }
+    Ok(())
+}
+
+/// Restore a file from its most recent readable backup, if the file itself can't be read,
+/// e.g. because it was corrupted. The unreadable file is kept as `<file>.corrupt`
+///
+/// Meant to be run at startup, before anything is loaded.
+///
+/// # Arguments
+///
+/// * `path` - The file to check
+/// * `load` - Reads the file, or one of its backups. None if it can't
+pub fn restore_from_backup<V>(path: &str, load: fn(&str) -> Option<V>) {
+    if !Path::new(path).exists() || load(path).is_some() {
+        return;
+    }
+
+    let good = backups(path)
+        .into_iter()
+        .rev()
+        .map(|(_, backup)| backup.to_string_lossy().into_owned())
+        .find(|backup| load(backup).is_some());
+    let backup = match good {
+        Some(backup) => backup,
+        None => {
+            println!(
+                "Warning! {} is unreadable, and has no readable backup",
+                path
+            );
+            return;
+        }
+    };
+
+    let corrupt = format!("{}.corrupt", path);
+    match fs::rename(path, &corrupt).and_then(|_| fs::copy(&backup, path)) {
+        Ok(_) => println!(
+            "Warning! {} was unreadable, so it was restored from {}. It was kept as {}",
+            path, backup, corrupt
+        ),
+        Err(e) => println!("Warning! Unable to restore {} from {}: {}", path, backup, e),
+    }
+}
+
+/// Where handlers and api keys are persisted
+///
+/// Saves are told which entries changed, i.e. were added, updated or removed, so that backends
+/// which can do so only write those. Entries which changed, but are no longer in the
+/// collection, were removed.
+pub trait Backend: Send + Sync {
+    /// A short description of where data is kept, for logs
+    fn describe(&self) -> String;
+
+    fn load_handlers(&self) -> Option<HashMap<String, Handler>>;
+
+    fn load_api_keys(&self) -> Option<HashMap<String, ApiKeyInfo>>;
+
+    fn save_handlers(
+        &self,
+        handlers: &HashMap<String, Handler>,
+        changed: &[String],
+    ) -> Result<(), String>;
+
+    fn save_api_keys(
+        &self,
+        api_keys: &HashMap<String, ApiKeyInfo>,
+        changed: &[String],
+    ) -> Result<(), String>;
+
+    /// Something which changes whenever the handlers are saved, by any instance
+    /// Read-only replicas reload the handlers when it does
+    fn handlers_version(&self) -> Option<u64>;
+
+    /// Something which changes whenever the api keys are saved, by any instance
+    fn api_keys_version(&self) -> Option<u64>;
+}
+
+/// The backend used by the server
+pub type Storage = Box<dyn Backend>;
+
+/// The original backend: each collection is a json file, rewritten as a whole on every save
+pub struct JsonBackend {
+    pub handlers_path: String,
+    pub api_keys_path: String,
+    /// How many backups of each file to keep, taken before every save. 0 disables backups
+    pub backups: usize,
+}
+
+impl Backend for JsonBackend {
+    fn describe(&self) -> String {
+        format!("{} and {}", self.handlers_path, self.api_keys_path)
+    }
+
+    fn load_handlers(&self) -> Option<HashMap<String, Handler>> {
+        load_handlers(&self.handlers_path)
+    }
+
+    fn load_api_keys(&self) -> Option<HashMap<String, ApiKeyInfo>> {
+        load_api_keys(&self.api_keys_path)
+    }
+
+    fn save_handlers(
+        &self,
+        handlers: &HashMap<String, Handler>,
+        _: &[String],
+    ) -> Result<(), String> {
+        rotate_backups(&self.handlers_path, self.backups)
+            .and_then(|_| save_map(handlers, &self.handlers_path))
+            .map_err(|e| e.to_string())
+    }
+
+    fn save_api_keys(
+        &self,
+        api_keys: &HashMap<String, ApiKeyInfo>,
+        _: &[String],
+    ) -> Result<(), String> {
+        rotate_backups(&self.api_keys_path, self.backups)
+            .and_then(|_| save_map(api_keys, &self.api_keys_path))
+            .map_err(|e| e.to_string())
+    }
+
+    fn handlers_version(&self) -> Option<u64> {
+        modified(&self.handlers_path)
+    }
+
+    fn api_keys_version(&self) -> Option<u64> {
+        modified(&self.api_keys_path)
+    }
+}
+
+/// Generate a new random id for a stored record, e.g. an approval
+pub fn new_id() -> String {
+    format!("{:016x}", rand::thread_rng().gen::<u64>())
+}
+
+/// A collection which is persisted to a json file after every change
+///
+/// This is what the smaller subsystems (approvals, reminders, ...) keep their state in. Like
+/// `save_map`, the whole collection is written out on each change, which is fine for the
+/// amount of data these hold.
+pub struct JsonStore<V> {
+    path: String,
+    map: RwLock<HashMap<String, V>>,
+}
+
+impl<V: Serialize + DeserializeOwned> JsonStore<V> {
+    /// Open the store saved at `path`, or an empty one if there is nothing there yet
+    pub fn open(path: String) -> JsonStore<V> {
+        let map = fs::read_to_string(Path::new(&path))
+            .ok()
+            .map(|data| serde_json::from_str(&data).ok())
+            .flatten()
+            .unwrap_or_else(|| {
+                if Path::new(&path).exists() {
+                    println!("Warning! Unable to load {}, starting from scratch!", path);
+                }
+                HashMap::new()
+            });
+
+        JsonStore {
+            path,
+            map: RwLock::new(map),
+        }
+    }
+
+    /// Read access to the collection
+    pub fn read(&self) -> RwLockReadGuard<HashMap<String, V>> {
+        self.map.read().unwrap()
+    }
+
+    /// Change the collection, then save it
+    /// The change is kept in memory even if saving fails
+    ///
+    /// # Arguments
+    ///
+    /// * `f` - The change to make. Its result is passed through
+    pub fn update<T, F: FnOnce(&mut HashMap<String, V>) -> T>(&self, f: F) -> T {
+        let mut map = self.map.write().unwrap();
+        let result = f(&mut map);
+        if let Err(e) = save_map(&map, &self.path) {
+            log_event!("store.save_error", path = self.path, error = e);
+        }
+        result
+    }
+
+    /// Change the collection without saving it, for changes too frequent to save every time.
+    /// Call `save` later on
+    ///
+    /// # Arguments
+    ///
+    /// * `f` - The change to make. Its result is passed through
+    pub fn update_unsaved<T, F: FnOnce(&mut HashMap<String, V>) -> T>(&self, f: F) -> T {
+        f(&mut self.map.write().unwrap())
+    }
+
+    /// Save the collection as it is
+    pub fn save(&self) {
+        let map = self.map.read().unwrap();
+        if let Err(e) = save_map(&map, &self.path) {
+            log_event!("store.save_error", path = self.path, error = e);
+        }
+    }
+}
+
+/// When a file was last modified, in nanoseconds since the epoch, if that can be determined
+fn modified(path: &str) -> Option<u64> {
+    fs::metadata(path)
+        .ok()
+        .map(|m| m.modified().ok())
+        .flatten()
+        .map(|t| t.duration_since(UNIX_EPOCH).ok())
+        .flatten()
+        .map(|d| d.as_nanos() as u64)
+}
+
+/// The refresh bookkeeping of a `ReplicaRefresher`
+struct RefreshState {
+    /// When we last checked the backend
+    checked: Instant,
+    /// The versions of the handlers and api keys when we last loaded them
+    handlers_version: Option<u64>,
+    api_keys_version: Option<u64>,
+}
+
+/// Rocket Fairing which keeps a read-only replica in sync with the shared storage
+///
+/// At most once per `interval`, before serving a request, the storage backend is checked, and
+/// the handlers and api keys are reloaded if they have been saved since they were last read.
+/// The primary instance is the only one writing to the storage.
+pub struct ReplicaRefresher {
+    interval: Duration,
+    state: Mutex<RefreshState>,
+}
+
+impl ReplicaRefresher {
+    pub fn new(storage: &Storage, interval: Duration) -> Self {
+        let state = RefreshState {
+            checked: Instant::now(),
+            handlers_version: storage.handlers_version(),
+            api_keys_version: storage.api_keys_version(),
+        };
+
+        ReplicaRefresher {
+            interval,
+            state: Mutex::new(state),
+        }
+    }
+}
+
+impl Fairing for ReplicaRefresher {
+    fn info(&self) -> Info {
+        Info {
+            name: "Replica Refresher",
+            kind: Kind::Request,
+        }
+    }
+
+    fn on_request(&self, request: &mut Request, _: &Data) {
+        let mut state = self.state.lock().unwrap();
+        if state.checked.elapsed() < self.interval {
+            return;
+        }
+        state.checked = Instant::now();
+
+        let storage = match request.guard::<State<Storage>>().succeeded() {
+            Some(storage) => storage,
+            None => return,
+        };
+
+        let handlers_version = storage.handlers_version();
+        if handlers_version != state.handlers_version {
+            let handlers = request.guard::<State<Arc<RwLock<HashMap<String, Handler>>>>>();
+            if let Some(count) = handlers
+                .succeeded()
+                .map(|h| reload_handlers(&storage, &h))
+                .flatten()
+            {
+                log_event!(
+                    "replica.reload",
+                    storage = storage.describe(),
+                    handlers = count
+                );
+                state.handlers_version = handlers_version;
+            }
+        }
+
+        let api_keys_version = storage.api_keys_version();
+        if api_keys_version != state.api_keys_version {
+            let api_keys = request.guard::<State<Arc<RwLock<HashMap<String, ApiKeyInfo>>>>>();
+            if let Some(count) = api_keys
+                .succeeded()
+                .map(|k| reload_api_keys(&storage, &k))
+                .flatten()
+            {
+                log_event!(
+                    "replica.reload",
+                    storage = storage.describe(),
+                    api_keys = count
+                );
+                state.api_keys_version = api_keys_version;
+            }
+        }
+    }
+}
+
+/// Replace the handlers in memory with those saved, e.g. after they were edited by hand.
+/// Returns how many there are, or None if they can't be loaded, e.g. because one of them
+/// doesn't compile, in which case those in memory are kept
+///
+/// # Arguments
+///
+/// * `storage` - Where the handlers are saved
+/// * `handlers` - The handlers in memory
+pub fn reload_handlers(
+    storage: &Storage,
+    handlers: &RwLock<HashMap<String, Handler>>,
+) -> Option<usize> {
+    let mut loaded = storage.load_handlers()?;
+    hash_plain_owners(&mut loaded);
+    let count = loaded.len();
+    *handlers.write().unwrap() = loaded;
+    Some(count)
+}
+
+/// Replace the api keys in memory with those saved, like `reload_handlers`
+///
+/// # Arguments
+///
+/// * `storage` - Where the api keys are saved
+/// * `api_keys` - The api keys in memory
+pub fn reload_api_keys(
+    storage: &Storage,
+    api_keys: &RwLock<HashMap<String, ApiKeyInfo>>,
+) -> Option<usize> {
+    let mut loaded = storage.load_api_keys()?;
+    hash_plain_keys(&mut loaded);
+    let count = loaded.len();
+    *api_keys.write().unwrap() = loaded;
+    Some(count)
+}
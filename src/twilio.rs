@@ -0,0 +1,191 @@
+use std::ops::Deref;
+
+use rhai::{Array, Dynamic, Map};
+
+use rocket::response::content::Xml;
+use rocket::State;
+
+use serde_json::Value;
+
+use crate::auth::TwilioBody;
+use crate::envelope::{self, Envelope};
+use crate::logging::CorrelationId;
+use crate::server::{run_handler, Collection};
+use crate::services::Services;
+use crate::types::{EnvInfo, Handler};
+
+/// The declaration every TwiML document starts with
+const XML_DECLARATION: &str = "<?xml version=\"1.0\" encoding=\"UTF-8\"?>";
+
+/// The uri of the handler the messages and calls to a phone number go to, e.g.
+/// `twilio-15551234567` for `+1 555 123 4567`
+///
+/// # Arguments
+///
+/// * `number` - The phone number, as Twilio sends it, e.g. `+15551234567`
+pub fn handler_uri(number: &str) -> String {
+    let digits = number
+        .chars()
+        .filter(char::is_ascii_digit)
+        .collect::<String>();
+    format!("twilio-{}", digits)
+}
+
+/// The value of a parameter Twilio posted, or an empty string
+fn param<'a>(params: &'a [(String, String)], name: &str) -> &'a str {
+    params
+        .iter()
+        .find(|(key, _)| key == name)
+        .map(|(_, value)| value.as_str())
+        .unwrap_or_default()
+}
+
+/// Whether a webhook is about a call, rather than a message
+fn is_call(params: &[(String, String)]) -> bool {
+    !param(params, "CallSid").is_empty() && param(params, "MessageSid").is_empty()
+}
+
+/// Describe a message or call for handlers
+///
+/// The map has the `kind`, `sms` or `voice`, the `sid` of the message or call, who it is
+/// `from` and `to`, and its `body`. For calls, the body is what the caller said, or else the
+/// digits they pressed, which are also in `speech_result` and `digits`, along with the
+/// `call_status`. For messages, `media` lists the urls of any pictures and such attached.
+///
+/// # Arguments
+///
+/// * `params` - The parameters Twilio posted
+fn context(params: &[(String, String)]) -> Map {
+    let text = |name: &str| Dynamic::from(param(params, name).to_string());
+
+    let mut map = Map::new();
+    map.insert("from".into(), text("From"));
+    map.insert("to".into(), text("To"));
+    if is_call(params) {
+        let speech = param(params, "SpeechResult");
+        let body = if speech.is_empty() {
+            param(params, "Digits")
+        } else {
+            speech
+        };
+        map.insert("kind".into(), Dynamic::from("voice".to_string()));
+        map.insert("sid".into(), text("CallSid"));
+        map.insert("body".into(), Dynamic::from(body.to_string()));
+        map.insert("speech_result".into(), text("SpeechResult"));
+        map.insert("digits".into(), text("Digits"));
+        map.insert("call_status".into(), text("CallStatus"));
+    } else {
+        let media_count = param(params, "NumMedia").parse::<usize>().unwrap_or(0);
+        let media = (0..media_count)
+            .map(|i| text(&format!("MediaUrl{}", i)))
+            .collect::<Array>();
+        map.insert("kind".into(), Dynamic::from("sms".to_string()));
+        map.insert("sid".into(), text("MessageSid"));
+        map.insert("body".into(), text("Body"));
+        map.insert("media".into(), Dynamic::from(media));
+    }
+    map
+}
+
+/// Escape text for use in an XML document
+pub fn escape_xml(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
+
+/// Turn what a handler returned into a TwiML response
+///
+/// Handlers which want to do more than reply can return their own TwiML, i.e. anything starting
+/// with `<`, which is passed on as is. Anything else is sent back as a message, or said to the
+/// caller. Nothing is sent back if the handler returned nothing.
+///
+/// # Arguments
+///
+/// * `call` - Whether Twilio asked about a call, rather than a message
+/// * `reply` - What the handler returned
+fn twiml(call: bool, reply: &str) -> String {
+    let reply = reply.trim();
+    if reply.starts_with('<') {
+        return reply.to_string();
+    }
+    if reply.is_empty() {
+        return format!("{}<Response/>", XML_DECLARATION);
+    }
+
+    let verb = if call { "Say" } else { "Message" };
+    format!(
+        "{}<Response><{}>{}</{}></Response>",
+        XML_DECLARATION,
+        verb,
+        escape_xml(reply),
+        verb
+    )
+}
+
+/// Rocket Endpoint which receives Twilio webhooks for incoming messages and calls, and passes
+/// them on to handlers
+///
+/// Both are routed by the number they were sent to, e.g. to `twilio-15551234567` for
+/// `+15551234567`, which is called as `handle(body)` with the text of the message, or what the
+/// caller said, or as `handle(body, message)` with a map describing it, see `context`, if it
+/// defines that. The response is TwiML, see `twiml`. Messages and calls nobody handles get an
+/// empty response.
+///
+/// # Arguments
+///
+/// * `id` - The correlation id of the request, attached to every log line
+/// * `env` - Environment variables
+/// * `services` - The stateful subsystems available to handlers
+/// * `handlers` - A reference to the collection of User created handlers, indexed by their uris
+/// * `body` - The parameters Twilio posted, once its signature has been checked
+#[post("/twilio", data = "<body>")]
+pub fn twilio_webhook(
+    id: CorrelationId,
+    env: State<EnvInfo>,
+    services: State<Services>,
+    handlers: Collection<String, Handler>,
+    body: TwilioBody,
+) -> Xml<String> {
+    let params = body.0;
+    let call = is_call(&params);
+    let addr = handler_uri(param(&params, "To"));
+    let context = context(&params);
+    let text = context
+        .get("body")
+        .map(|b| b.to_string())
+        .unwrap_or_default();
+
+    let raw = Value::Object(
+        params
+            .iter()
+            .map(|(k, v)| (k.clone(), Value::String(v.clone())))
+            .collect(),
+    );
+    let from = param(&params, "From");
+    let envelope = Envelope::sms(call, from, &text, raw);
+
+    let guard = handlers.read().unwrap();
+    let reply = match guard.deref().get(&addr) {
+        Some(handler) => {
+            match run_handler(&env, &services, &id, &addr, handler, text, Some(context)) {
+                Ok(reply) => reply,
+                Err(e) => {
+                    log_event!("twilio.handler_error", id = id.0, handler = addr, error = e);
+                    String::new()
+                }
+            }
+        }
+        None => {
+            log_event!("twilio.unhandled", id = id.0, handler = addr);
+            String::new()
+        }
+    };
+    drop(guard);
+
+    // Subscribers hear about the message once its own handler has run
+    envelope::fan_in(&env, &services, &id, envelope);
+    Xml(twiml(call, &reply))
+}
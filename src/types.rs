@@ -1,12 +1,43 @@
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap};
 use std::fmt;
 use std::fmt::Debug;
+use std::time::Duration;
 
 use serde::export::Formatter;
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
 
 use rhai::{Engine, ParseError, AST};
 
+use crate::clock::unix_now;
+use crate::envelope::Subscription;
+use crate::flags::Rules;
+use crate::relay::Relay;
+use crate::windows::ActiveWindow;
+
+/// How many previous revisions of its code a handler keeps
+pub const MAX_HISTORY: usize = 10;
+
+/// How many operations a handler may take per run, unless it asks for something else
+pub const DEFAULT_MAX_OPERATIONS: u64 = 1000;
+
+/// How long a handler may run for, in milliseconds, unless it asks for something else
+pub const DEFAULT_TIMEOUT_MS: u64 = 5000;
+
+/// The scope which lets a key call handlers
+pub const INVOKE_SCOPE: &str = "invoke";
+
+/// The scope which lets a key look at its handlers, e.g. their code, history and logs
+pub const READ_SCOPE: &str = "read";
+
+/// The scope which lets a key create and change handlers. Implies `READ_SCOPE`
+pub const WRITE_SCOPE: &str = "write";
+
+/// Every scope a key may be given
+pub const SCOPES: [&str; 3] = [INVOKE_SCOPE, READ_SCOPE, WRITE_SCOPE];
+
 /// A wrapper type which contains immutable state information for the server
+#[derive(Clone)]
 pub struct EnvInfo {
     /// The slack token for Majordomo
     pub slack_token: String,
@@ -14,9 +45,160 @@ pub struct EnvInfo {
     pub github_token: String,
     /// The filepath to save the handlers to
     pub handlers_path: String,
+    /// The filepath the api keys are loaded from
+    pub api_keys_path: String,
+    /// The directory the state of the smaller subsystems (approvals, ...) is saved in
+    pub data_dir: String,
+    /// The key which authorizes administrative operations, such as syncing. None disables them
+    pub admin_key: Option<String>,
+    /// The port to start the server on
+    pub port: u16,
+    /// The fraction of successful requests to log, between 0 and 1
+    pub log_sample_rate: f64,
+    /// Whether this instance is a read-only replica. Replicas reject management writes
+    pub read_only: bool,
+    /// How often a read-only replica reloads handlers and api keys from disk, in seconds
+    pub replica_refresh_secs: u64,
+    /// The `Cache-Control` header sent along with static assets, such as the frontend
+    pub static_cache_control: String,
+    /// The Slack channel alerts are posted to, e.g. on brute-force attempts. None disables this
+    pub alert_channel: Option<String>,
+    /// The Slack channel where changes to protected handlers are posted to be approved. None
+    /// means they can only be decided with `/decide_change`
+    pub change_channel: Option<String>,
+    /// The uri of the handler invoked when no handler matches a `/h/...` request, if any
+    /// It is called as `handle(addr, payload)`, with the address that was attempted
+    pub default_handler: Option<String>,
+    /// The domains handlers may make http requests to, lowercased. Subdomains are allowed too
+    pub http_allowlist: Vec<String>,
+    /// Files attached to Slack messages up to this size, in bytes, are downloaded and passed to
+    /// handlers, if they are text. 0 disables downloads
+    pub slack_download_max_bytes: u64,
+    /// The largest file handlers may upload to Slack, in bytes. 0 disables uploads
+    pub slack_upload_max_bytes: u64,
+    /// The signing secret of the Slack app, used to check that events really come from Slack.
+    /// None disables the check
+    pub slack_signing_secret: Option<String>,
+    /// The name of the slash command Slack sends to `/slack_command`, without its `/`, e.g.
+    /// `majordomo` for `/majordomo deploy`
+    pub slack_command: String,
+    /// The secret GitHub webhooks are signed with. None disables `/github_webhook`
+    pub github_webhook_secret: Option<String>,
+    /// The port handlers can be called over gRPC on, see `grpc::Invoker`. None disables gRPC
+    pub grpc_port: Option<u16>,
+    /// The most operations any handler may ask to take per run
+    pub max_operations: u64,
+    /// The longest any handler may ask to run for, in milliseconds
+    pub max_timeout_ms: u64,
+    /// The handlers GitHub events are fanned out to by the paths they change
+    pub github_path_routes: Vec<PathRoute>,
+    /// The auth token of the Twilio account, used to check its webhooks. None disables `/twilio`
+    pub twilio_auth_token: Option<String>,
+    /// The base url Twilio reaches us at, if it differs from what the `Host` header says
+    pub twilio_webhook_url: Option<String>,
+    /// How many requests per minute each Client API key may make, unless it sets its own
+    /// limit. 0 means there is none
+    pub key_rate_limit: u64,
+    /// How many times per minute each handler may be called, unless it has its own limit in
+    /// `handler_rate_limits`. 0 means there is none
+    pub handler_rate_limit: u64,
+    /// Per handler limits, in calls per minute, by the uri of the handler
+    pub handler_rate_limits: HashMap<String, u64>,
+    /// What the secrets of Clients are encrypted with at rest. None disables secrets
+    pub secrets_key: Option<String>,
+    /// Where the metrics of handlers are pushed, as `<host>:<port>`. None disables metrics
+    pub metrics_addr: Option<String>,
+    /// Whether metrics are pushed to StatsD or InfluxDB
+    pub metrics_format: MetricsFormat,
+    /// The Kubernetes cluster handlers may use. None disables the `k8s_` functions
+    pub k8s: Option<K8sConfig>,
+    /// The commands handlers may run on other hosts over ssh, indexed by their names
+    pub remote_commands: HashMap<String, RemoteCommand>,
+    /// The uris of the handlers whose events are archived, or `*` for all. Empty disables the
+    /// archive
+    pub archive_handlers: Vec<String>,
+    /// Where archived events are kept
+    pub archive_dir: String,
+    /// How many days a handler must go without running, or being saved, before its owner is told
+    /// it looks dead. 0 disables telling them
+    pub dead_handler_days: u64,
+}
+
+/// The wire format metrics are pushed in
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MetricsFormat {
+    /// StatsD gauges, with DogStatsD style tags, e.g. `deploys:1|g|#env:prod`
+    Statsd,
+    /// InfluxDB line protocol, e.g. `deploys,env=prod value=1`
+    Influx,
+}
+
+impl MetricsFormat {
+    /// Read a format as configured, `statsd` or `influx`
+    pub fn parse(format: &str) -> Option<MetricsFormat> {
+        match format.to_lowercase().as_str() {
+            "statsd" => Some(MetricsFormat::Statsd),
+            "influx" | "influxdb" => Some(MetricsFormat::Influx),
+            _ => None,
+        }
+    }
+}
+
+/// Where the Kubernetes cluster handlers may run chat-ops against is, and what they may do
+#[derive(Debug, Clone)]
+pub struct K8sConfig {
+    /// The base url of the API server, e.g. `https://10.0.0.1:443`
+    pub api_url: String,
+    /// The token to authenticate with. Read from `token_path` on every call, if None
+    pub token: Option<String>,
+    /// Where the service account token is mounted
+    pub token_path: String,
+    /// The certificate authority of the API server, as a PEM file, if it isn't publicly trusted
+    pub ca_cert_path: Option<String>,
+    /// The only namespace handlers may act in
+    pub namespace: String,
+    /// What handlers may do, any of `k8s::SCALE_VERB`, `k8s::RESTART_VERB` and
+    /// `k8s::GET_PODS_VERB`
+    pub verbs: Vec<String>,
+}
+
+/// A command the operator allows handlers to run on another host over ssh, see `remote_run`
+#[derive(Debug, Clone, Deserialize)]
+pub struct RemoteCommand {
+    /// The host to connect to
+    pub host: String,
+    /// The user to log in as
+    pub user: String,
+    /// The port ssh listens on, 22 if None
+    #[serde(default)]
+    pub port: Option<u16>,
+    /// The private key to log in with. ssh's defaults, if None
+    #[serde(default)]
+    pub identity_file: Option<String>,
+    /// The command to run, e.g. `sudo systemctl restart {}`. Each `{}` is filled in with an
+    /// argument from the handler, quoted
+    pub command: String,
+    /// How long the command may take, in seconds, up to `remote::MAX_TIMEOUT_SECS`
+    #[serde(default)]
+    pub timeout_secs: Option<u64>,
+    /// The uris of the handlers which may run the command. Any handler may, if empty
+    #[serde(default)]
+    pub handlers: Vec<String>,
+}
+
+/// Sends the GitHub events of a repository which change certain paths to a handler
+#[derive(Debug, Clone)]
+pub struct PathRoute {
+    /// The full name of the repository, `<owner>/<repo>`, lowercased
+    pub repo: String,
+    /// Which paths, as a CODEOWNERS style pattern, e.g. `services/foo/`
+    pub pattern: String,
+    /// The uri of the handler
+    pub handler: String,
 }
 
 /// A wrapper type which allows us to serialize and deserialize the AST
+#[derive(Clone)]
 pub struct ASTBox {
     pub ast: AST,
     pub raw: String,
@@ -28,36 +210,297 @@ impl Debug for ASTBox {
     }
 }
 
+/// The number of parameters of each function with the given name which the code defines
+///
+/// # Arguments
+///
+/// * `ast` - The compiled code
+/// * `name` - The name of the function
+pub fn arities(ast: &AST, name: &str) -> Vec<usize> {
+    let found = RefCell::new(Vec::new());
+    // rhai only shows the functions of an AST to the filter, so note them down and copy none
+    ast.clone_functions_only_filtered(|_, function, params| {
+        if function == name {
+            found.borrow_mut().push(params);
+        }
+        false
+    });
+    found.into_inner()
+}
+
+/// What we know about a Client's API Key
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ApiKeyInfo {
+    /// A human readable name for the key, e.g. "frontend team"
+    #[serde(default)]
+    pub label: Option<String>,
+    /// How to reach the owner of the key, as a Slack user id or an email address
+    #[serde(default)]
+    pub contact: Option<String>,
+    /// What the key is allowed to do, any of `SCOPES`. Keys without any scopes predate them, and
+    /// may do everything
+    #[serde(default)]
+    pub scopes: Vec<String>,
+    /// When the key stops working, as a unix timestamp. Never, if None
+    #[serde(default)]
+    pub expires_at: Option<u64>,
+    /// Whether the key must be activated via `/activate_key` before it can be used
+    #[serde(default)]
+    pub requires_activation: bool,
+    /// The Slack user who activated the key, if it has been activated
+    #[serde(default)]
+    pub activated_by: Option<String>,
+    /// How many requests per minute the key may make, overriding `EnvInfo::key_rate_limit`.
+    /// 0 means there is no limit
+    #[serde(default)]
+    pub rate_limit: Option<u64>,
+    /// The team prefix the key's handlers are saved under, as `<namespace>/<name>`. The start of
+    /// the key's hash, if None, see `namespaces::namespace_of`
+    #[serde(default)]
+    pub namespace: Option<String>,
+    /// The Ed25519 public keys, as PEM, the key's changes to its protected handlers must be
+    /// signed with, see `signing::check_signature`. Only admins may register them
+    #[serde(default)]
+    pub signing_keys: Vec<String>,
+    /// Whether the key is saved in plain text or hashed, see `auth::hash_plain_keys`
+    #[serde(default = "KeyFormat::legacy")]
+    pub key_format: KeyFormat,
+}
+
+/// How an API Key is saved
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum KeyFormat {
+    /// As it is, as keys were saved before they were hashed at rest
+    Plain,
+    /// As its hash, see `auth::hash_key`
+    Hashed,
+}
+
+impl KeyFormat {
+    /// The format of keys saved before it was recorded along with them
+    pub fn legacy() -> KeyFormat {
+        KeyFormat::Plain
+    }
+}
+
+impl Default for KeyFormat {
+    fn default() -> KeyFormat {
+        KeyFormat::Hashed
+    }
+}
+
+impl ApiKeyInfo {
+    /// Whether the key may be used right now, i.e. it is activated (if it needs to be), and has
+    /// not expired
+    ///
+    /// # Arguments
+    ///
+    /// * `now` - The current unix timestamp
+    pub fn is_usable(&self, now: u64) -> bool {
+        let activated = !self.requires_activation || self.activated_by.is_some();
+        let expired = self.expires_at.map(|t| t <= now).unwrap_or(false);
+        activated && !expired
+    }
+
+    /// Whether the key has been given a scope, see `SCOPES`
+    ///
+    /// # Arguments
+    ///
+    /// * `scope` - The scope, e.g. `READ_SCOPE`
+    pub fn allows(&self, scope: &str) -> bool {
+        let granted = |s: &str| self.scopes.iter().any(|granted| granted == s);
+        self.scopes.is_empty() || granted(scope) || (scope == READ_SCOPE && granted(WRITE_SCOPE))
+    }
+}
+
 /// Represents a handler, i.e. a Client defined bit of code, which reacts to events
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Handler {
     /// The URI of the handler, where it is reachable
     pub uri: String,
-    /// The API Key associated with the owner of the handler
+    /// The hash of the API Key of the owner of the handler, see `auth::hash_key`
     pub api_key: String,
     /// A wrapper around the AST and source code for serialization/deserialization purposes
     #[serde(serialize_with = "serialize_astbox")]
     #[serde(deserialize_with = "deserialize_astbox")]
     pub code: ASTBox,
+    /// Whether to invoke the handler with a `"warmup"` payload on deploy and on server start
+    #[serde(default)]
+    pub warmup: bool,
+    /// A short, human readable description of what the handler does, shown by `help`
+    #[serde(default)]
+    pub description: Option<String>,
+    /// Free form tags, used to group handlers in `help`. Tagging a handler `hidden` omits it
+    #[serde(default)]
+    pub tags: Vec<String>,
+    /// When the current code was saved, as a unix timestamp. 0 if unknown
+    #[serde(default)]
+    pub saved_at: u64,
+    /// When the handler was first saved, as a unix timestamp. 0 if unknown, for handlers saved
+    /// before this was recorded
+    #[serde(default)]
+    pub created_at: u64,
+    /// Previous revisions of the code, most recent first. At most `MAX_HISTORY` are kept
+    #[serde(default)]
+    pub history: Vec<Revision>,
+    /// How many operations a run may take. `DEFAULT_MAX_OPERATIONS`, if None
+    #[serde(default)]
+    pub max_operations: Option<u64>,
+    /// How long a run may take, in milliseconds. `DEFAULT_TIMEOUT_MS`, if None
+    #[serde(default)]
+    pub timeout_ms: Option<u64>,
+    /// When calls to the handler may run. Always, if None
+    #[serde(default)]
+    pub active_window: Option<ActiveWindow>,
+    /// The events from any source the handler's `on_event(envelope)` is called with, see
+    /// `envelope::fan_in`. None, if None
+    #[serde(default)]
+    pub subscription: Option<Subscription>,
+    /// Where calls are forwarded to, reshaped, instead of running the code, see `relay::Relay`
+    #[serde(default)]
+    pub relay: Option<Relay>,
+    /// Free form notes for whoever looks after the handler, e.g. what to do when it alerts
+    #[serde(default)]
+    pub runbook: Option<String>,
+    /// The hashes of other API Keys which may manage the handler as its owner does, e.g. the
+    /// keys of a team. It still runs as its owner, with the owner's secrets and modules
+    #[serde(default)]
+    pub co_owners: Vec<String>,
+    /// Whether changes to the handler only go live once another of its owners approves them,
+    /// see `changes::PendingChange`
+    #[serde(default)]
+    pub protected: bool,
+    /// Whether the handler is shown on the public `/status` page
+    #[serde(default)]
+    pub public_status: bool,
+    /// Whether `api_key` is the owner's key in plain text or its hash, see
+    /// `auth::hash_plain_owners`
+    #[serde(default = "KeyFormat::legacy")]
+    pub owner_format: KeyFormat,
+}
+
+/// A previous version of a handler's code
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Revision {
+    #[serde(serialize_with = "serialize_astbox")]
+    #[serde(deserialize_with = "deserialize_astbox")]
+    pub code: ASTBox,
+    /// When this code was saved, as a unix timestamp. 0 if unknown
+    pub saved_at: u64,
 }
 
 impl Handler {
     pub fn new(uri: String, api_key: String, code: String) -> Result<Handler, ParseError> {
         let engine = Engine::new();
         let ast = engine.compile(&code)?;
+        let now = unix_now();
         Ok(Handler {
             uri,
             api_key,
             code: ASTBox { ast, raw: code },
+            warmup: false,
+            description: None,
+            tags: Vec::new(),
+            saved_at: now,
+            created_at: now,
+            history: Vec::new(),
+            max_operations: None,
+            timeout_ms: None,
+            active_window: None,
+            subscription: None,
+            relay: None,
+            runbook: None,
+            co_owners: Vec::new(),
+            protected: false,
+            public_status: false,
+            owner_format: KeyFormat::Hashed,
         })
     }
+
+    /// Whether an API Key may manage the handler, i.e. it is its owner or one of its co-owners
+    ///
+    /// # Arguments
+    ///
+    /// * `key_hash` - The hash of the API Key, see `auth::hash_key`
+    pub fn owned_by(&self, key_hash: &str) -> bool {
+        self.api_key == key_hash || self.co_owners.iter().any(|k| k == key_hash)
+    }
+
+    /// How many operations a run of the handler may take
+    ///
+    /// # Arguments
+    ///
+    /// * `env` - Environment variables, for the server's ceiling
+    pub fn operations_limit(&self, env: &EnvInfo) -> u64 {
+        self.max_operations
+            .unwrap_or(DEFAULT_MAX_OPERATIONS)
+            .min(env.max_operations)
+    }
+
+    /// How long a run of the handler may take
+    ///
+    /// # Arguments
+    ///
+    /// * `env` - Environment variables, for the server's ceiling
+    pub fn time_limit(&self, env: &EnvInfo) -> Duration {
+        Duration::from_millis(
+            self.timeout_ms
+                .unwrap_or(DEFAULT_TIMEOUT_MS)
+                .min(env.max_timeout_ms),
+        )
+    }
+
+    /// Take over the history of the handler this one replaces, adding its code as the most
+    /// recent revision
+    ///
+    /// # Arguments
+    ///
+    /// * `previous` - The handler being replaced
+    pub fn supersede(&mut self, previous: Handler) {
+        self.created_at = previous.created_at;
+        self.history = previous.history;
+        self.push_revision(previous.code, previous.saved_at);
+    }
+
+    /// Replace the code with a revision from the history. The current code becomes the most
+    /// recent revision, so a rollback can itself be rolled back
+    ///
+    /// # Arguments
+    ///
+    /// * `index` - The position of the revision in the history, 0 being the most recent
+    pub fn rollback(&mut self, index: usize) -> Result<(), String> {
+        if index >= self.history.len() {
+            return Err(format!(
+                "No revision {}, the handler has {}",
+                index,
+                self.history.len()
+            ));
+        }
+
+        let revision = self.history.remove(index);
+        let current = std::mem::replace(&mut self.code, revision.code);
+        let saved_at = std::mem::replace(&mut self.saved_at, unix_now());
+        self.push_revision(current, saved_at);
+        Ok(())
+    }
+
+    /// Whether the code defines a function with the given name and number of parameters
+    pub fn defines(&self, name: &str, params: usize) -> bool {
+        arities(&self.code.ast, name).contains(&params)
+    }
+
+    fn push_revision(&mut self, code: ASTBox, saved_at: u64) {
+        self.history.insert(0, Revision { code, saved_at });
+        self.history.truncate(MAX_HISTORY);
+    }
 }
 
-fn serialize_astbox<S: Serializer>(astbox: &ASTBox, s: S) -> Result<S::Ok, S::Error> {
+pub fn serialize_astbox<S: Serializer>(astbox: &ASTBox, s: S) -> Result<S::Ok, S::Error> {
     s.serialize_str(&astbox.raw)
 }
 
-fn deserialize_astbox<'de, D: Deserializer<'de>>(d: D) -> Result<ASTBox, D::Error> {
+pub fn deserialize_astbox<'de, D: Deserializer<'de>>(d: D) -> Result<ASTBox, D::Error> {
     let code = String::deserialize(d)?;
     let engine = Engine::new();
     let ast = engine
@@ -73,9 +516,67 @@ pub struct UpsertHandlerRequest {
     /// The URI of the handler to update
     pub uri: String,
     /// The Client's API Key. Must match the api key specified in the handler
+    /// May be omitted in favor of an `Authorization: Bearer` header
+    #[serde(default)]
     pub api_key: String,
-    /// The new code to push
+    /// What the handler is
+    #[serde(flatten)]
+    pub spec: HandlerSpec,
+}
+
+/// Represents what a handler is, as its owner describes it: everything but its uri and owner
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HandlerSpec {
+    /// The code of the handler. May be omitted for relays
+    #[serde(default)]
     pub code: String,
+    /// If true, the handler is invoked once with a `"warmup"` payload after being saved, and again
+    /// whenever the server starts. Any error is reported back in the response.
+    #[serde(default)]
+    pub warmup: bool,
+    /// A short, human readable description of what the handler does, shown by `help`
+    #[serde(default)]
+    pub description: Option<String>,
+    /// Free form tags, used to group handlers in `help`
+    #[serde(default)]
+    pub tags: Vec<String>,
+    /// How many operations a run of the handler may take, up to the server's ceiling
+    #[serde(default)]
+    pub max_operations: Option<u64>,
+    /// How long a run of the handler may take, in milliseconds, up to the server's ceiling
+    #[serde(default)]
+    pub timeout_ms: Option<u64>,
+    /// When calls to the handler may run, e.g. business hours, and what happens to calls
+    /// outside of it
+    #[serde(default)]
+    pub active_window: Option<ActiveWindow>,
+    /// Which events from any source, e.g. everything mentioning a service, are passed to the
+    /// handler's `on_event(envelope)`
+    #[serde(default)]
+    pub subscription: Option<Subscription>,
+    /// Forward calls to a url or Slack channel, reshaped by a template, rather than run code
+    #[serde(default)]
+    pub relay: Option<Relay>,
+    /// Free form notes for whoever looks after the handler, e.g. what to do when it alerts.
+    /// Handlers can read it with `runbook(uri)`
+    #[serde(default)]
+    pub runbook: Option<String>,
+    /// The hashes of other API Keys which may also manage the handler, see `Handler::co_owners`.
+    /// Only its owner may change them. Left as they are, if omitted
+    #[serde(default)]
+    pub co_owners: Option<Vec<String>>,
+    /// If true, later changes to the handler must be approved by another of its owners before
+    /// they go live, see `changes`. Protected handlers need co-owners
+    #[serde(default)]
+    pub protected: bool,
+    /// The signature of `code` by one of the signing keys of the API Key saving the handler,
+    /// base64 encoded, see `signing::check_signature`
+    #[serde(default)]
+    pub signature: Option<String>,
+    /// If true, anyone can see how the handler has been doing, e.g. when it last ran, on the
+    /// `/status` page, see `status::status_page`
+    #[serde(default)]
+    pub public_status: bool,
 }
 
 /// Represents a client's request to find out more about a handler
@@ -84,7 +585,208 @@ pub struct FindHandlerRequest {
     /// The uri of the handler to find
     pub uri: String,
     /// The API Key associated with the handler. Must match what is present in db!
+    /// May be omitted in favor of an `Authorization: Bearer` header
+    #[serde(default)]
+    pub api_key: String,
+}
+
+/// Represents a client's request to create or change a feature flag
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SetFlagRequest {
+    /// The name of the flag
+    pub name: String,
+    /// Whether it is on, and for whom
+    #[serde(flatten)]
+    pub rules: Rules,
+    /// The API Key of the client, which will own the flag
+    /// May be omitted in favor of an `Authorization: Bearer` header
+    #[serde(default)]
+    pub api_key: String,
+}
+
+/// Represents a client's request to search the archive of a handler, see `archive::archive_search`
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ArchiveSearchRequest {
+    /// The uri of the handler
+    pub uri: String,
+    /// The earliest event to find, as a unix timestamp
+    #[serde(default)]
+    pub since: Option<u64>,
+    /// The latest event to find, as a unix timestamp
+    #[serde(default)]
+    pub until: Option<u64>,
+    /// Text the payload or context of events must contain
+    #[serde(default)]
+    pub contains: Option<String>,
+    /// The most events to return, up to 1000. 100 if omitted
+    #[serde(default)]
+    pub limit: Option<usize>,
+    /// The API Key associated with the handler
+    /// May be omitted in favor of an `Authorization: Bearer` header
+    #[serde(default)]
+    pub api_key: String,
+}
+
+/// Represents a client's request to replay archived events, see `reprocess::reprocess`
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ReprocessRequest {
+    /// The uri of the handler whose events to replay
+    pub uri: String,
+    /// The earliest event to replay, as a unix timestamp
+    #[serde(default)]
+    pub since: Option<u64>,
+    /// The latest event to replay, as a unix timestamp
+    #[serde(default)]
+    pub until: Option<u64>,
+    /// Text the payload or context of events must contain
+    #[serde(default)]
+    pub contains: Option<String>,
+    /// The previous revision of the handler to replay the events through, as listed by
+    /// `/handler_history`. The current code, if omitted
+    #[serde(default)]
+    pub revision: Option<usize>,
+    /// Whether the events are replayed for real, rather than with integrations mocked
+    #[serde(default)]
+    pub live: bool,
+    /// How many events to replay per minute, when live. 60 if omitted
+    #[serde(default)]
+    pub per_minute: Option<u64>,
+    /// The API Key associated with the handler
+    /// May be omitted in favor of an `Authorization: Bearer` header
+    #[serde(default)]
+    pub api_key: String,
+}
+
+/// Represents a client's request to search handlers, see `search::search_handlers`
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SearchHandlersRequest {
+    /// The text to find, ignoring case
+    pub query: String,
+    /// The API Key of the client, or the admin key
+    /// May be omitted in favor of an `Authorization: Bearer` header
+    #[serde(default)]
+    pub api_key: String,
+}
+
+/// Represents a client's request to list their dead handlers, see `cleanup::dead_handlers`
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DeadHandlersRequest {
+    /// How many days a handler must have been idle. `EnvInfo::dead_handler_days`, if omitted
+    #[serde(default)]
+    pub days: Option<u64>,
+    /// The API Key of the client, or the admin key
+    /// May be omitted in favor of an `Authorization: Bearer` header
+    #[serde(default)]
+    pub api_key: String,
+}
+
+/// Represents a client's request to check some code, see `codecheck::check_code`
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CheckCodeRequest {
+    /// The code to check
+    pub code: String,
+    /// The API Key of the client
+    /// May be omitted in favor of an `Authorization: Bearer` header
+    #[serde(default)]
+    pub api_key: String,
+}
+
+/// Represents a client's request to try out a handler, see `dryrun::test_handler`
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TestHandlerRequest {
+    /// The uri of a saved handler to try out. Either this or `code` must be given
+    #[serde(default)]
+    pub uri: Option<String>,
+    /// Code to try out without saving it, used instead of the handler at `uri`
+    #[serde(default)]
+    pub code: Option<String>,
+    /// The API Key associated with the handler
+    /// May be omitted in favor of an `Authorization: Bearer` header
+    #[serde(default)]
+    pub api_key: String,
+    /// The payload to pass to the handler
+    #[serde(default)]
+    pub post_data: String,
+}
+
+/// Represents a client's request for the recent log entries of a handler
+#[derive(Debug, Serialize, Deserialize)]
+pub struct HandlerLogsRequest {
+    /// The uri of the handler
+    pub uri: String,
+    /// The API Key associated with the handler
+    /// May be omitted in favor of an `Authorization: Bearer` header
+    #[serde(default)]
+    pub api_key: String,
+    /// How many of the most recent entries to return
+    #[serde(default)]
+    pub limit: Option<usize>,
+}
+
+/// Represents a client's request to approve or reject a change to a protected handler
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DecideChangeRequest {
+    /// The API Key of another owner of the handler than the one which proposed the change
+    /// May be omitted in favor of an `Authorization: Bearer` header
+    #[serde(default)]
+    pub api_key: String,
+    /// The id of the change, as returned when it was proposed
+    pub id: String,
+    /// Whether to approve the change, putting it live, or reject it
+    pub approve: bool,
+}
+
+/// Represents a client's request to restore a previous revision of a handler
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RollbackHandlerRequest {
+    /// The uri of the handler to roll back
+    pub uri: String,
+    /// The API Key associated with the handler
+    /// May be omitted in favor of an `Authorization: Bearer` header
+    #[serde(default)]
     pub api_key: String,
+    /// The position of the revision in the handler's history, 0 being the most recent
+    pub index: usize,
+}
+
+/// What a handler's owner is told about it, besides its code
+#[derive(Debug, Serialize, Deserialize)]
+pub struct HandlerMetadata {
+    pub description: Option<String>,
+    pub tags: Vec<String>,
+    /// When the handler was first saved, as a unix timestamp. 0 if unknown
+    pub created_at: u64,
+    /// When the handler was last saved, as a unix timestamp. 0 if unknown
+    pub updated_at: u64,
+    /// The hashes of the API Keys which may manage the handler besides its owner
+    pub co_owners: Vec<String>,
+    /// Whether changes to the handler must be approved before they go live
+    pub protected: bool,
+    /// Whether the handler is shown on the public `/status` page
+    pub public_status: bool,
+}
+
+impl HandlerMetadata {
+    pub fn of(handler: &Handler) -> HandlerMetadata {
+        HandlerMetadata {
+            description: handler.description.clone(),
+            tags: handler.tags.clone(),
+            created_at: handler.created_at,
+            updated_at: handler.saved_at,
+            co_owners: handler.co_owners.clone(),
+            protected: handler.protected,
+            public_status: handler.public_status,
+        }
+    }
+}
+
+/// Represents a handler, as listed by `list_handlers`
+#[derive(Debug, Serialize, Deserialize)]
+pub struct HandlerSummary {
+    pub uri: String,
+    /// Only given for the handlers the API Key owns
+    #[serde(flatten)]
+    pub metadata: Option<HandlerMetadata>,
 }
 
 /// Represents the result of an attempt to find a handler
@@ -92,13 +794,336 @@ pub struct FindHandlerRequest {
 pub struct FindHandlerResponse {
     /// The code associated with this handler
     pub code: String,
+    /// Where calls are forwarded to, if the handler is a relay
+    pub relay: Option<Relay>,
+    /// The handler's notes for whoever looks after it, if any
+    pub runbook: Option<String>,
+    #[serde(flatten)]
+    pub metadata: HandlerMetadata,
 }
 
 /// Represents a request which takes only an api key
 /// E.g. verify_key, list_handlers
 #[derive(Debug, Serialize, Deserialize)]
 pub struct APIKeyRequest {
+    #[serde(default)]
+    pub api_key: String,
+}
+
+/// Represents a request to cancel a pending reminder
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CancelReminderRequest {
+    #[serde(default)]
     pub api_key: String,
+    /// The id `remind` returned when the reminder was set
+    pub id: String,
+}
+
+/// Represents a client's request to create/update a module of shared functions
+#[derive(Debug, Serialize, Deserialize)]
+pub struct UpsertModuleRequest {
+    #[serde(default)]
+    pub api_key: String,
+    /// What handlers import the module by, e.g. `import "<name>" as lib;`
+    pub name: String,
+    pub code: String,
+}
+
+/// Represents a request to store a secret for the handlers of an API Key
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SetSecretRequest {
+    #[serde(default)]
+    pub api_key: String,
+    /// What handlers get the secret by, via `secret_get(name)`
+    pub name: String,
+    pub value: String,
+}
+
+/// Represents a request to remove a secret of an API Key
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DeleteSecretRequest {
+    #[serde(default)]
+    pub api_key: String,
+    pub name: String,
+}
+
+/// Represents an administrative request which takes only the admin key
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AdminRequest {
+    #[serde(default)]
+    pub admin_key: String,
+}
+
+/// Represents an admin's request to inject faults into the calls handlers make, see
+/// `faults::inject_fault`
+#[derive(Debug, Serialize, Deserialize)]
+pub struct InjectFaultRequest {
+    #[serde(default)]
+    pub admin_key: String,
+    /// Which calls to inject faults into: `github`, `http` or `slack`
+    pub integration: String,
+    /// How many of the calls fail, in percent
+    #[serde(default)]
+    pub fail_percent: u32,
+    /// How many of the calls are slowed down, in percent
+    #[serde(default)]
+    pub slow_percent: u32,
+    /// How long slowed calls are held up, in milliseconds
+    #[serde(default)]
+    pub delay_ms: u64,
+    /// Only the calls of these handlers, by uri. Every handler's, if empty
+    #[serde(default)]
+    pub handlers: Vec<String>,
+    /// How long to inject faults for. 0 stops injecting them
+    #[serde(default)]
+    pub minutes: u64,
+}
+
+/// Represents a request to pull handlers from another majordomo instance
+/// This is how a vetted set of handlers is promoted, e.g. from staging to production
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SyncFromRequest {
+    /// The admin key of this instance
+    /// May be omitted in favor of an `Authorization: Bearer` header
+    #[serde(default)]
+    pub admin_key: String,
+    /// The base url of the instance to pull from, e.g. `https://staging.example.com`
+    pub source: String,
+    /// The admin key of the instance to pull from
+    pub source_admin_key: String,
+    /// If present, only these uris are pulled. Otherwise every handler on the source is
+    #[serde(default)]
+    pub uris: Option<Vec<String>>,
+    /// If true, local handlers which are absent from the source are deleted
+    #[serde(default)]
+    pub prune: bool,
+    /// If true, only compute and return the diff, without changing anything
+    #[serde(default)]
+    pub dry_run: bool,
+}
+
+/// Represents the differences between the handlers of two instances
+#[derive(Debug, Serialize, Deserialize, Default)]
+pub struct SyncDiff {
+    /// Uris present on the source but not locally
+    pub added: Vec<String>,
+    /// Uris present on both, but which differ in anything saved about them but when, see
+    /// `server::comparable`
+    pub changed: Vec<String>,
+    /// Uris present locally but not on the source. Only deleted if `prune` was set
+    pub removed: Vec<String>,
+    /// Whether the diff was applied, i.e. this was not a dry run
+    pub applied: bool,
+}
+
+/// Represents a client's request to make its handlers what a document declares
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ApplyRequest {
+    /// The Client's API Key
+    /// May be omitted in favor of an `Authorization: Bearer` header
+    #[serde(default)]
+    pub api_key: String,
+    /// Every handler the API Key should have, by name or uri. Its other handlers are archived
+    #[serde(default)]
+    pub handlers: BTreeMap<String, HandlerSpec>,
+    /// The fingerprint of the plan to apply, as returned when this was omitted. If absent, only
+    /// the plan is returned
+    #[serde(default)]
+    pub confirm: Option<String>,
+}
+
+/// Represents what applying a document changes, see `apply::apply`
+#[derive(Debug, Serialize, Deserialize, Default)]
+pub struct ApplyPlan {
+    /// Uris declared by the document which don't exist yet
+    pub create: Vec<String>,
+    /// Uris declared by the document whose code or settings differ from it
+    pub update: Vec<String>,
+    /// Uris of the API Key's handlers which the document doesn't declare, to be archived
+    pub delete: Vec<String>,
+    /// Uris declared by the document which already match it
+    pub unchanged: Vec<String>,
+    /// The fingerprints of the signing keys which signed the code of the handlers to create or
+    /// update, by uri, for those which were signed
+    pub signed: BTreeMap<String, String>,
+    /// Identifies this plan, for `confirm`. It changes along with the document, or with any of
+    /// the handlers it covers
+    pub fingerprint: String,
+    /// Whether the plan was applied, i.e. it was confirmed
+    pub applied: bool,
+}
+
+/// Represents a single key in a bulk import
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ImportedKey {
+    /// The key itself. If omitted, a random key is generated. It must be random too, see
+    /// `auth::check_imported_key`
+    #[serde(default)]
+    pub key: Option<String>,
+    #[serde(default)]
+    pub label: Option<String>,
+    #[serde(default)]
+    pub contact: Option<String>,
+    #[serde(default)]
+    pub scopes: Vec<String>,
+    /// When the key stops working, as a unix timestamp
+    #[serde(default)]
+    pub expires_at: Option<u64>,
+    /// If true, the key only works once it has been bound to a Slack user via `/activate_key`
+    #[serde(default)]
+    pub requires_activation: bool,
+    /// How many requests per minute the key may make. The server wide limit, if omitted
+    #[serde(default)]
+    pub rate_limit: Option<u64>,
+    /// The team prefix the key's handlers are saved under. The start of the key's hash, if
+    /// omitted
+    #[serde(default)]
+    pub namespace: Option<String>,
+}
+
+/// Represents an admin's request to add a batch of API Keys
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ImportKeysRequest {
+    /// May be omitted in favor of an `Authorization: Bearer` header
+    #[serde(default)]
+    pub admin_key: String,
+    pub keys: Vec<ImportedKey>,
+}
+
+/// Represents a Client's request to activate their key on first use
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ActivateKeyRequest {
+    /// May be omitted in favor of an `Authorization: Bearer` header
+    #[serde(default)]
+    pub api_key: String,
+    /// The Slack user id to bind the key to, e.g. `U012AB3CD`
+    pub slack_user: String,
+}
+
+/// Represents an admin's request to change the label, contact or scopes of an existing key
+#[derive(Debug, Serialize, Deserialize)]
+pub struct UpdateKeyRequest {
+    /// May be omitted in favor of an `Authorization: Bearer` header
+    #[serde(default)]
+    pub admin_key: String,
+    /// The sha256 of the key to update, as listed by `/admin/export_keys`
+    pub key_hash: String,
+    /// The new label. Left unchanged if omitted
+    #[serde(default)]
+    pub label: Option<String>,
+    /// The new contact. Left unchanged if omitted
+    #[serde(default)]
+    pub contact: Option<String>,
+    /// The new scopes, see `SCOPES`. Left unchanged if omitted, and an empty list gives every
+    /// scope, like keys which predate scopes
+    #[serde(default)]
+    pub scopes: Option<Vec<String>>,
+    /// The new rate limit, in requests per minute. Left unchanged if omitted
+    #[serde(default)]
+    pub rate_limit: Option<u64>,
+    /// The new namespace, see `ApiKeyInfo::namespace`. Left unchanged if omitted. Handlers
+    /// already saved keep their uris
+    #[serde(default)]
+    pub namespace: Option<String>,
+    /// The new signing keys, see `ApiKeyInfo::signing_keys`. Left unchanged if omitted
+    #[serde(default)]
+    pub signing_keys: Option<Vec<String>>,
+}
+
+/// Represents an admin's request to issue a single new API Key
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CreateKeyRequest {
+    /// May be omitted in favor of an `Authorization: Bearer` header
+    #[serde(default)]
+    pub admin_key: String,
+    #[serde(default)]
+    pub label: Option<String>,
+    #[serde(default)]
+    pub contact: Option<String>,
+    #[serde(default)]
+    pub scopes: Vec<String>,
+    /// When the key stops working, as a unix timestamp
+    #[serde(default)]
+    pub expires_at: Option<u64>,
+    /// If true, the key only works once it has been bound to a Slack user via `/activate_key`
+    #[serde(default)]
+    pub requires_activation: bool,
+    /// How many requests per minute the key may make. The server wide limit, if omitted
+    #[serde(default)]
+    pub rate_limit: Option<u64>,
+    /// The team prefix the key's handlers are saved under. The start of the key's hash, if
+    /// omitted
+    #[serde(default)]
+    pub namespace: Option<String>,
+}
+
+/// Represents an admin's request to revoke an API Key
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RevokeKeyRequest {
+    /// May be omitted in favor of an `Authorization: Bearer` header
+    #[serde(default)]
+    pub admin_key: String,
+    /// The sha256 of the key to revoke, as listed by `/admin/list_keys`
+    pub key_hash: String,
+}
+
+/// Represents a single key in an export of the key registry
+/// The key itself is never exported, only its hash
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ExportedKey {
+    /// The sha256 of the key, in hex
+    pub key_hash: String,
+    #[serde(flatten)]
+    pub info: ApiKeyInfo,
+}
+
+/// The failure of requests which would change something on a read-only replica
+pub const READ_ONLY_FAILURE: &str = "This instance is a read-only replica";
+
+/// What kind of failure a request ran into, so that `/api/v1` can answer with the status which
+/// fits it, see `api::status_of`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FailureKind {
+    /// The request itself was wrong, e.g. a field was missing or the code didn't compile
+    Invalid,
+    /// The key was missing or wrong
+    Unauthorized,
+    /// Too many failed attempts came from the same address or key, see `auth::Lockouts`
+    LockedOut,
+    /// The key made too many requests, see `ApiKeyInfo::rate_limit`
+    RateLimited,
+    /// The key lacks the scope the request needs
+    Forbidden,
+    /// This instance is a read-only replica
+    ReadOnly,
+    NotFound,
+    /// The request conflicts with what exists, e.g. the uri is taken, or the handler is protected
+    Conflict,
+    /// The handler was saved, but its warm-up failed
+    WarmUpFailed,
+    /// Something went wrong on our side, e.g. saving failed
+    Internal,
+}
+
+impl Default for FailureKind {
+    fn default() -> FailureKind {
+        FailureKind::Invalid
+    }
+}
+
+/// Why a request failed, for the checks whose callers answer differently depending on the kind
+/// of failure, e.g. `auth::AuthHeader::verify`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Failure {
+    pub kind: FailureKind,
+    /// What the Client is told
+    pub message: String,
+}
+
+impl Failure {
+    pub fn new(kind: FailureKind, message: String) -> Failure {
+        Failure { kind, message }
+    }
 }
 
 /// Represents the response to a User query
@@ -110,6 +1135,9 @@ pub struct UserResponse {
     /// On a success, this might be json returned from a handler
     /// On a failure, this is the cause of the failure
     pub data: Option<String>,
+    /// On a failure, what kind of failure it is. Only the cause is sent
+    #[serde(skip)]
+    pub kind: FailureKind,
 }
 
 impl UserResponse {
@@ -117,6 +1145,7 @@ impl UserResponse {
         UserResponse {
             status: true,
             data: None,
+            kind: FailureKind::default(),
         }
     }
 
@@ -124,6 +1153,7 @@ impl UserResponse {
         UserResponse {
             status: true,
             data: Some(data),
+            kind: FailureKind::default(),
         }
     }
 
@@ -133,17 +1163,35 @@ impl UserResponse {
             Ok(s) => Some(UserResponse {
                 status: true,
                 data: Some(s),
+                kind: FailureKind::default(),
             }),
             Err(_) => None,
         }
     }
 
+    /// A failure because the request was wrong, see `FailureKind::Invalid`
     pub fn failure(cause: String) -> UserResponse {
+        UserResponse::failure_of(FailureKind::Invalid, cause)
+    }
+
+    pub fn failure_of(kind: FailureKind, cause: String) -> UserResponse {
         UserResponse {
             status: false,
             data: Some(cause),
+            kind,
         }
     }
+
+    /// The failure of requests which would change something on a read-only replica
+    pub fn read_only() -> UserResponse {
+        UserResponse::failure_of(FailureKind::ReadOnly, READ_ONLY_FAILURE.into())
+    }
+}
+
+impl From<Failure> for UserResponse {
+    fn from(failure: Failure) -> UserResponse {
+        UserResponse::failure_of(failure.kind, failure.message)
+    }
 }
 
 /// Represents the challenge send by slack
@@ -161,18 +1209,142 @@ pub struct SlackEvent {
     pub token: String,
     pub event: SlackEventInner,
     pub event_time: i64,
+    /// Unique to the event, and the same on each of Slack's retries of it
+    #[serde(default)]
+    pub event_id: Option<String>,
 }
 
-/// Represents the inner event
-/// TODO: this only conforms to a text message. Too bad. No emoji reacts yet
+/// Represents the inner event, by its `type`
+/// Events of other types are ignored
 #[derive(Serialize, Deserialize, Debug)]
-pub struct SlackEventInner {
-    #[serde(rename = "type")]
-    pub req_type: String,
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum SlackEventInner {
+    Message(SlackMessage),
+    AppMention(SlackMessage),
+    ReactionAdded(SlackReaction),
+    MemberJoinedChannel(SlackMemberJoined),
+    ChannelRename(SlackChannelRename),
+    AppHomeOpened(SlackAppHomeOpened),
+    #[serde(other)]
+    Other,
+}
+
+/// Represents a message posted in a channel, or one which mentions us
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SlackMessage {
     pub channel: String,
     pub user: String,
     pub text: String,
     pub ts: String,
+    /// The ts of the parent message, if this message was posted in a thread
+    #[serde(default)]
+    pub thread_ts: Option<String>,
+    /// Files uploaded along with the message
+    #[serde(default)]
+    pub files: Vec<SlackFile>,
+    /// Shared messages and link previews
+    #[serde(default)]
+    pub attachments: Vec<SlackAttachment>,
+}
+
+/// Represents an emoji reaction being added to a message
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SlackReaction {
+    /// Who reacted
+    pub user: String,
+    /// The name of the emoji, without colons, e.g. `thumbsup`
+    pub reaction: String,
+    /// Who posted the message which was reacted to
+    #[serde(default)]
+    pub item_user: String,
+    pub item: SlackReactionItem,
+}
+
+/// Represents what was reacted to
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SlackReactionItem {
+    /// `message` or `file`
+    #[serde(rename = "type")]
+    pub item_type: String,
+    /// The channel of the message. Empty for files
+    #[serde(default)]
+    pub channel: String,
+    /// The ts of the message. Empty for files
+    #[serde(default)]
+    pub ts: String,
+}
+
+/// Represents a user opening one of the tabs of our App Home
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SlackAppHomeOpened {
+    pub user: String,
+    /// `home` or `messages`
+    #[serde(default)]
+    pub tab: String,
+}
+
+/// Represents a user joining a channel
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SlackMemberJoined {
+    pub user: String,
+    pub channel: String,
+    /// Who invited them, if they didn't join by themselves
+    #[serde(default)]
+    pub inviter: Option<String>,
+}
+
+/// Represents a channel being renamed
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SlackChannelRename {
+    pub channel: SlackRenamedChannel,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SlackRenamedChannel {
+    pub id: String,
+    /// The new name of the channel
+    pub name: String,
+}
+
+/// Represents a file uploaded along with a message
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SlackFile {
+    pub id: String,
+    #[serde(default)]
+    pub name: String,
+    #[serde(default)]
+    pub mimetype: String,
+    /// The size of the file, in bytes
+    #[serde(default)]
+    pub size: u64,
+    /// Where to download the file from, with the Slack token
+    #[serde(default)]
+    pub url_private: String,
+    /// Where users can view the file in Slack
+    #[serde(default)]
+    pub permalink: String,
+}
+
+/// Represents an attachment of a message, e.g. another message which was shared into it
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SlackAttachment {
+    /// Whether this is a share of another message, rather than e.g. a link preview
+    #[serde(default)]
+    pub is_share: bool,
+    /// The permalink of the shared message, or the url of the preview
+    #[serde(default)]
+    pub from_url: Option<String>,
+    #[serde(default)]
+    pub text: Option<String>,
+    /// The user who posted the shared message
+    #[serde(default)]
+    pub author_id: Option<String>,
+    /// The channel the shared message was posted in
+    #[serde(default)]
+    pub channel_id: Option<String>,
+    /// The ts of the shared message
+    #[serde(default)]
+    pub ts: Option<String>,
 }
 
 /// When a response has an Ok, and that ok is all we care about
@@ -215,3 +1387,25 @@ impl GithubIssueCreateResponse {
         self.id.clone()
     }
 }
+
+/// A comment on an issue or pull request, as `github_issue_comment` returns it
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct GithubIssueCommentResponse {
+    pub html_url: String,
+    pub body: String,
+    pub id: i64,
+}
+
+impl GithubIssueCommentResponse {
+    pub fn get_url(&mut self) -> String {
+        self.html_url.clone()
+    }
+
+    pub fn get_body(&mut self) -> String {
+        self.body.clone()
+    }
+
+    pub fn get_id(&mut self) -> i64 {
+        self.id
+    }
+}
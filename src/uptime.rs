@@ -0,0 +1,330 @@
+use std::collections::HashMap;
+use std::sync::RwLock;
+use std::time::{Duration, Instant};
+
+use reqwest::blocking::Client;
+use reqwest::Url;
+
+use rhai::{Array, Dynamic, EvalAltResult, ImmutableString, Map, Module, Scope, INT};
+
+use serde::{Deserialize, Serialize};
+
+use crate::http_client::is_allowed;
+use crate::logging::CorrelationId;
+use crate::server::run_function;
+use crate::services::Services;
+use crate::types::{EnvInfo, Handler};
+
+/// How long a probe may take before the check counts as down
+const PROBE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The most often a check may run, in seconds
+const MIN_INTERVAL_SECS: INT = 30;
+
+/// How many checks a single handler may register
+const MAX_CHECKS: usize = 20;
+
+/// How many probes of each check are kept
+const MAX_HISTORY: usize = 100;
+
+/// The outcome of probing a check once
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Probe {
+    /// When the probe ran, as a unix timestamp
+    pub at: u64,
+    /// The status the url responded with. None if it didn't respond at all
+    pub status: Option<u16>,
+    /// Whether the status was the expected one
+    pub up: bool,
+    /// How long the response took, in milliseconds
+    pub latency_ms: u64,
+}
+
+/// A url a handler wants to know is up
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Check {
+    /// The uri of the handler which registered the check, and whose `on_uptime_change(event)` is
+    /// called when the check goes down or comes back up
+    pub handler: String,
+    /// What the handler calls the check
+    pub name: String,
+    pub url: String,
+    /// The status the url responds with when it is up, e.g. 200
+    pub expected_status: u16,
+    /// How often to probe the url, in seconds
+    pub interval_secs: u64,
+    /// When the check was last probed, as a unix timestamp. 0 if never
+    pub last_run_at: u64,
+    /// Whether the check was up when last probed. None if it hasn't been yet
+    pub up: Option<bool>,
+    /// When the check last went down or came back up, as a unix timestamp. 0 if never
+    pub changed_at: u64,
+    /// The most recent probes, oldest first. At most `MAX_HISTORY` are kept
+    pub history: Vec<Probe>,
+}
+
+impl Check {
+    /// The key of the check a handler registered under a name
+    fn key(handler: &str, name: &str) -> String {
+        format!("{} {}", handler, name)
+    }
+
+    /// Describe the check for handlers
+    fn to_map(&self) -> Map {
+        let history = self
+            .history
+            .iter()
+            .map(|p| {
+                let mut map = Map::new();
+                map.insert("at".into(), Dynamic::from(p.at as INT));
+                map.insert("status".into(), Dynamic::from(p.status.unwrap_or(0) as INT));
+                map.insert("up".into(), Dynamic::from(p.up));
+                map.insert("latency_ms".into(), Dynamic::from(p.latency_ms as INT));
+                Dynamic::from(map)
+            })
+            .collect::<Array>();
+
+        let mut map = Map::new();
+        map.insert("name".into(), Dynamic::from(self.name.clone()));
+        map.insert("url".into(), Dynamic::from(self.url.clone()));
+        map.insert("up".into(), Dynamic::from(self.up.unwrap_or(false)));
+        map.insert("changed_at".into(), Dynamic::from(self.changed_at as INT));
+        map.insert("history".into(), Dynamic::from(history));
+        map
+    }
+}
+
+/// Register the uptime monitoring functions available to clients
+///
+/// * `uptime_check(name, url, expected_status, interval_secs)` probes `url` every
+///   `interval_secs`, at least 30, and counts it as up while it responds with
+///   `expected_status`. When it goes down or comes back up, the handler's
+///   `on_uptime_change(event)` is called, see `tick`. The url must be allowed by the http
+///   allowlist. Calling it again with the same name changes the check
+/// * `uptime_cancel(name)` stops a check. Returns whether there was one
+/// * `uptime_status(name)` returns a map of the check's `name`, `url`, whether it is `up`, when
+///   it `changed_at`, and its recent `history`, as maps of when the probe ran `at`, the `status`
+///   it got, 0 if none, whether it was `up` and its `latency_ms`. Returns `()` if there is no
+///   such check
+///
+/// # Arguments
+///
+/// * `module` - The module to register the functions in
+/// * `env` - Environment variables, for the http allowlist
+/// * `services` - Where checks are stored
+/// * `handler_addr` - The uri of the handler the functions are for
+pub fn register(module: &mut Module, env: &EnvInfo, services: &Services, handler_addr: &str) {
+    let uptime = services.uptime.clone();
+    let allowlist = env.http_allowlist.clone();
+    let addr = handler_addr.to_string();
+    let uptime_check = move |name: ImmutableString,
+                             url: ImmutableString,
+                             expected_status: INT,
+                             interval_secs: INT|
+          -> Result<(), Box<EvalAltResult>> {
+        let parsed = Url::parse(&url).map_err(|e| format!("Invalid url {}: {}", url, e))?;
+        if !is_allowed(&parsed, &allowlist) {
+            return Err(format!(
+                "Requests to {} are not allowed",
+                parsed.host_str().unwrap_or("")
+            )
+            .into());
+        }
+        if !(100..=599).contains(&expected_status) {
+            return Err(format!("{} is not an http status", expected_status).into());
+        }
+        if interval_secs < MIN_INTERVAL_SECS {
+            return Err(format!("Checks may run at most every {}s", MIN_INTERVAL_SECS).into());
+        }
+
+        log_event!("uptime.check", handler = addr, name = name, url = url);
+        uptime.update(|map| {
+            let key = Check::key(&addr, &name);
+            let count = map.values().filter(|c| c.handler == addr).count();
+            if count >= MAX_CHECKS && !map.contains_key(&key) {
+                return Err(format!(
+                    "A handler may register at most {} checks",
+                    MAX_CHECKS
+                ));
+            }
+
+            // Changing a check starts its history over, since it may now mean something else
+            map.insert(
+                key,
+                Check {
+                    handler: addr.clone(),
+                    name: name.to_string(),
+                    url: url.to_string(),
+                    expected_status: expected_status as u16,
+                    interval_secs: interval_secs as u64,
+                    last_run_at: 0,
+                    up: None,
+                    changed_at: 0,
+                    history: Vec::new(),
+                },
+            );
+            Ok(())
+        })?;
+        Ok(())
+    };
+
+    let uptime = services.uptime.clone();
+    let addr = handler_addr.to_string();
+    let uptime_cancel = move |name: ImmutableString| {
+        Ok(uptime.update(|map| map.remove(&Check::key(&addr, &name)).is_some()))
+    };
+
+    let uptime = services.uptime.clone();
+    let addr = handler_addr.to_string();
+    let uptime_status = move |name: ImmutableString| {
+        Ok(match uptime.read().get(&Check::key(&addr, &name)) {
+            Some(check) => Dynamic::from(check.to_map()),
+            None => Dynamic::from(()),
+        })
+    };
+
+    module.set_fn_4("uptime_check", uptime_check);
+    module.set_fn_1("uptime_cancel", uptime_cancel);
+    module.set_fn_1("uptime_status", uptime_status);
+}
+
+/// Probe a check's url once
+///
+/// Redirects are not followed, so a check can't be pointed somewhere the allowlist forbids.
+fn probe(client: &Client, check: &Check, now: u64) -> Probe {
+    let started = Instant::now();
+    let status = client
+        .get(&check.url)
+        .timeout(PROBE_TIMEOUT)
+        .send()
+        .map(|r| r.status().as_u16())
+        .ok();
+    Probe {
+        at: now,
+        status,
+        up: status == Some(check.expected_status),
+        latency_ms: started.elapsed().as_millis() as u64,
+    }
+}
+
+/// Probe every check which is due, and tell handlers about the checks which went down or came
+/// back up. Run by the scheduler
+///
+/// Handlers are called as `on_uptime_change(event)`, where `event` is a map of the check's
+/// `name` and `url`, whether it is now `up`, and the `status` it responded with, 0 if none.
+/// A check which is down the first time it is probed counts as having gone down. Checks of
+/// handlers which no longer exist are dropped.
+///
+/// # Arguments
+///
+/// * `env` - Environment variables
+/// * `services` - Where checks are stored, and the shared http client to probe them with
+/// * `handlers` - The handlers to tell about changes
+/// * `now` - The current unix timestamp
+pub fn tick(
+    env: &EnvInfo,
+    services: &Services,
+    handlers: &RwLock<HashMap<String, Handler>>,
+    now: u64,
+) {
+    let is_due = |c: &Check| now.saturating_sub(c.last_run_at) >= c.interval_secs;
+
+    // Most ticks have nothing to do, and shouldn't save the checks for nothing
+    let changed = {
+        let guard = handlers.read().unwrap();
+        services
+            .uptime
+            .read()
+            .values()
+            .any(|c| is_due(c) || !guard.contains_key(&c.handler))
+    };
+    if !changed {
+        return;
+    }
+
+    let due = services.uptime.update(|map| {
+        let guard = handlers.read().unwrap();
+        map.retain(|_, c| guard.contains_key(&c.handler));
+        map.values_mut()
+            .filter(|c| is_due(c))
+            .map(|c| {
+                c.last_run_at = now;
+                c.clone()
+            })
+            .collect::<Vec<Check>>()
+    });
+    if due.is_empty() {
+        return;
+    }
+
+    for check in due {
+        let result = probe(&services.http, &check, now);
+        let went = match check.up {
+            Some(up) => up != result.up,
+            None => !result.up,
+        };
+
+        let key = Check::key(&check.handler, &check.name);
+        services.uptime.update(|map| {
+            // The check may have been changed or cancelled while it was probed
+            if let Some(c) = map.get_mut(&key).filter(|c| c.url == check.url) {
+                c.up = Some(result.up);
+                if went {
+                    c.changed_at = now;
+                }
+                c.history.push(result.clone());
+                if c.history.len() > MAX_HISTORY {
+                    let excess = c.history.len() - MAX_HISTORY;
+                    c.history.drain(..excess);
+                }
+            }
+        });
+        if !went {
+            continue;
+        }
+
+        let id = CorrelationId::generate();
+        log_event!(
+            "uptime.change",
+            id = id.0,
+            handler = check.handler,
+            name = check.name,
+            up = result.up
+        );
+
+        let guard = handlers.read().unwrap();
+        let handler = match guard.get(&check.handler) {
+            Some(handler) if handler.defines("on_uptime_change", 1) => handler,
+            _ => continue,
+        };
+
+        let mut event = Map::new();
+        event.insert("name".into(), Dynamic::from(check.name.clone()));
+        event.insert("url".into(), Dynamic::from(check.url.clone()));
+        event.insert("up".into(), Dynamic::from(result.up));
+        event.insert(
+            "status".into(),
+            Dynamic::from(result.status.unwrap_or(0) as INT),
+        );
+
+        let ast = &handler.code.ast;
+        let result = run_function(
+            env,
+            services,
+            &id,
+            &check.handler,
+            handler,
+            None,
+            "on_uptime_change",
+            |engine| engine.call_fn(&mut Scope::new(), ast, "on_uptime_change", (event,)),
+        );
+        if let Err(e) = result {
+            log_event!(
+                "handler.error",
+                id = id.0,
+                handler = check.handler,
+                error = e
+            );
+        }
+    }
+}
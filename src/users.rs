@@ -0,0 +1,101 @@
+use std::collections::HashMap;
+use std::sync::RwLock;
+use std::time::{Duration, Instant};
+
+use reqwest::blocking::Client;
+
+use rhai::{Dynamic, Map};
+
+use serde_json::Value;
+
+use crate::slack::slack_api_get;
+
+/// How long what Slack told us about a user is trusted for. Names rarely change, and a stale one
+/// only means a greeting uses the old name for a while
+const USER_TTL: Duration = Duration::from_secs(60 * 60);
+
+/// A Slack user, as `users.info` describes them
+#[derive(Debug, Clone)]
+pub struct SlackUser {
+    pub id: String,
+    /// Their handle, e.g. `jdoe`
+    pub name: String,
+    /// The name shown in Slack: the one they picked, or their real name if they didn't pick one
+    pub display_name: String,
+    pub real_name: String,
+    pub is_bot: bool,
+}
+
+impl SlackUser {
+    /// Read a user from the `user` of a `users.info` response
+    fn from_json(user: &Value) -> SlackUser {
+        let text = |value: &Value| value.as_str().unwrap_or_default().to_string();
+        let real_name = Some(text(&user["profile"]["real_name"]))
+            .filter(|n| !n.is_empty())
+            .unwrap_or_else(|| text(&user["real_name"]));
+        let display_name = Some(text(&user["profile"]["display_name"]))
+            .filter(|n| !n.is_empty())
+            .unwrap_or_else(|| real_name.clone());
+        SlackUser {
+            id: text(&user["id"]),
+            name: text(&user["name"]),
+            display_name,
+            real_name,
+            is_bot: user["is_bot"].as_bool().unwrap_or(false),
+        }
+    }
+
+    /// The user, as handlers see them
+    pub fn to_map(&self) -> Map {
+        let mut map = Map::new();
+        map.insert("id".into(), Dynamic::from(self.id.clone()));
+        map.insert("name".into(), Dynamic::from(self.name.clone()));
+        map.insert(
+            "display_name".into(),
+            Dynamic::from(self.display_name.clone()),
+        );
+        map.insert("real_name".into(), Dynamic::from(self.real_name.clone()));
+        map.insert("is_bot".into(), Dynamic::from(self.is_bot));
+        map
+    }
+}
+
+/// The Slack users recently looked up, by their id, so that a busy handler greeting people
+/// doesn't cost a call to `users.info` every time
+#[derive(Default)]
+pub struct SlackUsers {
+    users: RwLock<HashMap<String, (SlackUser, Instant)>>,
+}
+
+impl SlackUsers {
+    /// Look up a user, unless they were looked up recently
+    ///
+    /// # Arguments
+    ///
+    /// * `client` - A reqwest HTTP "client" to make the request
+    /// * `token` - The slack token to authenticate with
+    /// * `id` - The id of the user, e.g. `U012AB3CD`
+    ///
+    /// Returns the user, or Slack's error code, e.g. `user_not_found`
+    pub fn lookup(&self, client: &Client, token: &str, id: &str) -> Result<SlackUser, String> {
+        let cached = self
+            .users
+            .read()
+            .unwrap()
+            .get(id)
+            .filter(|(_, at)| at.elapsed() < USER_TTL)
+            .map(|(user, _)| user.clone());
+        if let Some(user) = cached {
+            return Ok(user);
+        }
+
+        let resp = slack_api_get(client, token, "users.info", &[("user", id)])?;
+        let user = SlackUser::from_json(&resp["user"]);
+
+        let mut users = self.users.write().unwrap();
+        // Users who expired are only dropped here, like channel names are
+        users.retain(|_, (_, at)| at.elapsed() < USER_TTL);
+        users.insert(id.to_string(), (user.clone(), Instant::now()));
+        Ok(user)
+    }
+}
@@ -0,0 +1,349 @@
+use std::collections::HashMap;
+use std::sync::RwLock;
+
+use rhai::de::from_dynamic;
+use rhai::ser::to_dynamic;
+use rhai::{Dynamic, Map};
+
+use serde::{Deserialize, Serialize};
+
+use serde_json::Value;
+
+use crate::logging::CorrelationId;
+use crate::server::run_handler_dynamic;
+use crate::services::Services;
+use crate::types::{EnvInfo, Handler};
+
+/// The days of the week, as windows name them, starting on Monday
+const DAYS: [&str; 7] = ["mon", "tue", "wed", "thu", "fri", "sat", "sun"];
+
+/// How many calls may wait for a single handler's window to open
+const MAX_QUEUED: usize = 100;
+
+/// How long a call may wait for a window to open, in seconds, before it is dropped
+const MAX_QUEUED_SECS: u64 = 7 * 24 * 60 * 60;
+
+/// What happens to calls to a handler outside its active window
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Outside {
+    /// The call fails
+    Reject,
+    /// The call waits until the window opens, see `tick`
+    Queue,
+    /// The call goes to the handler at `route_to` instead
+    Route,
+}
+
+impl Default for Outside {
+    fn default() -> Outside {
+        Outside::Reject
+    }
+}
+
+/// When a handler is active, e.g. during business hours
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ActiveWindow {
+    /// The days the window opens on, e.g. `["mon", "tue"]`. Every day, if empty
+    #[serde(default)]
+    pub days: Vec<String>,
+    /// When the window opens, as `HH:MM` local time
+    pub start: String,
+    /// When the window closes, as `HH:MM` local time. Windows which close before they open run
+    /// overnight, into the next day
+    pub end: String,
+    /// How far local time is ahead of UTC, in minutes, e.g. -300 for New York in winter.
+    /// Fixed, so it has to be changed along with daylight saving time
+    #[serde(default)]
+    pub utc_offset_minutes: i64,
+    #[serde(default)]
+    pub outside: Outside,
+    /// The uri of the handler calls go to outside the window, if `outside` is `route`
+    #[serde(default)]
+    pub route_to: Option<String>,
+}
+
+/// Read a local time of day, `HH:MM`, as minutes since midnight
+fn parse_time(time: &str) -> Option<i64> {
+    let mut parts = time.splitn(2, ':');
+    let hours = parts.next()?.parse::<i64>().ok()?;
+    let minutes = parts.next()?.parse::<i64>().ok()?;
+    Some(hours * 60 + minutes).filter(|_| (0..24).contains(&hours) && (0..60).contains(&minutes))
+}
+
+impl ActiveWindow {
+    /// Check the window makes sense, before it is saved
+    ///
+    /// # Arguments
+    ///
+    /// * `handler_addr` - The uri of the handler the window is for
+    pub fn check(&self, handler_addr: &str) -> Result<(), String> {
+        if parse_time(&self.start).is_none() || parse_time(&self.end).is_none() {
+            return Err("Window times must be HH:MM, on a 24 hour clock".into());
+        }
+        if let Some(day) = self.days.iter().find(|d| !DAYS.contains(&d.as_str())) {
+            return Err(format!(
+                "Unknown day {}, expected one of {}",
+                day,
+                DAYS.join(", ")
+            ));
+        }
+        if self.utc_offset_minutes.abs() > 14 * 60 {
+            return Err("utc_offset_minutes must be within 14 hours of UTC".into());
+        }
+        match (self.outside, &self.route_to) {
+            (Outside::Route, None) => Err("Routing outside the window needs a route_to".into()),
+            (Outside::Route, Some(to)) if to == handler_addr => {
+                Err("A handler can't route to itself".into())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Whether the window is open at a time
+    ///
+    /// # Arguments
+    ///
+    /// * `now` - The time, as a unix timestamp
+    pub fn is_open(&self, now: u64) -> bool {
+        let start = parse_time(&self.start).unwrap_or(0);
+        let end = parse_time(&self.end).unwrap_or(0);
+        let local = now as i64 + self.utc_offset_minutes * 60;
+        let minute = local.rem_euclid(86_400) / 60;
+        // The epoch was a Thursday
+        let day = (local.div_euclid(86_400) + 3).rem_euclid(7) as usize;
+        let opens_on =
+            |day: usize| self.days.is_empty() || self.days.iter().any(|d| d == DAYS[day]);
+
+        if start < end {
+            opens_on(day) && minute >= start && minute < end
+        } else {
+            // Overnight, so the early hours belong to the window which opened the day before
+            (opens_on(day) && minute >= start) || (opens_on((day + 6) % 7) && minute < end)
+        }
+    }
+}
+
+/// A call waiting for the window of its handler to open
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Queued {
+    pub payload: String,
+    /// The context the call came with, if any, as json
+    pub context: Option<Value>,
+    /// When the call was made, as a unix timestamp
+    pub queued_at: u64,
+}
+
+/// Whether a call to a handler may go ahead
+pub enum Admission<'a> {
+    /// The call goes ahead, with this handler, which may not be the one it was made to
+    Run(&'a str, &'a Handler),
+    /// The call waits for the window to open
+    Queued,
+    /// The call fails, for this reason
+    Rejected(String),
+}
+
+/// Decide what happens to a call to a handler, given its active window
+///
+/// # Arguments
+///
+/// * `services` - Where queued calls are kept
+/// * `handlers` - The handlers, for routing
+/// * `handler_addr` - The uri of the handler being called
+/// * `handler` - The handler being called
+/// * `payload` - The payload of the call, to queue it
+/// * `context` - The context of the call, to queue it
+/// * `now` - The current unix timestamp
+pub fn admit<'a>(
+    services: &Services,
+    handlers: &'a HashMap<String, Handler>,
+    handler_addr: &'a str,
+    handler: &'a Handler,
+    payload: &str,
+    context: &Option<Map>,
+    now: u64,
+) -> Admission<'a> {
+    let window = match &handler.active_window {
+        Some(window) if !window.is_open(now) => window,
+        _ => return Admission::Run(handler_addr, handler),
+    };
+
+    match window.outside {
+        Outside::Reject => Admission::Rejected(format!(
+            "{} is only active {} to {}",
+            handler_addr, window.start, window.end
+        )),
+        Outside::Route => {
+            let to = window.route_to.as_deref().unwrap_or_default();
+            match handlers.get_key_value(to) {
+                Some((to, target)) => Admission::Run(to, target),
+                None => Admission::Rejected(format!("Unable to find endpoint {}", to)),
+            }
+        }
+        Outside::Queue => {
+            let context = context
+                .as_ref()
+                .map(|c| from_dynamic::<Value>(&Dynamic::from(c.clone())).ok())
+                .flatten();
+            let call = Queued {
+                payload: payload.to_string(),
+                context,
+                queued_at: now,
+            };
+            services.queued.update(|map| {
+                let queue = map.entry(handler_addr.to_string()).or_default();
+                if queue.len() >= MAX_QUEUED {
+                    return Admission::Rejected(format!(
+                        "{} is inactive, and already has {} calls waiting",
+                        handler_addr, MAX_QUEUED
+                    ));
+                }
+                queue.push(call);
+                Admission::Queued
+            })
+        }
+    }
+}
+
+/// Run the calls which were waiting for windows that have opened, and drop those which waited
+/// too long. Run by the scheduler
+///
+/// # Arguments
+///
+/// * `env` - Environment variables
+/// * `services` - Where queued calls are kept
+/// * `handlers` - The handlers to run the calls with
+/// * `now` - The current unix timestamp
+pub fn tick(
+    env: &EnvInfo,
+    services: &Services,
+    handlers: &RwLock<HashMap<String, Handler>>,
+    now: u64,
+) {
+    // Most ticks have nothing to do, and shouldn't save the queues for nothing
+    if services.queued.read().is_empty() {
+        return;
+    }
+
+    let due = services.queued.update(|map| {
+        let guard = handlers.read().unwrap();
+        let mut due = Vec::new();
+        map.retain(|addr, queue| {
+            queue.retain(|q| now.saturating_sub(q.queued_at) < MAX_QUEUED_SECS);
+            let open = match guard.get(addr) {
+                Some(handler) => handler
+                    .active_window
+                    .as_ref()
+                    .map(|w| w.is_open(now))
+                    .unwrap_or(true),
+                None => return false,
+            };
+            if open {
+                due.extend(queue.drain(..).map(|q| (addr.clone(), q)));
+            }
+            !queue.is_empty()
+        });
+        due
+    });
+
+    for (addr, call) in due {
+        let id = CorrelationId::generate();
+        let guard = handlers.read().unwrap();
+        let handler = match guard.get(&addr) {
+            Some(handler) => handler,
+            None => continue,
+        };
+        let context = call
+            .context
+            .map(|c| to_dynamic(c).ok())
+            .flatten()
+            .map(|c| c.try_cast::<Map>())
+            .flatten();
+
+        log_event!(
+            "window.dequeue",
+            id = id.0,
+            handler = addr,
+            waited = now.saturating_sub(call.queued_at)
+        );
+        if let Err(e) =
+            run_handler_dynamic(env, services, &id, &addr, handler, call.payload, context)
+        {
+            log_event!("handler.error", id = id.0, handler = addr, error = e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Monday 2021-03-15 00:00 UTC
+    const MONDAY: u64 = 1_615_766_400;
+
+    fn window(days: &[&str], start: &str, end: &str, utc_offset_minutes: i64) -> ActiveWindow {
+        ActiveWindow {
+            days: days.iter().map(|d| d.to_string()).collect(),
+            start: start.to_string(),
+            end: end.to_string(),
+            utc_offset_minutes,
+            outside: Outside::Reject,
+            route_to: None,
+        }
+    }
+
+    /// The time on a day of the week starting on `MONDAY`, e.g. 0 for Monday
+    fn at(day: u64, hour: u64, minute: u64) -> u64 {
+        MONDAY + day * 86_400 + hour * 3_600 + minute * 60
+    }
+
+    #[test]
+    fn business_hours_are_open_on_weekdays_only() {
+        let hours = window(&["mon", "tue", "wed", "thu", "fri"], "09:00", "17:30", 0);
+        assert!(hours.is_open(at(0, 9, 0)));
+        assert!(hours.is_open(at(4, 17, 29)));
+        assert!(!hours.is_open(at(0, 8, 59)));
+        assert!(!hours.is_open(at(0, 17, 30)));
+        assert!(!hours.is_open(at(5, 12, 0)));
+    }
+
+    #[test]
+    fn overnight_windows_belong_to_the_day_they_open() {
+        let nights = window(&["fri"], "22:00", "06:00", 0);
+        assert!(nights.is_open(at(4, 23, 0)));
+        assert!(nights.is_open(at(5, 5, 59)));
+        assert!(!nights.is_open(at(4, 5, 0)));
+        assert!(!nights.is_open(at(5, 22, 0)));
+    }
+
+    #[test]
+    fn windows_are_in_local_time() {
+        // 09:00 in New York in winter is 14:00 UTC, and Sunday evening there is Monday in UTC
+        let new_york = window(&["mon"], "09:00", "17:00", -300);
+        assert!(new_york.is_open(at(0, 14, 0)));
+        assert!(!new_york.is_open(at(0, 9, 0)));
+        assert!(!window(&["mon"], "00:00", "23:59", -300).is_open(at(0, 3, 0)));
+    }
+
+    #[test]
+    fn check_refuses_windows_which_make_no_sense() {
+        assert!(window(&[], "09:00", "17:00", 0).check("deploy").is_ok());
+        assert!(window(&[], "9am", "17:00", 0).check("deploy").is_err());
+        assert!(window(&[], "09:00", "24:00", 0).check("deploy").is_err());
+        assert!(window(&["monday"], "09:00", "17:00", 0)
+            .check("deploy")
+            .is_err());
+        assert!(window(&[], "09:00", "17:00", 15 * 60)
+            .check("deploy")
+            .is_err());
+
+        let mut routed = window(&[], "09:00", "17:00", 0);
+        routed.outside = Outside::Route;
+        assert!(routed.check("deploy").is_err());
+        routed.route_to = Some("deploy".to_string());
+        assert!(routed.check("deploy").is_err());
+        routed.route_to = Some("deploy-queue".to_string());
+        assert!(routed.check("deploy").is_ok());
+    }
+}
@@ -0,0 +1,127 @@
+use std::collections::BTreeMap;
+use std::ops::Deref;
+
+use rhai::{Dynamic, Map};
+
+use rocket::State;
+
+use rocket_contrib::json::Json;
+
+use serde_json::Value;
+
+use crate::logging::CorrelationId;
+use crate::server::{run_handler, Collection};
+use crate::services::Services;
+use crate::types::{EnvInfo, Handler, UserResponse};
+
+/// Flatten the variables a workflow sent into a single level of string values
+///
+/// Nested objects and lists are joined with dots, so `{"ticket": {"tags": ["a"]}}` becomes
+/// `{"ticket.tags.0": "a"}`. Numbers and booleans are written out as text, and nulls are empty.
+///
+/// # Arguments
+///
+/// * `prefix` - The key of `value`, empty at the top level
+/// * `value` - The variables to flatten
+/// * `out` - Where to put the flattened variables
+fn flatten(prefix: &str, value: &Value, out: &mut BTreeMap<String, String>) {
+    let key = |k: &str| {
+        if prefix.is_empty() {
+            k.to_string()
+        } else {
+            format!("{}.{}", prefix, k)
+        }
+    };
+
+    match value {
+        Value::Object(map) => map.iter().for_each(|(k, v)| flatten(&key(k), v, out)),
+        Value::Array(list) => list
+            .iter()
+            .enumerate()
+            .for_each(|(i, v)| flatten(&key(&i.to_string()), v, out)),
+        Value::String(s) => {
+            out.insert(prefix.to_string(), s.clone());
+        }
+        Value::Null => {
+            out.insert(prefix.to_string(), String::new());
+        }
+        other => {
+            out.insert(prefix.to_string(), other.to_string());
+        }
+    }
+}
+
+/// Rocket Endpoint which receives requests from Slack Workflow Builder, and passes them on to a
+/// handler
+///
+/// Point a workflow's webhook step at `/workflow/<handler_addr>` and add whatever variables the
+/// handler needs. They are flattened into a json object of strings, see `flatten`, which is
+/// passed as the payload, i.e. `handle(payload)`. Handlers which define `handle(payload, vars)`
+/// also get the variables as a map, so they don't have to parse anything.
+///
+/// # Arguments
+///
+/// * `id` - The correlation id of the request, attached to every log line
+/// * `env` - Environment variables
+/// * `services` - The stateful subsystems available to handlers
+/// * `handlers` - A reference to the collection of User created handlers, indexed by their uris
+/// * `handler_addr` - The address of the handler the workflow invokes
+/// * `post_data` - The variables sent by the workflow
+#[post("/workflow/<handler_addr>", data = "<post_data>")]
+pub fn workflow_webhook(
+    id: CorrelationId,
+    env: State<EnvInfo>,
+    services: State<Services>,
+    handlers: Collection<String, Handler>,
+    handler_addr: String,
+    post_data: String,
+) -> Json<UserResponse> {
+    // Workflows may send nothing at all if no variables were added
+    let body = if post_data.trim().is_empty() {
+        Value::Object(Default::default())
+    } else {
+        match serde_json::from_str::<Value>(&post_data) {
+            Ok(body) if body.is_object() => body,
+            _ => return Json(UserResponse::failure("Expected a json object".into())),
+        }
+    };
+
+    let mut vars = BTreeMap::new();
+    flatten("", &body, &mut vars);
+
+    let guard = handlers.read().unwrap();
+    let handler = match guard.deref().get(&handler_addr) {
+        Some(handler) => handler,
+        None => {
+            let cause = format!("Unable to find endpoint {}", handler_addr);
+            return Json(UserResponse::failure(cause));
+        }
+    };
+
+    let payload = serde_json::to_string(&vars).unwrap_or_default();
+    let context = vars
+        .into_iter()
+        .map(|(k, v)| (k.into(), Dynamic::from(v)))
+        .collect::<Map>();
+
+    match run_handler(
+        &env,
+        &services,
+        &id,
+        &handler_addr,
+        handler,
+        payload,
+        Some(context),
+    ) {
+        Ok(res) => Json(UserResponse::success_with_data(res)),
+        Err(e) => {
+            log_event!(
+                "workflow.handler_error",
+                id = id.0,
+                handler = handler_addr,
+                error = e
+            );
+            Json(UserResponse::failure("Error running client code!".into()))
+        }
+    }
+}